@@ -6,6 +6,8 @@ use std::sync::Arc;
 use std::sync::Mutex;
 use std::sync::RwLock;
 
+#[cfg(not(target_arch = "wasm32"))]
+mod osc;
 mod synth;
 mod ui;
 
@@ -31,6 +33,8 @@ impl AudioEngine {
         audio_config: &synth::AudioConfig,
         plan: Arc<Mutex<Vec<synth::SharedSynthModule>>>,
         output: Arc<Mutex<Option<synth::SharedSynthModule>>>,
+        midi_mappings: Arc<Mutex<synth::midi_learn::MidiMappings>>,
+        faults: Arc<Mutex<synth::Faults>>,
         mut ctx: Option<egui::Context>,
     ) -> Self {
         let host = cpal::default_host();
@@ -58,12 +62,16 @@ impl AudioEngine {
                 },
                 move |data: &mut [f32], _: &cpal::OutputCallbackInfo| {
                     let plan = plan.lock().unwrap();
+                    let mut repaint = false;
                     for out_idx in 0..data.len() {
                         if src_buf_idx == 0 && out_idx % channels == 0 {
-                            synth::execute(&plan);
+                            repaint |= midi_mappings.lock().unwrap().apply();
+                            repaint |= synth::execute(&plan, &faults);
                             let output_mutex = output.lock().unwrap();
                             if let Some(output_mutex_value) = output_mutex.as_ref() {
-                                let module = output_mutex_value.read().unwrap();
+                                let module = output_mutex_value
+                                    .read()
+                                    .unwrap_or_else(std::sync::PoisonError::into_inner);
                                 let output_module = module
                                     .as_any()
                                     .downcast_ref::<synth::output::OutputModule>()
@@ -84,7 +92,7 @@ impl AudioEngine {
                         }
                     }
 
-                    if ctx.is_some() && synth::ui_dirty(&plan) {
+                    if ctx.is_some() && (repaint || synth::ui_dirty(&plan)) {
                         ctx.as_mut().unwrap().request_repaint();
                     }
                 },
@@ -105,6 +113,12 @@ struct SRackApp {
     audio_config: synth::AudioConfig,
     audio_engine: Option<AudioEngine>,
     web: bool,
+    show_midi_mappings: bool,
+    #[cfg(not(target_arch = "wasm32"))]
+    osc_server: Option<osc::OscServer>,
+    /// Address to listen for OSC on, as typed
+    #[cfg(not(target_arch = "wasm32"))]
+    osc_addr: String,
 }
 
 impl SRackApp {
@@ -118,6 +132,11 @@ impl SRackApp {
             },
             audio_engine: None,
             web,
+            show_midi_mappings: false,
+            #[cfg(not(target_arch = "wasm32"))]
+            osc_server: None,
+            #[cfg(not(target_arch = "wasm32"))]
+            osc_addr: osc::DEFAULT_ADDR.to_string(),
         }
     }
 }
@@ -134,6 +153,8 @@ impl eframe::App for SRackApp {
                 &self.audio_config,
                 self.workspace.get_plan(),
                 self.workspace.get_output(),
+                self.workspace.get_midi_mappings(),
+                self.workspace.get_faults(),
                 Some(ctx.clone()),
             ));
         }
@@ -161,8 +182,58 @@ impl eframe::App for SRackApp {
                         }
                     }
                 });
+                ui.menu_button("MIDI", |ui| {
+                    if ui.button("Mappings").clicked() {
+                        self.show_midi_mappings = !self.show_midi_mappings;
+                        ui.close_menu();
+                    }
+                });
+                #[cfg(not(target_arch = "wasm32"))]
+                ui.menu_button("OSC", |ui| {
+                    let mut listening = self.osc_server.is_some();
+                    ui.horizontal(|ui| {
+                        ui.label("Address");
+                        ui.add_enabled(
+                            !listening,
+                            egui::TextEdit::singleline(&mut self.osc_addr).desired_width(120.0),
+                        )
+                        .on_hover_text(
+                            "Anyone who can reach this address can change the patch. \
+                             Use 0.0.0.0 to listen on every network.",
+                        );
+                    });
+                    let label = match self.osc_server.as_ref() {
+                        Some(server) => format!("Listening on UDP {}", server.addr()),
+                        None => "Listen on UDP".to_string(),
+                    };
+                    if ui.checkbox(&mut listening, label).changed() {
+                        self.osc_server = if listening {
+                            self.osc_addr
+                                .trim()
+                                .parse()
+                                .map_err(|err| println!("Invalid OSC address: {}", err))
+                                .ok()
+                                .and_then(|addr| {
+                                    osc::OscServer::start(
+                                        self.workspace.clone(),
+                                        addr,
+                                        Some(ctx.clone()),
+                                    )
+                                    .map_err(|err| println!("Could not start OSC server: {}", err))
+                                    .ok()
+                                })
+                        } else {
+                            None
+                        };
+                    }
+                });
             });
         });
+        egui::Window::new("MIDI mappings")
+            .open(&mut self.show_midi_mappings)
+            .show(ctx, |ui| {
+                self.workspace.midi_mappings_ui(ui);
+            });
         egui::CentralPanel::default().show(ctx, |ui| {
             self.workspace.ui(ui);
         });
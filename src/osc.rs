@@ -0,0 +1,492 @@
+use crate::synth::SharedSynthModule;
+use crate::ui::SynthModuleWorkspace;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, PoisonError};
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+/// Only reachable from this machine, as anyone who can send to the server can
+/// rewire the patch
+pub const DEFAULT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 9000));
+/// How long the listener waits for a packet before checking if it should stop
+const POLL: Duration = Duration::from_millis(100);
+const BUNDLE: &[u8] = b"#bundle\0";
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OscArg {
+    Int(i32),
+    Float(f32),
+    Double(f64),
+    Str(String),
+    Bool(bool),
+}
+
+impl OscArg {
+    fn as_f32(&self) -> Option<f32> {
+        match self {
+            OscArg::Int(value) => Some(*value as f32),
+            OscArg::Float(value) => Some(*value),
+            OscArg::Double(value) => Some(*value as f32),
+            OscArg::Bool(value) => Some(if *value { 1.0 } else { 0.0 }),
+            OscArg::Str(_) => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            OscArg::Str(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Port number, which some controllers can only send as a float
+    fn as_port(&self) -> Option<u8> {
+        self.as_f32()
+            .filter(|value| value.fract() == 0.0 && (0.0..=255.0).contains(value))
+            .map(|value| value as u8)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct OscMessage {
+    pub address: String,
+    pub args: Vec<OscArg>,
+}
+
+impl OscMessage {
+    pub fn new(address: &str, args: Vec<OscArg>) -> Self {
+        Self {
+            address: address.to_string(),
+            args,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut buf = vec![];
+        push_str(&mut buf, &self.address);
+        let mut tags = ",".to_string();
+        for arg in self.args.iter() {
+            tags.push(match arg {
+                OscArg::Int(_) => 'i',
+                OscArg::Float(_) => 'f',
+                OscArg::Double(_) => 'd',
+                OscArg::Str(_) => 's',
+                OscArg::Bool(true) => 'T',
+                OscArg::Bool(false) => 'F',
+            });
+        }
+        push_str(&mut buf, &tags);
+        for arg in self.args.iter() {
+            match arg {
+                OscArg::Int(value) => buf.extend(value.to_be_bytes()),
+                OscArg::Float(value) => buf.extend(value.to_be_bytes()),
+                OscArg::Double(value) => buf.extend(value.to_be_bytes()),
+                OscArg::Str(value) => push_str(&mut buf, value),
+                OscArg::Bool(_) => (),
+            }
+        }
+        buf
+    }
+
+    fn decode(packet: &[u8]) -> Result<Self, ()> {
+        let mut reader = Reader(packet);
+        let address = reader.str()?;
+        if !address.starts_with('/') {
+            return Err(());
+        }
+        // a missing type tag string is allowed by older senders
+        let tags = if reader.0.is_empty() {
+            ",".to_string()
+        } else {
+            reader.str()?
+        };
+        let mut args = vec![];
+        for tag in tags.strip_prefix(',').ok_or(())?.chars() {
+            args.push(match tag {
+                'i' => OscArg::Int(i32::from_be_bytes(reader.take()?)),
+                'f' => OscArg::Float(f32::from_be_bytes(reader.take()?)),
+                'd' => OscArg::Double(f64::from_be_bytes(reader.take()?)),
+                's' => OscArg::Str(reader.str()?),
+                'T' => OscArg::Bool(true),
+                'F' => OscArg::Bool(false),
+                _ => return Err(()),
+            });
+        }
+        Ok(Self { address, args })
+    }
+}
+
+/// Null terminated and padded to a multiple of 4 bytes
+fn push_str(buf: &mut Vec<u8>, value: &str) {
+    buf.extend(value.as_bytes());
+    buf.extend(std::iter::repeat_n(0, 4 - value.len() % 4));
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl Reader<'_> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ()> {
+        let (bytes, rest) = self.0.split_first_chunk::<N>().ok_or(())?;
+        self.0 = rest;
+        Ok(*bytes)
+    }
+
+    fn str(&mut self) -> Result<String, ()> {
+        let len = self.0.iter().position(|byte| *byte == 0).ok_or(())?;
+        let value = std::str::from_utf8(&self.0[..len]).map_err(|_| ())?;
+        let padded = (len / 4 + 1) * 4;
+        let value = value.to_string();
+        self.0 = self.0.get(padded..).ok_or(())?;
+        Ok(value)
+    }
+}
+
+/// The messages in a packet, unpacking bundles. Time tags are ignored and
+/// bundled messages handled right away.
+pub fn decode(packet: &[u8]) -> Result<Vec<OscMessage>, ()> {
+    let Some(mut rest) = packet.strip_prefix(BUNDLE) else {
+        return Ok(vec![OscMessage::decode(packet)?]);
+    };
+    let mut reader = Reader(rest);
+    reader.take::<8>()?;
+    rest = reader.0;
+    let mut messages = vec![];
+    while !rest.is_empty() {
+        let mut reader = Reader(rest);
+        let len = i32::from_be_bytes(reader.take()?);
+        let len = usize::try_from(len).map_err(|_| ())?;
+        let element = reader.0.get(..len).ok_or(())?;
+        messages.extend(decode(element)?);
+        rest = &reader.0[len..];
+    }
+    Ok(messages)
+}
+
+/// Carry out a message on the workspace, returning any replies.
+///
+/// - `/modules` replies with `/module <id> <name>` for each module
+/// - `/module/<id>/params` replies with `/module/<id>/param/<param> <value>
+///   <min> <max>` for each parameter
+/// - `/module/<id>/param/<param> <value>` sets a parameter, clamped to its
+///   range, or replies with its value when sent without one
+/// - `/connect <src id> <src port> <sink id> <sink port>`
+/// - `/disconnect <sink id> <sink port>`
+pub fn handle(
+    workspace: &SynthModuleWorkspace,
+    message: &OscMessage,
+) -> Result<Vec<OscMessage>, ()> {
+    let parts: Vec<&str> = message.address.split('/').skip(1).collect();
+    let args = &message.args;
+    match parts.as_slice() {
+        ["modules"] => Ok(workspace
+            .get_modules()
+            .iter()
+            .map(|module| {
+                let module = module.read().unwrap_or_else(PoisonError::into_inner);
+                OscMessage::new(
+                    "/module",
+                    vec![OscArg::Str(module.get_id()), OscArg::Str(module.get_name())],
+                )
+            })
+            .collect()),
+        ["module", id, "params"] => {
+            let module = workspace.find_module(id).ok_or(())?;
+            let mut module = module.write().unwrap_or_else(PoisonError::into_inner);
+            Ok(module
+                .params()
+                .into_iter()
+                .map(|(param, value)| {
+                    OscMessage::new(
+                        &format!("/module/{}/param/{}", id, param.id),
+                        vec![
+                            OscArg::Float(value.get()),
+                            OscArg::Float(param.min),
+                            OscArg::Float(param.max),
+                        ],
+                    )
+                })
+                .collect())
+        }
+        ["module", id, "param", param_id] => {
+            let module = workspace.find_module(id).ok_or(())?;
+            let mut module = module.write().unwrap_or_else(PoisonError::into_inner);
+            let (param, mut value) = module
+                .params()
+                .into_iter()
+                .find(|(param, _)| param.id == *param_id)
+                .ok_or(())?;
+            match args.first() {
+                Some(arg) => {
+                    let new = arg.as_f32().ok_or(())?;
+                    value.set(new.clamp(param.min, param.max));
+                    Ok(vec![])
+                }
+                None => Ok(vec![OscMessage::new(
+                    &message.address,
+                    vec![OscArg::Float(value.get())],
+                )]),
+            }
+        }
+        ["connect"] => {
+            let [src_id, src_port, sink_id, sink_port] = args.as_slice() else {
+                return Err(());
+            };
+            let src = find(workspace, src_id)?;
+            let sink = find(workspace, sink_id)?;
+            workspace.connect(
+                &src,
+                src_port.as_port().ok_or(())?,
+                &sink,
+                sink_port.as_port().ok_or(())?,
+            )?;
+            Ok(vec![])
+        }
+        ["disconnect"] => {
+            let [sink_id, sink_port] = args.as_slice() else {
+                return Err(());
+            };
+            let sink = find(workspace, sink_id)?;
+            workspace.disconnect(&sink, sink_port.as_port().ok_or(())?)?;
+            Ok(vec![])
+        }
+        _ => Err(()),
+    }
+}
+
+fn find(workspace: &SynthModuleWorkspace, id: &OscArg) -> Result<SharedSynthModule, ()> {
+    workspace.find_module(id.as_str().ok_or(())?).ok_or(())
+}
+
+/// Listens for OSC messages on a UDP address, and carries them out on the
+/// workspace until dropped
+pub struct OscServer {
+    addr: SocketAddr,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl OscServer {
+    pub fn start(
+        workspace: SynthModuleWorkspace,
+        addr: SocketAddr,
+        ctx: Option<egui::Context>,
+    ) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_read_timeout(Some(POLL))?;
+        let addr = socket.local_addr()?;
+        let running = Arc::new(AtomicBool::new(true));
+        let thread = {
+            let running = running.clone();
+            std::thread::spawn(move || {
+                let mut buf = [0; 65536];
+                while running.load(Ordering::Relaxed) {
+                    let Ok((len, sender)) = socket.recv_from(&mut buf) else {
+                        continue;
+                    };
+                    let Ok(messages) = decode(&buf[..len]) else {
+                        continue;
+                    };
+                    for message in messages.iter() {
+                        for reply in handle(&workspace, message).unwrap_or_default() {
+                            let _ = socket.send_to(&reply.encode(), sender);
+                        }
+                    }
+                    if let Some(ctx) = ctx.as_ref() {
+                        ctx.request_repaint();
+                    }
+                }
+            })
+        };
+        Ok(Self {
+            addr,
+            running,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+}
+
+impl Drop for OscServer {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{AudioConfig, get_catalog};
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 16,
+        channels: 2,
+    };
+
+    fn add(workspace: &SynthModuleWorkspace, name: &str) -> SharedSynthModule {
+        let (_, construct) = get_catalog()
+            .into_iter()
+            .find(|(catalog_name, _)| catalog_name == name)
+            .unwrap();
+        let module = construct(&CONFIG);
+        workspace.add_module(module.clone());
+        module
+    }
+
+    fn request(socket: &UdpSocket, addr: SocketAddr, message: OscMessage) -> Vec<OscMessage> {
+        socket.send_to(&message.encode(), addr).unwrap();
+        let mut replies = vec![];
+        let mut buf = [0; 1024];
+        while let Ok(len) = socket.recv(&mut buf) {
+            replies.extend(decode(&buf[..len]).unwrap());
+        }
+        replies
+    }
+
+    #[test]
+    fn encodes_and_decodes_messages() {
+        let message = OscMessage::new(
+            "/module/abc/param/freq",
+            vec![
+                OscArg::Float(0.5),
+                OscArg::Int(-3),
+                OscArg::Str("four".to_string()),
+                OscArg::Bool(true),
+                OscArg::Double(0.25),
+            ],
+        );
+        let encoded = message.encode();
+        assert_eq!(encoded.len() % 4, 0);
+        assert_eq!(&encoded[..24], b"/module/abc/param/freq\0\0");
+        assert_eq!(decode(&encoded), Ok(vec![message.clone()]));
+
+        let mut bundle = BUNDLE.to_vec();
+        bundle.extend([0, 0, 0, 0, 0, 0, 0, 1]);
+        for _ in 0..2 {
+            bundle.extend((encoded.len() as i32).to_be_bytes());
+            bundle.extend(&encoded);
+        }
+        assert_eq!(decode(&bundle), Ok(vec![message.clone(), message]));
+
+        assert_eq!(decode(b"/abc"), Err(()));
+        assert_eq!(decode(b"nope\0\0\0\0,\0\0\0"), Err(()));
+    }
+
+    #[test]
+    fn controls_workspace_over_udp() {
+        let workspace = SynthModuleWorkspace::new();
+        let distortion = add(&workspace, "Distortion");
+        let comparator = add(&workspace, "Comparator");
+        let distortion_id = distortion.read().unwrap().get_id();
+        let comparator_id = comparator.read().unwrap().get_id();
+
+        let server =
+            OscServer::start(workspace.clone(), "127.0.0.1:0".parse().unwrap(), None).unwrap();
+        assert!(server.addr().ip().is_loopback());
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(300)))
+            .unwrap();
+
+        let modules = request(&socket, server.addr(), OscMessage::new("/modules", vec![]));
+        assert_eq!(
+            modules,
+            vec![
+                OscMessage::new(
+                    "/module",
+                    vec![
+                        OscArg::Str(distortion_id.clone()),
+                        OscArg::Str("Distortion".to_string())
+                    ]
+                ),
+                OscMessage::new(
+                    "/module",
+                    vec![
+                        OscArg::Str(comparator_id.clone()),
+                        OscArg::Str("Comparator".to_string())
+                    ]
+                ),
+            ]
+        );
+
+        // values are clamped to the parameter's range
+        let threshold = format!("/module/{}/param/threshold", comparator_id);
+        request(
+            &socket,
+            server.addr(),
+            OscMessage::new(&threshold, vec![OscArg::Float(5.0)]),
+        );
+        assert_eq!(
+            request(&socket, server.addr(), OscMessage::new(&threshold, vec![])),
+            vec![OscMessage::new(&threshold, vec![OscArg::Float(2.0)])]
+        );
+        let params = request(
+            &socket,
+            server.addr(),
+            OscMessage::new(&format!("/module/{}/params", comparator_id), vec![]),
+        );
+        assert_eq!(params.len(), 2);
+        assert_eq!(params[0].address, threshold);
+
+        request(
+            &socket,
+            server.addr(),
+            OscMessage::new(
+                "/connect",
+                vec![
+                    OscArg::Str(distortion_id.clone()),
+                    OscArg::Float(0.0),
+                    OscArg::Str(comparator_id.clone()),
+                    OscArg::Int(1),
+                ],
+            ),
+        );
+        let (src, port) = comparator.read().unwrap().get_input(1).unwrap().unwrap();
+        assert!(crate::synth::shared_are_eq(&src, &distortion));
+        assert_eq!(port, 0);
+
+        request(
+            &socket,
+            server.addr(),
+            OscMessage::new(
+                "/disconnect",
+                vec![OscArg::Str(comparator_id.clone()), OscArg::Int(1)],
+            ),
+        );
+        assert!(comparator.read().unwrap().get_input(1).unwrap().is_none());
+    }
+
+    #[test]
+    fn handles_poisoned_modules() {
+        let workspace = SynthModuleWorkspace::new();
+        let comparator = add(&workspace, "Comparator");
+        let id = comparator.read().unwrap().get_id();
+        let poisoner = comparator.clone();
+        let _ = std::thread::spawn(move || {
+            let _locked = poisoner.write().unwrap();
+            panic!("poison the module");
+        })
+        .join();
+        assert!(comparator.is_poisoned());
+
+        let replies = handle(&workspace, &OscMessage::new("/modules", vec![])).unwrap();
+        assert_eq!(replies.len(), 1);
+        let threshold = format!("/module/{}/param/threshold", id);
+        handle(
+            &workspace,
+            &OscMessage::new(&threshold, vec![OscArg::Float(1.0)]),
+        )
+        .unwrap();
+        assert_eq!(
+            handle(&workspace, &OscMessage::new(&threshold, vec![])),
+            Ok(vec![OscMessage::new(&threshold, vec![OscArg::Float(1.0)])])
+        );
+    }
+}
@@ -1,21 +1,38 @@
 mod adsr;
+mod chords;
+mod controller;
+mod decode;
+mod distortion;
+mod drums;
+mod euclidean;
 mod filter;
 mod freeverb;
+mod generative;
+mod keyboard;
+mod logic;
 mod math;
-mod mixer;
+mod midi;
+mod midi_io;
+pub mod midi_learn;
+pub mod mixer;
 mod oscillator;
 pub mod output;
+mod quantizer;
 mod sample;
 mod sequencer;
+mod tuning;
+mod utility;
 mod vca;
 
 use by_address::ByAddress;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::cell::Cell;
 use std::collections::{HashMap, HashSet};
 use std::ops::DerefMut;
+use std::panic::{self, AssertUnwindSafe};
 use std::sync::Arc;
-use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 #[derive(Clone)]
 pub struct AudioConfig {
@@ -34,7 +51,12 @@ impl AudioBuffer {
 
     fn resize(&mut self, size: usize) {
         if self.0.is_some() {
-            let locked = self.0.as_ref().unwrap().read().unwrap();
+            let locked = self
+                .0
+                .as_ref()
+                .unwrap()
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
             if locked.len() == size {
                 return;
             }
@@ -45,14 +67,14 @@ impl AudioBuffer {
 
     fn get(&self) -> Option<RwLockReadGuard<Box<[ControlVoltage]>>> {
         if let Some(arc) = &self.0 {
-            return Some(arc.read().unwrap());
+            return Some(arc.read().unwrap_or_else(PoisonError::into_inner));
         }
         None
     }
 
     fn get_mut(&self) -> Option<RwLockWriteGuard<Box<[ControlVoltage]>>> {
         if let Some(arc) = &self.0 {
-            return Some(arc.write().unwrap());
+            return Some(arc.write().unwrap_or_else(PoisonError::into_inner));
         }
         None
     }
@@ -94,14 +116,74 @@ impl AudioBuffer {
     }
 }
 
-pub fn execute(plan: &Vec<SharedSynthModule>) {
+/// Modules disabled after panicking, by ID, with the error message
+#[derive(Default)]
+pub struct Faults(HashMap<String, String>);
+
+impl Faults {
+    pub fn get(&self, module_id: &str) -> Option<&String> {
+        self.0.get(module_id)
+    }
+
+    /// Run the module again from the next buffer
+    pub fn clear(&mut self, module_id: &str) {
+        self.0.remove(module_id);
+    }
+
+    /// Forget every fault, for when the modules are replaced
+    pub fn clear_all(&mut self) {
+        self.0.clear();
+    }
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Unknown error".to_string())
+}
+
+/// Calculate the modules in the plan. A module which panics, or whose lock
+/// was poisoned by a panic elsewhere, is disabled with its outputs zeroed
+/// until its fault is cleared, and the rest of the patch keeps running.
+/// Returns true when a module was disabled.
+pub fn execute(plan: &Vec<SharedSynthModule>, faults: &Mutex<Faults>) -> bool {
+    let lock_faults = || faults.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut disabled = false;
     for ssm in plan {
-        ssm.write().unwrap().calc();
+        let mut module = ssm.write().unwrap_or_else(|poisoned| {
+            ssm.clear_poison();
+            let module = poisoned.into_inner();
+            lock_faults().0.insert(
+                module.get_id(),
+                "Lock poisoned by a panic elsewhere".to_string(),
+            );
+            disabled = true;
+            module
+        });
+        let id = module.get_id();
+        if lock_faults().get(&id).is_none() {
+            match panic::catch_unwind(AssertUnwindSafe(|| module.calc())) {
+                Ok(()) => continue,
+                Err(payload) => {
+                    lock_faults().0.insert(id, panic_message(payload));
+                    disabled = true;
+                }
+            }
+        }
+        module.zero_outputs();
     }
+    disabled
 }
 
 pub fn ui_dirty(plan: &Vec<SharedSynthModule>) -> bool {
-    plan.iter().any(|module| module.read().unwrap().ui_dirty())
+    plan.iter().any(|module| {
+        module
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .ui_dirty()
+    })
 }
 
 fn is_loop(
@@ -219,6 +301,80 @@ pub fn get_inputs(module: &dyn SynthModule) -> Vec<Option<(SharedSynthModule, u8
 
 type ControlVoltage = f32;
 
+/// A control of a module which can be set from outside its UI, addressed by
+/// an id unique within the module
+#[derive(Clone, Debug, PartialEq)]
+pub struct Param {
+    pub id: String,
+    pub label: String,
+    pub min: f32,
+    pub max: f32,
+}
+
+impl Param {
+    fn new(id: &str, label: &str, range: std::ops::RangeInclusive<f32>) -> Self {
+        Self {
+            id: id.to_string(),
+            label: label.to_string(),
+            min: *range.start(),
+            max: *range.end(),
+        }
+    }
+}
+
+/// The value a parameter sets
+pub enum ParamValue<'a> {
+    F32(&'a mut f32),
+    F64(&'a mut f64),
+    /// Whole numbers, set to the nearest
+    U8(&'a mut u8),
+    I32(&'a mut i32),
+    I64(&'a mut i64),
+    /// Part of a value above `base`, such as the note within an octave of a
+    /// pitch, rounded to multiples of `step` when it isn't 0. Shared, so
+    /// several parameters can set parts of the same value.
+    Offset {
+        value: &'a Cell<f32>,
+        base: f32,
+        step: f32,
+    },
+}
+
+impl ParamValue<'_> {
+    pub fn get(&self) -> f32 {
+        match self {
+            ParamValue::F32(value) => **value,
+            ParamValue::F64(value) => **value as f32,
+            ParamValue::U8(value) => **value as f32,
+            ParamValue::I32(value) => **value as f32,
+            ParamValue::I64(value) => **value as f32,
+            ParamValue::Offset { value, base, .. } => value.get() - *base,
+        }
+    }
+
+    pub fn set(&mut self, value: f32) {
+        match self {
+            ParamValue::F32(param) => **param = value,
+            ParamValue::F64(param) => **param = value as f64,
+            ParamValue::U8(param) => **param = value.round() as u8,
+            ParamValue::I32(param) => **param = value.round() as i32,
+            ParamValue::I64(param) => **param = value.round() as i64,
+            ParamValue::Offset {
+                value: param,
+                base,
+                step,
+            } => {
+                let offset = if *step > 0.0 {
+                    (value / *step).round() * *step
+                } else {
+                    value
+                };
+                param.set(*base + offset);
+            }
+        }
+    }
+}
+
 pub trait SynthModule: Any {
     fn get_id(&self) -> String;
     fn get_name(&self) -> String;
@@ -248,10 +404,21 @@ pub trait SynthModule: Any {
     #[inline]
     fn resolve_input(&self, input_idx: u8) -> Result<AudioBuffer, ()> {
         match self.get_input(input_idx)? {
-            Some((src_module, src_port)) => Ok(src_module.read().unwrap().get_output(src_port)?),
+            Some((src_module, src_port)) => Ok(src_module
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_output(src_port)?),
             None => Ok(AudioBuffer::new(None)),
         }
     }
+    /// Silence the outputs, such as after the module was disabled
+    fn zero_outputs(&self) {
+        for idx in 0..self.get_num_outputs() {
+            if let Ok(buf) = self.get_output(idx) {
+                buf.with_write(|buf| buf.map(|buf| buf.fill(0.0)));
+            }
+        }
+    }
     fn ui(&mut self, _ui: &mut egui::Ui) {}
     /// Return true when this module needs to be re-displayed
     fn ui_dirty(&self) -> bool {
@@ -260,6 +427,17 @@ pub trait SynthModule: Any {
     /// Change the audio configuration. Used after deserialize.
     fn set_audio_config(&mut self, audio_config: &AudioConfig);
     fn as_any(&self) -> &dyn Any;
+    /// Controls which can be set from outside the UI, such as by MIDI, along
+    /// with the values they set
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![]
+    }
+    /// The value set by the parameter at `idx` in [`SynthModule::params`].
+    /// Called on the audio thread, so modules with parameters override it to
+    /// avoid building the whole list.
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        self.params().into_iter().nth(idx).map(|(_, value)| value)
+    }
 }
 impl PartialEq for dyn SynthModule {
     fn eq(&self, other: &Self) -> bool {
@@ -297,23 +475,243 @@ impl TransitionDetector {
     }
 }
 
+/// How long parameters glide to a new value by default
+const SMOOTHING_SEC: f32 = 0.02;
+/// Sample rate assumed until a smoother is given one, such as after loading
+const DEFAULT_SAMPLE_RATE: u16 = 48000;
+
+#[derive(Clone, Copy, PartialEq, Default)]
+pub enum Ramp {
+    /// Exponential approach, quick at first and within 1% after the smoothing time
+    #[default]
+    OnePole,
+    /// Constant rate, arriving after exactly the smoothing time
+    Linear,
+}
+
+/// Glides a parameter to each new value it's set to, one sample at a time, so
+/// moving a control doesn't step the output and make zipper noise.
+///
+/// Call `next` with the parameter's value for every sample. The first call
+/// starts at that value.
+#[derive(Clone)]
+pub struct Smoother {
+    ramp: Ramp,
+    time: f32,
+    sample_rate: u16,
+    coefficient: f32,
+    value: Option<f32>,
+    target: f32,
+    step: f32,
+    remaining: u32,
+}
+
+impl Default for Smoother {
+    fn default() -> Self {
+        Self::new(DEFAULT_SAMPLE_RATE)
+    }
+}
+
+impl Smoother {
+    pub fn new(sample_rate: u16) -> Self {
+        let mut smoother = Self {
+            ramp: Ramp::OnePole,
+            time: SMOOTHING_SEC,
+            sample_rate,
+            coefficient: 0.0,
+            value: None,
+            target: 0.0,
+            step: 0.0,
+            remaining: 0,
+        };
+        smoother.update_coefficient();
+        smoother
+    }
+
+    pub fn with_ramp(mut self, ramp: Ramp) -> Self {
+        self.ramp = ramp;
+        self
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u16) {
+        self.sample_rate = sample_rate;
+        self.update_coefficient();
+    }
+
+    /// Seconds to glide to a new value, with 0 jumping straight to it
+    pub fn set_time(&mut self, time: f32) {
+        if time != self.time {
+            self.time = time;
+            self.update_coefficient();
+        }
+    }
+
+    fn ramp_samples(&self) -> u32 {
+        (self.time * self.sample_rate as f32) as u32
+    }
+
+    fn update_coefficient(&mut self) {
+        self.coefficient = match self.ramp_samples() {
+            0 => 0.0,
+            samples => (-4.6 / samples as f32).exp(),
+        };
+    }
+
+    /// The value for the next sample, gliding towards the target
+    pub fn next(&mut self, target: f32) -> f32 {
+        let Some(value) = self.value else {
+            self.value = Some(target);
+            self.target = target;
+            return target;
+        };
+        let value = match self.ramp {
+            Ramp::OnePole => {
+                let value = target + (value - target) * self.coefficient;
+                // settle exactly, rather than approaching forever
+                if (value - target).abs() <= f32::EPSILON * target.abs().max(1.0) {
+                    target
+                } else {
+                    value
+                }
+            }
+            Ramp::Linear => {
+                if target != self.target {
+                    self.target = target;
+                    self.remaining = self.ramp_samples().max(1);
+                    self.step = (target - value) / self.remaining as f32;
+                }
+                if self.remaining > 1 {
+                    self.remaining -= 1;
+                    value + self.step
+                } else {
+                    self.remaining = 0;
+                    target
+                }
+            }
+        };
+        self.value = Some(value);
+        value
+    }
+}
+
+/// Small random number generator which can be saved with a patch and restarted
+/// from its seed, so random patterns can be repeated.
+#[derive(Serialize, Deserialize, Clone)]
+struct SeededRng {
+    seed: u64,
+    state: u64,
+}
+
+impl SeededRng {
+    fn new(seed: u64) -> Self {
+        let mut rng = Self { seed, state: 0 };
+        rng.reset();
+        rng
+    }
+
+    /// Start the sequence over from the seed
+    fn reset(&mut self) {
+        // xorshift gets stuck at zero
+        self.state = self.seed ^ 0x9E37_79B9_7F4A_7C15;
+        if self.state == 0 {
+            self.state = 1;
+        }
+    }
+
+    fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+        self.reset();
+    }
+
+    /// xorshift64*
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+
+    /// Uniform in 0.0..1.0
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
+
+/// Shows a generator's seed to edit, and a button for a new random one
+fn seed_ui(ui: &mut egui::Ui, rng: &mut SeededRng) {
+    ui.horizontal(|ui| {
+        ui.label("Seed: ");
+        // A text field rather than a DragValue, which goes through f64 and
+        // can't hold every u64. The text is kept while editing so it can be
+        // invalid along the way.
+        let id = ui.make_persistent_id("seed");
+        let mut text = ui
+            .data_mut(|d| d.get_temp::<String>(id))
+            .unwrap_or_else(|| rng.seed.to_string());
+        let response = ui.add(egui::TextEdit::singleline(&mut text).desired_width(150.0));
+        if response.changed()
+            && let Ok(seed) = text.trim().parse()
+        {
+            rng.set_seed(seed);
+        }
+        if response.has_focus() {
+            ui.data_mut(|d| d.insert_temp(id, text));
+        } else {
+            ui.data_mut(|d| d.remove::<String>(id));
+        }
+        if ui.button("New").clicked() {
+            rng.set_seed(rand::random());
+        }
+    });
+}
+
 #[derive(Serialize, Deserialize)]
 pub enum SynthModuleType {
-    OutputModuleV0(output::OutputModule),
+    OutputModuleV0(output::OutputModuleV0),
+    OutputModuleV1(output::OutputModule),
     OscillatorModuleV0(oscillator::OscillatorModule),
     NoiseModuleV0(oscillator::NoiseModule),
     GridSequencerModuleV0(sequencer::GridSequencerModuleV0),
-    GridSequencerModuleV1(sequencer::GridSequencerModule),
-    PatternSequencerModuleV0(sequencer::PatternSequencerModule),
-    ADSRModuleV0(adsr::ADSRModule),
+    GridSequencerModuleV1(sequencer::GridSequencerModuleV1),
+    PatternSequencerModuleV0(sequencer::PatternSequencerModuleV0),
+    ADSRModuleV0(adsr::ADSRModuleV0),
+    ADSRModuleV1(adsr::ADSRModule),
     VCAModuleV0(vca::VCAModule),
     MoogFilterModuleV0(filter::MoogFilterModuleV0),
     MoogFilterModuleV1(filter::MoogFilterModule),
-    MonoMixerModuleV0(mixer::MonoMixerModule),
-    SampleModuleV0(sample::SampleModule),
-    MathModuleV0(math::MathModule),
+    MonoMixerModuleV0(mixer::MonoMixerModuleV0),
+    MonoMixerModuleV1(mixer::MonoMixerModule),
+    SampleModuleV0(sample::SampleModuleV0),
+    SampleModuleV1(sample::SampleModule),
+    MathModuleV0(math::MathModuleV0),
     NonLinearModuleV0(math::NonLinearModule),
     FreeverbModuleV0(freeverb::FreeverbModule),
+    StereoMixerModuleV0(mixer::StereoMixerModule),
+    GridSequencerModuleV2(sequencer::GridSequencerModuleV2),
+    QuantizerModuleV0(quantizer::QuantizerModule),
+    HoldModuleV0(utility::HoldModule),
+    SlewModuleV0(utility::SlewModule),
+    LogicModuleV0(logic::LogicModule),
+    ComparatorModuleV0(logic::ComparatorModule),
+    ClockDividerModuleV0(logic::ClockDividerModule),
+    BernoulliGateModuleV0(logic::BernoulliGateModule),
+    PulseStretcherModuleV0(logic::PulseStretcherModule),
+    MathModuleV1(math::MathModule),
+    DistortionModuleV0(distortion::DistortionModule),
+    DrumModuleV0(drums::DrumModule),
+    GridSequencerModuleV3(sequencer::GridSequencerModule),
+    PatternSequencerModuleV1(sequencer::PatternSequencerModuleV1),
+    PatternSequencerModuleV2(sequencer::PatternSequencerModule),
+    EuclideanModuleV0(euclidean::EuclideanModule),
+    TuringMachineModuleV0(generative::TuringMachineModule),
+    MarkovModuleV0(generative::MarkovModule),
+    ChordModuleV0(chords::ChordModule),
+    ArpeggiatorModuleV0(chords::ArpeggiatorModule),
+    MidiOutModuleV0(midi_io::MidiOutModule),
+    MidiClockOutModuleV0(midi_io::MidiClockOutModule),
+    MidiClockInModuleV0(midi_io::MidiClockInModule),
+    KeyboardModuleV0(keyboard::KeyboardModule),
+    ControllerModuleV0(controller::ControllerModule),
 }
 
 fn prep_for_serialization<T: SynthModule + Clone>(module: &T) -> T {
@@ -325,25 +723,67 @@ fn prep_for_serialization<T: SynthModule + Clone>(module: &T) -> T {
 /// Unpack a module from an enum which came from deserialization
 pub fn enum_to_sharedsynthmodule(synthmoduleenum: SynthModuleType) -> SharedSynthModule {
     match synthmoduleenum {
-        SynthModuleType::OutputModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::OutputModuleV0(m) => Arc::new(RwLock::new(output::OutputModule::from(m))),
+        SynthModuleType::OutputModuleV1(m) => Arc::new(RwLock::new(m)),
         SynthModuleType::OscillatorModuleV0(m) => Arc::new(RwLock::new(m)),
         SynthModuleType::NoiseModuleV0(m) => Arc::new(RwLock::new(m)),
         SynthModuleType::GridSequencerModuleV0(m) => {
-            Arc::new(RwLock::new(sequencer::GridSequencerModule::from(m)))
+            Arc::new(RwLock::new(sequencer::GridSequencerModule::from(
+                sequencer::GridSequencerModuleV2::from(sequencer::GridSequencerModuleV1::from(m)),
+            )))
         }
-        SynthModuleType::GridSequencerModuleV1(m) => Arc::new(RwLock::new(m)),
-        SynthModuleType::PatternSequencerModuleV0(m) => Arc::new(RwLock::new(m)),
-        SynthModuleType::ADSRModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::GridSequencerModuleV1(m) => Arc::new(RwLock::new(
+            sequencer::GridSequencerModule::from(sequencer::GridSequencerModuleV2::from(m)),
+        )),
+        SynthModuleType::PatternSequencerModuleV0(m) => Arc::new(RwLock::new(
+            sequencer::PatternSequencerModule::from(sequencer::PatternSequencerModuleV1::from(m)),
+        )),
+        SynthModuleType::ADSRModuleV0(m) => Arc::new(RwLock::new(adsr::ADSRModule::from(m))),
+        SynthModuleType::ADSRModuleV1(m) => Arc::new(RwLock::new(m)),
         SynthModuleType::VCAModuleV0(m) => Arc::new(RwLock::new(m)),
         SynthModuleType::MoogFilterModuleV0(m) => {
             Arc::new(RwLock::new(filter::MoogFilterModule::from(m)))
         }
         SynthModuleType::MoogFilterModuleV1(m) => Arc::new(RwLock::new(m)),
-        SynthModuleType::MonoMixerModuleV0(m) => Arc::new(RwLock::new(m)),
-        SynthModuleType::SampleModuleV0(m) => Arc::new(RwLock::new(m)),
-        SynthModuleType::MathModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MonoMixerModuleV0(m) => {
+            Arc::new(RwLock::new(mixer::MonoMixerModule::from(m)))
+        }
+        SynthModuleType::MonoMixerModuleV1(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::SampleModuleV0(m) => Arc::new(RwLock::new(sample::SampleModule::from(m))),
+        SynthModuleType::SampleModuleV1(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MathModuleV0(m) => Arc::new(RwLock::new(math::MathModule::from(m))),
         SynthModuleType::NonLinearModuleV0(m) => Arc::new(RwLock::new(m)),
         SynthModuleType::FreeverbModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::StereoMixerModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::GridSequencerModuleV2(m) => {
+            Arc::new(RwLock::new(sequencer::GridSequencerModule::from(m)))
+        }
+        SynthModuleType::QuantizerModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::HoldModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::SlewModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::LogicModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::ComparatorModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::ClockDividerModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::BernoulliGateModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::PulseStretcherModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MathModuleV1(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::DistortionModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::DrumModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::GridSequencerModuleV3(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::PatternSequencerModuleV1(m) => {
+            Arc::new(RwLock::new(sequencer::PatternSequencerModule::from(m)))
+        }
+        SynthModuleType::PatternSequencerModuleV2(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::EuclideanModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::TuringMachineModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MarkovModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::ChordModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::ArpeggiatorModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MidiOutModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MidiClockOutModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::MidiClockInModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::KeyboardModuleV0(m) => Arc::new(RwLock::new(m)),
+        SynthModuleType::ControllerModuleV0(m) => Arc::new(RwLock::new(m)),
     }
 }
 
@@ -351,7 +791,7 @@ pub fn enum_to_sharedsynthmodule(synthmoduleenum: SynthModuleType) -> SharedSynt
 pub fn any_module_to_enum(module: Box<&dyn SynthModule>) -> Result<SynthModuleType, ()> {
     let module = module.as_any();
     if let Some(module) = module.downcast_ref::<output::OutputModule>() {
-        return Ok(SynthModuleType::OutputModuleV0(prep_for_serialization(
+        return Ok(SynthModuleType::OutputModuleV1(prep_for_serialization(
             module,
         )));
     }
@@ -366,17 +806,107 @@ pub fn any_module_to_enum(module: Box<&dyn SynthModule>) -> Result<SynthModuleTy
         )));
     }
     if let Some(module) = module.downcast_ref::<sequencer::GridSequencerModule>() {
-        return Ok(SynthModuleType::GridSequencerModuleV1(
+        return Ok(SynthModuleType::GridSequencerModuleV3(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<quantizer::QuantizerModule>() {
+        return Ok(SynthModuleType::QuantizerModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<utility::HoldModule>() {
+        return Ok(SynthModuleType::HoldModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<utility::SlewModule>() {
+        return Ok(SynthModuleType::SlewModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<logic::LogicModule>() {
+        return Ok(SynthModuleType::LogicModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<logic::ComparatorModule>() {
+        return Ok(SynthModuleType::ComparatorModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<logic::ClockDividerModule>() {
+        return Ok(SynthModuleType::ClockDividerModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<logic::BernoulliGateModule>() {
+        return Ok(SynthModuleType::BernoulliGateModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<logic::PulseStretcherModule>() {
+        return Ok(SynthModuleType::PulseStretcherModuleV0(
             prep_for_serialization(module),
         ));
     }
     if let Some(module) = module.downcast_ref::<sequencer::PatternSequencerModule>() {
-        return Ok(SynthModuleType::PatternSequencerModuleV0(
+        return Ok(SynthModuleType::PatternSequencerModuleV2(
             prep_for_serialization(module),
         ));
     }
+    if let Some(module) = module.downcast_ref::<euclidean::EuclideanModule>() {
+        return Ok(SynthModuleType::EuclideanModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<generative::TuringMachineModule>() {
+        return Ok(SynthModuleType::TuringMachineModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<generative::MarkovModule>() {
+        return Ok(SynthModuleType::MarkovModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<chords::ChordModule>() {
+        return Ok(SynthModuleType::ChordModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<chords::ArpeggiatorModule>() {
+        return Ok(SynthModuleType::ArpeggiatorModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<midi_io::MidiOutModule>() {
+        return Ok(SynthModuleType::MidiOutModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<midi_io::MidiClockOutModule>() {
+        return Ok(SynthModuleType::MidiClockOutModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<midi_io::MidiClockInModule>() {
+        return Ok(SynthModuleType::MidiClockInModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
+    if let Some(module) = module.downcast_ref::<keyboard::KeyboardModule>() {
+        return Ok(SynthModuleType::KeyboardModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<controller::ControllerModule>() {
+        return Ok(SynthModuleType::ControllerModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
     if let Some(module) = module.downcast_ref::<adsr::ADSRModule>() {
-        return Ok(SynthModuleType::ADSRModuleV0(prep_for_serialization(
+        return Ok(SynthModuleType::ADSRModuleV1(prep_for_serialization(
             module,
         )));
     }
@@ -391,17 +921,32 @@ pub fn any_module_to_enum(module: Box<&dyn SynthModule>) -> Result<SynthModuleTy
         )));
     }
     if let Some(module) = module.downcast_ref::<mixer::MonoMixerModule>() {
-        return Ok(SynthModuleType::MonoMixerModuleV0(prep_for_serialization(
+        return Ok(SynthModuleType::MonoMixerModuleV1(prep_for_serialization(
             module,
         )));
     }
+    if let Some(module) = module.downcast_ref::<mixer::StereoMixerModule>() {
+        return Ok(SynthModuleType::StereoMixerModuleV0(
+            prep_for_serialization(module),
+        ));
+    }
     if let Some(module) = module.downcast_ref::<sample::SampleModule>() {
-        return Ok(SynthModuleType::SampleModuleV0(prep_for_serialization(
+        return Ok(SynthModuleType::SampleModuleV1(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<distortion::DistortionModule>() {
+        return Ok(SynthModuleType::DistortionModuleV0(prep_for_serialization(
+            module,
+        )));
+    }
+    if let Some(module) = module.downcast_ref::<drums::DrumModule>() {
+        return Ok(SynthModuleType::DrumModuleV0(prep_for_serialization(
             module,
         )));
     }
     if let Some(module) = module.downcast_ref::<math::MathModule>() {
-        return Ok(SynthModuleType::MathModuleV0(prep_for_serialization(
+        return Ok(SynthModuleType::MathModuleV1(prep_for_serialization(
             module,
         )));
     }
@@ -418,8 +963,11 @@ pub fn any_module_to_enum(module: Box<&dyn SynthModule>) -> Result<SynthModuleTy
     Err(())
 }
 
-pub fn get_catalog() -> Vec<(String, Box<dyn Fn(&AudioConfig) -> SharedSynthModule>)> {
-    vec![
+/// A module name and a way to make one
+pub type CatalogEntry = (String, Box<dyn Fn(&AudioConfig) -> SharedSynthModule>);
+
+pub fn get_catalog() -> Vec<CatalogEntry> {
+    let mut catalog: Vec<CatalogEntry> = vec![
         (
             oscillator::OscillatorModule::get_name(),
             Box::new(|audio_config| {
@@ -448,6 +996,122 @@ pub fn get_catalog() -> Vec<(String, Box<dyn Fn(&AudioConfig) -> SharedSynthModu
                 )))
             }),
         ),
+        (
+            euclidean::EuclideanModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(euclidean::EuclideanModule::new(audio_config)))
+            }),
+        ),
+        (
+            generative::TuringMachineModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(generative::TuringMachineModule::new(
+                    audio_config,
+                )))
+            }),
+        ),
+        (
+            generative::MarkovModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(generative::MarkovModule::new(audio_config)))
+            }),
+        ),
+        (
+            chords::ChordModule::get_name(),
+            Box::new(|audio_config| Arc::new(RwLock::new(chords::ChordModule::new(audio_config)))),
+        ),
+        (
+            chords::ArpeggiatorModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(chords::ArpeggiatorModule::new(audio_config)))
+            }),
+        ),
+        (
+            midi_io::MidiOutModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(midi_io::MidiOutModule::new(audio_config)))
+            }),
+        ),
+        (
+            midi_io::MidiClockOutModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(midi_io::MidiClockOutModule::new(audio_config)))
+            }),
+        ),
+        (
+            midi_io::MidiClockInModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(midi_io::MidiClockInModule::new(audio_config)))
+            }),
+        ),
+        (
+            keyboard::KeyboardModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(keyboard::KeyboardModule::new(audio_config)))
+            }),
+        ),
+        (
+            controller::ControllerModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(controller::ControllerModule::new(audio_config)))
+            }),
+        ),
+        (
+            quantizer::QuantizerModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(quantizer::QuantizerModule::new(audio_config)))
+            }),
+        ),
+        (
+            utility::HoldModule::get_name(&utility::HoldMode::Sample),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(utility::HoldModule::new(
+                    audio_config,
+                    utility::HoldMode::Sample,
+                )))
+            }),
+        ),
+        (
+            utility::HoldModule::get_name(&utility::HoldMode::Track),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(utility::HoldModule::new(
+                    audio_config,
+                    utility::HoldMode::Track,
+                )))
+            }),
+        ),
+        (
+            utility::SlewModule::get_name(),
+            Box::new(|audio_config| Arc::new(RwLock::new(utility::SlewModule::new(audio_config)))),
+        ),
+        (
+            logic::LogicModule::get_name(),
+            Box::new(|audio_config| Arc::new(RwLock::new(logic::LogicModule::new(audio_config)))),
+        ),
+        (
+            logic::ComparatorModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(logic::ComparatorModule::new(audio_config)))
+            }),
+        ),
+        (
+            logic::ClockDividerModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(logic::ClockDividerModule::new(audio_config)))
+            }),
+        ),
+        (
+            logic::BernoulliGateModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(logic::BernoulliGateModule::new(audio_config)))
+            }),
+        ),
+        (
+            logic::PulseStretcherModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(logic::PulseStretcherModule::new(audio_config)))
+            }),
+        ),
         (
             adsr::ADSRModule::get_name(),
             Box::new(|audio_config| Arc::new(RwLock::new(adsr::ADSRModule::new(audio_config)))),
@@ -468,41 +1132,66 @@ pub fn get_catalog() -> Vec<(String, Box<dyn Fn(&AudioConfig) -> SharedSynthModu
                 Arc::new(RwLock::new(mixer::MonoMixerModule::new(audio_config)))
             }),
         ),
+        (
+            mixer::StereoMixerModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(mixer::StereoMixerModule::new(audio_config)))
+            }),
+        ),
         (
             sample::SampleModule::get_name(),
             Box::new(|audio_config| Arc::new(RwLock::new(sample::SampleModule::new(audio_config)))),
         ),
         (
-            math::MathModule::get_name(&math::MathOperation::Add),
+            math::MathModule::get_name(),
+            Box::new(|audio_config| Arc::new(RwLock::new(math::MathModule::new(audio_config)))),
+        ),
+        (
+            math::NonLinearModule::get_name(),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(math::NonLinearModule::new(audio_config)))
+            }),
+        ),
+        (
+            distortion::DistortionModule::get_name(),
             Box::new(|audio_config| {
-                Arc::new(RwLock::new(math::MathModule::new(
+                Arc::new(RwLock::new(distortion::DistortionModule::new(audio_config)))
+            }),
+        ),
+        (
+            drums::DrumModule::get_name(&drums::DrumVoice::Kick),
+            Box::new(|audio_config| {
+                Arc::new(RwLock::new(drums::DrumModule::new(
                     audio_config,
-                    math::MathOperation::Add,
+                    drums::DrumVoice::Kick,
                 )))
             }),
         ),
         (
-            math::MathModule::get_name(&math::MathOperation::Subtract),
+            drums::DrumModule::get_name(&drums::DrumVoice::Snare),
             Box::new(|audio_config| {
-                Arc::new(RwLock::new(math::MathModule::new(
+                Arc::new(RwLock::new(drums::DrumModule::new(
                     audio_config,
-                    math::MathOperation::Subtract,
+                    drums::DrumVoice::Snare,
                 )))
             }),
         ),
         (
-            math::MathModule::get_name(&math::MathOperation::Multiply),
+            drums::DrumModule::get_name(&drums::DrumVoice::HiHat),
             Box::new(|audio_config| {
-                Arc::new(RwLock::new(math::MathModule::new(
+                Arc::new(RwLock::new(drums::DrumModule::new(
                     audio_config,
-                    math::MathOperation::Multiply,
+                    drums::DrumVoice::HiHat,
                 )))
             }),
         ),
         (
-            math::NonLinearModule::get_name(),
+            drums::DrumModule::get_name(&drums::DrumVoice::Clap),
             Box::new(|audio_config| {
-                Arc::new(RwLock::new(math::NonLinearModule::new(audio_config)))
+                Arc::new(RwLock::new(drums::DrumModule::new(
+                    audio_config,
+                    drums::DrumVoice::Clap,
+                )))
             }),
         ),
         (
@@ -511,7 +1200,17 @@ pub fn get_catalog() -> Vec<(String, Box<dyn Fn(&AudioConfig) -> SharedSynthModu
                 Arc::new(RwLock::new(freeverb::FreeverbModule::new(audio_config)))
             }),
         ),
-    ]
+    ];
+    // Without a MIDI backend these would do nothing, so leave them out
+    if !midi::available() {
+        let midi_modules = [
+            midi_io::MidiOutModule::get_name(),
+            midi_io::MidiClockOutModule::get_name(),
+            midi_io::MidiClockInModule::get_name(),
+        ];
+        catalog.retain(|(name, _)| !midi_modules.contains(name));
+    }
+    catalog
 }
 
 #[cfg(test)]
@@ -611,4 +1310,188 @@ mod tests {
             );
         }
     }
+
+    /// Fills its output with ones, then panics if told to
+    struct PanicModule {
+        id: String,
+        out: AudioBuffer,
+        panic: bool,
+    }
+
+    impl PanicModule {
+        fn shared(id: &str, panic: bool) -> Arc<RwLock<Self>> {
+            Arc::new(RwLock::new(Self {
+                id: id.to_string(),
+                out: AudioBuffer::new(Some(4)),
+                panic,
+            }))
+        }
+    }
+
+    impl SynthModule for PanicModule {
+        fn get_id(&self) -> String {
+            self.id.clone()
+        }
+
+        fn get_name(&self) -> String {
+            "Panic".to_string()
+        }
+
+        fn calc(&mut self) {
+            self.out.with_write(|buf| {
+                buf.unwrap().fill(1.0);
+                // while holding the buffer's lock, to poison it
+                assert!(!self.panic, "module failed");
+            });
+        }
+
+        fn get_num_inputs(&self) -> u8 {
+            0
+        }
+
+        fn get_num_outputs(&self) -> u8 {
+            1
+        }
+
+        fn get_input(&self, _input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+            Err(())
+        }
+
+        fn get_input_label(&self, _input_idx: u8) -> Result<Option<String>, ()> {
+            Err(())
+        }
+
+        fn get_output_label(&self, _output_idx: u8) -> Result<Option<String>, ()> {
+            Ok(None)
+        }
+
+        fn get_output(&self, _output_idx: u8) -> Result<AudioBuffer, ()> {
+            Ok(self.out.clone())
+        }
+
+        fn set_input(
+            &mut self,
+            _input_idx: u8,
+            _src_module: SharedSynthModule,
+            _src_port: u8,
+        ) -> Result<(), ()> {
+            Err(())
+        }
+
+        fn disconnect_input(&mut self, _input_idx: u8) -> Result<(), ()> {
+            Err(())
+        }
+
+        fn set_audio_config(&mut self, _audio_config: &AudioConfig) {}
+
+        fn as_any(&self) -> &dyn Any {
+            self
+        }
+    }
+
+    fn output_of(module: &SharedSynthModule) -> Vec<ControlVoltage> {
+        module
+            .read()
+            .unwrap()
+            .get_output(0)
+            .unwrap()
+            .with_read(|buf| buf.unwrap().to_vec())
+    }
+
+    #[test]
+    fn disables_panicking_module() {
+        let failing = PanicModule::shared("failing", true);
+        let plan: Vec<SharedSynthModule> = vec![failing.clone(), PanicModule::shared("ok", false)];
+        let faults = Mutex::new(Faults::default());
+        assert!(execute(&plan, &faults));
+        assert_eq!(
+            faults.lock().unwrap().get("failing"),
+            Some(&"module failed".to_string())
+        );
+        assert_eq!(output_of(&plan[0]), vec![0.0; 4]);
+        assert_eq!(output_of(&plan[1]), vec![1.0; 4]);
+        // stays disabled without panicking again
+        assert!(!execute(&plan, &faults));
+
+        failing.write().unwrap().panic = false;
+        faults.lock().unwrap().clear("failing");
+        assert!(!execute(&plan, &faults));
+        assert_eq!(output_of(&plan[0]), vec![1.0; 4]);
+    }
+
+    #[test]
+    fn recovers_poisoned_lock() {
+        let plan: Vec<SharedSynthModule> = vec![PanicModule::shared("poisoned", false)];
+        let module = plan[0].clone();
+        std::thread::spawn(move || {
+            let _module = module.write().unwrap();
+            panic!("poison the lock");
+        })
+        .join()
+        .unwrap_err();
+        assert!(plan[0].is_poisoned());
+        assert!(!ui_dirty(&plan));
+
+        let faults = Mutex::new(Faults::default());
+        assert!(execute(&plan, &faults));
+        assert!(!plan[0].is_poisoned());
+        assert!(faults.lock().unwrap().get("poisoned").is_some());
+        assert_eq!(output_of(&plan[0]), vec![0.0; 4]);
+
+        faults.lock().unwrap().clear("poisoned");
+        assert!(!execute(&plan, &faults));
+        assert_eq!(output_of(&plan[0]), vec![1.0; 4]);
+    }
+
+    #[test]
+    fn smoother_settles_one_pole() {
+        let mut smoother = Smoother::new(1000);
+        assert_eq!(smoother.next(0.5), 0.5);
+        let first = smoother.next(1.0);
+        assert!(first > 0.5 && first < 1.0);
+        // 20 ms at 1 kHz gets within 1%
+        let value = (1..20).map(|_| smoother.next(1.0)).last().unwrap();
+        assert!(value > 0.99 && value < 1.0);
+        let value = (0..1000).map(|_| smoother.next(1.0)).last().unwrap();
+        assert_eq!(value, 1.0);
+    }
+
+    #[test]
+    fn smoother_ramps_linearly() {
+        let mut smoother = Smoother::new(1000).with_ramp(Ramp::Linear);
+        smoother.set_time(0.004);
+        smoother.next(0.0);
+        let values: Vec<_> = (0..5).map(|_| smoother.next(1.0)).collect();
+        assert_eq!(values, vec![0.25, 0.5, 0.75, 1.0, 1.0]);
+        smoother.set_time(0.0);
+        assert_eq!(smoother.next(-1.0), -1.0);
+    }
+
+    #[test]
+    fn param_values_follow_params() {
+        let config = AudioConfig {
+            buffer_size: 16,
+            sample_rate: 1000,
+            channels: 2,
+        };
+        for (name, make) in get_catalog() {
+            let module = make(&config);
+            let mut module = module.write().unwrap();
+            let ranges: Vec<_> = module
+                .params()
+                .into_iter()
+                .map(|(param, _)| (param.min, param.max))
+                .collect();
+            for (idx, (min, max)) in ranges.iter().enumerate() {
+                // an unlikely value, so a mismatched field shows up
+                module
+                    .param_value(idx)
+                    .unwrap()
+                    .set(min + (max - min) * 0.37);
+                let set = module.param_value(idx).unwrap().get();
+                assert_eq!(module.params().remove(idx).1.get(), set, "{name} {idx}");
+            }
+            assert!(module.param_value(ranges.len()).is_none(), "{name}");
+        }
+    }
 }
@@ -1,60 +1,376 @@
+use super::midi_learn::learnable;
 use super::{
-    AudioBuffer, AudioConfig, ControlVoltage, SharedSynthModule, SynthModule, TransitionDetector,
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, Smoother,
+    SynthModule, TransitionDetector,
 };
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 
+const DELAY_PARAM: &str = "delay";
+const ATTACK_PARAM: &str = "attack";
+const HOLD_PARAM: &str = "hold";
+const DECAY_PARAM: &str = "decay";
+const SUSTAIN_PARAM: &str = "sustain";
+const RELEASE_PARAM: &str = "release";
+
+/// Shortest time a stage can take, in seconds
+const MIN_STAGE_SEC: f32 = 0.0001;
+/// Longest time a stage can take, in seconds
+const MAX_STAGE_SEC: f32 = 30.0;
+/// Length of the end-of-cycle trigger, in seconds
+const EOC_PULSE_SEC: f32 = 0.001;
+/// How steep the exponential and logarithmic curves are
+const CURVE_AMOUNT: f32 = 5.0;
+
+const ENVELOPE_WIDTH: f32 = 180.0;
+const ENVELOPE_HEIGHT: f32 = 60.0;
+/// Width of the sustain stage in the envelope drawing, in the same units as
+/// [`ADSRModule::display_width`]
+const SUSTAIN_DISPLAY_WIDTH: f32 = 4.0;
+
+/// Shape of a single envelope stage.
+///
+/// Curves describe the progress through the stage: exponential starts slow and
+/// finishes fast, logarithmic starts fast and finishes slow, whether the stage
+/// rises or falls.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+enum Curve {
+    Linear,
+    Exponential,
+    Logarithmic,
+}
+
+impl Curve {
+    #[inline]
+    fn shape(&self, phase: f32) -> f32 {
+        let phase = phase.clamp(0.0, 1.0);
+        match self {
+            Curve::Linear => phase,
+            Curve::Exponential => ((CURVE_AMOUNT * phase).exp() - 1.0) / (CURVE_AMOUNT.exp() - 1.0),
+            Curve::Logarithmic => {
+                1.0 - ((CURVE_AMOUNT * (1.0 - phase)).exp() - 1.0) / (CURVE_AMOUNT.exp() - 1.0)
+            }
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Curve::Linear => "Lin",
+            Curve::Exponential => "Exp",
+            Curve::Logarithmic => "Log",
+        }
+    }
+}
+
+/// Delay, attack, hold, decay, sustain, release envelope.
+///
+/// With looping enabled the sustain stage is skipped and the envelope restarts
+/// after the release for as long as the gate is high, or forever when the gate
+/// is not connected. The second input scales the envelope by the velocity
+/// sampled at the start of each cycle.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct ADSRModule {
     id: String,
+    delay_sec: f32,
     a_sec: f32,
+    hold_sec: f32,
     d_sec: f32,
     s_val: ControlVoltage,
     r_sec: f32,
+    a_curve: Curve,
+    d_curve: Curve,
+    r_curve: Curve,
+    looping: bool,
     phase: f32,
     mode: ADSRMode,
-    r_val: ControlVoltage,
-    from_a_val: ControlVoltage,
+    /// Envelope value when the current stage started
+    start_val: ControlVoltage,
+    /// Envelope value before velocity scaling
+    current: ControlVoltage,
+    velocity: ControlVoltage,
+    eoc_remaining: u32,
     sample_rate: f32,
     #[serde(skip)]
     gate_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    velocity_in: Option<(SharedSynthModule, u8)>,
     transition_detector: TransitionDetector,
     output_buffer: AudioBuffer,
+    eoc_buffer: AudioBuffer,
     ui_dirty: bool,
+    /// Glides the sustain level, which is held while the gate is high
+    #[serde(skip)]
+    s_smoother: Smoother,
 }
 
-#[derive(Serialize, Deserialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 enum ADSRMode {
     Attack,
     Decay,
     Sustain,
     Release,
     None,
+    Delay,
+    Hold,
 }
 
 impl ADSRModule {
     pub fn new(audio_config: &AudioConfig) -> Self {
         Self {
             id: uuid::Uuid::new_v4().to_string(),
-            a_sec: 0.0,
+            delay_sec: 0.0,
+            a_sec: 0.001,
+            hold_sec: 0.0,
             d_sec: 0.5,
             s_val: 0.25,
             r_sec: 0.5,
+            a_curve: Curve::Linear,
+            d_curve: Curve::Logarithmic,
+            r_curve: Curve::Logarithmic,
+            looping: false,
             phase: 0.0,
             mode: ADSRMode::None,
-            r_val: 0.0,
-            from_a_val: 0.0,
+            start_val: 0.0,
+            current: 0.0,
+            velocity: 1.0,
+            eoc_remaining: 0,
             sample_rate: audio_config.sample_rate as f32,
             gate_in: None,
+            velocity_in: None,
             transition_detector: TransitionDetector::new(),
             output_buffer: AudioBuffer::new(Some(audio_config.buffer_size)),
+            eoc_buffer: AudioBuffer::new(Some(audio_config.buffer_size)),
             ui_dirty: false,
+            s_smoother: Smoother::new(audio_config.sample_rate),
         }
     }
 
     pub fn get_name() -> String {
         "ADSR".to_string()
     }
+
+    fn enter(&mut self, mode: ADSRMode) {
+        self.mode = mode;
+        self.phase = 0.0;
+        self.start_val = self.current;
+        self.ui_dirty = true;
+    }
+
+    fn start_cycle(&mut self, velocity: Option<ControlVoltage>) {
+        self.velocity = velocity.map(|v| v.clamp(0.0, 1.0)).unwrap_or(1.0);
+        if self.delay_sec > 0.0 {
+            self.enter(ADSRMode::Delay);
+        } else {
+            self.enter(ADSRMode::Attack);
+        }
+    }
+
+    /// Move through a stage lasting `sec` seconds. Returns true when the stage is over.
+    #[inline]
+    fn advance(&mut self, sec: f32) -> bool {
+        self.phase += 1.0 / (self.sample_rate * sec.max(MIN_STAGE_SEC));
+        self.phase >= 1.0
+    }
+
+    #[inline]
+    fn value(&self, s_val: ControlVoltage) -> ControlVoltage {
+        let lerp = |to: ControlVoltage, curve: &Curve| {
+            self.start_val + (to - self.start_val) * curve.shape(self.phase)
+        };
+        match self.mode {
+            ADSRMode::None => 0.0,
+            ADSRMode::Delay => self.start_val,
+            ADSRMode::Attack => lerp(1.0, &self.a_curve),
+            ADSRMode::Hold => 1.0,
+            ADSRMode::Decay => lerp(s_val, &self.d_curve),
+            ADSRMode::Sustain => s_val,
+            ADSRMode::Release => lerp(0.0, &self.r_curve),
+        }
+    }
+
+    /// Step the envelope by one sample, returning the envelope and end-of-cycle outputs
+    #[inline]
+    fn process(
+        &mut self,
+        gate: Option<ControlVoltage>,
+        velocity: Option<ControlVoltage>,
+    ) -> (ControlVoltage, ControlVoltage) {
+        let gate_high = gate.is_some_and(|v| TransitionDetector::is_above_threshold(&v));
+        let is_transition = self
+            .transition_detector
+            .is_transition(gate.as_ref().unwrap_or(&0.0));
+        let free_running = self.looping && gate.is_none();
+        if is_transition || (self.mode == ADSRMode::None && (gate_high || free_running)) {
+            self.start_cycle(velocity);
+        }
+        match self.mode {
+            ADSRMode::None => {}
+            ADSRMode::Delay => {
+                if self.advance(self.delay_sec) {
+                    self.enter(ADSRMode::Attack);
+                }
+            }
+            ADSRMode::Attack => {
+                if self.advance(self.a_sec) {
+                    self.current = 1.0;
+                    if self.hold_sec > 0.0 {
+                        self.enter(ADSRMode::Hold);
+                    } else {
+                        self.enter(ADSRMode::Decay);
+                    }
+                }
+            }
+            ADSRMode::Hold => {
+                if self.advance(self.hold_sec) {
+                    self.enter(ADSRMode::Decay);
+                }
+            }
+            ADSRMode::Decay => {
+                if self.advance(self.d_sec) {
+                    self.current = self.s_val;
+                    if self.looping {
+                        self.enter(ADSRMode::Release);
+                    } else {
+                        self.enter(ADSRMode::Sustain);
+                    }
+                }
+            }
+            ADSRMode::Sustain => {
+                if !gate_high {
+                    self.enter(ADSRMode::Release);
+                }
+            }
+            ADSRMode::Release => {
+                if self.advance(self.r_sec) {
+                    self.current = 0.0;
+                    self.eoc_remaining = (EOC_PULSE_SEC * self.sample_rate).ceil() as u32;
+                    if self.looping && (gate_high || free_running) {
+                        self.start_cycle(velocity);
+                    } else {
+                        self.enter(ADSRMode::None);
+                    }
+                }
+            }
+        }
+        let s_val = self.s_smoother.next(self.s_val);
+        self.current = self.value(s_val);
+        let eoc = if self.eoc_remaining > 0 {
+            self.eoc_remaining -= 1;
+            1.0
+        } else {
+            0.0
+        };
+        (self.current * self.velocity, eoc)
+    }
+
+    /// Width of a stage in the envelope drawing. Stage times span several
+    /// orders of magnitude, so they are drawn on a log scale.
+    fn display_width(sec: f32) -> f32 {
+        if sec <= 0.0 {
+            0.0
+        } else {
+            (1.0 + sec / MIN_STAGE_SEC).ln()
+        }
+    }
+
+    fn envelope_ui(&self, ui: &mut egui::Ui) {
+        let (_id, rect) = ui.allocate_space([ENVELOPE_WIDTH, ENVELOPE_HEIGHT].into());
+        let painter = ui.painter();
+        painter.rect_filled(rect, 1.0, ui.visuals().extreme_bg_color);
+        let segments = [
+            (ADSRMode::Delay, self.delay_sec, 0.0, 0.0, Curve::Linear),
+            (ADSRMode::Attack, self.a_sec, 0.0, 1.0, self.a_curve),
+            (ADSRMode::Hold, self.hold_sec, 1.0, 1.0, Curve::Linear),
+            (ADSRMode::Decay, self.d_sec, 1.0, self.s_val, self.d_curve),
+            (
+                ADSRMode::Sustain,
+                0.0,
+                self.s_val,
+                self.s_val,
+                Curve::Linear,
+            ),
+            (ADSRMode::Release, self.r_sec, self.s_val, 0.0, self.r_curve),
+        ]
+        .map(|(mode, sec, from, to, curve)| {
+            let width = match mode {
+                ADSRMode::Sustain if !self.looping => SUSTAIN_DISPLAY_WIDTH,
+                ADSRMode::Attack | ADSRMode::Decay | ADSRMode::Release => {
+                    Self::display_width(sec.max(MIN_STAGE_SEC))
+                }
+                _ => Self::display_width(sec),
+            };
+            (mode, width, from, to, curve)
+        });
+        let total: f32 = segments.iter().map(|(_, width, ..)| width).sum();
+        let to_pos = |x: f32, y: ControlVoltage| {
+            egui::pos2(
+                rect.min.x + x / total * rect.width(),
+                rect.max.y - y.clamp(0.0, 1.0) * rect.height(),
+            )
+        };
+        let mut points = vec![];
+        let mut marker = None;
+        let mut x = 0.0;
+        for (mode, width, from, to, curve) in segments {
+            for step in 0..=16 {
+                let phase = step as f32 / 16.0;
+                points.push(to_pos(
+                    x + phase * width,
+                    from + (to - from) * curve.shape(phase),
+                ));
+            }
+            if mode == self.mode {
+                marker = Some(to_pos(x + self.phase.min(1.0) * width, self.current));
+            }
+            x += width;
+        }
+        painter.add(egui::Shape::line(
+            points,
+            egui::Stroke::new(1.0, ui.visuals().text_color()),
+        ));
+        if let Some(marker) = marker {
+            painter.circle_filled(marker, 3.0, egui::Color32::RED);
+        }
+    }
+}
+
+fn stage_slider(
+    ui: &mut egui::Ui,
+    (module_id, param_id): (&str, &str),
+    value: &mut f32,
+    label: &str,
+    active: bool,
+    range: std::ops::RangeInclusive<f32>,
+) {
+    ui.vertical(|ui| {
+        let response = ui
+            .add(
+                egui::Slider::new(value, range)
+                    .logarithmic(true)
+                    .smallest_positive(MIN_STAGE_SEC as f64)
+                    .show_value(false)
+                    .orientation(egui::SliderOrientation::Vertical),
+            )
+            .on_hover_text(format!("{value:.4}"));
+        learnable(response, module_id, param_id);
+        if active {
+            ui.colored_label(egui::Color32::RED, label);
+        } else {
+            ui.label(label);
+        }
+    });
+}
+
+fn curve_ui(ui: &mut egui::Ui, id: (&String, &str), curve: &mut Curve) {
+    egui::ComboBox::from_id_source(id)
+        .width(40.0)
+        .selected_text(curve.label())
+        .show_ui(ui, |ui| {
+            for option in [Curve::Linear, Curve::Exponential, Curve::Logarithmic] {
+                ui.selectable_value(curve, option, option.label());
+            }
+        });
 }
 
 impl SynthModule for ADSRModule {
@@ -67,16 +383,20 @@ impl SynthModule for ADSRModule {
     }
 
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate as f32;
         self.output_buffer.resize(audio_config.buffer_size);
+        self.eoc_buffer.resize(audio_config.buffer_size);
+        self.s_smoother.set_sample_rate(audio_config.sample_rate);
     }
 
     fn get_num_inputs(&self) -> u8 {
-        1
+        2
     }
 
     fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
         match input_idx {
             0 => Ok(self.gate_in.clone()),
+            1 => Ok(self.velocity_in.clone()),
             _ => Err(()),
         }
     }
@@ -92,6 +412,10 @@ impl SynthModule for ADSRModule {
                 self.gate_in = Some((src_module, src_port));
                 Ok(())
             }
+            1 => {
+                self.velocity_in = Some((src_module, src_port));
+                Ok(())
+            }
             _ => Err(()),
         }
     }
@@ -102,6 +426,10 @@ impl SynthModule for ADSRModule {
                 self.gate_in = None;
                 Ok(())
             }
+            1 => {
+                self.velocity_in = None;
+                Ok(())
+            }
             _ => Err(()),
         }
     }
@@ -109,17 +437,19 @@ impl SynthModule for ADSRModule {
     fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
         match input_idx {
             0 => Ok(Some("Gate".to_string())),
+            1 => Ok(Some("Velocity".to_string())),
             _ => Err(()),
         }
     }
 
     fn get_num_outputs(&self) -> u8 {
-        1
+        2
     }
 
     fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
         match output_idx {
             0 => Ok(self.output_buffer.clone()),
+            1 => Ok(self.eoc_buffer.clone()),
             _ => Err(()),
         }
     }
@@ -127,141 +457,111 @@ impl SynthModule for ADSRModule {
     fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
         match output_idx {
             0 => Ok(None),
+            1 => Ok(Some("End of cycle".to_string())),
             _ => Err(()),
         }
     }
 
     fn calc(&mut self) {
-        self.resolve_input(0).unwrap().with_read(|gate_in_buf| {
-            self.output_buffer.with_write(|output_buffer| {
-                let output_buffer = output_buffer.unwrap();
-                for idx in 0..output_buffer.len() {
-                    let is_transition = self.transition_detector.is_transition(match gate_in_buf {
-                        Some(buf) => &buf[idx],
-                        None => &0.0,
-                    });
-                    match self.mode {
-                        ADSRMode::None => {
-                            if gate_in_buf.is_some() && gate_in_buf.unwrap()[idx] > 0.0 {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Attack;
-                                self.ui_dirty = true;
-                            }
-                        }
-                        ADSRMode::Attack => {
-                            self.phase += 1.0 / (self.sample_rate * self.a_sec);
-                            if self.phase >= 1.0 {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Decay;
-                                self.ui_dirty = true;
-                            } else if is_transition {
-                                self.phase = 0.0;
-                                self.r_val = self.from_a_val;
-                            }
-                        }
-                        ADSRMode::Decay => {
-                            self.phase += 1.0 / (self.sample_rate * self.d_sec);
-                            if self.phase >= 1.0 {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Sustain;
-                                self.ui_dirty = true;
-                            }
-                            if is_transition {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Attack;
-                                self.ui_dirty = true;
-                            }
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (gate_in_buf, velocity_in_buf) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.output_buffer.clone(), self.eoc_buffer.clone()],
+                    |bufs| {
+                        let (output_buffer, eoc_buffer) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        for idx in 0..output_buffer.len() {
+                            (output_buffer[idx], eoc_buffer[idx]) = self.process(
+                                gate_in_buf.map(|buf| buf[idx]),
+                                velocity_in_buf.map(|buf| buf[idx]),
+                            );
                         }
-                        ADSRMode::Sustain => {
-                            if gate_in_buf.is_none() || gate_in_buf.unwrap()[idx] <= 0.0 {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Release;
-                                self.ui_dirty = true;
-                            }
-                            if is_transition {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Attack;
-                                self.ui_dirty = true;
-                            }
-                        }
-                        ADSRMode::Release => {
-                            if gate_in_buf.is_some() && gate_in_buf.unwrap()[idx] > 0.0 {
-                                self.phase = 0.0;
-                                self.mode = ADSRMode::Attack;
-                                self.ui_dirty = true;
-                            }
-                            self.phase += 1.0 / (self.sample_rate * self.r_sec);
-                            if self.phase >= 1.0 {
-                                self.phase = 0.0;
-                                self.r_val = 0.0;
-                                self.mode = ADSRMode::None;
-                                self.ui_dirty = true;
-                            }
-                        }
-                    }
-                    output_buffer[idx] = match self.mode {
-                        ADSRMode::None => 0.0,
-                        ADSRMode::Attack => self.r_val + (1.0 - self.r_val) * self.phase,
-                        ADSRMode::Decay => self.s_val + (1.0 - self.s_val) * (1.0 - self.phase),
-                        ADSRMode::Sustain => self.s_val,
-                        ADSRMode::Release => self.s_val * (1.0 - self.phase),
-                    };
-                    if !matches!(self.mode, ADSRMode::Attack) {
-                        self.r_val = output_buffer[idx];
-                    } else {
-                        self.from_a_val = output_buffer[idx];
-                    }
-                }
-            });
-        });
+                    },
+                );
+            },
+        );
+        if self.mode != ADSRMode::None {
+            self.ui_dirty = true;
+        }
     }
 
     fn ui(&mut self, ui: &mut egui::Ui) {
-        ui.horizontal(|ui| {
-            ui.vertical(|ui| {
-                ui.add(
-                    egui::Slider::new(&mut self.a_sec, 0.0..=1.0)
-                        .orientation(egui::SliderOrientation::Vertical),
+        ui.vertical(|ui| {
+            self.envelope_ui(ui);
+            ui.horizontal(|ui| {
+                stage_slider(
+                    ui,
+                    (&self.id, DELAY_PARAM),
+                    &mut self.delay_sec,
+                    "Dl",
+                    self.mode == ADSRMode::Delay,
+                    0.0..=MAX_STAGE_SEC,
                 );
-                if matches!(self.mode, ADSRMode::Attack) {
-                    ui.colored_label(egui::Color32::RED, "A");
-                } else {
-                    ui.label("A");
-                }
-            });
-            ui.vertical(|ui| {
-                ui.add(
-                    egui::Slider::new(&mut self.d_sec, 0.0..=1.0)
-                        .orientation(egui::SliderOrientation::Vertical),
+                stage_slider(
+                    ui,
+                    (&self.id, ATTACK_PARAM),
+                    &mut self.a_sec,
+                    "A",
+                    self.mode == ADSRMode::Attack,
+                    MIN_STAGE_SEC..=MAX_STAGE_SEC,
                 );
-                if matches!(self.mode, ADSRMode::Decay) {
-                    ui.colored_label(egui::Color32::RED, "D");
-                } else {
-                    ui.label("D");
-                }
-            });
-            ui.vertical(|ui| {
-                ui.add(
-                    egui::Slider::new(&mut self.s_val, 0.0..=1.0)
-                        .orientation(egui::SliderOrientation::Vertical),
+                stage_slider(
+                    ui,
+                    (&self.id, HOLD_PARAM),
+                    &mut self.hold_sec,
+                    "H",
+                    self.mode == ADSRMode::Hold,
+                    0.0..=MAX_STAGE_SEC,
                 );
-                if matches!(self.mode, ADSRMode::Sustain) {
-                    ui.colored_label(egui::Color32::RED, "S");
-                } else {
-                    ui.label("S");
-                }
-            });
-            ui.vertical(|ui| {
-                ui.add(
-                    egui::Slider::new(&mut self.r_sec, 0.0..=1.0)
-                        .orientation(egui::SliderOrientation::Vertical),
+                stage_slider(
+                    ui,
+                    (&self.id, DECAY_PARAM),
+                    &mut self.d_sec,
+                    "D",
+                    self.mode == ADSRMode::Decay,
+                    MIN_STAGE_SEC..=MAX_STAGE_SEC,
+                );
+                ui.vertical(|ui| {
+                    let response = ui
+                        .add(
+                            egui::Slider::new(&mut self.s_val, 0.0..=1.0)
+                                .show_value(false)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        )
+                        .on_hover_text(format!("{:.2}", self.s_val));
+                    learnable(response, &self.id, SUSTAIN_PARAM);
+                    if self.mode == ADSRMode::Sustain {
+                        ui.colored_label(egui::Color32::RED, "S");
+                    } else {
+                        ui.label("S");
+                    }
+                });
+                stage_slider(
+                    ui,
+                    (&self.id, RELEASE_PARAM),
+                    &mut self.r_sec,
+                    "R",
+                    self.mode == ADSRMode::Release,
+                    MIN_STAGE_SEC..=MAX_STAGE_SEC,
                 );
-                if matches!(self.mode, ADSRMode::Release) {
-                    ui.colored_label(egui::Color32::RED, "R");
-                } else {
-                    ui.label("R");
-                }
             });
+            ui.horizontal(|ui| {
+                ui.label("A");
+                curve_ui(ui, (&self.id, "a_curve"), &mut self.a_curve);
+                ui.label("D");
+                curve_ui(ui, (&self.id, "d_curve"), &mut self.d_curve);
+                ui.label("R");
+                curve_ui(ui, (&self.id, "r_curve"), &mut self.r_curve);
+            });
+            ui.checkbox(&mut self.looping, "Loop");
         });
         self.ui_dirty = false;
     }
@@ -270,7 +570,246 @@ impl SynthModule for ADSRModule {
         self
     }
 
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(DELAY_PARAM, "Delay", 0.0..=MAX_STAGE_SEC),
+                ParamValue::F32(&mut self.delay_sec),
+            ),
+            (
+                Param::new(ATTACK_PARAM, "Attack", MIN_STAGE_SEC..=MAX_STAGE_SEC),
+                ParamValue::F32(&mut self.a_sec),
+            ),
+            (
+                Param::new(HOLD_PARAM, "Hold", 0.0..=MAX_STAGE_SEC),
+                ParamValue::F32(&mut self.hold_sec),
+            ),
+            (
+                Param::new(DECAY_PARAM, "Decay", MIN_STAGE_SEC..=MAX_STAGE_SEC),
+                ParamValue::F32(&mut self.d_sec),
+            ),
+            (
+                Param::new(SUSTAIN_PARAM, "Sustain", 0.0..=1.0),
+                ParamValue::F32(&mut self.s_val),
+            ),
+            (
+                Param::new(RELEASE_PARAM, "Release", MIN_STAGE_SEC..=MAX_STAGE_SEC),
+                ParamValue::F32(&mut self.r_sec),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.delay_sec),
+            1 => ParamValue::F32(&mut self.a_sec),
+            2 => ParamValue::F32(&mut self.hold_sec),
+            3 => ParamValue::F32(&mut self.d_sec),
+            4 => ParamValue::F32(&mut self.s_val),
+            5 => ParamValue::F32(&mut self.r_sec),
+            _ => return None,
+        })
+    }
+
     fn ui_dirty(&self) -> bool {
         self.ui_dirty
     }
 }
+
+// MIGRATIONS
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ADSRModuleV0 {
+    id: String,
+    a_sec: f32,
+    d_sec: f32,
+    s_val: ControlVoltage,
+    r_sec: f32,
+    phase: f32,
+    mode: ADSRMode,
+    r_val: ControlVoltage,
+    from_a_val: ControlVoltage,
+    sample_rate: f32,
+    #[serde(skip)]
+    gate_in: Option<(SharedSynthModule, u8)>,
+    transition_detector: TransitionDetector,
+    output_buffer: AudioBuffer,
+    ui_dirty: bool,
+}
+
+impl From<ADSRModuleV0> for ADSRModule {
+    fn from(other: ADSRModuleV0) -> Self {
+        let buf_size = other.output_buffer.get().unwrap().len();
+        Self {
+            id: other.id,
+            delay_sec: 0.0,
+            a_sec: other.a_sec.max(MIN_STAGE_SEC),
+            hold_sec: 0.0,
+            d_sec: other.d_sec.max(MIN_STAGE_SEC),
+            s_val: other.s_val,
+            r_sec: other.r_sec.max(MIN_STAGE_SEC),
+            a_curve: Curve::Linear,
+            d_curve: Curve::Linear,
+            r_curve: Curve::Linear,
+            looping: false,
+            phase: 0.0,
+            mode: ADSRMode::None,
+            start_val: 0.0,
+            current: 0.0,
+            velocity: 1.0,
+            eoc_remaining: 0,
+            sample_rate: other.sample_rate,
+            gate_in: other.gate_in,
+            velocity_in: None,
+            transition_detector: other.transition_detector,
+            output_buffer: other.output_buffer,
+            eoc_buffer: AudioBuffer::new(Some(buf_size)),
+            ui_dirty: false,
+            s_smoother: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{SynthModuleType, enum_to_sharedsynthmodule};
+
+    /// Stage times are whole samples at this rate, so the envelope steps exactly
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1024,
+        buffer_size: 8,
+        channels: 2,
+    };
+
+    fn samples(count: f32) -> f32 {
+        count / CONFIG.sample_rate as f32
+    }
+
+    fn linear(adsr: &mut ADSRModule) {
+        adsr.a_curve = Curve::Linear;
+        adsr.d_curve = Curve::Linear;
+        adsr.r_curve = Curve::Linear;
+    }
+
+    fn run(
+        adsr: &mut ADSRModule,
+        gate: Option<ControlVoltage>,
+        velocity: Option<ControlVoltage>,
+        count: usize,
+    ) -> (Vec<ControlVoltage>, Vec<ControlVoltage>) {
+        (0..count).map(|_| adsr.process(gate, velocity)).unzip()
+    }
+
+    #[test]
+    fn stages_last_their_time() {
+        let mut adsr = ADSRModule::new(&CONFIG);
+        linear(&mut adsr);
+        adsr.delay_sec = samples(2.0);
+        adsr.a_sec = samples(4.0);
+        adsr.hold_sec = samples(2.0);
+        adsr.d_sec = samples(4.0);
+        adsr.s_val = 0.5;
+        adsr.r_sec = samples(4.0);
+        let (env, eoc) = run(&mut adsr, Some(1.0), None, 14);
+        assert_eq!(
+            env,
+            vec![
+                0.0, 0.0, 0.25, 0.5, 0.75, 1.0, 1.0, 1.0, 0.875, 0.75, 0.625, 0.5, 0.5, 0.5
+            ]
+        );
+        assert_eq!(eoc, vec![0.0; 14]);
+        let (env, eoc) = run(&mut adsr, Some(0.0), None, 7);
+        assert_eq!(env, vec![0.5, 0.375, 0.25, 0.125, 0.0, 0.0, 0.0]);
+        // EOC_PULSE_SEC rounded up to whole samples
+        assert_eq!(eoc, vec![0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0]);
+        assert!(adsr.mode == ADSRMode::None);
+    }
+
+    #[test]
+    fn curves_shape_stages() {
+        let attack_midpoint = |curve| {
+            let mut adsr = ADSRModule::new(&CONFIG);
+            adsr.a_sec = samples(4.0);
+            adsr.a_curve = curve;
+            run(&mut adsr, Some(1.0), None, 2).0[1]
+        };
+        assert_eq!(attack_midpoint(Curve::Linear), 0.5);
+        assert!(attack_midpoint(Curve::Exponential) < 0.2);
+        assert!(attack_midpoint(Curve::Logarithmic) > 0.8);
+    }
+
+    #[test]
+    fn loops_while_gate_is_high() {
+        let mut adsr = ADSRModule::new(&CONFIG);
+        linear(&mut adsr);
+        adsr.looping = true;
+        adsr.a_sec = samples(2.0);
+        adsr.d_sec = samples(2.0);
+        adsr.s_val = 0.5;
+        adsr.r_sec = samples(2.0);
+        // free running without a gate, restarting from the end of the release
+        let (env, eoc) = run(&mut adsr, None, None, 11);
+        assert_eq!(
+            env,
+            vec![0.5, 1.0, 0.75, 0.5, 0.25, 0.0, 0.5, 1.0, 0.75, 0.5, 0.25]
+        );
+        assert_eq!(
+            eoc,
+            vec![0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 1.0, 0.0, 0.0, 0.0, 0.0]
+        );
+        // the cycle finishes after the gate goes low, and doesn't restart
+        let (env, eoc) = run(&mut adsr, Some(0.0), None, 4);
+        assert_eq!(env, vec![0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(eoc, vec![1.0, 1.0, 0.0, 0.0]);
+        assert!(adsr.mode == ADSRMode::None);
+    }
+
+    #[test]
+    fn velocity_scales_each_cycle() {
+        let mut adsr = ADSRModule::new(&CONFIG);
+        adsr.a_sec = samples(1.0);
+        let (env, _) = run(&mut adsr, Some(1.0), Some(0.5), 1);
+        assert_eq!(env, vec![0.5]);
+        // held until the next cycle starts
+        let (env, _) = run(&mut adsr, Some(1.0), Some(1.0), 1);
+        assert!(env[0] <= 0.5);
+        run(&mut adsr, Some(0.0), None, 1);
+        let (env, _) = run(&mut adsr, Some(1.0), Some(2.0), 1);
+        assert_eq!(env, vec![1.0]);
+    }
+
+    #[test]
+    fn migrates_v0() {
+        let v0 = ADSRModuleV0 {
+            id: "adsr".to_string(),
+            a_sec: 0.0,
+            d_sec: 0.2,
+            s_val: 0.3,
+            r_sec: 0.4,
+            phase: 0.5,
+            mode: ADSRMode::Decay,
+            r_val: 0.0,
+            from_a_val: 0.0,
+            sample_rate: 48000.0,
+            gate_in: None,
+            transition_detector: TransitionDetector::new(),
+            output_buffer: AudioBuffer::new(Some(16)),
+            ui_dirty: false,
+        };
+        let bytes = rmp_serde::to_vec(&SynthModuleType::ADSRModuleV0(v0)).unwrap();
+        let module = enum_to_sharedsynthmodule(rmp_serde::from_slice(&bytes).unwrap());
+        let module = module.read().unwrap();
+        let adsr = module.as_any().downcast_ref::<ADSRModule>().unwrap();
+        assert_eq!(adsr.get_id(), "adsr");
+        assert_eq!(
+            (adsr.delay_sec, adsr.a_sec, adsr.hold_sec),
+            (0.0, MIN_STAGE_SEC, 0.0)
+        );
+        assert_eq!((adsr.d_sec, adsr.s_val, adsr.r_sec), (0.2, 0.3, 0.4));
+        assert!(adsr.a_curve == Curve::Linear && adsr.r_curve == Curve::Linear);
+        assert!(adsr.mode == ADSRMode::None && !adsr.looping);
+        assert_eq!(adsr.get_num_outputs(), 2);
+        assert_eq!(adsr.eoc_buffer.get().unwrap().len(), 16);
+    }
+}
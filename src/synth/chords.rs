@@ -0,0 +1,664 @@
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SeededRng, SharedSynthModule,
+    SynthModule, TransitionDetector, seed_ui,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const INVERSION_PARAM: &str = "inversion";
+const OCTAVES_PARAM: &str = "octaves";
+
+const CHORD_OUTPUTS: usize = 4;
+const ARP_INPUTS: usize = 4;
+const MAX_INVERSION: i32 = 3;
+/// Furthest the inversion input can move a chord, in octaves
+const MAX_INVERSION_OCTAVES: i32 = 4;
+const MAX_OCTAVES: u8 = 4;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    Major7,
+    Minor7,
+    Dominant7,
+    HalfDiminished7,
+    Diminished7,
+}
+
+impl ChordQuality {
+    const ALL: [ChordQuality; 11] = [
+        ChordQuality::Major,
+        ChordQuality::Minor,
+        ChordQuality::Diminished,
+        ChordQuality::Augmented,
+        ChordQuality::Sus2,
+        ChordQuality::Sus4,
+        ChordQuality::Major7,
+        ChordQuality::Minor7,
+        ChordQuality::Dominant7,
+        ChordQuality::HalfDiminished7,
+        ChordQuality::Diminished7,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            ChordQuality::Major => "Major",
+            ChordQuality::Minor => "Minor",
+            ChordQuality::Diminished => "Diminished",
+            ChordQuality::Augmented => "Augmented",
+            ChordQuality::Sus2 => "Sus2",
+            ChordQuality::Sus4 => "Sus4",
+            ChordQuality::Major7 => "Major 7th",
+            ChordQuality::Minor7 => "Minor 7th",
+            ChordQuality::Dominant7 => "Dominant 7th",
+            ChordQuality::HalfDiminished7 => "Half-diminished 7th",
+            ChordQuality::Diminished7 => "Diminished 7th",
+        }
+    }
+
+    /// Semitones above the root
+    fn intervals(&self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Sus2 => &[0, 2, 7],
+            ChordQuality::Sus4 => &[0, 5, 7],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::HalfDiminished7 => &[0, 3, 6, 10],
+            ChordQuality::Diminished7 => &[0, 3, 6, 9],
+        }
+    }
+
+    /// Pitches of the chord, lowest first, and how many there are. Each
+    /// inversion moves the lowest note up an octave, and negative inversions
+    /// move the highest down.
+    fn pitches(
+        &self,
+        root: ControlVoltage,
+        inversion: i32,
+    ) -> ([ControlVoltage; CHORD_OUTPUTS], usize) {
+        let intervals = self.intervals();
+        let mut pitches = [0.0; CHORD_OUTPUTS];
+        for (pitch, semitones) in pitches.iter_mut().zip(intervals) {
+            *pitch = root + *semitones as ControlVoltage / 12.0;
+        }
+        let notes = &mut pitches[..intervals.len()];
+        for _ in 0..inversion.max(0) {
+            notes[0] += 1.0;
+            notes.rotate_left(1);
+        }
+        for _ in inversion.min(0)..0 {
+            *notes.last_mut().unwrap() -= 1.0;
+            notes.rotate_right(1);
+        }
+        (pitches, intervals.len())
+    }
+}
+
+/// Pitches of a chord on a root. Triads double the lowest note an octave up
+/// on the last output.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ChordModule {
+    id: String,
+    #[serde(skip)]
+    root_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    inversion_in: Option<(SharedSynthModule, u8)>,
+    outs: Vec<AudioBuffer>,
+    quality: ChordQuality,
+    inversion: i32,
+}
+
+impl ChordModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            root_in: None,
+            inversion_in: None,
+            outs: (0..CHORD_OUTPUTS)
+                .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
+                .collect(),
+            quality: ChordQuality::Major,
+            inversion: 0,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Chord".to_string()
+    }
+
+    fn process(&self, root: ControlVoltage, inversion_cv: ControlVoltage) -> [ControlVoltage; 4] {
+        // each inversion is a step of pitches, so keep them to a few octaves
+        let limit = self.quality.intervals().len() as i32 * MAX_INVERSION_OCTAVES;
+        let inversion = self
+            .inversion
+            .saturating_add(inversion_cv.round() as i32)
+            .clamp(-limit, limit);
+        let (mut pitches, len) = self.quality.pitches(root, inversion);
+        if len < 4 {
+            pitches[3] = pitches[0] + 1.0;
+        }
+        pitches
+    }
+}
+
+impl SynthModule for ChordModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        for out in self.outs.iter_mut() {
+            out.resize(audio_config.buffer_size);
+        }
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.root_in.clone()),
+            1 => Ok(self.inversion_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.root_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.inversion_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.root_in = None;
+                Ok(())
+            }
+            1 => {
+                self.inversion_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Root".to_string())),
+            1 => Ok(Some("Inversion".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        CHORD_OUTPUTS as u8
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match self.outs.get(output_idx as usize) {
+            Some(out) => Ok(out.clone()),
+            None => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        if (output_idx as usize) < CHORD_OUTPUTS {
+            return Ok(Some(format!("{}", output_idx + 1)));
+        }
+        Err(())
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (root_in, inversion_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(self.outs.clone(), |outs| {
+                    let mut outs: Vec<_> = outs.into_iter().map(|o| o.unwrap()).collect();
+                    for idx in 0..outs[0].len() {
+                        let pitches = self.process(
+                            root_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                            inversion_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                        );
+                        for (out, pitch) in outs.iter_mut().zip(pitches) {
+                            out[idx] = pitch;
+                        }
+                    }
+                });
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            egui::ComboBox::from_id_source((&self.id, "quality"))
+                .selected_text(self.quality.label())
+                .show_ui(ui, |ui| {
+                    for quality in ChordQuality::ALL {
+                        ui.selectable_value(&mut self.quality, quality, quality.label());
+                    }
+                });
+            ui.horizontal(|ui| {
+                ui.label("Inversion: ");
+                learnable(
+                    ui.add(
+                        egui::DragValue::new(&mut self.inversion)
+                            .range(-MAX_INVERSION..=MAX_INVERSION),
+                    ),
+                    &self.id,
+                    INVERSION_PARAM,
+                );
+            });
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        let max = MAX_INVERSION as f32;
+        vec![(
+            Param::new(INVERSION_PARAM, "Inversion", -max..=max),
+            ParamValue::I32(&mut self.inversion),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::I32(&mut self.inversion))
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum ArpOrder {
+    Up,
+    Down,
+    Random,
+    /// In the order of the inputs
+    AsPlayed,
+}
+
+impl ArpOrder {
+    const ALL: [ArpOrder; 4] = [
+        ArpOrder::Up,
+        ArpOrder::Down,
+        ArpOrder::Random,
+        ArpOrder::AsPlayed,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            ArpOrder::Up => "Up",
+            ArpOrder::Down => "Down",
+            ArpOrder::Random => "Random",
+            ArpOrder::AsPlayed => "As played",
+        }
+    }
+}
+
+/// Steps through the pitches at its connected note inputs, over a number of
+/// octaves.
+///
+/// Inputs are Step and Sync as for the sequencers, then the notes. Notes are
+/// read as each step starts.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ArpeggiatorModule {
+    id: String,
+    #[serde(skip)]
+    step_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    sync_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    note_in: [Option<(SharedSynthModule, u8)>; ARP_INPUTS],
+    cv_out: AudioBuffer,
+    gate_out: AudioBuffer,
+    sync_out: AudioBuffer,
+    order: ArpOrder,
+    octaves: u8,
+    position: usize,
+    cv: ControlVoltage,
+    transition_detector: TransitionDetector,
+    sync_transition_detector: TransitionDetector,
+    /// Picks notes in random order, restarted by sync
+    rng: SeededRng,
+}
+
+fn random_rng() -> SeededRng {
+    SeededRng::new(rand::random())
+}
+
+impl ArpeggiatorModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            step_in: None,
+            sync_in: None,
+            note_in: Default::default(),
+            cv_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            gate_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            sync_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            order: ArpOrder::Up,
+            octaves: 1,
+            position: 0,
+            cv: 0.0,
+            transition_detector: TransitionDetector::new(),
+            sync_transition_detector: TransitionDetector::new(),
+            rng: random_rng(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Arpeggiator".to_string()
+    }
+
+    /// Number of pitches played from a number of notes, before repeating
+    fn sequence_len(&self, notes: usize) -> usize {
+        notes * self.octaves as usize
+    }
+
+    /// Pitch at a position of the sequence played from some notes, given in
+    /// input order
+    fn pitch_at(&self, notes: &[ControlVoltage], position: usize) -> ControlVoltage {
+        let mut ordered = [0.0; ARP_INPUTS];
+        let ordered = &mut ordered[..notes.len()];
+        ordered.copy_from_slice(notes);
+        if matches!(self.order, ArpOrder::Up | ArpOrder::Down) {
+            ordered.sort_unstable_by(|a, b| a.total_cmp(b));
+        }
+        let position = if self.order == ArpOrder::Down {
+            self.sequence_len(notes.len()) - 1 - position
+        } else {
+            position
+        };
+        ordered[position % notes.len()] + (position / notes.len()) as ControlVoltage
+    }
+}
+
+impl SynthModule for ArpeggiatorModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.cv_out.resize(audio_config.buffer_size);
+        self.gate_out.resize(audio_config.buffer_size);
+        self.sync_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2 + ARP_INPUTS as u8
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.step_in.clone()),
+            1 => Ok(self.sync_in.clone()),
+            n => match self.note_in.get(n as usize - 2) {
+                Some(input) => Ok(input.clone()),
+                None => Err(()),
+            },
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.step_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.sync_in = Some((src_module, src_port));
+                Ok(())
+            }
+            n => match self.note_in.get_mut(n as usize - 2) {
+                Some(input) => {
+                    *input = Some((src_module, src_port));
+                    Ok(())
+                }
+                None => Err(()),
+            },
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.step_in = None;
+                Ok(())
+            }
+            1 => {
+                self.sync_in = None;
+                Ok(())
+            }
+            n => match self.note_in.get_mut(n as usize - 2) {
+                Some(input) => {
+                    *input = None;
+                    Ok(())
+                }
+                None => Err(()),
+            },
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Step".to_string())),
+            1 => Ok(Some("Sync".to_string())),
+            n if (n as usize) < 2 + ARP_INPUTS => Ok(Some(format!("Note {}", n - 1))),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        3
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.cv_out.clone()),
+            1 => Ok(self.gate_out.clone()),
+            2 => Ok(self.sync_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("CV".to_string())),
+            1 => Ok(Some("Gate".to_string())),
+            2 => Ok(Some("Sync".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        let inputs: Vec<_> = (0..self.get_num_inputs())
+            .map(|n| self.resolve_input(n).unwrap())
+            .collect();
+        AudioBuffer::with_read_many(inputs, |bufs| {
+            let (step_in, sync_in) = (bufs[0], bufs[1]);
+            let note_in = &bufs[2..];
+            AudioBuffer::with_write_many(
+                vec![
+                    self.cv_out.clone(),
+                    self.gate_out.clone(),
+                    self.sync_out.clone(),
+                ],
+                |bufs| {
+                    let (cv_out, gate_out, sync_out) = bufs
+                        .into_iter()
+                        .map(|b| b.unwrap())
+                        .collect_tuple()
+                        .unwrap();
+                    for idx in 0..cv_out.len() {
+                        let step = step_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                        let sync = sync_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                        let mut new_step = false;
+                        if self.transition_detector.is_transition(&step) {
+                            self.position += 1;
+                            new_step = true;
+                        }
+                        if self.sync_transition_detector.is_transition(&sync) {
+                            self.position = 0;
+                            self.rng.reset();
+                            new_step = true;
+                        }
+                        let mut notes = [0.0; ARP_INPUTS];
+                        let mut connected = 0;
+                        for buf in note_in.iter().flatten() {
+                            notes[connected] = buf[idx];
+                            connected += 1;
+                        }
+                        let notes = &notes[..connected];
+                        if new_step && !notes.is_empty() {
+                            let len = self.sequence_len(notes.len());
+                            self.position %= len;
+                            let position = if self.order == ArpOrder::Random {
+                                (self.rng.next_u64() % len as u64) as usize
+                            } else {
+                                self.position
+                            };
+                            self.cv = self.pitch_at(notes, position);
+                        }
+                        cv_out[idx] = self.cv;
+                        gate_out[idx] = if notes.is_empty() { 0.0 } else { step };
+                        sync_out[idx] = if self.position == 0 { 1.0 } else { 0.0 };
+                    }
+                },
+            );
+        });
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.horizontal(|ui| {
+                for order in ArpOrder::ALL {
+                    ui.selectable_value(&mut self.order, order, order.label());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.label("Octaves: ");
+                learnable(
+                    ui.add(egui::DragValue::new(&mut self.octaves).range(1..=MAX_OCTAVES)),
+                    &self.id,
+                    OCTAVES_PARAM,
+                );
+            });
+            if self.order == ArpOrder::Random {
+                seed_ui(ui, &mut self.rng);
+            }
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(OCTAVES_PARAM, "Octaves", 1.0..=MAX_OCTAVES as f32),
+            ParamValue::U8(&mut self.octaves),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::U8(&mut self.octaves))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn semitones(pitches: &[ControlVoltage]) -> Vec<i32> {
+        pitches.iter().map(|p| (p * 12.0).round() as i32).collect()
+    }
+
+    fn chord(quality: ChordQuality, root: ControlVoltage, inversion: i32) -> Vec<i32> {
+        let (pitches, len) = quality.pitches(root, inversion);
+        semitones(&pitches[..len])
+    }
+
+    #[test]
+    fn chords_and_inversions() {
+        assert_eq!(chord(ChordQuality::Minor, 0.0, 0), [0, 3, 7]);
+        assert_eq!(chord(ChordQuality::Major, 0.0, 1), [4, 7, 12]);
+        assert_eq!(chord(ChordQuality::Major, 0.0, 2), [7, 12, 16]);
+        assert_eq!(chord(ChordQuality::Major, 0.0, -1), [-5, 0, 4]);
+        assert_eq!(chord(ChordQuality::Dominant7, 1.0, 0), [12, 16, 19, 22]);
+        let chord = ChordModule::new(&AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 16,
+            channels: 2,
+        });
+        assert_eq!(semitones(&chord.process(0.0, 1.0)), [4, 7, 12, 16]);
+        assert_eq!(semitones(&chord.process(0.0, 1e9)), [48, 52, 55, 60]);
+        assert_eq!(semitones(&chord.process(0.0, -1e9)), [-48, -44, -41, -36]);
+    }
+
+    #[test]
+    fn arpeggiates_across_octaves() {
+        let mut arp = ArpeggiatorModule::new(&AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 16,
+            channels: 2,
+        });
+        let notes = [7.0 / 12.0, 0.0, 4.0 / 12.0];
+        let sequence = |arp: &ArpeggiatorModule| {
+            let pitches: Vec<_> = (0..arp.sequence_len(notes.len()))
+                .map(|position| arp.pitch_at(&notes, position))
+                .collect();
+            semitones(&pitches)
+        };
+        arp.octaves = 2;
+        assert_eq!(sequence(&arp), [0, 4, 7, 12, 16, 19]);
+        arp.order = ArpOrder::Down;
+        assert_eq!(sequence(&arp), [19, 16, 12, 7, 4, 0]);
+        arp.order = ArpOrder::AsPlayed;
+        arp.octaves = 1;
+        assert_eq!(sequence(&arp), [7, 0, 4]);
+    }
+}
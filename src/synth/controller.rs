@@ -0,0 +1,403 @@
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, Param, ParamValue, SharedSynthModule, Smoother, SynthModule,
+};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const X_PARAM: &str = "x";
+const Y_PARAM: &str = "y";
+const SMOOTHING_PARAM: &str = "smoothing";
+
+const KNOBS: usize = 4;
+const BUTTONS: usize = 4;
+/// X and Y, then the knobs, then the buttons
+const OUTPUTS: usize = 2 + KNOBS + BUTTONS;
+const PAD_SIZE: f32 = 96.0;
+const MAX_SMOOTHING_SEC: f32 = 1.0;
+
+fn knob_param(idx: usize) -> String {
+    format!("knob {}", idx + 1)
+}
+
+fn button_param(idx: usize) -> String {
+    format!("button {}", idx + 1)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Knob {
+    label: String,
+    value: f32,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Button {
+    label: String,
+    /// 1.0 when on. Anything from 0.5 counts as on, for values set by MIDI.
+    value: f32,
+    /// Click on and off instead of being on while held
+    toggle: bool,
+}
+
+/// Performance controls not tied to any module: an XY pad, knobs and buttons,
+/// each on its own output from 0 to 1.
+///
+/// Smoothing glides the outputs to new values over about the time set, to
+/// avoid zipper noise from jumps.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ControllerModule {
+    id: String,
+    outs: Vec<AudioBuffer>,
+    x: f32,
+    y: f32,
+    knobs: Vec<Knob>,
+    buttons: Vec<Button>,
+    smoothing: f32,
+    #[serde(skip)]
+    smoothers: [Smoother; OUTPUTS],
+}
+
+impl ControllerModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            outs: (0..OUTPUTS)
+                .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
+                .collect(),
+            x: 0.5,
+            y: 0.5,
+            knobs: (1..=KNOBS)
+                .map(|idx| Knob {
+                    label: format!("Knob {}", idx),
+                    value: 0.0,
+                })
+                .collect(),
+            buttons: (1..=BUTTONS)
+                .map(|idx| Button {
+                    label: format!("Button {}", idx),
+                    value: 0.0,
+                    toggle: false,
+                })
+                .collect(),
+            smoothing: 0.0,
+            smoothers: std::array::from_fn(|_| Smoother::new(audio_config.sample_rate)),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Controller".to_string()
+    }
+
+    fn targets(&self) -> [f32; OUTPUTS] {
+        let mut targets = [0.0; OUTPUTS];
+        targets[0] = self.x;
+        targets[1] = self.y;
+        for (target, knob) in targets[2..].iter_mut().zip(self.knobs.iter()) {
+            *target = knob.value;
+        }
+        for (target, button) in targets[2 + KNOBS..].iter_mut().zip(self.buttons.iter()) {
+            *target = if button.value >= 0.5 { 1.0 } else { 0.0 };
+        }
+        targets
+    }
+
+    fn pad_ui(&mut self, ui: &mut egui::Ui) {
+        let (rect, response) = ui.allocate_exact_size(
+            egui::vec2(PAD_SIZE, PAD_SIZE),
+            egui::Sense::click_and_drag(),
+        );
+        if let Some(pos) = response
+            .interact_pointer_pos()
+            .filter(|_| response.is_pointer_button_down_on())
+        {
+            self.x = ((pos.x - rect.left()) / rect.width()).clamp(0.0, 1.0);
+            self.y = ((rect.bottom() - pos.y) / rect.height()).clamp(0.0, 1.0);
+        }
+
+        let painter = ui.painter();
+        let visuals = ui.visuals();
+        painter.rect(
+            rect,
+            2.0,
+            visuals.extreme_bg_color,
+            visuals.widgets.noninteractive.bg_stroke,
+        );
+        let point = egui::pos2(
+            rect.left() + self.x * rect.width(),
+            rect.bottom() - self.y * rect.height(),
+        );
+        let stroke = egui::Stroke::new(1.0, visuals.weak_text_color());
+        painter.hline(rect.x_range(), point.y, stroke);
+        painter.vline(point.x, rect.y_range(), stroke);
+        painter.circle_filled(point, 4.0, visuals.selection.bg_fill);
+
+        // sliders too, to MIDI learn the axes separately
+        for (value, param_id) in [(&mut self.x, X_PARAM), (&mut self.y, Y_PARAM)] {
+            ui.spacing_mut().slider_width = PAD_SIZE;
+            learnable(
+                ui.add(egui::Slider::new(value, 0.0..=1.0).show_value(false)),
+                &self.id,
+                param_id,
+            );
+        }
+    }
+}
+
+impl SynthModule for ControllerModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        for out in self.outs.iter_mut() {
+            out.resize(audio_config.buffer_size);
+        }
+        for smoother in self.smoothers.iter_mut() {
+            smoother.set_sample_rate(audio_config.sample_rate);
+        }
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        0
+    }
+
+    fn get_input(&self, _input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        Err(())
+    }
+
+    fn set_input(
+        &mut self,
+        _input_idx: u8,
+        _src_module: SharedSynthModule,
+        _src_port: u8,
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn disconnect_input(&mut self, _input_idx: u8) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn get_input_label(&self, _input_idx: u8) -> Result<Option<String>, ()> {
+        Err(())
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        OUTPUTS as u8
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        self.outs.get(output_idx as usize).cloned().ok_or(())
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        let idx = output_idx as usize;
+        match idx {
+            0 => Ok(Some("X".to_string())),
+            1 => Ok(Some("Y".to_string())),
+            _ if idx < 2 + KNOBS => Ok(Some(self.knobs[idx - 2].label.clone())),
+            _ if idx < OUTPUTS => Ok(Some(self.buttons[idx - 2 - KNOBS].label.clone())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        let targets = self.targets();
+        for smoother in self.smoothers.iter_mut() {
+            smoother.set_time(self.smoothing);
+        }
+        AudioBuffer::with_write_many(self.outs.clone(), |outs| {
+            let mut outs: Vec<_> = outs.into_iter().map(|o| o.unwrap()).collect();
+            for idx in 0..outs[0].len() {
+                for ((out, smoother), target) in
+                    outs.iter_mut().zip(self.smoothers.iter_mut()).zip(targets)
+                {
+                    out[idx] = smoother.next(target);
+                }
+            }
+        });
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.vertical(|ui| self.pad_ui(ui));
+            for (idx, knob) in self.knobs.iter_mut().enumerate() {
+                ui.vertical(|ui| {
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut knob.value, 0.0..=1.0)
+                                .show_value(false)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        )
+                        .on_hover_text(format!("{:.2}", knob.value)),
+                        &self.id,
+                        &knob_param(idx),
+                    );
+                    ui.label(&knob.label);
+                });
+            }
+        });
+        ui.horizontal(|ui| {
+            for (idx, button) in self.buttons.iter_mut().enumerate() {
+                let on = button.value >= 0.5;
+                let response = learnable(
+                    ui.add(
+                        egui::Button::new(&button.label)
+                            .selected(on)
+                            .sense(egui::Sense::click_and_drag()),
+                    ),
+                    &self.id,
+                    &button_param(idx),
+                );
+                if button.toggle {
+                    if response.clicked() {
+                        button.value = if on { 0.0 } else { 1.0 };
+                    }
+                } else if response.is_pointer_button_down_on() {
+                    button.value = 1.0;
+                } else if response.drag_stopped() || response.clicked() {
+                    button.value = 0.0;
+                }
+            }
+        });
+        egui::CollapsingHeader::new("Setup")
+            .id_source((&self.id, "setup"))
+            .show(ui, |ui| {
+                ui.horizontal(|ui| {
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut self.smoothing, 0.0..=MAX_SMOOTHING_SEC)
+                                .suffix(" s"),
+                        ),
+                        &self.id,
+                        SMOOTHING_PARAM,
+                    );
+                    ui.label("Smoothing");
+                });
+                ui.horizontal(|ui| {
+                    for knob in self.knobs.iter_mut() {
+                        ui.add(egui::TextEdit::singleline(&mut knob.label).desired_width(56.0));
+                    }
+                });
+                ui.horizontal(|ui| {
+                    for button in self.buttons.iter_mut() {
+                        ui.vertical(|ui| {
+                            ui.add(
+                                egui::TextEdit::singleline(&mut button.label).desired_width(56.0),
+                            );
+                            ui.checkbox(&mut button.toggle, "Toggle");
+                        });
+                    }
+                });
+            });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        let mut params = vec![
+            (
+                Param::new(X_PARAM, "X", 0.0..=1.0),
+                ParamValue::F32(&mut self.x),
+            ),
+            (
+                Param::new(Y_PARAM, "Y", 0.0..=1.0),
+                ParamValue::F32(&mut self.y),
+            ),
+            (
+                Param::new(SMOOTHING_PARAM, "Smoothing", 0.0..=MAX_SMOOTHING_SEC),
+                ParamValue::F32(&mut self.smoothing),
+            ),
+        ];
+        for (idx, knob) in self.knobs.iter_mut().enumerate() {
+            params.push((
+                Param::new(&knob_param(idx), &knob.label, 0.0..=1.0),
+                ParamValue::F32(&mut knob.value),
+            ));
+        }
+        for (idx, button) in self.buttons.iter_mut().enumerate() {
+            params.push((
+                Param::new(&button_param(idx), &button.label, 0.0..=1.0),
+                ParamValue::F32(&mut button.value),
+            ));
+        }
+        params
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        match idx {
+            0 => Some(ParamValue::F32(&mut self.x)),
+            1 => Some(ParamValue::F32(&mut self.y)),
+            2 => Some(ParamValue::F32(&mut self.smoothing)),
+            _ => {
+                let idx = idx - 3;
+                let knobs = self.knobs.len();
+                if idx < knobs {
+                    Some(ParamValue::F32(&mut self.knobs[idx].value))
+                } else {
+                    let button = self.buttons.get_mut(idx - knobs)?;
+                    Some(ParamValue::F32(&mut button.value))
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 100,
+        channels: 2,
+    };
+
+    fn last_values(controller: &mut ControllerModule) -> Vec<f32> {
+        controller.calc();
+        (0..OUTPUTS as u8)
+            .map(|idx| {
+                controller
+                    .get_output(idx)
+                    .unwrap()
+                    .with_read(|buf| *buf.unwrap().last().unwrap())
+            })
+            .collect()
+    }
+
+    #[test]
+    fn outputs_controls() {
+        let mut controller = ControllerModule::new(&CONFIG);
+        controller.x = 0.25;
+        controller.knobs[1].value = 0.75;
+        controller.buttons[2].value = 0.6;
+        assert_eq!(
+            last_values(&mut controller),
+            vec![0.25, 0.5, 0.0, 0.75, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0]
+        );
+        assert_eq!(
+            controller.get_output_label(3).unwrap(),
+            Some("Knob 2".to_string())
+        );
+    }
+
+    #[test]
+    fn smooths_changes() {
+        let mut controller = ControllerModule::new(&CONFIG);
+        controller.smoothing = 0.2;
+        last_values(&mut controller);
+        controller.knobs[0].value = 1.0;
+        // half way through the smoothing time
+        let value = last_values(&mut controller)[2];
+        assert!(value > 0.8 && value < 0.95, "{}", value);
+        last_values(&mut controller);
+        let value = last_values(&mut controller)[2];
+        assert!(value > 0.99, "{}", value);
+    }
+}
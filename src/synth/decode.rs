@@ -0,0 +1,210 @@
+use super::ControlVoltage;
+use std::error::Error;
+use std::f64::consts::PI;
+use std::io::Cursor;
+use symphonia::core::audio::SampleBuffer;
+use symphonia::core::codecs::{CODEC_TYPE_NULL, DecoderOptions};
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatOptions;
+use symphonia::core::io::MediaSourceStream;
+use symphonia::core::meta::MetadataOptions;
+use symphonia::core::probe::Hint;
+
+/// File extensions which can be decoded, for file dialogs
+pub const EXTENSIONS: &[&str] = &[
+    "wav", "wave", "flac", "ogg", "oga", "mp3", "aif", "aiff", "aifc",
+];
+
+/// Half the number of input samples each resampled sample is built from, when
+/// not lowering the sample rate
+const RESAMPLE_HALF_TAPS: f64 = 16.0;
+/// Entries per input sample in the table of the resampling filter, which is
+/// interpolated between them
+const RESAMPLE_TABLE_RESOLUTION: f64 = 256.0;
+
+#[derive(Debug)]
+struct DecodeError {}
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error decoding audio file")
+    }
+}
+impl Error for DecodeError {}
+
+pub struct DecodedAudio {
+    /// Samples for each channel
+    pub channels: Vec<Vec<ControlVoltage>>,
+    pub sample_rate: f32,
+}
+
+/// Decode a whole audio file in any supported format. The extension, if
+/// known, speeds up guessing the format.
+pub fn decode(data: Vec<u8>, extension: Option<&str>) -> Result<DecodedAudio, Box<dyn Error>> {
+    let stream = MediaSourceStream::new(Box::new(Cursor::new(data)), Default::default());
+    let mut hint = Hint::new();
+    if let Some(extension) = extension {
+        hint.with_extension(extension);
+    }
+    let mut format = symphonia::default::get_probe()
+        .format(
+            &hint,
+            stream,
+            &FormatOptions::default(),
+            &MetadataOptions::default(),
+        )?
+        .format;
+    let track = format
+        .tracks()
+        .iter()
+        .find(|t| t.codec_params.codec != CODEC_TYPE_NULL)
+        .ok_or(DecodeError {})?;
+    let track_id = track.id;
+    let sample_rate = track.codec_params.sample_rate.ok_or(DecodeError {})?;
+    let mut decoder =
+        symphonia::default::get_codecs().make(&track.codec_params, &DecoderOptions::default())?;
+    let mut channels: Vec<Vec<ControlVoltage>> = vec![];
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphoniaError::IoError(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+                break;
+            }
+            Err(e) => return Err(Box::new(e)),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            // a corrupt frame only loses that frame
+            Err(SymphoniaError::DecodeError(_)) => continue,
+            Err(e) => return Err(Box::new(e)),
+        };
+        let spec = *decoded.spec();
+        let num_channels = spec.channels.count();
+        if channels.is_empty() {
+            channels = vec![vec![]; num_channels];
+        }
+        // Every packet has to fill the same channels
+        if num_channels == 0 || num_channels != channels.len() {
+            return Err(Box::new(DecodeError {}));
+        }
+        let mut buf = SampleBuffer::<ControlVoltage>::new(decoded.capacity() as u64, spec);
+        buf.copy_interleaved_ref(decoded);
+        for (idx, sample) in buf.samples().iter().enumerate() {
+            channels[idx % num_channels].push(*sample);
+        }
+    }
+    if channels.is_empty() {
+        return Err(Box::new(DecodeError {}));
+    }
+    Ok(DecodedAudio {
+        channels,
+        sample_rate: sample_rate as f32,
+    })
+}
+
+/// Convert samples from one sample rate to another with a windowed sinc
+/// filter, low-passing first when the rate goes down.
+pub fn resample(samples: &[ControlVoltage], from: f32, to: f32) -> Vec<ControlVoltage> {
+    if from == to || samples.is_empty() || from <= 0.0 || to <= 0.0 {
+        return samples.to_vec();
+    }
+    let step = from as f64 / to as f64;
+    let cutoff = (1.0 / step).min(1.0);
+    let half_width = RESAMPLE_HALF_TAPS / cutoff;
+    let out_len = (samples.len() as f64 / step).round() as usize;
+    let last = samples.len() as isize - 1;
+    // The filter is symmetric, so only the right half is kept, with an extra
+    // entry past the end to interpolate to
+    let table_len = (half_width * RESAMPLE_TABLE_RESOLUTION).ceil() as usize + 2;
+    let table: Vec<f64> = (0..table_len)
+        .map(|i| {
+            let x = i as f64 / RESAMPLE_TABLE_RESOLUTION;
+            if x >= half_width {
+                return 0.0;
+            }
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * x * cutoff).sin() / (PI * x * cutoff)
+            };
+            // Blackman window
+            let w =
+                0.42 + 0.5 * (PI * x / half_width).cos() + 0.08 * (2.0 * PI * x / half_width).cos();
+            sinc * w
+        })
+        .collect();
+    (0..out_len)
+        .map(|n| {
+            let center = n as f64 * step;
+            let first = (center - half_width).ceil() as isize;
+            let last_tap = (center + half_width).floor() as isize;
+            let mut acc = 0.0;
+            let mut weights = 0.0;
+            for idx in first..=last_tap {
+                let pos = (center - idx as f64).abs() * RESAMPLE_TABLE_RESOLUTION;
+                let i = pos as usize;
+                let weight = table[i] + (table[i + 1] - table[i]) * (pos - i as f64);
+                acc += samples[idx.clamp(0, last) as usize] as f64 * weight;
+                weights += weight;
+            }
+            (acc / weights) as ControlVoltage
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build a mono WAV file with 32 bit integer samples
+    fn wav_i32(samples: &[i32], sample_rate: u32) -> Vec<u8> {
+        let data_len = samples.len() as u32 * 4;
+        let mut wav = vec![];
+        wav.extend(b"RIFF");
+        wav.extend((36 + data_len).to_le_bytes());
+        wav.extend(b"WAVEfmt ");
+        wav.extend(16_u32.to_le_bytes());
+        wav.extend(1_u16.to_le_bytes()); // PCM
+        wav.extend(1_u16.to_le_bytes()); // channels
+        wav.extend(sample_rate.to_le_bytes());
+        wav.extend((sample_rate * 4).to_le_bytes());
+        wav.extend(4_u16.to_le_bytes()); // block align
+        wav.extend(32_u16.to_le_bytes()); // bits per sample
+        wav.extend(b"data");
+        wav.extend(data_len.to_le_bytes());
+        for sample in samples {
+            wav.extend(sample.to_le_bytes());
+        }
+        wav
+    }
+
+    #[test]
+    fn decodes_32_bit_int_wav() {
+        let decoded = decode(
+            wav_i32(&[0, i32::MAX, i32::MIN, 1 << 30], 44100),
+            Some("wav"),
+        )
+        .unwrap();
+        assert_eq!(decoded.sample_rate, 44100.0);
+        assert_eq!(decoded.channels.len(), 1);
+        let expected = [0.0, 1.0, -1.0, 0.5];
+        for (sample, expected) in decoded.channels[0].iter().zip(expected) {
+            assert!((sample - expected).abs() < 0.0001);
+        }
+    }
+
+    #[test]
+    fn resamples_sine() {
+        let sine: Vec<ControlVoltage> = (0..44100)
+            .map(|n| (n as f32 * 2.0 * std::f32::consts::PI * 441.0 / 44100.0).sin())
+            .collect();
+        let resampled = resample(&sine, 44100.0, 48000.0);
+        assert_eq!(resampled.len(), 48000);
+        for (n, sample) in resampled.iter().enumerate().skip(100).take(1000) {
+            let expected = (n as f32 * 2.0 * std::f32::consts::PI * 441.0 / 48000.0).sin();
+            assert!((sample - expected).abs() < 0.01);
+        }
+    }
+}
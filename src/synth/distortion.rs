@@ -0,0 +1,596 @@
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, Smoother,
+    SynthModule,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::f32::consts::PI;
+
+const DRIVE_PARAM: &str = "drive";
+const AMOUNT_PARAM: &str = "amount";
+const MIX_PARAM: &str = "mix";
+
+/// Most samples the decimator holds for, at the engine rate
+const MAX_DECIMATION: f32 = 64.0;
+/// Quality factors of two cascaded biquads making a 4th order Butterworth
+/// filter
+const BUTTERWORTH_Q: [f32; 2] = [0.541_196_1, 1.306_563];
+/// Cutoff of the oversampling filters as a fraction of the engine's Nyquist
+/// frequency
+const OVERSAMPLING_CUTOFF: f32 = 0.9;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DistortionMode {
+    SoftClip,
+    HardClip,
+    Fold,
+    Tube,
+    Bitcrush,
+    Decimate,
+}
+
+impl DistortionMode {
+    const ALL: [DistortionMode; 6] = [
+        DistortionMode::SoftClip,
+        DistortionMode::HardClip,
+        DistortionMode::Fold,
+        DistortionMode::Tube,
+        DistortionMode::Bitcrush,
+        DistortionMode::Decimate,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            DistortionMode::SoftClip => "Soft clip",
+            DistortionMode::HardClip => "Hard clip",
+            DistortionMode::Fold => "Wavefolder",
+            DistortionMode::Tube => "Tube",
+            DistortionMode::Bitcrush => "Bitcrusher",
+            DistortionMode::Decimate => "Decimator",
+        }
+    }
+
+    /// What the amount control does in this mode, if anything
+    fn amount_label(&self) -> Option<&'static str> {
+        match self {
+            DistortionMode::Tube => Some("Asymmetry"),
+            DistortionMode::Bitcrush => Some("Bits"),
+            DistortionMode::Decimate => Some("Rate"),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Oversampling {
+    Off,
+    X2,
+    X4,
+}
+
+impl Oversampling {
+    fn factor(&self) -> usize {
+        match self {
+            Oversampling::Off => 1,
+            Oversampling::X2 => 2,
+            Oversampling::X4 => 4,
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            Oversampling::Off => "1x",
+            Oversampling::X2 => "2x",
+            Oversampling::X4 => "4x",
+        }
+    }
+}
+
+/// Low pass biquad filter
+#[derive(Default, Clone)]
+struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    x1: f32,
+    x2: f32,
+    y1: f32,
+    y2: f32,
+}
+
+impl Biquad {
+    /// Set the cutoff as a fraction of the sample rate
+    fn set_lowpass(&mut self, cutoff: f32, q: f32) {
+        let w0 = 2.0 * PI * cutoff;
+        let alpha = w0.sin() / (2.0 * q);
+        let cos = w0.cos();
+        let a0 = 1.0 + alpha;
+        self.b0 = (1.0 - cos) / 2.0 / a0;
+        self.b1 = (1.0 - cos) / a0;
+        self.b2 = self.b0;
+        self.a1 = -2.0 * cos / a0;
+        self.a2 = (1.0 - alpha) / a0;
+    }
+
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.b0 * x + self.b1 * self.x1 + self.b2 * self.x2
+            - self.a1 * self.y1
+            - self.a2 * self.y2;
+        self.x2 = self.x1;
+        self.x1 = x;
+        self.y2 = self.y1;
+        self.y1 = y;
+        y
+    }
+
+    /// Group delay at 0Hz, in samples
+    fn dc_delay(&self) -> f32 {
+        (self.b1 + 2.0 * self.b2) / (self.b0 + self.b1 + self.b2)
+            - (self.a1 + 2.0 * self.a2) / (1.0 + self.a1 + self.a2)
+    }
+}
+
+/// First order allpass delaying by a fraction of a sample, most accurately
+/// from 0.5 to 1.5 samples
+#[derive(Default, Clone)]
+struct FractionalDelay {
+    coefficient: f32,
+    x1: f32,
+    y1: f32,
+}
+
+impl FractionalDelay {
+    fn set_delay(&mut self, delay: f32) {
+        self.coefficient = (1.0 - delay) / (1.0 + delay);
+    }
+
+    #[inline]
+    fn process(&mut self, x: f32) -> f32 {
+        let y = self.coefficient * (x - self.y1) + self.x1;
+        self.x1 = x;
+        self.y1 = y;
+        y
+    }
+}
+
+/// Saturation, clipping, folding and lo-fi effects.
+///
+/// The drive input multiplies the drive by 2 per volt, the amount input adds to
+/// the amount. Oversampling runs the shaping at a multiple of the engine rate,
+/// filtered on the way up and down, to limit aliasing. The dry signal is
+/// delayed to match the filters, so mixing doesn't comb filter.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DistortionModule {
+    id: String,
+    #[serde(skip)]
+    audio_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    drive_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    amount_in: Option<(SharedSynthModule, u8)>,
+    buf: AudioBuffer,
+    mode: DistortionMode,
+    drive: f32,
+    amount: f32,
+    mix: f32,
+    oversampling: Oversampling,
+    /// Sample the decimator holds
+    held: ControlVoltage,
+    /// Progress to the next decimator sample
+    hold_phase: f32,
+    #[serde(skip)]
+    up_filters: [Biquad; 2],
+    #[serde(skip)]
+    down_filters: [Biquad; 2],
+    /// Oversampling the filters were last set up for
+    #[serde(skip)]
+    filters_oversampling: Option<Oversampling>,
+    #[serde(skip)]
+    dry_delay: FractionalDelay,
+    /// Drive, amount and mix
+    #[serde(skip)]
+    smoothers: [Smoother; 3],
+}
+
+impl DistortionModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            audio_in: None,
+            drive_in: None,
+            amount_in: None,
+            buf: AudioBuffer::new(Some(audio_config.buffer_size)),
+            mode: DistortionMode::SoftClip,
+            drive: 1.0,
+            amount: 0.5,
+            mix: 1.0,
+            oversampling: Oversampling::X2,
+            held: 0.0,
+            hold_phase: 1.0,
+            up_filters: Default::default(),
+            down_filters: Default::default(),
+            filters_oversampling: None,
+            dry_delay: FractionalDelay::default(),
+            smoothers: std::array::from_fn(|_| Smoother::new(audio_config.sample_rate)),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Distortion".to_string()
+    }
+
+    /// Shape one sample at the oversampled rate
+    #[inline]
+    fn shape(&mut self, x: f32, amount: f32) -> f32 {
+        match self.mode {
+            DistortionMode::SoftClip => x.tanh(),
+            DistortionMode::HardClip => x.clamp(-1.0, 1.0),
+            DistortionMode::Fold => fold(x),
+            DistortionMode::Tube => {
+                let bias = amount * 0.5;
+                (x + bias).tanh() - bias.tanh()
+            }
+            DistortionMode::Bitcrush => crush(x, 16.0 - amount * 15.0),
+            DistortionMode::Decimate => {
+                let hold = 1.0 + amount * (MAX_DECIMATION - 1.0);
+                self.hold_phase += 1.0 / (hold * self.oversampling.factor() as f32);
+                if self.hold_phase >= 1.0 {
+                    self.hold_phase -= self.hold_phase.floor();
+                    self.held = x;
+                }
+                self.held
+            }
+        }
+    }
+
+    fn update_filters(&mut self) {
+        let factor = self.oversampling.factor() as f32;
+        let cutoff = 0.5 * OVERSAMPLING_CUTOFF / factor;
+        for (filters, q) in [&mut self.up_filters, &mut self.down_filters]
+            .into_iter()
+            .flat_map(|filters| filters.iter_mut())
+            .zip(BUTTERWORTH_Q.iter().cycle())
+        {
+            filters.set_lowpass(cutoff, *q);
+        }
+        let filters_delay: f32 = self
+            .up_filters
+            .iter()
+            .chain(self.down_filters.iter())
+            .map(Biquad::dc_delay)
+            .sum();
+        // Each input sample comes out of the filters as the last of the
+        // oversampled samples it's stuffed into
+        self.dry_delay
+            .set_delay((filters_delay - (factor - 1.0)) / factor);
+        self.filters_oversampling = Some(self.oversampling);
+    }
+
+    /// Process one sample at the engine rate
+    #[inline]
+    fn process(&mut self, x: f32, drive: f32, amount: f32) -> f32 {
+        let factor = self.oversampling.factor();
+        let driven = x * drive;
+        let (wet, dry) = if factor == 1 {
+            (self.shape(driven, amount), x)
+        } else {
+            let mut out = 0.0;
+            for n in 0..factor {
+                // zero stuffing, with the filter bringing back the level
+                let stuffed = if n == 0 { driven * factor as f32 } else { 0.0 };
+                let up = self
+                    .up_filters
+                    .iter_mut()
+                    .fold(stuffed, |v, f| f.process(v));
+                let shaped = self.shape(up, amount);
+                out = self
+                    .down_filters
+                    .iter_mut()
+                    .fold(shaped, |v, f| f.process(v));
+            }
+            (out, self.dry_delay.process(x))
+        };
+        let mix = self.smoothers[2].next(self.mix);
+        wet * mix + dry * (1.0 - mix)
+    }
+}
+
+/// Triangle wavefolder, reflecting the signal back each time it passes ±1
+#[inline]
+fn fold(x: f32) -> f32 {
+    1.0 - ((x + 1.0).rem_euclid(4.0) - 2.0).abs()
+}
+
+/// Reduce to a number of bits, which may be fractional
+#[inline]
+fn crush(x: f32, bits: f32) -> f32 {
+    let steps = 2.0_f32.powf(bits - 1.0);
+    (x * steps).round() / steps
+}
+
+impl SynthModule for DistortionModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.buf.resize(audio_config.buffer_size);
+        for smoother in self.smoothers.iter_mut() {
+            smoother.set_sample_rate(audio_config.sample_rate);
+        }
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        3
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.audio_in.clone()),
+            1 => Ok(self.drive_in.clone()),
+            2 => Ok(self.amount_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.audio_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.drive_in = Some((src_module, src_port));
+                Ok(())
+            }
+            2 => {
+                self.amount_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.audio_in = None;
+                Ok(())
+            }
+            1 => {
+                self.drive_in = None;
+                Ok(())
+            }
+            2 => {
+                self.amount_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("In".to_string())),
+            1 => Ok(Some("Drive".to_string())),
+            2 => Ok(Some("Amount".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        1
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.buf.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        if self.filters_oversampling != Some(self.oversampling) {
+            self.update_filters();
+        }
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
+            ],
+            |bufs| {
+                let (audio_in, drive_in, amount_in) = bufs.into_iter().collect_tuple().unwrap();
+                let buf = self.buf.clone();
+                buf.with_write(|output| {
+                    let output = output.unwrap();
+                    for idx in 0..output.len() {
+                        let drive = self.smoothers[0].next(self.drive)
+                            * 2.0_f32.powf(drive_in.map(|buf| buf[idx]).unwrap_or(0.0));
+                        let amount = (self.smoothers[1].next(self.amount)
+                            + amount_in.map(|buf| buf[idx]).unwrap_or(0.0))
+                        .clamp(0.0, 1.0);
+                        output[idx] = self.process(
+                            audio_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                            drive,
+                            amount,
+                        );
+                    }
+                });
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.horizontal(|ui| {
+                egui::ComboBox::from_id_source((&self.id, "mode"))
+                    .selected_text(self.mode.label())
+                    .show_ui(ui, |ui| {
+                        for mode in DistortionMode::ALL {
+                            ui.selectable_value(&mut self.mode, mode, mode.label());
+                        }
+                    });
+                for oversampling in [Oversampling::Off, Oversampling::X2, Oversampling::X4] {
+                    ui.selectable_value(&mut self.oversampling, oversampling, oversampling.label());
+                }
+            });
+            ui.horizontal(|ui| {
+                ui.vertical(|ui| {
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut self.drive, 0.1..=50.0)
+                                .logarithmic(true)
+                                .show_value(false)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        )
+                        .on_hover_text(format!("{:.2}", self.drive)),
+                        &self.id,
+                        DRIVE_PARAM,
+                    );
+                    ui.label("Drive");
+                });
+                if let Some(label) = self.mode.amount_label() {
+                    ui.vertical(|ui| {
+                        learnable(
+                            ui.add(
+                                egui::Slider::new(&mut self.amount, 0.0..=1.0)
+                                    .show_value(false)
+                                    .orientation(egui::SliderOrientation::Vertical),
+                            )
+                            .on_hover_text(format!("{:.2}", self.amount)),
+                            &self.id,
+                            AMOUNT_PARAM,
+                        );
+                        ui.label(label);
+                    });
+                }
+                ui.vertical(|ui| {
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut self.mix, 0.0..=1.0)
+                                .show_value(false)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        )
+                        .on_hover_text(format!("{:.2}", self.mix)),
+                        &self.id,
+                        MIX_PARAM,
+                    );
+                    ui.label("Mix");
+                });
+            });
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(DRIVE_PARAM, "Drive", 0.1..=50.0),
+                ParamValue::F32(&mut self.drive),
+            ),
+            (
+                Param::new(AMOUNT_PARAM, "Amount", 0.0..=1.0),
+                ParamValue::F32(&mut self.amount),
+            ),
+            (
+                Param::new(MIX_PARAM, "Mix", 0.0..=1.0),
+                ParamValue::F32(&mut self.mix),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.drive),
+            1 => ParamValue::F32(&mut self.amount),
+            2 => ParamValue::F32(&mut self.mix),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shapes() {
+        assert_eq!(fold(0.5), 0.5);
+        assert_eq!(fold(1.5), 0.5);
+        assert_eq!(fold(-2.5), 0.5);
+        assert_eq!(crush(0.3, 1.0), 0.0);
+        assert_eq!(crush(0.3, 2.0), 0.5);
+    }
+
+    #[test]
+    fn oversampling_keeps_level() {
+        let mut distortion = DistortionModule::new(&AudioConfig {
+            sample_rate: 48000,
+            buffer_size: 64,
+            channels: 2,
+        });
+        distortion.mode = DistortionMode::HardClip;
+        distortion.oversampling = Oversampling::X4;
+        distortion.update_filters();
+        let sine = |n: usize| 0.5 * (n as f32 * 2.0 * PI * 100.0 / 48000.0).sin();
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        let out: Vec<f32> = (0..9600)
+            .map(|n| distortion.process(sine(n), 1.0, 0.0))
+            .collect();
+        let input: Vec<f32> = (0..9600).map(sine).collect();
+        assert!((rms(&out[4800..]) / rms(&input[4800..]) - 1.0).abs() < 0.01);
+    }
+
+    #[test]
+    fn dry_lines_up_with_wet() {
+        let mut distortion = DistortionModule::new(&AudioConfig {
+            sample_rate: 48000,
+            buffer_size: 64,
+            channels: 2,
+        });
+        // Linear, so the wet signal only differs from the dry by the filters
+        distortion.mode = DistortionMode::HardClip;
+        distortion.mix = 0.5;
+        let sine = |n: usize| 0.5 * (n as f32 * 2.0 * PI * 6000.0 / 48000.0).sin();
+        let rms = |samples: &[f32]| {
+            (samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32).sqrt()
+        };
+        let input: Vec<f32> = (0..9600).map(sine).collect();
+        for oversampling in [Oversampling::X2, Oversampling::X4] {
+            distortion.oversampling = oversampling;
+            distortion.update_filters();
+            let out: Vec<f32> = input
+                .iter()
+                .map(|x| distortion.process(*x, 1.0, 0.0))
+                .collect();
+            assert!((rms(&out[4800..]) / rms(&input[4800..]) - 1.0).abs() < 0.02);
+        }
+    }
+}
@@ -0,0 +1,542 @@
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, SynthModule,
+    TransitionDetector,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::f32::consts::PI;
+
+const TUNE_PARAM: &str = "tune";
+const DECAY_PARAM: &str = "decay";
+const TONE_PARAM: &str = "tone";
+const ACCENT_PARAM: &str = "accent";
+
+/// Envelope level below which a voice stops
+const SILENCE: f32 = 0.0001;
+/// Level of unaccented hits with the accent control at maximum
+const MIN_UNACCENTED_LEVEL: f32 = 0.3;
+/// Ratios of the hi-hat's square wave oscillators, as in the TR-808
+const HIHAT_RATIOS: [f32; 6] = [1.0, 1.483, 1.804, 2.546, 2.63, 3.897];
+/// Gap between the clap's first bursts, in seconds
+const CLAP_BURST_SEC: f32 = 0.01;
+/// Number of bursts before the clap's tail
+const CLAP_BURSTS: u32 = 3;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum DrumVoice {
+    Kick,
+    Snare,
+    HiHat,
+    Clap,
+}
+
+impl DrumVoice {
+    /// Base frequency in Hz at 0V tune
+    fn frequency(&self) -> f32 {
+        match self {
+            DrumVoice::Kick => 50.0,
+            DrumVoice::Snare => 180.0,
+            DrumVoice::HiHat => 205.0,
+            DrumVoice::Clap => 1000.0,
+        }
+    }
+
+    /// Shortest and longest decay, in seconds
+    fn decay_range(&self) -> (f32, f32) {
+        match self {
+            DrumVoice::Kick => (0.05, 2.0),
+            DrumVoice::Snare => (0.05, 0.8),
+            DrumVoice::HiHat => (0.02, 1.0),
+            DrumVoice::Clap => (0.05, 1.0),
+        }
+    }
+}
+
+/// Topology preserving state variable filter
+#[derive(Default, Clone)]
+struct Svf {
+    k: f32,
+    a1: f32,
+    a2: f32,
+    a3: f32,
+    ic1: f32,
+    ic2: f32,
+}
+
+impl Svf {
+    fn set(&mut self, cutoff: f32, q: f32, sample_rate: f32) {
+        let g = (PI * cutoff.min(0.49 * sample_rate) / sample_rate).tan();
+        self.k = 1.0 / q;
+        self.a1 = 1.0 / (1.0 + g * (g + self.k));
+        self.a2 = g * self.a1;
+        self.a3 = g * self.a2;
+    }
+
+    /// Returns the band pass and high pass outputs
+    #[inline]
+    fn process(&mut self, v0: f32) -> (f32, f32) {
+        let v3 = v0 - self.ic2;
+        let v1 = self.a1 * self.ic1 + self.a2 * v3;
+        let v2 = self.ic2 + self.a2 * self.ic1 + self.a3 * v3;
+        self.ic1 = 2.0 * v1 - self.ic1;
+        self.ic2 = 2.0 * v2 - self.ic2;
+        (v1, v0 - self.k * v1 - v2)
+    }
+}
+
+/// State of a hit, set up on each trigger
+#[derive(Default, Clone)]
+struct Hit {
+    active: bool,
+    level: f32,
+    frequency: f32,
+    tone: f32,
+    amp_env: f32,
+    amp_coef: f32,
+    /// Short envelope for pitch sweeps, clicks and snare bodies
+    fast_env: f32,
+    fast_coef: f32,
+    phases: [f32; 6],
+    samples: u32,
+    filter: Svf,
+}
+
+#[inline]
+fn noise() -> f32 {
+    (rand::random::<f32>() - 0.5) * 2.0
+}
+
+#[inline]
+fn decay_coef(sec: f32, sample_rate: f32) -> f32 {
+    (-1.0 / (sec * sample_rate)).exp()
+}
+
+/// Analog style drum voice, hit by a rising edge on the trigger input.
+///
+/// The tune input adds to the tune in volts per octave, and the decay and tone
+/// inputs add to the decay and tone, all read when the drum is hit. Hits are quieter by the accent
+/// amount unless the accent input is high when the drum is hit.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct DrumModule {
+    id: String,
+    voice: DrumVoice,
+    #[serde(skip)]
+    trigger_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    accent_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    tune_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    decay_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    tone_in: Option<(SharedSynthModule, u8)>,
+    buf: AudioBuffer,
+    /// Volts per octave
+    tune: f32,
+    decay: f32,
+    tone: f32,
+    accent: f32,
+    sample_rate: f32,
+    transition_detector: TransitionDetector,
+    #[serde(skip)]
+    hit: Hit,
+}
+
+impl DrumModule {
+    pub fn new(audio_config: &AudioConfig, voice: DrumVoice) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            voice,
+            trigger_in: None,
+            accent_in: None,
+            tune_in: None,
+            decay_in: None,
+            tone_in: None,
+            buf: AudioBuffer::new(Some(audio_config.buffer_size)),
+            tune: 0.0,
+            decay: 0.5,
+            tone: 0.5,
+            accent: 0.5,
+            sample_rate: audio_config.sample_rate as f32,
+            transition_detector: TransitionDetector::new(),
+            hit: Hit::default(),
+        }
+    }
+
+    pub fn get_name(voice: &DrumVoice) -> String {
+        match voice {
+            DrumVoice::Kick => "Kick".to_string(),
+            DrumVoice::Snare => "Snare".to_string(),
+            DrumVoice::HiHat => "Hi-Hat".to_string(),
+            DrumVoice::Clap => "Clap".to_string(),
+        }
+    }
+
+    fn strike(&mut self, accented: bool, tune_cv: f32, decay_cv: f32, tone_cv: f32) {
+        let sample_rate = self.sample_rate;
+        let (min_decay, max_decay) = self.voice.decay_range();
+        let decay = (self.decay + decay_cv).clamp(0.0, 1.0);
+        let decay_sec = min_decay * (max_decay / min_decay).powf(decay);
+        let frequency = self.voice.frequency() * 2.0_f32.powf(self.tune + tune_cv);
+        let tone = (self.tone + tone_cv).clamp(0.0, 1.0);
+        let mut hit = Hit {
+            active: true,
+            level: if accented {
+                1.0
+            } else {
+                1.0 - self.accent * (1.0 - MIN_UNACCENTED_LEVEL)
+            },
+            frequency,
+            tone,
+            amp_env: 1.0,
+            amp_coef: decay_coef(decay_sec, sample_rate),
+            fast_env: 1.0,
+            ..Default::default()
+        };
+        match self.voice {
+            DrumVoice::Kick => {
+                hit.fast_coef = decay_coef(0.03, sample_rate);
+            }
+            DrumVoice::Snare => {
+                hit.fast_coef = decay_coef(decay_sec * 0.3, sample_rate);
+                hit.filter
+                    .set(1500.0 * 4.0_f32.powf(tone), 0.7, sample_rate);
+            }
+            DrumVoice::HiHat => {
+                hit.filter
+                    .set(6000.0 * 2.0_f32.powf(tone), 0.7, sample_rate);
+            }
+            DrumVoice::Clap => {
+                hit.fast_coef = decay_coef(0.003, sample_rate);
+                hit.filter.set(frequency, 1.0 + tone * 4.0, sample_rate);
+            }
+        }
+        self.hit = hit;
+    }
+
+    #[inline]
+    fn advance_phase(&mut self, osc: usize, frequency: f32) -> f32 {
+        let phase = &mut self.hit.phases[osc];
+        *phase = (*phase + frequency / self.sample_rate).fract();
+        *phase
+    }
+
+    #[inline]
+    fn process(&mut self) -> ControlVoltage {
+        if !self.hit.active {
+            return 0.0;
+        }
+        let tone = self.hit.tone;
+        let frequency = self.hit.frequency;
+        let out = match self.voice {
+            DrumVoice::Kick => {
+                let swept = frequency * (1.0 + 3.0 * self.hit.fast_env);
+                let body = (2.0 * PI * self.advance_phase(0, swept)).sin() * self.hit.amp_env;
+                let click = noise() * self.hit.fast_env * self.hit.fast_env * tone;
+                (body * (1.0 + 2.0 * tone) + click).tanh()
+            }
+            DrumVoice::Snare => {
+                let body = 0.6 * (2.0 * PI * self.advance_phase(0, frequency)).sin()
+                    + 0.4 * (2.0 * PI * self.advance_phase(1, frequency * 1.83)).sin();
+                let (_, rattle) = self.hit.filter.process(noise());
+                body * self.hit.fast_env * (1.0 - tone * 0.5)
+                    + rattle * self.hit.amp_env * (0.5 + tone * 0.5)
+            }
+            DrumVoice::HiHat => {
+                let mut metal = 0.0;
+                for (osc, ratio) in HIHAT_RATIOS.iter().enumerate() {
+                    metal += if self.advance_phase(osc, frequency * ratio) < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    };
+                }
+                let source =
+                    metal / HIHAT_RATIOS.len() as f32 * (1.0 - tone * 0.5) + noise() * tone * 0.5;
+                let (_, high) = self.hit.filter.process(source);
+                high * self.hit.amp_env * 2.0
+            }
+            DrumVoice::Clap => {
+                let burst_len = (CLAP_BURST_SEC * self.sample_rate) as u32;
+                let env = if self.hit.samples < burst_len * CLAP_BURSTS {
+                    if self.hit.samples.is_multiple_of(burst_len) {
+                        self.hit.fast_env = 1.0;
+                    }
+                    self.hit.fast_env
+                } else {
+                    self.hit.amp_env
+                };
+                let (band, _) = self.hit.filter.process(noise());
+                band * env * 2.0
+            }
+        };
+        self.hit.samples += 1;
+        self.hit.fast_env *= self.hit.fast_coef;
+        if self.voice != DrumVoice::Clap || self.hit.samples >= self.clap_tail_start() {
+            self.hit.amp_env *= self.hit.amp_coef;
+        }
+        if self.hit.amp_env < SILENCE {
+            self.hit.active = false;
+        }
+        out * self.hit.level
+    }
+
+    fn clap_tail_start(&self) -> u32 {
+        (CLAP_BURST_SEC * self.sample_rate) as u32 * CLAP_BURSTS
+    }
+}
+
+impl SynthModule for DrumModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name(&self.voice)
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate as f32;
+        self.buf.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        5
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.trigger_in.clone()),
+            1 => Ok(self.accent_in.clone()),
+            2 => Ok(self.tune_in.clone()),
+            3 => Ok(self.decay_in.clone()),
+            4 => Ok(self.tone_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.trigger_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.accent_in = Some((src_module, src_port));
+                Ok(())
+            }
+            2 => {
+                self.tune_in = Some((src_module, src_port));
+                Ok(())
+            }
+            3 => {
+                self.decay_in = Some((src_module, src_port));
+                Ok(())
+            }
+            4 => {
+                self.tone_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.trigger_in = None;
+                Ok(())
+            }
+            1 => {
+                self.accent_in = None;
+                Ok(())
+            }
+            2 => {
+                self.tune_in = None;
+                Ok(())
+            }
+            3 => {
+                self.decay_in = None;
+                Ok(())
+            }
+            4 => {
+                self.tone_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Trigger".to_string())),
+            1 => Ok(Some("Accent".to_string())),
+            2 => Ok(Some("Tune".to_string())),
+            3 => Ok(Some("Decay".to_string())),
+            4 => Ok(Some("Tone".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        1
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.buf.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
+                self.resolve_input(3).unwrap(),
+                self.resolve_input(4).unwrap(),
+            ],
+            |bufs| {
+                let (trigger_in, accent_in, tune_in, decay_in, tone_in) =
+                    bufs.into_iter().collect_tuple().unwrap();
+                let buf = self.buf.clone();
+                buf.with_write(|output| {
+                    let output = output.unwrap();
+                    for idx in 0..output.len() {
+                        let trigger = trigger_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                        if self.transition_detector.is_transition(&trigger) {
+                            self.strike(
+                                accent_in.is_some_and(|buf| {
+                                    TransitionDetector::is_above_threshold(&buf[idx])
+                                }),
+                                tune_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                                decay_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                                tone_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                            );
+                        }
+                        output[idx] = self.process();
+                    }
+                });
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for (value, range, label, param_id) in [
+                (&mut self.tune, -1.0..=1.0, "Tune", TUNE_PARAM),
+                (&mut self.decay, 0.0..=1.0, "Decay", DECAY_PARAM),
+                (&mut self.tone, 0.0..=1.0, "Tone", TONE_PARAM),
+                (&mut self.accent, 0.0..=1.0, "Accent", ACCENT_PARAM),
+            ] {
+                ui.vertical(|ui| {
+                    let response = ui
+                        .add(
+                            egui::Slider::new(value, range)
+                                .show_value(false)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        )
+                        .on_hover_text(format!("{value:.2}"));
+                    learnable(response, &self.id, param_id);
+                    ui.label(label);
+                });
+            }
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(TUNE_PARAM, "Tune", -1.0..=1.0),
+                ParamValue::F32(&mut self.tune),
+            ),
+            (
+                Param::new(DECAY_PARAM, "Decay", 0.0..=1.0),
+                ParamValue::F32(&mut self.decay),
+            ),
+            (
+                Param::new(TONE_PARAM, "Tone", 0.0..=1.0),
+                ParamValue::F32(&mut self.tone),
+            ),
+            (
+                Param::new(ACCENT_PARAM, "Accent", 0.0..=1.0),
+                ParamValue::F32(&mut self.accent),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.tune),
+            1 => ParamValue::F32(&mut self.decay),
+            2 => ParamValue::F32(&mut self.tone),
+            3 => ParamValue::F32(&mut self.accent),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn voices_sound_and_decay() {
+        let config = AudioConfig {
+            sample_rate: 48000,
+            buffer_size: 64,
+            channels: 2,
+        };
+        for voice in [
+            DrumVoice::Kick,
+            DrumVoice::Snare,
+            DrumVoice::HiHat,
+            DrumVoice::Clap,
+        ] {
+            let mut drum = DrumModule::new(&config, voice);
+            drum.decay = 0.0;
+            drum.strike(true, 0.0, 0.0, 0.0);
+            let out: Vec<f32> = (0..48000).map(|_| drum.process()).collect();
+            let peak = out[..4800].iter().fold(0.0_f32, |a, b| a.max(b.abs()));
+            assert!(peak > 0.1 && peak < 2.0);
+            assert!(out.iter().all(|s| s.is_finite()));
+            assert!(!drum.hit.active);
+        }
+    }
+
+    #[test]
+    fn tone_input_adds_to_tone() {
+        let mut drum = DrumModule::new(
+            &AudioConfig {
+                sample_rate: 48000,
+                buffer_size: 64,
+                channels: 2,
+            },
+            DrumVoice::HiHat,
+        );
+        drum.strike(true, 0.0, 0.0, 0.25);
+        assert_eq!(drum.hit.tone, 0.75);
+        drum.strike(true, 0.0, 0.0, 1.0);
+        assert_eq!(drum.hit.tone, 1.0);
+    }
+}
@@ -0,0 +1,417 @@
+use super::midi_learn::learnable;
+use super::sequencer::copy_rhythm;
+use super::{
+    AudioBuffer, AudioConfig, Param, ParamValue, SharedSynthModule, SynthModule, TransitionDetector,
+};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const CHANNELS: usize = 4;
+const MAX_STEPS: u8 = 32;
+const CELL_SIZE: f32 = 7.0;
+const CELL_PADDING: f32 = 1.0;
+
+/// Id of a channel's parameter, such as "hits 0"
+fn rhythm_param(name: &str, channel: usize) -> String {
+    format!("{name} {channel}")
+}
+
+/// Hits spread as evenly as possible over a number of steps, then rotated
+#[derive(Serialize, Deserialize, Clone)]
+struct Rhythm {
+    hits: u8,
+    steps: u8,
+    rotation: u8,
+    position: u8,
+}
+
+impl Rhythm {
+    /// Rhythm with its settings moved by CV, scaled so 1V sweeps the whole
+    /// range of each
+    fn modulated(&self, hits_cv: f32, steps_cv: f32, rotation_cv: f32) -> Self {
+        let steps = (self.steps as f32 + (steps_cv * MAX_STEPS as f32).round())
+            .clamp(1.0, MAX_STEPS as f32) as u8;
+        let hits =
+            (self.hits as f32 + (hits_cv * steps as f32).round()).clamp(0.0, steps as f32) as u8;
+        let rotation = (self.rotation as i32 + (rotation_cv * steps as f32).round() as i32)
+            .rem_euclid(steps as i32) as u8;
+        Self {
+            hits,
+            steps,
+            rotation,
+            position: self.position % steps,
+        }
+    }
+
+    fn is_hit(&self, position: u8) -> bool {
+        let steps = self.steps as u32;
+        let hits = self.hits.min(self.steps) as u32;
+        let idx = (position as u32 + steps - self.rotation as u32 % steps) % steps;
+        (idx * hits) % steps < hits
+    }
+
+    fn pattern(&self) -> Vec<bool> {
+        (0..self.steps)
+            .map(|position| self.is_hit(position))
+            .collect()
+    }
+}
+
+/// Euclidean rhythm generator, with a channel per gate output.
+///
+/// Inputs are Step and Sync as for the pattern sequencer, then Hits, Steps and
+/// Rotate CV for each channel. Outputs are the channel gates, then Sync.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct EuclideanModule {
+    id: String,
+    gate_outs: Vec<AudioBuffer>,
+    sync_out: AudioBuffer,
+    rhythms: Vec<Rhythm>,
+    #[serde(skip)]
+    step_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    sync_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    cv_in: [Option<(SharedSynthModule, u8)>; CHANNELS * 3],
+    transition_detector: TransitionDetector,
+    sync_transition_detector: TransitionDetector,
+    /// Rhythms as last played, including CV
+    #[serde(skip)]
+    playing: Vec<Rhythm>,
+    ui_dirty: bool,
+}
+
+impl EuclideanModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        let rhythms: Vec<_> = [(4, 16), (3, 8), (5, 16), (2, 5)]
+            .into_iter()
+            .map(|(hits, steps)| Rhythm {
+                hits,
+                steps,
+                rotation: 0,
+                position: 0,
+            })
+            .collect();
+        Self {
+            id: uuid::Uuid::new_v4().into(),
+            gate_outs: (0..CHANNELS)
+                .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
+                .collect(),
+            sync_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            playing: rhythms.clone(),
+            rhythms,
+            step_in: None,
+            sync_in: None,
+            cv_in: Default::default(),
+            transition_detector: TransitionDetector::new(),
+            sync_transition_detector: TransitionDetector::new(),
+            ui_dirty: false,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Euclidean Rhythm".to_string()
+    }
+}
+
+impl SynthModule for EuclideanModule {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        let max = MAX_STEPS as f32;
+        let mut params = vec![];
+        for (channel, rhythm) in self.rhythms.iter_mut().enumerate() {
+            params.extend([
+                (
+                    Param::new(
+                        &rhythm_param("hits", channel),
+                        &format!("Hits {channel}"),
+                        0.0..=max,
+                    ),
+                    ParamValue::U8(&mut rhythm.hits),
+                ),
+                (
+                    Param::new(
+                        &rhythm_param("steps", channel),
+                        &format!("Steps {channel}"),
+                        1.0..=max,
+                    ),
+                    ParamValue::U8(&mut rhythm.steps),
+                ),
+                (
+                    Param::new(
+                        &rhythm_param("rotation", channel),
+                        &format!("Rotation {channel}"),
+                        0.0..=max - 1.0,
+                    ),
+                    ParamValue::U8(&mut rhythm.rotation),
+                ),
+            ]);
+        }
+        params
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        let rhythm = self.rhythms.get_mut(idx / 3)?;
+        Some(ParamValue::U8(match idx % 3 {
+            0 => &mut rhythm.hits,
+            1 => &mut rhythm.steps,
+            _ => &mut rhythm.rotation,
+        }))
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        for out in self.gate_outs.iter_mut() {
+            out.resize(audio_config.buffer_size)
+        }
+        self.sync_out.resize(audio_config.buffer_size);
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        egui::Grid::new((&self.id, "rhythms")).show(ui, |ui| {
+            for (channel, rhythm) in self.rhythms.iter_mut().enumerate() {
+                ui.label(channel.to_string());
+                learnable(
+                    ui.add(
+                        egui::DragValue::new(&mut rhythm.hits)
+                            .range(0..=rhythm.steps)
+                            .suffix(" hits"),
+                    ),
+                    &self.id,
+                    &rhythm_param("hits", channel),
+                );
+                learnable(
+                    ui.add(
+                        egui::DragValue::new(&mut rhythm.steps)
+                            .range(1..=MAX_STEPS)
+                            .suffix(" steps"),
+                    ),
+                    &self.id,
+                    &rhythm_param("steps", channel),
+                );
+                learnable(
+                    ui.add(
+                        egui::DragValue::new(&mut rhythm.rotation)
+                            .range(0..=rhythm.steps - 1)
+                            .prefix("↻ "),
+                    ),
+                    &self.id,
+                    &rhythm_param("rotation", channel),
+                );
+                rhythm.hits = rhythm.hits.min(rhythm.steps);
+                rhythm.rotation = rhythm.rotation.min(rhythm.steps - 1);
+
+                let playing = self.playing.get(channel).unwrap_or(rhythm);
+                let (_, rect) = ui.allocate_space(
+                    [MAX_STEPS as f32 * (CELL_SIZE + CELL_PADDING), CELL_SIZE].into(),
+                );
+                for (col, hit) in playing.pattern().into_iter().enumerate() {
+                    let top_left = egui::Pos2::new(
+                        rect.min.x + col as f32 * (CELL_SIZE + CELL_PADDING),
+                        rect.min.y,
+                    );
+                    let color = if usize::from(playing.position) == col {
+                        egui::Color32::RED
+                    } else if hit {
+                        egui::Color32::BLUE
+                    } else {
+                        egui::Color32::LIGHT_GRAY
+                    };
+                    ui.painter().rect_filled(
+                        egui::Rect::from_min_size(top_left, [CELL_SIZE, CELL_SIZE].into()),
+                        1.0,
+                        color,
+                    );
+                }
+                if ui
+                    .button("Copy")
+                    .on_hover_text("Copy the rhythm, to paste into a pattern sequencer lane")
+                    .clicked()
+                {
+                    copy_rhythm(ui.ctx(), playing.pattern());
+                }
+                ui.end_row();
+            }
+        });
+        self.ui_dirty = false;
+    }
+
+    fn calc(&mut self) {
+        let inputs: Vec<_> = (0..self.get_num_inputs())
+            .map(|n| self.resolve_input(n).unwrap())
+            .collect();
+        AudioBuffer::with_read_many(inputs, |bufs| {
+            let (step_in_buf, sync_in_buf) = (bufs[0], bufs[1]);
+            let cv_bufs = &bufs[2..];
+            AudioBuffer::with_write_many(self.gate_outs.clone(), |outputs| {
+                self.sync_out.with_write(|sync_out| {
+                    let sync_out = sync_out.unwrap();
+                    let mut outputs: Vec<_> = outputs.into_iter().map(|o| o.unwrap()).collect();
+                    self.playing
+                        .resize(self.rhythms.len(), self.rhythms[0].clone());
+                    for idx in 0..sync_out.len() {
+                        let step_in = step_in_buf.map_or(0.0, |buf| buf[idx]);
+                        let sync_in = sync_in_buf.map_or(0.0, |buf| buf[idx]);
+                        let pulse = self.transition_detector.is_transition(&step_in);
+                        let sync = self.sync_transition_detector.is_transition(&sync_in);
+                        if pulse {
+                            self.ui_dirty = true;
+                        }
+                        for (channel, rhythm) in self.rhythms.iter_mut().enumerate() {
+                            if sync {
+                                rhythm.position = 0;
+                            } else if pulse {
+                                rhythm.position = rhythm.position.wrapping_add(1);
+                            }
+                            let cv =
+                                |n: usize| cv_bufs[channel * 3 + n].map_or(0.0, |buf| buf[idx]);
+                            let playing = rhythm.modulated(cv(0), cv(1), cv(2));
+                            rhythm.position = playing.position;
+                            outputs[channel][idx] = if playing.is_hit(playing.position) {
+                                step_in
+                            } else {
+                                0.0
+                            };
+                            self.playing[channel] = playing;
+                        }
+                        sync_out[idx] = if self.rhythms.iter().all(|r| r.position == 0) {
+                            1.0
+                        } else {
+                            0.0
+                        };
+                    }
+                });
+            });
+        });
+    }
+
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.step_in.clone()),
+            1 => Ok(self.sync_in.clone()),
+            n => match self.cv_in.get(n as usize - 2) {
+                Some(input) => Ok(input.clone()),
+                None => Err(()),
+            },
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Step".to_string())),
+            1 => Ok(Some("Sync".to_string())),
+            n if (n as usize - 2) < CHANNELS * 3 => {
+                let channel = (n - 2) / 3;
+                let label = ["Hits", "Steps", "Rotate"][(n as usize - 2) % 3];
+                Ok(Some(format!("{label} {channel}")))
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => self.step_in = Some((src_module, src_port)),
+            1 => self.sync_in = Some((src_module, src_port)),
+            n => match self.cv_in.get_mut(n as usize - 2) {
+                Some(input) => *input = Some((src_module, src_port)),
+                None => return Err(()),
+            },
+        }
+        Ok(())
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => self.step_in = None,
+            1 => self.sync_in = None,
+            n => match self.cv_in.get_mut(n as usize - 2) {
+                Some(input) => *input = None,
+                None => return Err(()),
+            },
+        }
+        Ok(())
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        let channels = self.gate_outs.len() as u8;
+        if output_idx == channels {
+            return Ok(self.sync_out.clone());
+        }
+        if output_idx < channels {
+            return Ok(self.gate_outs[output_idx as usize].clone());
+        }
+        Err(())
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        let channels = self.gate_outs.len() as u8;
+        if output_idx == channels {
+            return Ok(Some("Sync".to_string()));
+        }
+        if output_idx < channels {
+            return Ok(Some(format!("{output_idx}")));
+        }
+        Err(())
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2 + self.cv_in.len() as u8
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        self.gate_outs.len() as u8 + 1
+    }
+
+    fn ui_dirty(&self) -> bool {
+        self.ui_dirty
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rhythm(hits: u8, steps: u8, rotation: u8) -> Rhythm {
+        Rhythm {
+            hits,
+            steps,
+            rotation,
+            position: 0,
+        }
+    }
+
+    fn show(pattern: Vec<bool>) -> String {
+        pattern
+            .into_iter()
+            .map(|hit| if hit { 'x' } else { '.' })
+            .collect()
+    }
+
+    #[test]
+    fn spreads_and_rotates_hits() {
+        assert_eq!(show(rhythm(3, 8, 0).pattern()), "x..x..x.");
+        assert_eq!(show(rhythm(3, 8, 1).pattern()), ".x..x..x");
+        assert_eq!(show(rhythm(4, 4, 0).pattern()), "xxxx");
+        assert_eq!(show(rhythm(0, 4, 0).pattern()), "....");
+        let modulated = rhythm(3, 8, 0).modulated(0.0, 0.125, 0.0);
+        assert_eq!(show(modulated.pattern()), "x...x...x...");
+        let modulated = rhythm(3, 8, 0).modulated(0.0, 0.125, 0.25);
+        assert_eq!(show(modulated.pattern()), "...x...x...x");
+    }
+}
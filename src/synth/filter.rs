@@ -1,8 +1,18 @@
-use super::{AudioBuffer, AudioConfig, SharedSynthModule, SynthModule};
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, Param, ParamValue, SharedSynthModule, Smoother, SynthModule,
+};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 
+const FREQ_PARAM: &str = "freq";
+const RES_PARAM: &str = "res";
+const EXP_AMT_PARAM: &str = "exp_amt";
+
+/// Smallest amount of exponential frequency modulation
+const MIN_EXP_AMT: f32 = 1.0 / 256.0;
+
 /// Moog Filter based on
 /// https://ccrma.stanford.edu/~stilti/papers/moogvcf.pdf
 /// and the implementation at
@@ -22,6 +32,9 @@ pub struct MoogFilterModule {
     res: f32,
     exp_amt: f32,
     state: InternalMoogFilterState,
+    /// Frequency, resonance and exponential amount
+    #[serde(skip)]
+    smoothers: [Smoother; 3],
 }
 
 impl MoogFilterModule {
@@ -37,6 +50,7 @@ impl MoogFilterModule {
             res: 0.5,
             exp_amt: 0.5,
             state: InternalMoogFilterState::default(),
+            smoothers: std::array::from_fn(|_| Smoother::new(audio_config.sample_rate)),
         }
     }
 
@@ -104,6 +118,9 @@ impl SynthModule for MoogFilterModule {
         self.lowpass.resize(audio_config.buffer_size);
         self.bandpass.resize(audio_config.buffer_size);
         self.highpass.resize(audio_config.buffer_size);
+        for smoother in self.smoothers.iter_mut() {
+            smoother.set_sample_rate(audio_config.sample_rate);
+        }
     }
 
     fn get_num_inputs(&self) -> u8 {
@@ -208,10 +225,14 @@ impl SynthModule for MoogFilterModule {
                                 Some(s) => s[idx],
                                 None => 0.0,
                             };
+                            let [freq, res, exp_amt] = &mut self.smoothers;
+                            let freq = freq.next(self.freq);
+                            let res = res.next(self.res);
+                            let exp_amt = exp_amt.next(self.exp_amt);
                             (lowpass[idx], highpass[idx], bandpass[idx]) = self.state.calc(
                                 audio,
-                                (self.freq + cv * self.exp_amt).max(0.0).min(0.9),
-                                self.res.max(0.0).min(1.0),
+                                (freq + cv * exp_amt).max(0.0).min(0.9),
+                                res.max(0.0).min(1.0),
                             );
                         }
                     },
@@ -222,18 +243,30 @@ impl SynthModule for MoogFilterModule {
 
     fn ui(&mut self, ui: &mut egui::Ui) {
         egui::Grid::new("vcf").show(ui, |ui| {
-            ui.add(
-                egui::Slider::new(&mut self.freq, 0.0..=1.0)
-                    .orientation(egui::SliderOrientation::Vertical),
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.freq, 0.0..=1.0)
+                        .orientation(egui::SliderOrientation::Vertical),
+                ),
+                &self.id,
+                FREQ_PARAM,
             );
-            ui.add(
-                egui::Slider::new(&mut self.res, 0.0..=1.0)
-                    .orientation(egui::SliderOrientation::Vertical),
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.res, 0.0..=1.0)
+                        .orientation(egui::SliderOrientation::Vertical),
+                ),
+                &self.id,
+                RES_PARAM,
             );
-            ui.add(
-                egui::Slider::new(&mut self.exp_amt, (1.0 / 2.0_f32.powi(8))..=1.0)
-                    .logarithmic(true)
-                    .orientation(egui::SliderOrientation::Vertical),
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.exp_amt, MIN_EXP_AMT..=1.0)
+                        .logarithmic(true)
+                        .orientation(egui::SliderOrientation::Vertical),
+                ),
+                &self.id,
+                EXP_AMT_PARAM,
             );
             ui.end_row();
             ui.label("f");
@@ -246,6 +279,32 @@ impl SynthModule for MoogFilterModule {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(FREQ_PARAM, "Frequency", 0.0..=1.0),
+                ParamValue::F32(&mut self.freq),
+            ),
+            (
+                Param::new(RES_PARAM, "Resonance", 0.0..=1.0),
+                ParamValue::F32(&mut self.res),
+            ),
+            (
+                Param::new(EXP_AMT_PARAM, "Exponential amount", MIN_EXP_AMT..=1.0),
+                ParamValue::F32(&mut self.exp_amt),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.freq),
+            1 => ParamValue::F32(&mut self.res),
+            2 => ParamValue::F32(&mut self.exp_amt),
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -276,6 +335,7 @@ impl From<MoogFilterModuleV0> for MoogFilterModule {
             res: other.res,
             exp_amt: other.exp_amt,
             state: other.state,
+            smoothers: Default::default(),
         }
     }
 }
@@ -1,9 +1,18 @@
-use super::{AudioBuffer, AudioConfig, SharedSynthModule, SynthModule};
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, Param, ParamValue, SharedSynthModule, Smoother, SynthModule,
+};
 use freeverb::Freeverb;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 
+const DAMPENING_PARAM: &str = "dampening";
+const WIDTH_PARAM: &str = "width";
+const ROOM_SIZE_PARAM: &str = "room_size";
+const WET_PARAM: &str = "wet";
+const DRY_PARAM: &str = "dry";
+
 #[derive(Serialize, Deserialize)]
 pub struct FreeverbModule {
     id: String,
@@ -28,6 +37,9 @@ pub struct FreeverbModule {
     room_size_ctl: f64,
     dry: f64,
     dry_ctl: f64,
+    /// Dampening, wet, width, room size and dry
+    #[serde(skip)]
+    smoothers: [Smoother; 5],
 }
 
 impl Clone for FreeverbModule {
@@ -52,6 +64,7 @@ impl Clone for FreeverbModule {
             room_size_ctl: self.room_size_ctl,
             dry: self.dry,
             dry_ctl: self.dry_ctl,
+            smoothers: self.smoothers.clone(),
         }
     }
 }
@@ -78,6 +91,7 @@ impl FreeverbModule {
             room_size_ctl: 0.5,
             dry: 0.0,
             dry_ctl: 0.0,
+            smoothers: std::array::from_fn(|_| Smoother::new(audio_config.sample_rate)),
         }
     }
 
@@ -85,30 +99,40 @@ impl FreeverbModule {
         "Freeverb".to_string()
     }
 
-    fn set_freeverb(&mut self, all: bool) {
+    /// Bring the reverb's settings a sample closer to the controls. Dampening
+    /// and room size retune every filter, so they're only passed on when
+    /// `retune` is set, at the start of each buffer.
+    fn set_freeverb(&mut self, all: bool, retune: bool) {
         let freeverb = self.freeverb.as_mut().expect("freeverb not initialized");
-        if self.dampening_ctl != self.dampening || all {
-            self.dampening = self.dampening_ctl;
+        let [dampening, wet, width, room_size, dry] = &mut self.smoothers;
+        let smooth = |smoother: &mut Smoother, ctl: f64| smoother.next(ctl as f32) as f64;
+        let dampening = smooth(dampening, self.dampening_ctl);
+        if (retune && dampening != self.dampening) || all {
+            self.dampening = dampening;
             freeverb.set_dampening(self.dampening);
         }
         if self.freeze_ctl != self.freeze || all {
             self.freeze = self.freeze_ctl;
             freeverb.set_freeze(self.freeze);
         }
-        if self.wet_ctl != self.wet || all {
-            self.wet = self.wet_ctl;
+        let wet = smooth(wet, self.wet_ctl);
+        if wet != self.wet || all {
+            self.wet = wet;
             freeverb.set_wet(self.wet);
         }
-        if self.width_ctl != self.width || all {
-            self.width = self.width_ctl;
+        let width = smooth(width, self.width_ctl);
+        if width != self.width || all {
+            self.width = width;
             freeverb.set_width(self.width);
         }
-        if self.room_size_ctl != self.room_size || all {
-            self.room_size = self.room_size_ctl;
+        let room_size = smooth(room_size, self.room_size_ctl);
+        if (retune && room_size != self.room_size) || all {
+            self.room_size = room_size;
             freeverb.set_room_size(self.room_size);
         }
-        if self.dry_ctl != self.dry || all {
-            self.dry = self.dry_ctl;
+        let dry = smooth(dry, self.dry_ctl);
+        if dry != self.dry || all {
+            self.dry = dry;
             freeverb.set_dry(self.dry);
         }
     }
@@ -130,6 +154,9 @@ impl SynthModule for FreeverbModule {
             self.freeverb = None;
             self.sample_rate = audio_config.sample_rate as usize;
         }
+        for smoother in self.smoothers.iter_mut() {
+            smoother.set_sample_rate(audio_config.sample_rate);
+        }
     }
 
     fn get_num_inputs(&self) -> u8 {
@@ -208,9 +235,7 @@ impl SynthModule for FreeverbModule {
     fn calc(&mut self) {
         if self.freeverb.is_none() {
             self.freeverb = Some(Freeverb::new(self.sample_rate));
-            self.set_freeverb(true);
-        } else {
-            self.set_freeverb(false);
+            self.set_freeverb(true, true);
         }
         AudioBuffer::with_read_many(
             vec![
@@ -227,41 +252,14 @@ impl SynthModule for FreeverbModule {
                             .map(|b| b.unwrap())
                             .collect_tuple()
                             .unwrap();
-                        let freeverb = self.freeverb.as_mut().unwrap();
-                        match (left_in, right_in) {
-                            (Some(l), Some(r)) => {
-                                for (((li, ri), lo), ro) in l
-                                    .iter()
-                                    .zip(r.iter())
-                                    .zip(left_out.iter_mut())
-                                    .zip(right_out.iter_mut())
-                                {
-                                    let (l, r) = freeverb.tick((*li as f64, *ri as f64));
-                                    (*lo, *ro) = (l as f32, r as f32);
-                                }
-                            }
-                            (Some(l), None) => {
-                                for ((li, lo), ro) in
-                                    l.iter().zip(left_out.iter_mut()).zip(right_out.iter_mut())
-                                {
-                                    let (l, r) = freeverb.tick((*li as f64, 0.0));
-                                    (*lo, *ro) = (l as f32, r as f32);
-                                }
-                            }
-                            (None, Some(r)) => {
-                                for ((ri, lo), ro) in
-                                    r.iter().zip(left_out.iter_mut()).zip(right_out.iter_mut())
-                                {
-                                    let (l, r) = freeverb.tick((0.0, *ri as f64));
-                                    (*lo, *ro) = (l as f32, r as f32);
-                                }
-                            }
-                            (None, None) => {
-                                for (lo, ro) in left_out.iter_mut().zip(right_out.iter_mut()) {
-                                    let (l, r) = freeverb.tick((0.0, 0.0));
-                                    (*lo, *ro) = (l as f32, r as f32);
-                                }
-                            }
+                        for idx in 0..left_out.len() {
+                            self.set_freeverb(false, idx == 0);
+                            let input = (
+                                left_in.map_or(0.0, |buf| buf[idx] as f64),
+                                right_in.map_or(0.0, |buf| buf[idx] as f64),
+                            );
+                            let (l, r) = self.freeverb.as_mut().unwrap().tick(input);
+                            (left_out[idx], right_out[idx]) = (l as f32, r as f32);
                         }
                     },
                 );
@@ -272,19 +270,75 @@ impl SynthModule for FreeverbModule {
     fn ui(&mut self, ui: &mut egui::Ui) {
         ui.vertical(|ui| {
             ui.label("Dampening");
-            ui.add(egui::Slider::new(&mut self.dampening_ctl, 0.0..=2.0));
+            learnable(
+                ui.add(egui::Slider::new(&mut self.dampening_ctl, 0.0..=2.0)),
+                &self.id,
+                DAMPENING_PARAM,
+            );
             ui.label("Width");
-            ui.add(egui::Slider::new(&mut self.width_ctl, 0.0..=1.0));
+            learnable(
+                ui.add(egui::Slider::new(&mut self.width_ctl, 0.0..=1.0)),
+                &self.id,
+                WIDTH_PARAM,
+            );
             ui.label("Room Size");
-            ui.add(egui::Slider::new(&mut self.room_size_ctl, 0.0..=1.0));
+            learnable(
+                ui.add(egui::Slider::new(&mut self.room_size_ctl, 0.0..=1.0)),
+                &self.id,
+                ROOM_SIZE_PARAM,
+            );
             ui.label("Wet");
-            ui.add(egui::Slider::new(&mut self.wet_ctl, 0.0..=1.0));
+            learnable(
+                ui.add(egui::Slider::new(&mut self.wet_ctl, 0.0..=1.0)),
+                &self.id,
+                WET_PARAM,
+            );
             ui.label("Dry");
-            ui.add(egui::Slider::new(&mut self.dry_ctl, 0.0..=1.0));
+            learnable(
+                ui.add(egui::Slider::new(&mut self.dry_ctl, 0.0..=1.0)),
+                &self.id,
+                DRY_PARAM,
+            );
         });
     }
 
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(DAMPENING_PARAM, "Dampening", 0.0..=2.0),
+                ParamValue::F64(&mut self.dampening_ctl),
+            ),
+            (
+                Param::new(WIDTH_PARAM, "Width", 0.0..=1.0),
+                ParamValue::F64(&mut self.width_ctl),
+            ),
+            (
+                Param::new(ROOM_SIZE_PARAM, "Room Size", 0.0..=1.0),
+                ParamValue::F64(&mut self.room_size_ctl),
+            ),
+            (
+                Param::new(WET_PARAM, "Wet", 0.0..=1.0),
+                ParamValue::F64(&mut self.wet_ctl),
+            ),
+            (
+                Param::new(DRY_PARAM, "Dry", 0.0..=1.0),
+                ParamValue::F64(&mut self.dry_ctl),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F64(&mut self.dampening_ctl),
+            1 => ParamValue::F64(&mut self.width_ctl),
+            2 => ParamValue::F64(&mut self.room_size_ctl),
+            3 => ParamValue::F64(&mut self.wet_ctl),
+            4 => ParamValue::F64(&mut self.dry_ctl),
+            _ => return None,
+        })
+    }
 }
@@ -0,0 +1,638 @@
+use super::midi_learn::learnable;
+use super::quantizer::SCALES;
+use super::sequencer::copied_notes;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SeededRng, SharedSynthModule,
+    SynthModule, TransitionDetector, seed_ui,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const LOCK_PARAM: &str = "lock";
+
+const MAX_LENGTH: u8 = 16;
+const CELL_SIZE: f32 = 7.0;
+const CELL_PADDING: f32 = 1.0;
+
+/// Shift register sequencer. On each clock the last bit of the loop is fed
+/// back in, kept with the lock probability or replaced with a random bit
+/// otherwise, so the loop can be anywhere from fixed to fully random.
+///
+/// The lowest 8 bits are output as a voltage, and also quantized to a scale.
+/// The gate output follows the clock when the lowest bit is set.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct TuringMachineModule {
+    id: String,
+    #[serde(skip)]
+    clock_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    reset_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    lock_in: Option<(SharedSynthModule, u8)>,
+    cv_out: AudioBuffer,
+    note_out: AudioBuffer,
+    gate_out: AudioBuffer,
+    register: u16,
+    /// Bits in the loop
+    length: u8,
+    /// Chance of a bit being kept as it goes round the loop
+    lock: f32,
+    octaves: u8,
+    /// Index into the quantizer scales
+    scale: usize,
+    rng: SeededRng,
+    transition_detector: TransitionDetector,
+    reset_transition_detector: TransitionDetector,
+    ui_dirty: bool,
+}
+
+impl TuringMachineModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        let mut module = Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            clock_in: None,
+            reset_in: None,
+            lock_in: None,
+            cv_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            note_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            gate_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            register: 0,
+            length: 8,
+            lock: 0.9,
+            octaves: 2,
+            scale: 1,
+            rng: SeededRng::new(rand::random()),
+            transition_detector: TransitionDetector::new(),
+            reset_transition_detector: TransitionDetector::new(),
+            ui_dirty: false,
+        };
+        module.reset();
+        module
+    }
+
+    pub fn get_name() -> String {
+        "Turing Machine".to_string()
+    }
+
+    /// Start over from the seed
+    fn reset(&mut self) {
+        self.rng.reset();
+        self.register = self.rng.next_u64() as u16;
+    }
+
+    fn step(&mut self, lock: f32) {
+        let last = (self.register >> (self.length - 1)) & 1;
+        let bit = if self.rng.next_f32() < lock {
+            last
+        } else {
+            (self.rng.next_u64() & 1) as u16
+        };
+        self.register = (self.register << 1) | bit;
+    }
+
+    /// Lowest 8 bits of the register, from 0.0 to 1.0
+    fn value(&self) -> f32 {
+        (self.register & 0xFF) as f32 / 255.0
+    }
+
+    fn note(&self) -> ControlVoltage {
+        let scale = SCALES[self.scale].1;
+        let notes = scale.len() * self.octaves as usize;
+        let note = ((self.value() * notes as f32) as usize).min(notes - 1);
+        (note / scale.len()) as ControlVoltage + scale[note % scale.len()] as ControlVoltage / 12.0
+    }
+}
+
+impl SynthModule for TuringMachineModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.cv_out.resize(audio_config.buffer_size);
+        self.note_out.resize(audio_config.buffer_size);
+        self.gate_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        3
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.clock_in.clone()),
+            1 => Ok(self.reset_in.clone()),
+            2 => Ok(self.lock_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.clock_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.reset_in = Some((src_module, src_port));
+                Ok(())
+            }
+            2 => {
+                self.lock_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.clock_in = None;
+                Ok(())
+            }
+            1 => {
+                self.reset_in = None;
+                Ok(())
+            }
+            2 => {
+                self.lock_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Clock".to_string())),
+            1 => Ok(Some("Reset".to_string())),
+            2 => Ok(Some("Lock".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        3
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.cv_out.clone()),
+            1 => Ok(self.note_out.clone()),
+            2 => Ok(self.gate_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("CV".to_string())),
+            1 => Ok(Some("Note".to_string())),
+            2 => Ok(Some("Gate".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
+            ],
+            |bufs| {
+                let (clock_in, reset_in, lock_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![
+                        self.cv_out.clone(),
+                        self.note_out.clone(),
+                        self.gate_out.clone(),
+                    ],
+                    |bufs| {
+                        let (cv_out, note_out, gate_out) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        for idx in 0..cv_out.len() {
+                            let clock = clock_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                            let reset = reset_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                            if self.reset_transition_detector.is_transition(&reset) {
+                                self.reset();
+                            }
+                            if self.transition_detector.is_transition(&clock) {
+                                let lock = self.lock + lock_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                                self.step(lock);
+                                self.ui_dirty = true;
+                            }
+                            cv_out[idx] = self.value() * self.octaves as ControlVoltage;
+                            note_out[idx] = self.note();
+                            gate_out[idx] = if self.register & 1 == 1 { clock } else { 0.0 };
+                        }
+                    },
+                );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            learnable(
+                ui.add(egui::Slider::new(&mut self.lock, 0.0..=1.0).text("Lock")),
+                &self.id,
+                LOCK_PARAM,
+            );
+            ui.horizontal(|ui| {
+                ui.label("Length: ");
+                ui.add(egui::DragValue::new(&mut self.length).range(2..=MAX_LENGTH));
+                ui.label("Octaves: ");
+                ui.add(egui::DragValue::new(&mut self.octaves).range(1..=4));
+            });
+            egui::ComboBox::from_id_source((&self.id, "scale"))
+                .selected_text(SCALES[self.scale].0)
+                .show_ui(ui, |ui| {
+                    for (idx, (name, _)) in SCALES.iter().enumerate() {
+                        ui.selectable_value(&mut self.scale, idx, *name);
+                    }
+                });
+            let (_, rect) = ui
+                .allocate_space([MAX_LENGTH as f32 * (CELL_SIZE + CELL_PADDING), CELL_SIZE].into());
+            for bit in 0..MAX_LENGTH {
+                let top_left = egui::Pos2::new(
+                    rect.min.x + bit as f32 * (CELL_SIZE + CELL_PADDING),
+                    rect.min.y,
+                );
+                let color = match (self.register >> bit & 1 == 1, bit < self.length) {
+                    (true, true) => egui::Color32::BLUE,
+                    (true, false) => egui::Color32::GRAY,
+                    (false, _) => egui::Color32::LIGHT_GRAY,
+                };
+                ui.painter().rect_filled(
+                    egui::Rect::from_min_size(top_left, [CELL_SIZE, CELL_SIZE].into()),
+                    1.0,
+                    color,
+                );
+            }
+            seed_ui(ui, &mut self.rng);
+        });
+        self.ui_dirty = false;
+    }
+
+    fn ui_dirty(&self) -> bool {
+        self.ui_dirty
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(LOCK_PARAM, "Lock", 0.0..=1.0),
+            ParamValue::F32(&mut self.lock),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::F32(&mut self.lock))
+    }
+}
+
+/// Plays notes chosen by how often each note followed the last in a grid
+/// sequencer pattern it learnt from.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MarkovModule {
+    id: String,
+    #[serde(skip)]
+    clock_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    reset_in: Option<(SharedSynthModule, u8)>,
+    cv_out: AudioBuffer,
+    gate_out: AudioBuffer,
+    /// Distinct pitches in the pattern, lowest first
+    notes: Vec<ControlVoltage>,
+    /// Times each note was followed by each other note
+    transitions: Vec<Vec<u32>>,
+    /// Note the pattern started on
+    first: usize,
+    current: usize,
+    rng: SeededRng,
+    transition_detector: TransitionDetector,
+    reset_transition_detector: TransitionDetector,
+}
+
+impl MarkovModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            clock_in: None,
+            reset_in: None,
+            cv_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            gate_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            notes: vec![],
+            transitions: vec![],
+            first: 0,
+            current: 0,
+            rng: SeededRng::new(rand::random()),
+            transition_detector: TransitionDetector::new(),
+            reset_transition_detector: TransitionDetector::new(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Markov Sequencer".to_string()
+    }
+
+    /// Count the transitions between notes in a pattern, which loops
+    fn learn(&mut self, pattern: &[ControlVoltage]) {
+        self.notes = pattern
+            .iter()
+            .copied()
+            .sorted_by(|a, b| a.total_cmp(b))
+            .dedup()
+            .collect();
+        self.transitions = vec![vec![0; self.notes.len()]; self.notes.len()];
+        let index = |pitch: &ControlVoltage| self.notes.iter().position(|n| n == pitch).unwrap();
+        let indexes: Vec<_> = pattern.iter().map(index).collect();
+        for (from, to) in indexes.iter().circular_tuple_windows() {
+            self.transitions[*from][*to] += 1;
+        }
+        self.first = indexes.first().copied().unwrap_or(0);
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.rng.reset();
+        self.current = self.first;
+    }
+
+    fn step(&mut self) {
+        let row = &self.transitions[self.current];
+        let total: u32 = row.iter().sum();
+        if total == 0 {
+            self.current = (self.rng.next_f32() * self.notes.len() as f32) as usize;
+            return;
+        }
+        let mut choice = (self.rng.next_f32() * total as f32) as u32;
+        for (to, count) in row.iter().enumerate() {
+            if choice < *count {
+                self.current = to;
+                return;
+            }
+            choice -= count;
+        }
+    }
+}
+
+impl SynthModule for MarkovModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.cv_out.resize(audio_config.buffer_size);
+        self.gate_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.clock_in.clone()),
+            1 => Ok(self.reset_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.clock_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.reset_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.clock_in = None;
+                Ok(())
+            }
+            1 => {
+                self.reset_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Clock".to_string())),
+            1 => Ok(Some("Reset".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        2
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.cv_out.clone()),
+            1 => Ok(self.gate_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("CV".to_string())),
+            1 => Ok(Some("Gate".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (clock_in, reset_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.cv_out.clone(), self.gate_out.clone()],
+                    |bufs| {
+                        let (cv_out, gate_out) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        if self.notes.is_empty() {
+                            cv_out.fill(0.0);
+                            gate_out.fill(0.0);
+                            return;
+                        }
+                        for idx in 0..cv_out.len() {
+                            let clock = clock_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                            let reset = reset_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                            if self.reset_transition_detector.is_transition(&reset) {
+                                self.reset();
+                            }
+                            if self.transition_detector.is_transition(&clock) {
+                                self.step();
+                            }
+                            cv_out[idx] = self.notes[self.current];
+                            gate_out[idx] = clock;
+                        }
+                    },
+                );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            ui.horizontal(|ui| {
+                let copied = copied_notes(ui.ctx());
+                ui.scope(|ui| {
+                    if copied.is_none() {
+                        ui.disable();
+                    }
+                    if ui
+                        .button("Learn")
+                        .on_hover_text("Learn from notes copied from a grid sequencer")
+                        .clicked()
+                        && let Some(pattern) = copied
+                    {
+                        self.learn(&pattern);
+                    }
+                });
+                ui.label(format!("{} notes", self.notes.len()));
+            });
+            let size = self.notes.len() as f32 * (CELL_SIZE + CELL_PADDING);
+            let (_, rect) = ui.allocate_space([size, size].into());
+            let most = self
+                .transitions
+                .iter()
+                .flatten()
+                .max()
+                .copied()
+                .unwrap_or(0);
+            for (from, row) in self.transitions.iter().enumerate() {
+                for (to, count) in row.iter().enumerate() {
+                    let top_left = egui::Pos2::new(
+                        rect.min.x + to as f32 * (CELL_SIZE + CELL_PADDING),
+                        rect.min.y + from as f32 * (CELL_SIZE + CELL_PADDING),
+                    );
+                    let shade = 255 - (*count * 255 / most.max(1)) as u8;
+                    ui.painter().rect_filled(
+                        egui::Rect::from_min_size(top_left, [CELL_SIZE, CELL_SIZE].into()),
+                        1.0,
+                        egui::Color32::from_rgb(shade, shade, 255),
+                    );
+                }
+            }
+            seed_ui(ui, &mut self.rng);
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 16,
+        channels: 2,
+    };
+
+    #[test]
+    fn locked_turing_machine_loops() {
+        let mut turing = TuringMachineModule::new(&CONFIG);
+        turing.length = 5;
+        let registers: Vec<_> = (0..10)
+            .map(|_| {
+                turing.step(1.0);
+                turing.register
+            })
+            .collect();
+        for (a, b) in registers.iter().zip(registers[5..].iter()) {
+            assert_eq!(a & 0x1F, b & 0x1F);
+        }
+
+        turing.reset();
+        let replayed: Vec<_> = (0..10)
+            .map(|_| {
+                turing.step(1.0);
+                turing.register
+            })
+            .collect();
+        assert_eq!(registers, replayed);
+    }
+
+    #[test]
+    fn markov_follows_learnt_transitions() {
+        let mut markov = MarkovModule::new(&CONFIG);
+        markov.learn(&[0.0, 0.25, 0.0, 0.5]);
+        assert_eq!(markov.notes, vec![0.0, 0.25, 0.5]);
+        assert_eq!(
+            markov.transitions,
+            vec![vec![0, 1, 1], vec![1, 0, 0], vec![1, 0, 0]]
+        );
+        let notes: Vec<_> = (0..20)
+            .map(|_| {
+                markov.step();
+                markov.current
+            })
+            .collect();
+        // every other note goes back to the first
+        for pair in notes.chunks(2) {
+            assert_ne!(pair[0], 0);
+            assert_eq!(pair[1], 0);
+        }
+        markov.reset();
+        let replayed: Vec<_> = (0..20)
+            .map(|_| {
+                markov.step();
+                markov.current
+            })
+            .collect();
+        assert_eq!(notes, replayed);
+    }
+}
@@ -0,0 +1,501 @@
+use super::midi_learn::learnable;
+use super::{AudioBuffer, AudioConfig, Param, ParamValue, SharedSynthModule, SynthModule};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const VELOCITY_PARAM: &str = "velocity";
+
+/// How long the gate drops between notes when not playing legato
+const RETRIGGER_MS: u32 = 2;
+const MAX_OCTAVE: i8 = 8;
+/// Octaves drawn on the on-screen piano
+const PIANO_OCTAVES: u8 = 2;
+const WHITE_KEY_WIDTH: f32 = 14.0;
+const PIANO_HEIGHT: f32 = 56.0;
+/// Semitones of the white keys in an octave
+const WHITE_KEYS: [u8; 7] = [0, 2, 4, 5, 7, 9, 11];
+/// Semitones of the black keys, with the white key each one follows
+const BLACK_KEYS: [(u8, u8); 5] = [(1, 0), (3, 1), (6, 3), (8, 4), (10, 5)];
+
+/// Tracker style layout: the bottom row plays from C of the current octave,
+/// the row of letters above it from C an octave up. Each note has one key, so
+/// the bottom row stops at B where the row above takes over.
+const KEY_LAYOUT: [(egui::Key, u8); 29] = [
+    (egui::Key::Z, 0),
+    (egui::Key::S, 1),
+    (egui::Key::X, 2),
+    (egui::Key::D, 3),
+    (egui::Key::C, 4),
+    (egui::Key::V, 5),
+    (egui::Key::G, 6),
+    (egui::Key::B, 7),
+    (egui::Key::H, 8),
+    (egui::Key::N, 9),
+    (egui::Key::J, 10),
+    (egui::Key::M, 11),
+    (egui::Key::Q, 12),
+    (egui::Key::Num2, 13),
+    (egui::Key::W, 14),
+    (egui::Key::Num3, 15),
+    (egui::Key::E, 16),
+    (egui::Key::R, 17),
+    (egui::Key::Num5, 18),
+    (egui::Key::T, 19),
+    (egui::Key::Num6, 20),
+    (egui::Key::Y, 21),
+    (egui::Key::Num7, 22),
+    (egui::Key::U, 23),
+    (egui::Key::I, 24),
+    (egui::Key::Num9, 25),
+    (egui::Key::O, 26),
+    (egui::Key::Num0, 27),
+    (egui::Key::P, 28),
+];
+
+/// Plays pitch, gate and velocity from the computer keyboard or by clicking
+/// the on-screen piano.
+///
+/// Monophonic with last note priority: releasing a key goes back to the one
+/// held before it. With latch the last note keeps playing after it's released,
+/// until another is played or it's played again. With legato the gate stays
+/// high moving from note to note instead of dropping briefly.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct KeyboardModule {
+    id: String,
+    pitch_out: AudioBuffer,
+    gate_out: AudioBuffer,
+    velocity_out: AudioBuffer,
+    /// Octave of the C played by Z
+    octave: i8,
+    velocity: f32,
+    /// Pitch of the last note played, held after it's released so envelopes
+    /// finish on it
+    pitch: f32,
+    computer_keys: bool,
+    latch: bool,
+    legato: bool,
+    retrigger_samples: u32,
+    /// Notes held, most recent last
+    #[serde(skip)]
+    held: Vec<u8>,
+    /// Keys held with the notes they started, so shifting octave doesn't
+    /// leave notes hanging
+    #[serde(skip)]
+    keys_down: Vec<(egui::Key, u8)>,
+    /// Note held by clicking the piano
+    #[serde(skip)]
+    mouse_note: Option<u8>,
+    #[serde(skip)]
+    note: Option<u8>,
+    /// Samples left with the gate dropped between notes
+    #[serde(skip)]
+    retrigger_remaining: u32,
+}
+
+impl KeyboardModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            pitch_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            gate_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            velocity_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            octave: 4,
+            velocity: 0.8,
+            pitch: 0.0,
+            computer_keys: true,
+            latch: false,
+            legato: false,
+            retrigger_samples: audio_config.sample_rate as u32 * RETRIGGER_MS / 1000,
+            held: vec![],
+            keys_down: vec![],
+            mouse_note: None,
+            note: None,
+            retrigger_remaining: 0,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Keyboard".to_string()
+    }
+
+    /// MIDI note number of C in the current octave
+    fn base_note(&self) -> u8 {
+        ((self.octave as u8) + 1) * 12
+    }
+
+    fn press(&mut self, note: u8) {
+        if self.held.contains(&note) {
+            return;
+        }
+        if self.latch && self.held.is_empty() && self.note == Some(note) {
+            // playing the latched note again stops it
+            self.play(None);
+            return;
+        }
+        self.held.push(note);
+        self.play(Some(note));
+    }
+
+    fn release(&mut self, note: u8) {
+        self.held.retain(|held| *held != note);
+        match self.held.last() {
+            Some(&last) => self.play(Some(last)),
+            None if !self.latch => self.play(None),
+            None => {}
+        }
+    }
+
+    fn play(&mut self, note: Option<u8>) {
+        if note == self.note {
+            return;
+        }
+        if self.note.is_some() && note.is_some() && !self.legato {
+            self.retrigger_remaining = self.retrigger_samples;
+        }
+        if let Some(note) = note {
+            self.pitch = (note as f32 - 69.0) / 12.0;
+        }
+        self.note = note;
+    }
+
+    fn read_keys(&mut self, ctx: &egui::Context) {
+        if ctx.wants_keyboard_input() {
+            return;
+        }
+        let events = ctx.input(|i| i.events.clone());
+        for event in events {
+            match event {
+                egui::Event::Key {
+                    key,
+                    physical_key,
+                    pressed,
+                    repeat: false,
+                    modifiers,
+                } if !modifiers.command && !modifiers.alt => {
+                    let key = physical_key.unwrap_or(key);
+                    if pressed {
+                        match key {
+                            egui::Key::Minus => self.octave = (self.octave - 1).max(0),
+                            egui::Key::Equals => self.octave = (self.octave + 1).min(MAX_OCTAVE),
+                            _ => {
+                                if let Some((_, offset)) =
+                                    KEY_LAYOUT.iter().find(|(k, _)| *k == key)
+                                {
+                                    let note = self.base_note() + offset;
+                                    self.keys_down.push((key, note));
+                                    self.press(note);
+                                }
+                            }
+                        }
+                    } else if let Some(idx) = self.keys_down.iter().position(|(k, _)| *k == key) {
+                        let (_, note) = self.keys_down.remove(idx);
+                        self.release(note);
+                    }
+                }
+                egui::Event::WindowFocused(false) => {
+                    // key releases won't arrive while unfocused
+                    for (_, note) in std::mem::take(&mut self.keys_down) {
+                        self.release(note);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn piano_ui(&mut self, ui: &mut egui::Ui) {
+        let white_keys = WHITE_KEYS.len() * PIANO_OCTAVES as usize;
+        let (rect, response) = ui.allocate_exact_size(
+            egui::vec2(WHITE_KEY_WIDTH * white_keys as f32, PIANO_HEIGHT),
+            egui::Sense::click_and_drag(),
+        );
+        let base_note = self.base_note();
+        let white_rect = |idx: usize| {
+            egui::Rect::from_min_size(
+                rect.min + egui::vec2(WHITE_KEY_WIDTH * idx as f32, 0.0),
+                egui::vec2(WHITE_KEY_WIDTH, PIANO_HEIGHT),
+            )
+        };
+        let black_rect = |white_idx: usize| {
+            egui::Rect::from_center_size(
+                white_rect(white_idx).right_top() + egui::vec2(0.0, PIANO_HEIGHT * 0.3),
+                egui::vec2(WHITE_KEY_WIDTH * 0.6, PIANO_HEIGHT * 0.6),
+            )
+        };
+        let whites: Vec<(egui::Rect, u8)> = (0..white_keys)
+            .map(|idx| {
+                let semitone = WHITE_KEYS[idx % WHITE_KEYS.len()];
+                let octave = (idx / WHITE_KEYS.len()) as u8;
+                (white_rect(idx), base_note + octave * 12 + semitone)
+            })
+            .collect();
+        let blacks: Vec<(egui::Rect, u8)> = (0..PIANO_OCTAVES)
+            .flat_map(|octave| {
+                BLACK_KEYS.iter().map(move |(semitone, after)| {
+                    (
+                        black_rect(octave as usize * WHITE_KEYS.len() + *after as usize),
+                        base_note + octave * 12 + semitone,
+                    )
+                })
+            })
+            .collect();
+
+        // black keys sit on top, so are hit first
+        let pointer_note = response
+            .is_pointer_button_down_on()
+            .then(|| response.interact_pointer_pos())
+            .flatten()
+            .and_then(|pos| {
+                blacks
+                    .iter()
+                    .chain(whites.iter())
+                    .find(|(key_rect, _)| key_rect.contains(pos))
+                    .map(|(_, note)| *note)
+            });
+        if pointer_note != self.mouse_note {
+            if let Some(note) = self.mouse_note.take() {
+                self.release(note);
+            }
+            if let Some(note) = pointer_note {
+                self.mouse_note = Some(note);
+                self.press(note);
+            }
+        }
+
+        let painter = ui.painter();
+        let visuals = ui.visuals();
+        let key_color = |note: u8, color: egui::Color32| {
+            if self.note == Some(note) {
+                visuals.selection.bg_fill
+            } else {
+                color
+            }
+        };
+        for (key_rect, note) in whites.iter() {
+            painter.rect(
+                *key_rect,
+                1.0,
+                key_color(*note, egui::Color32::WHITE),
+                visuals.widgets.noninteractive.bg_stroke,
+            );
+        }
+        for (key_rect, note) in blacks.iter() {
+            painter.rect_filled(*key_rect, 1.0, key_color(*note, egui::Color32::BLACK));
+        }
+    }
+}
+
+impl SynthModule for KeyboardModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.pitch_out.resize(audio_config.buffer_size);
+        self.gate_out.resize(audio_config.buffer_size);
+        self.velocity_out.resize(audio_config.buffer_size);
+        self.retrigger_samples = audio_config.sample_rate as u32 * RETRIGGER_MS / 1000;
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        0
+    }
+
+    fn get_input(&self, _input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        Err(())
+    }
+
+    fn set_input(
+        &mut self,
+        _input_idx: u8,
+        _src_module: SharedSynthModule,
+        _src_port: u8,
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn disconnect_input(&mut self, _input_idx: u8) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn get_input_label(&self, _input_idx: u8) -> Result<Option<String>, ()> {
+        Err(())
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        3
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.pitch_out.clone()),
+            1 => Ok(self.gate_out.clone()),
+            2 => Ok(self.velocity_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("Pitch".to_string())),
+            1 => Ok(Some("Gate".to_string())),
+            2 => Ok(Some("Velocity".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_write_many(
+            vec![
+                self.pitch_out.clone(),
+                self.gate_out.clone(),
+                self.velocity_out.clone(),
+            ],
+            |outs| {
+                let mut outs: Vec<_> = outs.into_iter().map(|o| o.unwrap()).collect();
+                for idx in 0..outs[0].len() {
+                    outs[0][idx] = self.pitch;
+                    outs[1][idx] = if self.note.is_some() && self.retrigger_remaining == 0 {
+                        1.0
+                    } else {
+                        0.0
+                    };
+                    outs[2][idx] = self.velocity;
+                    self.retrigger_remaining = self.retrigger_remaining.saturating_sub(1);
+                }
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        if self.computer_keys {
+            self.read_keys(ui.ctx());
+        }
+        ui.vertical(|ui| {
+            self.piano_ui(ui);
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut self.octave).range(0..=MAX_OCTAVE))
+                    .on_hover_text("Octave, also changed with - and =");
+                ui.label("Octave");
+                learnable(
+                    ui.add(egui::Slider::new(&mut self.velocity, 0.0..=1.0).show_value(false))
+                        .on_hover_text(format!("{:.2}", self.velocity)),
+                    &self.id,
+                    VELOCITY_PARAM,
+                );
+                ui.label("Velocity");
+            });
+            ui.horizontal(|ui| {
+                ui.checkbox(&mut self.computer_keys, "Keys")
+                    .on_hover_text("Play from the computer keyboard, Z to M and Q to P");
+                if ui.checkbox(&mut self.latch, "Latch").changed()
+                    && !self.latch
+                    && self.held.is_empty()
+                {
+                    self.play(None);
+                }
+                ui.checkbox(&mut self.legato, "Legato");
+            });
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(VELOCITY_PARAM, "Velocity", 0.0..=1.0),
+            ParamValue::F32(&mut self.velocity),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::F32(&mut self.velocity))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 8,
+        channels: 2,
+    };
+
+    fn outputs(keyboard: &mut KeyboardModule) -> [Vec<f32>; 3] {
+        keyboard.calc();
+        [0, 1, 2].map(|idx| {
+            let mut values = vec![];
+            keyboard
+                .get_output(idx)
+                .unwrap()
+                .with_read(|buf| values.extend_from_slice(buf.unwrap()));
+            values
+        })
+    }
+
+    #[test]
+    fn plays_last_note_held() {
+        let mut keyboard = KeyboardModule::new(&CONFIG);
+        keyboard.press(69);
+        let [pitch, gate, velocity] = outputs(&mut keyboard);
+        assert_eq!(pitch, vec![0.0; 8]);
+        assert_eq!(gate, vec![1.0; 8]);
+        assert_eq!(velocity, vec![0.8; 8]);
+
+        // a new note retriggers the gate, and releasing it goes back
+        keyboard.press(81);
+        let [pitch, gate, _] = outputs(&mut keyboard);
+        assert_eq!(pitch, vec![1.0; 8]);
+        assert_eq!(gate, vec![0.0, 0.0, 1.0, 1.0, 1.0, 1.0, 1.0, 1.0]);
+        keyboard.release(81);
+        let [pitch, _, _] = outputs(&mut keyboard);
+        assert_eq!(pitch, vec![0.0; 8]);
+
+        // pitch holds after the gate closes
+        keyboard.release(69);
+        let [pitch, gate, _] = outputs(&mut keyboard);
+        assert_eq!(pitch, vec![0.0; 8]);
+        assert_eq!(gate, vec![0.0; 8]);
+    }
+
+    #[test]
+    fn legato_keeps_gate_high() {
+        let mut keyboard = KeyboardModule::new(&CONFIG);
+        keyboard.legato = true;
+        keyboard.press(60);
+        keyboard.press(62);
+        let [_, gate, _] = outputs(&mut keyboard);
+        assert_eq!(gate, vec![1.0; 8]);
+    }
+
+    #[test]
+    fn latch_holds_until_played_again() {
+        let mut keyboard = KeyboardModule::new(&CONFIG);
+        keyboard.latch = true;
+        keyboard.press(60);
+        keyboard.release(60);
+        assert_eq!(outputs(&mut keyboard)[1], vec![1.0; 8]);
+
+        keyboard.press(64);
+        keyboard.release(64);
+        assert_eq!(keyboard.note, Some(64));
+
+        keyboard.press(64);
+        keyboard.release(64);
+        assert_eq!(outputs(&mut keyboard)[1], vec![0.0; 8]);
+    }
+    #[test]
+    fn layout_has_one_key_per_note() {
+        let mut notes: Vec<_> = KEY_LAYOUT.iter().map(|(_, note)| *note).collect();
+        notes.sort();
+        assert_eq!(notes, (0..29).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,1109 @@
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SeededRng, SharedSynthModule,
+    SynthModule, TransitionDetector, seed_ui,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const THRESHOLD_PARAM: &str = "threshold";
+const HYSTERESIS_PARAM: &str = "hysteresis";
+const PROBABILITY_PARAM: &str = "probability";
+const LENGTH_PARAM: &str = "length";
+
+/// Shortest stretched pulse, in seconds
+const MIN_PULSE_SEC: f32 = 0.001;
+/// Longest stretched pulse, in seconds
+const MAX_PULSE_SEC: f32 = 10.0;
+/// Largest clock division or multiplication
+const MAX_CLOCK_RATIO: u32 = 32;
+
+#[inline]
+fn gate(high: bool) -> ControlVoltage {
+    if high { 1.0 } else { 0.0 }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum LogicOperation {
+    And,
+    Or,
+    Xor,
+}
+
+impl LogicOperation {
+    fn label(&self) -> &'static str {
+        match self {
+            LogicOperation::And => "AND",
+            LogicOperation::Or => "OR",
+            LogicOperation::Xor => "XOR",
+        }
+    }
+
+    #[inline]
+    fn apply(&self, a: bool, b: bool) -> bool {
+        match self {
+            LogicOperation::And => a && b,
+            LogicOperation::Or => a || b,
+            LogicOperation::Xor => a != b,
+        }
+    }
+}
+
+/// Boolean logic on two gates, with an inverted output. Inputs are high above
+/// 0.0 and disconnected inputs are low.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct LogicModule {
+    id: String,
+    #[serde(skip)]
+    in1: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    in2: Option<(SharedSynthModule, u8)>,
+    out: AudioBuffer,
+    inverted_out: AudioBuffer,
+    operation: LogicOperation,
+}
+
+impl LogicModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            in1: None,
+            in2: None,
+            out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            inverted_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            operation: LogicOperation::And,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Logic".to_string()
+    }
+}
+
+impl SynthModule for LogicModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.out.resize(audio_config.buffer_size);
+        self.inverted_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.in1.clone()),
+            1 => Ok(self.in2.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.in1 = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.in2 = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.in1 = None;
+                Ok(())
+            }
+            1 => {
+                self.in2 = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("In1".to_string())),
+            1 => Ok(Some("In2".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        2
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.out.clone()),
+            1 => Ok(self.inverted_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("Out".to_string())),
+            1 => Ok(Some("Inverted".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (i1, i2) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.out.clone(), self.inverted_out.clone()],
+                    |bufs| {
+                        let (out, inverted_out) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        for idx in 0..out.len() {
+                            let high = |buf: Option<&[ControlVoltage]>| {
+                                buf.is_some_and(|b| TransitionDetector::is_above_threshold(&b[idx]))
+                            };
+                            let result = self.operation.apply(high(i1), high(i2));
+                            out[idx] = gate(result);
+                            inverted_out[idx] = gate(!result);
+                        }
+                    },
+                );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for operation in [LogicOperation::And, LogicOperation::Or, LogicOperation::Xor] {
+                ui.selectable_value(&mut self.operation, operation, operation.label());
+            }
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Gate which goes high when the input rises above the threshold and low when
+/// it falls below the threshold minus the hysteresis.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ComparatorModule {
+    id: String,
+    #[serde(skip)]
+    signal_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    threshold_in: Option<(SharedSynthModule, u8)>,
+    out: AudioBuffer,
+    inverted_out: AudioBuffer,
+    threshold: ControlVoltage,
+    hysteresis: ControlVoltage,
+    high: bool,
+}
+
+impl ComparatorModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            signal_in: None,
+            threshold_in: None,
+            out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            inverted_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            threshold: 0.0,
+            hysteresis: 0.1,
+            high: false,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Comparator".to_string()
+    }
+
+    #[inline]
+    fn process(&mut self, signal: ControlVoltage, threshold: ControlVoltage) -> bool {
+        if self.high {
+            self.high = signal > threshold - self.hysteresis;
+        } else {
+            self.high = signal > threshold;
+        }
+        self.high
+    }
+}
+
+impl SynthModule for ComparatorModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.out.resize(audio_config.buffer_size);
+        self.inverted_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.signal_in.clone()),
+            1 => Ok(self.threshold_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.signal_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.threshold_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.signal_in = None;
+                Ok(())
+            }
+            1 => {
+                self.threshold_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("In".to_string())),
+            1 => Ok(Some("Threshold".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        2
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.out.clone()),
+            1 => Ok(self.inverted_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("Out".to_string())),
+            1 => Ok(Some("Inverted".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (signal_in, threshold_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.out.clone(), self.inverted_out.clone()],
+                    |bufs| {
+                        let (out, inverted_out) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        for idx in 0..out.len() {
+                            let high = self.process(
+                                signal_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                                threshold_in.map(|buf| buf[idx]).unwrap_or(self.threshold),
+                            );
+                            out[idx] = gate(high);
+                            inverted_out[idx] = gate(!high);
+                        }
+                    },
+                );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.scope(|ui| {
+                if self.threshold_in.is_some() {
+                    ui.disable();
+                }
+                ui.vertical(|ui| {
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut self.threshold, -2.0..=2.0)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        ),
+                        &self.id,
+                        THRESHOLD_PARAM,
+                    );
+                    ui.label("Threshold");
+                });
+            });
+            ui.vertical(|ui| {
+                learnable(
+                    ui.add(
+                        egui::Slider::new(&mut self.hysteresis, 0.0..=1.0)
+                            .orientation(egui::SliderOrientation::Vertical),
+                    ),
+                    &self.id,
+                    HYSTERESIS_PARAM,
+                );
+                ui.label("Hysteresis");
+            });
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(THRESHOLD_PARAM, "Threshold", -2.0..=2.0),
+                ParamValue::F32(&mut self.threshold),
+            ),
+            (
+                Param::new(HYSTERESIS_PARAM, "Hysteresis", 0.0..=1.0),
+                ParamValue::F32(&mut self.hysteresis),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.threshold),
+            1 => ParamValue::F32(&mut self.hysteresis),
+            _ => return None,
+        })
+    }
+}
+
+/// Divides and multiplies a clock, so the output runs at multiply / divide
+/// times the input rate. Multiplying measures the time between input pulses.
+/// The output stays low until the clock starts. The reset input restarts the
+/// count, so the next input pulse starts the first output pulse of a division.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct ClockDividerModule {
+    id: String,
+    #[serde(skip)]
+    clock_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    reset_in: Option<(SharedSynthModule, u8)>,
+    out: AudioBuffer,
+    divide: u32,
+    multiply: u32,
+    /// Input pulses since the start of the division
+    count: u32,
+    samples_since_pulse: u32,
+    /// Samples between the last two input pulses, 0 until measured
+    period: u32,
+    /// Whether there's been an input pulse since starting or resetting
+    #[serde(skip)]
+    clocked: bool,
+    transition_detector: TransitionDetector,
+    reset_transition_detector: TransitionDetector,
+}
+
+impl ClockDividerModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            clock_in: None,
+            reset_in: None,
+            out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            divide: 2,
+            multiply: 1,
+            count: 0,
+            samples_since_pulse: 0,
+            period: 0,
+            clocked: false,
+            transition_detector: TransitionDetector::new(),
+            reset_transition_detector: TransitionDetector::new(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Clock Divider".to_string()
+    }
+
+    #[inline]
+    fn process(&mut self, clock: ControlVoltage, reset: ControlVoltage) -> bool {
+        if self.reset_transition_detector.is_transition(&reset) {
+            self.clocked = false;
+        }
+        if self.transition_detector.is_transition(&clock) {
+            // The time since a reset isn't a clock period
+            if self.clocked {
+                self.period = self.samples_since_pulse;
+                self.count = (self.count + 1) % self.divide;
+            } else {
+                self.count = 0;
+            }
+            self.clocked = true;
+            self.samples_since_pulse = 0;
+        }
+        if !self.clocked {
+            return false;
+        }
+        let within_pulse = if self.period > 0 {
+            (self.samples_since_pulse as f32 / self.period as f32).min(0.999)
+        } else {
+            0.0
+        };
+        self.samples_since_pulse = self.samples_since_pulse.saturating_add(1);
+        let phase = (self.count as f32 + within_pulse) * self.multiply as f32 / self.divide as f32;
+        phase.fract() < 0.5
+    }
+}
+
+impl SynthModule for ClockDividerModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.clock_in.clone()),
+            1 => Ok(self.reset_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.clock_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.reset_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.clock_in = None;
+                Ok(())
+            }
+            1 => {
+                self.reset_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Clock".to_string())),
+            1 => Ok(Some("Reset".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        1
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (clock_in, reset_in) = bufs.into_iter().collect_tuple().unwrap();
+                let out = self.out.clone();
+                out.with_write(|out| {
+                    let out = out.unwrap();
+                    for idx in 0..out.len() {
+                        let high = self.process(
+                            clock_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                            reset_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                        );
+                        out[idx] = gate(high);
+                    }
+                });
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            ui.label("÷");
+            ui.add(egui::DragValue::new(&mut self.divide).range(1..=MAX_CLOCK_RATIO));
+            ui.label("×");
+            ui.add(egui::DragValue::new(&mut self.multiply).range(1..=MAX_CLOCK_RATIO));
+        });
+        self.count %= self.divide;
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Routes each gate to one of two outputs, to A with the set probability.
+/// The random sequence restarts from the seed on reset.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct BernoulliGateModule {
+    id: String,
+    #[serde(skip)]
+    gate_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    probability_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    reset_in: Option<(SharedSynthModule, u8)>,
+    a_out: AudioBuffer,
+    b_out: AudioBuffer,
+    probability: f32,
+    rng: SeededRng,
+    to_a: bool,
+    transition_detector: TransitionDetector,
+    reset_transition_detector: TransitionDetector,
+}
+
+impl BernoulliGateModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            gate_in: None,
+            probability_in: None,
+            reset_in: None,
+            a_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            b_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            probability: 0.5,
+            rng: SeededRng::new(rand::random()),
+            to_a: true,
+            transition_detector: TransitionDetector::new(),
+            reset_transition_detector: TransitionDetector::new(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Bernoulli Gate".to_string()
+    }
+
+    /// Returns the outputs for A and B
+    #[inline]
+    fn process(
+        &mut self,
+        gate_in: ControlVoltage,
+        probability: f32,
+        reset: ControlVoltage,
+    ) -> (ControlVoltage, ControlVoltage) {
+        if self.reset_transition_detector.is_transition(&reset) {
+            self.rng.reset();
+        }
+        if self.transition_detector.is_transition(&gate_in) {
+            self.to_a = self.rng.next_f32() < probability;
+        }
+        if !TransitionDetector::is_above_threshold(&gate_in) {
+            return (0.0, 0.0);
+        }
+        if self.to_a {
+            (gate_in, 0.0)
+        } else {
+            (0.0, gate_in)
+        }
+    }
+}
+
+impl SynthModule for BernoulliGateModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.a_out.resize(audio_config.buffer_size);
+        self.b_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        3
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.gate_in.clone()),
+            1 => Ok(self.probability_in.clone()),
+            2 => Ok(self.reset_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.gate_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.probability_in = Some((src_module, src_port));
+                Ok(())
+            }
+            2 => {
+                self.reset_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.gate_in = None;
+                Ok(())
+            }
+            1 => {
+                self.probability_in = None;
+                Ok(())
+            }
+            2 => {
+                self.reset_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Gate".to_string())),
+            1 => Ok(Some("Probability".to_string())),
+            2 => Ok(Some("Reset".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        2
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.a_out.clone()),
+            1 => Ok(self.b_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("A".to_string())),
+            1 => Ok(Some("B".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
+            ],
+            |bufs| {
+                let (gate_in, probability_in, reset_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.a_out.clone(), self.b_out.clone()],
+                    |bufs| {
+                        let (a_out, b_out) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        for idx in 0..a_out.len() {
+                            let probability = self.probability
+                                + probability_in.map(|buf| buf[idx]).unwrap_or(0.0);
+                            (a_out[idx], b_out[idx]) = self.process(
+                                gate_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                                probability,
+                                reset_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                            );
+                        }
+                    },
+                );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            learnable(
+                ui.add(egui::Slider::new(&mut self.probability, 0.0..=1.0).text("P(A)")),
+                &self.id,
+                PROBABILITY_PARAM,
+            );
+            seed_ui(ui, &mut self.rng);
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(PROBABILITY_PARAM, "P(A)", 0.0..=1.0),
+            ParamValue::F32(&mut self.probability),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::F32(&mut self.probability))
+    }
+}
+
+/// Turns triggers into gates of a set length. New triggers during the gate
+/// restart it.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PulseStretcherModule {
+    id: String,
+    #[serde(skip)]
+    trigger_in: Option<(SharedSynthModule, u8)>,
+    out: AudioBuffer,
+    length_sec: f32,
+    remaining: u32,
+    sample_rate: f32,
+    transition_detector: TransitionDetector,
+}
+
+impl PulseStretcherModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            trigger_in: None,
+            out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            length_sec: 0.1,
+            remaining: 0,
+            sample_rate: audio_config.sample_rate as f32,
+            transition_detector: TransitionDetector::new(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Pulse Stretcher".to_string()
+    }
+
+    #[inline]
+    fn process(&mut self, trigger: ControlVoltage) -> bool {
+        if self.transition_detector.is_transition(&trigger) {
+            self.remaining = (self.length_sec * self.sample_rate).ceil() as u32;
+        }
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+impl SynthModule for PulseStretcherModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate as f32;
+        self.out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        1
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.trigger_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.trigger_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.trigger_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Trigger".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        1
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("Gate".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        let input = self.resolve_input(0).unwrap();
+        let out = self.out.clone();
+        AudioBuffer::with_read_many(vec![input], |bufs| {
+            let trigger_in = bufs[0];
+            out.with_write(|out| {
+                let out = out.unwrap();
+                for idx in 0..out.len() {
+                    out[idx] = gate(self.process(trigger_in.map(|buf| buf[idx]).unwrap_or(0.0)));
+                }
+            });
+        });
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.length_sec, MIN_PULSE_SEC..=MAX_PULSE_SEC)
+                        .logarithmic(true)
+                        .show_value(false)
+                        .orientation(egui::SliderOrientation::Vertical),
+                )
+                .on_hover_text(format!("{:.3}", self.length_sec)),
+                &self.id,
+                LENGTH_PARAM,
+            );
+            ui.label("Length");
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(LENGTH_PARAM, "Length", MIN_PULSE_SEC..=MAX_PULSE_SEC),
+            ParamValue::F32(&mut self.length_sec),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::F32(&mut self.length_sec))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 16,
+        channels: 2,
+    };
+
+    #[test]
+    fn comparator_has_hysteresis() {
+        let mut comparator = ComparatorModule::new(&CONFIG);
+        comparator.threshold = 0.5;
+        comparator.hysteresis = 0.2;
+        let outputs: Vec<bool> = [0.4, 0.6, 0.4, 0.31, 0.29, 0.4]
+            .iter()
+            .map(|v| comparator.process(*v, 0.5))
+            .collect();
+        assert_eq!(outputs, vec![false, true, true, true, false, false]);
+    }
+
+    #[test]
+    fn divides_and_multiplies_clock() {
+        // clock with a period of 4 samples, high for 2
+        let clock = |n: usize| if n % 4 < 2 { 1.0 } else { 0.0 };
+        let mut divider = ClockDividerModule::new(&CONFIG);
+        divider.transition_detector = TransitionDetector { last: false };
+        let rising_edges = |divider: &mut ClockDividerModule| {
+            let mut last = false;
+            (0..64)
+                .filter(|n| {
+                    let high = divider.process(clock(*n), 0.0);
+                    let edge = high && !last;
+                    last = high;
+                    edge
+                })
+                .count()
+        };
+        divider.divide = 2;
+        assert_eq!(rising_edges(&mut divider), 8);
+        divider.divide = 1;
+        divider.multiply = 2;
+        assert_eq!(rising_edges(&mut divider), 32);
+    }
+
+    #[test]
+    fn clock_divider_resets() {
+        let mut divider = ClockDividerModule::new(&CONFIG);
+        divider.divide = 4;
+        divider.transition_detector = TransitionDetector { last: false };
+        divider.reset_transition_detector = TransitionDetector { last: false };
+        // Low without a clock
+        assert!((0..8).all(|_| !divider.process(0.0, 0.0)));
+        let mut run = |samples: usize, reset: usize| {
+            (0..samples)
+                .map(|n| {
+                    let clock = if n % 4 < 2 { 1.0 } else { 0.0 };
+                    divider.process(clock, if n == reset { 1.0 } else { 0.0 })
+                })
+                .collect::<Vec<_>>()
+        };
+        // Reset between pulses, partway through a division
+        let outputs = run(24, 6);
+        assert_eq!(outputs[..6], [true; 6]);
+        assert_eq!(outputs[6..8], [false; 2]);
+        // The next pulse is the first of a division, still at the old period
+        assert_eq!(outputs[8..16], [true; 8]);
+        assert_eq!(outputs[16..24], [false; 8]);
+    }
+
+    #[test]
+    fn bernoulli_repeats_after_reset() {
+        let mut bernoulli = BernoulliGateModule::new(&CONFIG);
+        bernoulli.transition_detector = TransitionDetector { last: false };
+        let run = |bernoulli: &mut BernoulliGateModule| {
+            bernoulli.process(0.0, 0.5, 1.0);
+            (0..32)
+                .map(|n| bernoulli.process(if n % 2 == 0 { 1.0 } else { 0.0 }, 0.5, 0.0))
+                .collect::<Vec<_>>()
+        };
+        let first = run(&mut bernoulli);
+        bernoulli.reset_transition_detector = TransitionDetector { last: false };
+        assert_eq!(first, run(&mut bernoulli));
+        assert!(first.contains(&(1.0, 0.0)) && first.contains(&(0.0, 1.0)));
+    }
+}
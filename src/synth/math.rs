@@ -1,15 +1,129 @@
-use super::{AudioBuffer, AudioConfig, ControlVoltage, SharedSynthModule, SynthModule};
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, Smoother,
+    SynthModule,
+};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 
-#[derive(Serialize, Deserialize, Clone)]
+const CONSTANT_PARAM: &str = "constant";
+const CONSTANT2_PARAM: &str = "constant2";
+const EXPONENT_PARAM: &str = "exponent";
+
+/// Below this magnitude a divisor counts as zero
+const DIVIDE_EPSILON: ControlVoltage = 1e-6;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
 pub enum MathOperation {
     Add,
     Subtract,
     Multiply,
+    Divide,
+    Min,
+    Max,
+    Abs,
+    Clamp,
+    Crossfade,
+    HalfWaveRectify,
+    Modulo,
+    ScaleOffset,
 }
 
+impl MathOperation {
+    const ALL: [MathOperation; 12] = [
+        MathOperation::Add,
+        MathOperation::Subtract,
+        MathOperation::Multiply,
+        MathOperation::Divide,
+        MathOperation::Min,
+        MathOperation::Max,
+        MathOperation::Abs,
+        MathOperation::Clamp,
+        MathOperation::Crossfade,
+        MathOperation::HalfWaveRectify,
+        MathOperation::Modulo,
+        MathOperation::ScaleOffset,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            MathOperation::Add => "Add",
+            MathOperation::Subtract => "Subtract",
+            MathOperation::Multiply => "Multiply",
+            MathOperation::Divide => "Divide",
+            MathOperation::Min => "Min",
+            MathOperation::Max => "Max",
+            MathOperation::Abs => "Abs (full-wave rectify)",
+            MathOperation::Clamp => "Clamp",
+            MathOperation::Crossfade => "Crossfade",
+            MathOperation::HalfWaveRectify => "Half-wave rectify",
+            MathOperation::Modulo => "Modulo",
+            MathOperation::ScaleOffset => "Scale & offset",
+        }
+    }
+
+    /// Labels for the three inputs, None where the operation ignores an input
+    fn input_labels(&self) -> [Option<&'static str>; 3] {
+        match self {
+            MathOperation::Abs | MathOperation::HalfWaveRectify => [Some("In"), None, None],
+            MathOperation::Clamp => [Some("In"), Some("Low"), Some("High")],
+            MathOperation::Crossfade => [Some("A"), Some("B"), Some("Mix")],
+            MathOperation::Modulo => [Some("In"), Some("Modulus"), None],
+            MathOperation::ScaleOffset => [Some("In"), Some("Scale"), Some("Offset")],
+            _ => [Some("In1"), Some("In2"), None],
+        }
+    }
+
+    /// Values used for the second and third inputs when they're disconnected
+    fn default_constants(&self) -> (ControlVoltage, ControlVoltage) {
+        match self {
+            MathOperation::Multiply
+            | MathOperation::Divide
+            | MathOperation::Modulo
+            | MathOperation::ScaleOffset => (1.0, 0.0),
+            MathOperation::Clamp => (-1.0, 1.0),
+            MathOperation::Crossfade => (0.0, 0.5),
+            _ => (0.0, 0.0),
+        }
+    }
+
+    #[inline]
+    fn apply(&self, x: ControlVoltage, y: ControlVoltage, z: ControlVoltage) -> ControlVoltage {
+        match self {
+            MathOperation::Add => x + y,
+            MathOperation::Subtract => x - y,
+            MathOperation::Multiply => x * y,
+            MathOperation::Divide => {
+                if y.abs() < DIVIDE_EPSILON {
+                    0.0
+                } else {
+                    x / y
+                }
+            }
+            MathOperation::Min => x.min(y),
+            MathOperation::Max => x.max(y),
+            MathOperation::Abs => x.abs(),
+            MathOperation::Clamp => x.clamp(y.min(z), y.max(z)),
+            MathOperation::Crossfade => {
+                let mix = z.clamp(0.0, 1.0);
+                x * (1.0 - mix) + y * mix
+            }
+            MathOperation::HalfWaveRectify => x.max(0.0),
+            MathOperation::Modulo => {
+                if y.abs() < DIVIDE_EPSILON {
+                    x
+                } else {
+                    x.rem_euclid(y)
+                }
+            }
+            MathOperation::ScaleOffset => x * y + z,
+        }
+    }
+}
+
+/// Math on up to three inputs, with the operation chosen in the UI.
+/// Disconnected inputs after the first use constants set in the UI.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MathModule {
     id: String,
@@ -17,38 +131,36 @@ pub struct MathModule {
     in1: Option<(SharedSynthModule, u8)>,
     #[serde(skip)]
     in2: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    in3: Option<(SharedSynthModule, u8)>,
     buf: AudioBuffer,
     constant: ControlVoltage,
+    constant2: ControlVoltage,
     operation: MathOperation,
+    /// For the two constants
+    #[serde(skip)]
+    smoothers: [Smoother; 2],
 }
 
 impl MathModule {
-    pub fn new(audio_config: &AudioConfig, operation: MathOperation) -> Self {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        let operation = MathOperation::Add;
+        let (constant, constant2) = operation.default_constants();
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             in1: None,
             in2: None,
+            in3: None,
             buf: AudioBuffer::new(Some(audio_config.buffer_size)),
-            constant: 0.0,
+            constant,
+            constant2,
             operation,
+            smoothers: std::array::from_fn(|_| Smoother::new(audio_config.sample_rate)),
         }
     }
 
-    pub fn get_name(operation: &MathOperation) -> String {
-        match operation {
-            MathOperation::Add => "Add".to_string(),
-            MathOperation::Subtract => "Subtract".to_string(),
-            MathOperation::Multiply => "Multiply".to_string(),
-        }
-    }
-
-    #[inline]
-    fn operation(&self, a: ControlVoltage, b: ControlVoltage) -> ControlVoltage {
-        match self.operation {
-            MathOperation::Add => a + b,
-            MathOperation::Subtract => a - b,
-            MathOperation::Multiply => a * b,
-        }
+    pub fn get_name() -> String {
+        "Math".to_string()
     }
 }
 
@@ -58,21 +170,25 @@ impl SynthModule for MathModule {
     }
 
     fn get_name(&self) -> String {
-        Self::get_name(&self.operation)
+        Self::get_name()
     }
 
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
         self.buf.resize(audio_config.buffer_size);
+        for smoother in self.smoothers.iter_mut() {
+            smoother.set_sample_rate(audio_config.sample_rate);
+        }
     }
 
     fn get_num_inputs(&self) -> u8 {
-        2
+        3
     }
 
     fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
         match input_idx {
             0 => Ok(self.in1.clone()),
             1 => Ok(self.in2.clone()),
+            2 => Ok(self.in3.clone()),
             _ => Err(()),
         }
     }
@@ -92,6 +208,10 @@ impl SynthModule for MathModule {
                 self.in2 = Some((src_module, src_port));
                 Ok(())
             }
+            2 => {
+                self.in3 = Some((src_module, src_port));
+                Ok(())
+            }
             _ => Err(()),
         }
     }
@@ -106,15 +226,18 @@ impl SynthModule for MathModule {
                 self.in2 = None;
                 Ok(())
             }
+            2 => {
+                self.in3 = None;
+                Ok(())
+            }
             _ => Err(()),
         }
     }
 
     fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
-        match input_idx {
-            0 => Ok(Some("In1".to_string())),
-            1 => Ok(Some("In2".to_string())),
-            _ => Err(()),
+        match self.operation.input_labels().get(input_idx as usize) {
+            Some(label) => Ok(label.map(|l| l.to_string())),
+            None => Err(()),
         }
     }
 
@@ -141,18 +264,21 @@ impl SynthModule for MathModule {
             vec![
                 self.resolve_input(0).unwrap(),
                 self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
             ],
             |bufs| {
-                let (i1, i2) = bufs.into_iter().collect_tuple().unwrap();
+                let (i1, i2, i3) = bufs.into_iter().collect_tuple().unwrap();
                 self.buf.with_write(|output| {
                     let output = output.unwrap();
                     for idx in 0..output.len() {
-                        output[idx] = match (i1, i2) {
-                            (Some(i1), Some(i2)) => self.operation(i1[idx], i2[idx]),
-                            (Some(i1), None) => self.operation(i1[idx], self.constant),
-                            (None, Some(i2)) => self.operation(0.0, i2[idx]),
-                            (None, None) => self.operation(0.0, self.constant),
-                        }
+                        let [constant, constant2] = &mut self.smoothers;
+                        let constant = constant.next(self.constant);
+                        let constant2 = constant2.next(self.constant2);
+                        output[idx] = self.operation.apply(
+                            i1.map(|i| i[idx]).unwrap_or(0.0),
+                            i2.map(|i| i[idx]).unwrap_or(constant),
+                            i3.map(|i| i[idx]).unwrap_or(constant2),
+                        );
                     }
                 });
             },
@@ -160,17 +286,79 @@ impl SynthModule for MathModule {
     }
 
     fn ui(&mut self, ui: &mut egui::Ui) {
-        if self.in2.is_none() {
-            ui.add(
-                egui::Slider::new(&mut self.constant, -2.0..=2.0)
-                    .orientation(egui::SliderOrientation::Vertical),
-            );
-        }
+        ui.vertical(|ui| {
+            egui::ComboBox::from_id_source((&self.id, "operation"))
+                .selected_text(self.operation.label())
+                .show_ui(ui, |ui| {
+                    for operation in MathOperation::ALL {
+                        if ui
+                            .selectable_label(self.operation == operation, operation.label())
+                            .clicked()
+                            && self.operation != operation
+                        {
+                            self.operation = operation;
+                            (self.constant, self.constant2) = operation.default_constants();
+                        }
+                    }
+                });
+            let [_, label2, label3] = self.operation.input_labels();
+            ui.horizontal(|ui| {
+                for (label, connected, constant, param_id) in [
+                    (
+                        label2,
+                        self.in2.is_some(),
+                        &mut self.constant,
+                        CONSTANT_PARAM,
+                    ),
+                    (
+                        label3,
+                        self.in3.is_some(),
+                        &mut self.constant2,
+                        CONSTANT2_PARAM,
+                    ),
+                ] {
+                    if let (Some(label), false) = (label, connected) {
+                        ui.vertical(|ui| {
+                            learnable(
+                                ui.add(
+                                    egui::Slider::new(constant, -2.0..=2.0)
+                                        .orientation(egui::SliderOrientation::Vertical),
+                                ),
+                                &self.id,
+                                param_id,
+                            );
+                            ui.label(label);
+                        });
+                    }
+                }
+            });
+        });
     }
 
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(CONSTANT_PARAM, "Constant", -2.0..=2.0),
+                ParamValue::F32(&mut self.constant),
+            ),
+            (
+                Param::new(CONSTANT2_PARAM, "Constant 2", -2.0..=2.0),
+                ParamValue::F32(&mut self.constant2),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.constant),
+            1 => ParamValue::F32(&mut self.constant2),
+            _ => return None,
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
@@ -182,6 +370,8 @@ pub struct NonLinearModule {
     in2: Option<(SharedSynthModule, u8)>,
     buf: AudioBuffer,
     constant: ControlVoltage,
+    #[serde(skip)]
+    smoother: Smoother,
 }
 
 impl NonLinearModule {
@@ -192,6 +382,7 @@ impl NonLinearModule {
             in2: None,
             buf: AudioBuffer::new(Some(audio_config.buffer_size)),
             constant: 1.0,
+            smoother: Smoother::new(audio_config.sample_rate),
         }
     }
 
@@ -200,7 +391,7 @@ impl NonLinearModule {
     }
 
     #[inline]
-    fn operation(&self, a: ControlVoltage, b: ControlVoltage) -> ControlVoltage {
+    fn operation(a: ControlVoltage, b: ControlVoltage) -> ControlVoltage {
         if a > 0.0 { a.powf(b) } else { -(-a).powf(b) }
     }
 }
@@ -216,6 +407,7 @@ impl SynthModule for NonLinearModule {
 
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
         self.buf.resize(audio_config.buffer_size);
+        self.smoother.set_sample_rate(audio_config.sample_rate);
     }
 
     fn get_num_inputs(&self) -> u8 {
@@ -300,11 +492,12 @@ impl SynthModule for NonLinearModule {
                 self.buf.with_write(|output| {
                     let output = output.unwrap();
                     for idx in 0..output.len() {
+                        let constant = self.smoother.next(self.constant);
                         output[idx] = match (i1, i2) {
-                            (Some(i1), Some(i2)) => self.operation(i1[idx], i2[idx]),
-                            (Some(i1), None) => self.operation(i1[idx], self.constant),
-                            (None, Some(i2)) => self.operation(0.0, i2[idx]),
-                            (None, None) => self.operation(0.0, self.constant),
+                            (Some(i1), Some(i2)) => Self::operation(i1[idx], i2[idx]),
+                            (Some(i1), None) => Self::operation(i1[idx], constant),
+                            (None, Some(i2)) => Self::operation(0.0, i2[idx]),
+                            (None, None) => Self::operation(0.0, constant),
                         }
                     }
                 });
@@ -314,9 +507,13 @@ impl SynthModule for NonLinearModule {
 
     fn ui(&mut self, ui: &mut egui::Ui) {
         if self.in2.is_none() {
-            ui.add(
-                egui::Slider::new(&mut self.constant, 0.5..=2.0)
-                    .orientation(egui::SliderOrientation::Vertical),
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.constant, 0.5..=2.0)
+                        .orientation(egui::SliderOrientation::Vertical),
+                ),
+                &self.id,
+                EXPONENT_PARAM,
             );
             ui.label("Exponent");
         }
@@ -325,4 +522,84 @@ impl SynthModule for NonLinearModule {
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(EXPONENT_PARAM, "Exponent", 0.5..=2.0),
+            ParamValue::F32(&mut self.constant),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::F32(&mut self.constant))
+    }
+}
+
+// MIGRATIONS
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MathModuleV0 {
+    id: String,
+    #[serde(skip)]
+    in1: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    in2: Option<(SharedSynthModule, u8)>,
+    buf: AudioBuffer,
+    constant: ControlVoltage,
+    operation: MathOperation,
+}
+
+impl From<MathModuleV0> for MathModule {
+    fn from(item: MathModuleV0) -> Self {
+        Self {
+            id: item.id,
+            in1: item.in1,
+            in2: item.in2,
+            in3: None,
+            buf: item.buf,
+            constant: item.constant,
+            constant2: item.operation.default_constants().1,
+            operation: item.operation,
+            smoothers: Default::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{SynthModuleType, enum_to_sharedsynthmodule};
+
+    #[test]
+    fn operations() {
+        assert_eq!(MathOperation::Divide.apply(1.0, 0.0, 0.0), 0.0);
+        assert_eq!(MathOperation::Divide.apply(1.0, 4.0, 0.0), 0.25);
+        assert_eq!(MathOperation::Clamp.apply(3.0, 1.0, -1.0), 1.0);
+        assert_eq!(MathOperation::Crossfade.apply(1.0, 3.0, 0.25), 1.5);
+        assert_eq!(MathOperation::Crossfade.apply(1.0, 3.0, 2.0), 3.0);
+        assert_eq!(MathOperation::Modulo.apply(-0.25, 1.0, 0.0), 0.75);
+        assert_eq!(MathOperation::HalfWaveRectify.apply(-0.5, 0.0, 0.0), 0.0);
+        assert_eq!(MathOperation::ScaleOffset.apply(2.0, 3.0, 1.0), 7.0);
+    }
+    #[test]
+    fn migrates_v0() {
+        let v0 = MathModuleV0 {
+            id: "math".to_string(),
+            in1: None,
+            in2: None,
+            buf: AudioBuffer::new(Some(16)),
+            constant: 0.5,
+            operation: MathOperation::Clamp,
+        };
+        let bytes = rmp_serde::to_vec(&SynthModuleType::MathModuleV0(v0)).unwrap();
+        let module = enum_to_sharedsynthmodule(rmp_serde::from_slice(&bytes).unwrap());
+        let module = module.read().unwrap();
+        let math = module.as_any().downcast_ref::<MathModule>().unwrap();
+        assert_eq!(math.get_id(), "math");
+        assert!(math.operation == MathOperation::Clamp);
+        // The first constant is kept, the new one starts at the default
+        assert_eq!((math.constant, math.constant2), (0.5, 1.0));
+        assert_eq!(math.get_num_inputs(), 3);
+        assert_eq!(math.buf.get().unwrap().len(), 16);
+    }
 }
@@ -0,0 +1,686 @@
+use crate::ui::run_async;
+#[cfg(target_os = "linux")]
+use crossbeam_queue::ArrayQueue;
+use rfd::AsyncFileDialog;
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock, Weak};
+use std::time::{Duration, Instant};
+
+/// Ticks per quarter note in files written out
+const TICKS_PER_QUARTER: u16 = 96;
+/// Tempo written to files, in microseconds per quarter note
+const TEMPO: u32 = 500_000;
+
+// Status bytes, with the channel in the low nibble of the channel messages
+pub const NOTE_OFF: u8 = 0x80;
+pub const NOTE_ON: u8 = 0x90;
+pub const CONTROL_CHANGE: u8 = 0xB0;
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+
+#[derive(Debug, PartialEq)]
+pub enum MidiError {
+    NotMidi,
+    Truncated,
+    /// Timing in SMPTE frames rather than ticks per quarter note
+    SmpteTiming,
+    UnexpectedByte(u8),
+    /// Events later than the longest time that can be counted in ticks
+    TooLong,
+}
+
+impl fmt::Display for MidiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MidiError::NotMidi => write!(f, "Not a MIDI file"),
+            MidiError::Truncated => write!(f, "MIDI file is truncated"),
+            MidiError::SmpteTiming => write!(f, "MIDI files with SMPTE timing aren't supported"),
+            MidiError::UnexpectedByte(byte) => {
+                write!(f, "Unexpected byte {byte:#04x} in MIDI file")
+            }
+            MidiError::TooLong => write!(f, "MIDI file is too long"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiNote {
+    pub channel: u8,
+    pub key: u8,
+    pub velocity: u8,
+    /// Start in ticks
+    pub start: u32,
+    /// Length in ticks
+    pub length: u32,
+}
+
+/// The notes of a Standard MIDI File, from all of its tracks
+#[derive(Clone, Debug, PartialEq)]
+pub struct MidiFile {
+    pub ticks_per_quarter: u16,
+    /// Notes in order of their start
+    pub notes: Vec<MidiNote>,
+}
+
+impl Default for MidiFile {
+    fn default() -> Self {
+        Self {
+            ticks_per_quarter: TICKS_PER_QUARTER,
+            notes: vec![],
+        }
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], MidiError> {
+        let end = self.pos.checked_add(len).ok_or(MidiError::Truncated)?;
+        let bytes = self.data.get(self.pos..end).ok_or(MidiError::Truncated)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, MidiError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32, MidiError> {
+        Ok(u32::from_be_bytes(self.bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Variable length quantity, 7 bits per byte with the top bit set on all
+    /// but the last
+    fn var_len(&mut self) -> Result<u32, MidiError> {
+        let mut value = 0;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(MidiError::UnexpectedByte(self.data[self.pos - 1]))
+    }
+
+    fn is_done(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+}
+
+fn write_var_len(out: &mut Vec<u8>, value: u32) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut value = value >> 7;
+    while value > 0 {
+        bytes.push((value & 0x7F) as u8 | 0x80);
+        value >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+impl MidiFile {
+    /// Ticks in a sixteenth note, the length of a sequencer step
+    pub fn step_ticks(&self) -> u32 {
+        (self.ticks_per_quarter as u32 / 4).max(1)
+    }
+
+    pub fn parse(data: &[u8]) -> Result<Self, MidiError> {
+        let mut reader = Reader { data, pos: 0 };
+        if reader.bytes(4).map_err(|_| MidiError::NotMidi)? != b"MThd" {
+            return Err(MidiError::NotMidi);
+        }
+        let header_len = reader.u32()? as usize;
+        let header = reader.bytes(header_len)?;
+        if header.len() < 6 {
+            return Err(MidiError::Truncated);
+        }
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        if division & 0x8000 != 0 {
+            return Err(MidiError::SmpteTiming);
+        }
+        let mut notes = vec![];
+        while !reader.is_done() {
+            let id = reader.bytes(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = reader.bytes(len)?;
+            // Other chunk types are to be skipped
+            if id == b"MTrk" {
+                Self::parse_track(chunk, &mut notes)?;
+            }
+        }
+        notes.sort_by_key(|note: &MidiNote| (note.start, note.key));
+        Ok(Self {
+            ticks_per_quarter: division.max(1),
+            notes,
+        })
+    }
+
+    fn parse_track(data: &[u8], notes: &mut Vec<MidiNote>) -> Result<(), MidiError> {
+        let mut reader = Reader { data, pos: 0 };
+        let mut tick = 0;
+        let mut status = 0;
+        let mut playing: Vec<MidiNote> = vec![];
+        while !reader.is_done() {
+            tick = reader
+                .var_len()?
+                .checked_add(tick)
+                .ok_or(MidiError::TooLong)?;
+            let mut byte = reader.u8()?;
+            match byte {
+                0xFF => {
+                    let kind = reader.u8()?;
+                    let len = reader.var_len()? as usize;
+                    reader.bytes(len)?;
+                    // End of track
+                    if kind == 0x2F {
+                        break;
+                    }
+                    continue;
+                }
+                0xF0 | 0xF7 => {
+                    let len = reader.var_len()? as usize;
+                    reader.bytes(len)?;
+                    continue;
+                }
+                0x80..=0xEF => {
+                    status = byte;
+                    byte = reader.u8()?;
+                }
+                // Running status, the byte is the first data byte
+                0x00..=0x7F if status != 0 => {}
+                _ => return Err(MidiError::UnexpectedByte(byte)),
+            }
+            let channel = status & 0x0F;
+            match status & 0xF0 {
+                0x80 | 0x90 => {
+                    let key = byte;
+                    let velocity = reader.u8()?;
+                    if status & 0xF0 == 0x90 && velocity > 0 {
+                        playing.push(MidiNote {
+                            channel,
+                            key,
+                            velocity,
+                            start: tick,
+                            length: 0,
+                        });
+                    } else if let Some(idx) = playing
+                        .iter()
+                        .position(|note| note.channel == channel && note.key == key)
+                    {
+                        let note = playing.remove(idx);
+                        notes.push(MidiNote {
+                            length: tick - note.start,
+                            ..note
+                        });
+                    }
+                }
+                // Program change and channel pressure have one data byte
+                0xC0 | 0xD0 => {}
+                _ => {
+                    reader.u8()?;
+                }
+            }
+        }
+        // Notes left on end with the track
+        notes.extend(playing.into_iter().map(|note| MidiNote {
+            length: tick - note.start,
+            ..note
+        }));
+        Ok(())
+    }
+
+    /// A type 0 file with a single track
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut events: Vec<(u32, bool, [u8; 3])> = vec![];
+        for note in self.notes.iter() {
+            let channel = note.channel & 0x0F;
+            events.push((note.start, true, [0x90 | channel, note.key, note.velocity]));
+            events.push((
+                note.start + note.length,
+                false,
+                [0x80 | channel, note.key, 0],
+            ));
+        }
+        // Note offs first, so notes ending and starting together don't overlap
+        events.sort_by_key(|(tick, on, _)| (*tick, *on));
+
+        let mut track = vec![];
+        write_var_len(&mut track, 0);
+        track.extend([0xFF, 0x51, 0x03]);
+        track.extend(&TEMPO.to_be_bytes()[1..]);
+        let mut last = 0;
+        for (tick, _, event) in events {
+            write_var_len(&mut track, tick - last);
+            track.extend(event);
+            last = tick;
+        }
+        write_var_len(&mut track, 0);
+        track.extend([0xFF, 0x2F, 0x00]);
+
+        let mut out = vec![];
+        out.extend(b"MThd");
+        out.extend(6_u32.to_be_bytes());
+        out.extend(0_u16.to_be_bytes()); // format
+        out.extend(1_u16.to_be_bytes()); // tracks
+        out.extend(self.ticks_per_quarter.to_be_bytes());
+        out.extend(b"MTrk");
+        out.extend((track.len() as u32).to_be_bytes());
+        out.extend(track);
+        out
+    }
+}
+
+/// Loads MIDI files in the background for a module to pick up from its UI.
+#[derive(Default, Clone)]
+pub struct MidiLoader(Arc<Mutex<Option<MidiFile>>>);
+
+impl MidiLoader {
+    /// Show a file dialog for `.mid` files
+    pub fn open(&self) {
+        let loaded = self.0.clone();
+        run_async(async move {
+            let file = AsyncFileDialog::new()
+                .add_filter("MIDI", &["mid", "midi"])
+                .pick_file()
+                .await;
+            if let Some(file) = file {
+                match MidiFile::parse(&file.read().await) {
+                    Ok(parsed) => *loaded.lock().unwrap() = Some(parsed),
+                    Err(e) => println!("{e}"),
+                }
+            }
+        });
+    }
+
+    pub fn take(&self) -> Option<MidiFile> {
+        self.0.try_lock().ok()?.take()
+    }
+}
+
+/// Show a file dialog to save a MIDI file
+pub fn save_midi(file: &MidiFile, name: &str) {
+    let data = file.to_bytes();
+    let name = format!("{name}.mid");
+    run_async(async move {
+        let file = AsyncFileDialog::new()
+            .add_filter("MIDI", &["mid", "midi"])
+            .set_file_name(name)
+            .save_file()
+            .await;
+        if let Some(file) = file {
+            let _ = file.write(&data).await;
+        }
+    });
+}
+
+/// Longest message modules send, such as a note or control change
+#[cfg(target_os = "linux")]
+const MAX_MESSAGE_LEN: usize = 3;
+/// Messages that can wait for the MIDI thread to send them
+#[cfg(target_os = "linux")]
+const OUTGOING_CAPACITY: usize = 4096;
+/// How often the MIDI thread checks for messages to send and receive
+#[cfg(target_os = "linux")]
+const POLL: Duration = Duration::from_millis(1);
+
+/// A message and when it was received, or is to be sent
+pub type Timed = (Instant, Vec<u8>);
+
+/// A message waiting for its time to be sent, without allocating
+#[cfg(target_os = "linux")]
+struct Scheduled {
+    at: Instant,
+    len: usize,
+    bytes: [u8; MAX_MESSAGE_LEN],
+}
+
+#[cfg(target_os = "linux")]
+impl Scheduled {
+    fn message(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+/// The time of a sample in the buffer being calculated, counting from when
+/// the calculation started. Spaces out the messages sent from a buffer the
+/// way its audio is.
+pub fn sample_time(start: Instant, sample: usize, sample_rate: u16) -> Instant {
+    start + Duration::from_secs_f64(sample as f64 / sample_rate.max(1) as f64)
+}
+
+/// Somewhere for modules to send MIDI messages to and receive them from.
+/// Messages are whole, with their status byte.
+pub trait MidiBackend: Send + Sync {
+    /// Send a message at a time, usually from [`sample_time`]. Never blocks,
+    /// so it can be called from the audio thread.
+    fn send(&self, message: &[u8], at: Instant);
+
+    /// Where received messages are delivered to. Readers go through a
+    /// [`MidiInbox`], so every reader sees every message.
+    fn inboxes(&self) -> &Inboxes;
+
+    /// False when there's no MIDI on this platform, and messages go nowhere
+    fn is_available(&self) -> bool {
+        true
+    }
+}
+
+pub type SharedMidiBackend = Arc<dyn MidiBackend>;
+
+type Messages = Mutex<Vec<Timed>>;
+type Queue = Arc<Messages>;
+
+/// The queues of a backend's inboxes, dropped along with their inbox
+#[derive(Default)]
+pub struct Inboxes(Mutex<Vec<Weak<Messages>>>);
+
+impl Inboxes {
+    fn deliver(&self, messages: Vec<Timed>) {
+        let mut queues = self.0.lock().unwrap();
+        queues.retain(|queue| queue.strong_count() > 0);
+        for queue in queues.iter().filter_map(|queue| queue.upgrade()) {
+            queue.lock().unwrap().extend(messages.iter().cloned());
+        }
+    }
+}
+
+/// Collects the messages received by a backend from when it was made
+#[derive(Clone)]
+pub struct MidiInbox {
+    queue: Queue,
+}
+
+impl MidiInbox {
+    pub fn new(backend: SharedMidiBackend) -> Self {
+        let queue = Queue::default();
+        backend
+            .inboxes()
+            .0
+            .lock()
+            .unwrap()
+            .push(Arc::downgrade(&queue));
+        Self { queue }
+    }
+
+    /// Messages received since the last call, with when they arrived. Doesn't
+    /// wait while messages are being delivered, leaving them for the next call.
+    pub fn take(&self) -> Vec<Timed> {
+        self.queue
+            .try_lock()
+            .map(|mut queue| std::mem::take(&mut *queue))
+            .unwrap_or_default()
+    }
+}
+
+/// Drops sent messages and never receives any, for platforms without MIDI
+#[derive(Default)]
+struct NullBackend {
+    inboxes: Inboxes,
+}
+
+impl MidiBackend for NullBackend {
+    fn send(&self, _message: &[u8], _at: Instant) {}
+
+    fn inboxes(&self) -> &Inboxes {
+        &self.inboxes
+    }
+
+    fn is_available(&self) -> bool {
+        false
+    }
+}
+
+/// An ALSA sequencer client named s-rack, with one port that other software
+/// and hardware can be connected to in either direction
+///
+/// The sequencer belongs to a thread of its own, which sends messages queued
+/// by modules when their time comes and timestamps messages as they arrive,
+/// so the audio thread never waits on it.
+#[cfg(target_os = "linux")]
+struct AlsaBackend {
+    outgoing: Arc<ArrayQueue<Scheduled>>,
+    inboxes: Arc<Inboxes>,
+}
+
+#[cfg(target_os = "linux")]
+impl AlsaBackend {
+    fn open() -> alsa::Result<Self> {
+        use alsa::seq::{PortCap, PortType, Seq};
+        let seq = Seq::open(None, None, true)?;
+        seq.set_client_name(c"s-rack")?;
+        let port = seq.create_simple_port(
+            c"s-rack",
+            PortCap::READ | PortCap::SUBS_READ | PortCap::WRITE | PortCap::SUBS_WRITE,
+            PortType::MIDI_GENERIC | PortType::APPLICATION,
+        )?;
+        let outgoing = Arc::new(ArrayQueue::new(OUTGOING_CAPACITY));
+        let inboxes = Arc::new(Inboxes::default());
+        {
+            let outgoing = outgoing.clone();
+            let inboxes = inboxes.clone();
+            std::thread::spawn(move || Self::run(seq, port, &outgoing, &inboxes));
+        }
+        Ok(Self { outgoing, inboxes })
+    }
+
+    /// Send and receive until the program ends
+    fn run(seq: alsa::seq::Seq, port: i32, outgoing: &ArrayQueue<Scheduled>, inboxes: &Inboxes) {
+        let mut pending: Vec<Scheduled> = vec![];
+        loop {
+            while let Some(scheduled) = outgoing.pop() {
+                pending.push(scheduled);
+            }
+            // stable, so messages due together go in the order they were sent
+            pending.sort_by_key(|scheduled| scheduled.at);
+            let now = Instant::now();
+            let due = pending.partition_point(|scheduled| scheduled.at <= now);
+            for scheduled in pending.drain(..due) {
+                Self::output(&seq, port, scheduled.message());
+            }
+
+            let received = Self::input(&seq);
+            if !received.is_empty() {
+                inboxes.deliver(received);
+            }
+
+            let next = pending.first().map_or(POLL, |scheduled| {
+                scheduled.at.saturating_duration_since(Instant::now())
+            });
+            std::thread::sleep(next.min(POLL));
+        }
+    }
+
+    fn output(seq: &alsa::seq::Seq, port: i32, message: &[u8]) {
+        let Ok(mut encoder) = alsa::seq::MidiEvent::new(message.len() as u32) else {
+            return;
+        };
+        if let Ok((_, Some(mut event))) = encoder.encode(message) {
+            event.set_source(port);
+            event.set_subs();
+            event.set_direct();
+            let _ = seq.event_output_direct(&mut event);
+        }
+    }
+
+    fn input(seq: &alsa::seq::Seq) -> Vec<Timed> {
+        let mut received = vec![];
+        let Ok(decoder) = alsa::seq::MidiEvent::new(16) else {
+            return received;
+        };
+        decoder.enable_running_status(false);
+        let mut input = seq.input();
+        // Non-blocking, so this ends when there's nothing more to read
+        while let Ok(mut event) = input.event_input() {
+            let mut buf = [0; 16];
+            if let Ok(len) = decoder.decode(&mut buf, &mut event) {
+                received.push((Instant::now(), buf[..len].to_vec()));
+            }
+        }
+        received
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl MidiBackend for AlsaBackend {
+    fn send(&self, message: &[u8], at: Instant) {
+        if message.len() > MAX_MESSAGE_LEN {
+            return;
+        }
+        let mut bytes = [0; MAX_MESSAGE_LEN];
+        bytes[..message.len()].copy_from_slice(message);
+        // Dropped if the MIDI thread has fallen this far behind
+        let _ = self.outgoing.push(Scheduled {
+            at,
+            len: message.len(),
+            bytes,
+        });
+    }
+
+    fn inboxes(&self) -> &Inboxes {
+        &self.inboxes
+    }
+}
+
+/// The backend shared by all MIDI modules, opened when first asked for
+pub fn backend() -> SharedMidiBackend {
+    static BACKEND: OnceLock<SharedMidiBackend> = OnceLock::new();
+    BACKEND
+        .get_or_init(|| {
+            #[cfg(target_os = "linux")]
+            match AlsaBackend::open() {
+                Ok(backend) => return Arc::new(backend),
+                Err(e) => println!("Couldn't open the ALSA sequencer: {e}"),
+            }
+            Arc::new(NullBackend::default())
+        })
+        .clone()
+}
+
+/// Whether MIDI can be sent and received on this platform
+pub fn available() -> bool {
+    backend().is_available()
+}
+
+/// Shown in the MIDI modules when there's no MIDI on this platform
+pub fn unavailable_ui(ui: &mut egui::Ui) {
+    if !available() {
+        ui.colored_label(egui::Color32::RED, "MIDI isn't available on this platform");
+    }
+}
+
+/// An inbox for messages received by the shared backend
+pub fn inbox() -> MidiInbox {
+    MidiInbox::new(backend())
+}
+
+/// Keeps sent messages, and delivers messages queued up as received
+#[cfg(test)]
+#[derive(Default)]
+pub struct CaptureBackend {
+    sent: Mutex<Vec<Vec<u8>>>,
+    inboxes: Inboxes,
+}
+
+#[cfg(test)]
+impl CaptureBackend {
+    /// Messages sent since the last call
+    pub fn take_sent(&self) -> Vec<Vec<u8>> {
+        std::mem::take(&mut self.sent.lock().unwrap())
+    }
+
+    pub fn queue(&self, message: &[u8]) {
+        self.queue_at(message, Instant::now());
+    }
+
+    pub fn queue_at(&self, message: &[u8], at: Instant) {
+        self.inboxes.deliver(vec![(at, message.to_vec())]);
+    }
+}
+
+#[cfg(test)]
+impl MidiBackend for CaptureBackend {
+    fn send(&self, message: &[u8], _at: Instant) {
+        self.sent.lock().unwrap().push(message.to_vec());
+    }
+
+    fn inboxes(&self) -> &Inboxes {
+        &self.inboxes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(channel: u8, key: u8, velocity: u8, start: u32, length: u32) -> MidiNote {
+        MidiNote {
+            channel,
+            key,
+            velocity,
+            start,
+            length,
+        }
+    }
+
+    #[test]
+    fn parses_type_1_file() {
+        let file = MidiFile::parse(include_bytes!("fixtures/melody.mid")).unwrap();
+        assert_eq!(file.ticks_per_quarter, 480);
+        assert_eq!(file.step_ticks(), 120);
+        assert_eq!(
+            file.notes,
+            vec![
+                note(0, 60, 100, 0, 120),
+                note(0, 64, 64, 120, 480),
+                note(0, 67, 127, 840, 60),
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_running_status_and_drums() {
+        let file = MidiFile::parse(include_bytes!("fixtures/drums.mid")).unwrap();
+        assert_eq!(file.ticks_per_quarter, 96);
+        let kicks: Vec<_> = file
+            .notes
+            .iter()
+            .filter(|note| note.key == 36)
+            .map(|note| note.start)
+            .collect();
+        assert_eq!(kicks, vec![0, 192]);
+        assert!(file.notes.iter().all(|note| note.channel == 9));
+        assert_eq!(file.notes.len(), 12);
+    }
+
+    #[test]
+    fn writes_what_it_reads() {
+        let file = MidiFile::parse(include_bytes!("fixtures/melody.mid")).unwrap();
+        assert_eq!(MidiFile::parse(&file.to_bytes()).unwrap(), file);
+        assert_eq!(MidiFile::parse(b"RIFF"), Err(MidiError::NotMidi));
+        assert_eq!(
+            MidiFile::parse(&file.to_bytes()[..30]),
+            Err(MidiError::Truncated)
+        );
+    }
+
+    #[test]
+    fn rejects_overflowing_times() {
+        let mut track = vec![];
+        // the longest delta time, then an empty text event, until past u32::MAX ticks
+        for _ in 0..17 {
+            track.extend([0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0x01, 0x00]);
+        }
+        let mut data = b"MThd".to_vec();
+        data.extend(6_u32.to_be_bytes());
+        data.extend([0, 0, 0, 1, 0, 96]);
+        data.extend(b"MTrk");
+        data.extend((track.len() as u32).to_be_bytes());
+        data.extend(track);
+        assert_eq!(MidiFile::parse(&data), Err(MidiError::TooLong));
+    }
+}
@@ -0,0 +1,800 @@
+use super::midi::{
+    CLOCK, CONTINUE, CONTROL_CHANGE, MidiInbox, NOTE_OFF, NOTE_ON, START, STOP, SharedMidiBackend,
+    Timed, backend, inbox, sample_time, unavailable_ui,
+};
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, SharedSynthModule, SynthModule, TransitionDetector,
+};
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::time::{Duration, Instant};
+
+/// Velocity of notes played with the velocity input unconnected
+const DEFAULT_VELOCITY: u8 = 100;
+const CC_INPUTS: usize = 4;
+/// MIDI clock rate
+const CLOCKS_PER_QUARTER: u32 = 24;
+/// Clock rates offered for the clock modules' own side, each dividing evenly
+/// into MIDI clock
+const PULSES_PER_QUARTER: [u8; 7] = [1, 2, 3, 4, 6, 8, 12];
+const RESET_MS: u32 = 5;
+
+fn pulses_per_quarter_ui(ui: &mut egui::Ui, id: &str, pulses_per_quarter: &mut u8) {
+    ui.horizontal(|ui| {
+        egui::ComboBox::from_id_source((id, "ppq"))
+            .selected_text(pulses_per_quarter.to_string())
+            .show_ui(ui, |ui| {
+                for ppq in PULSES_PER_QUARTER {
+                    ui.selectable_value(pulses_per_quarter, ppq, ppq.to_string());
+                }
+            });
+        ui.label("pulses per quarter note");
+    });
+}
+
+/// A CC input's controller number, and the voltages sent as 0 and 127
+#[derive(Serialize, Deserialize, Clone)]
+struct CcMapping {
+    controller: u8,
+    low: ControlVoltage,
+    high: ControlVoltage,
+}
+
+impl CcMapping {
+    fn value(&self, cv: ControlVoltage) -> u8 {
+        let range = self.high - self.low;
+        if range == 0.0 {
+            return 0;
+        }
+        (((cv - self.low) / range).clamp(0.0, 1.0) * 127.0).round() as u8
+    }
+}
+
+/// Plays pitch CV and gate as notes on a MIDI channel, and sends CV inputs as
+/// control changes.
+///
+/// Pitch is 1V per octave with A4 at 0V. A new pitch while the gate is high
+/// starts its note before ending the last one, for legato. Each CC is sent at
+/// most once a buffer, when its value changes.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MidiOutModule {
+    id: String,
+    #[serde(skip)]
+    pitch_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    gate_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    velocity_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    cc_in: [Option<(SharedSynthModule, u8)>; CC_INPUTS],
+    /// From 1 to 16
+    channel: u8,
+    ccs: Vec<CcMapping>,
+    /// Channel and key of the note playing
+    #[serde(skip)]
+    playing: Option<(u8, u8)>,
+    /// Controller and value last sent for each CC input
+    #[serde(skip)]
+    cc_sent: [Option<(u8, u8)>; CC_INPUTS],
+    #[serde(skip)]
+    sample_rate: u16,
+    #[serde(skip, default = "backend")]
+    backend: SharedMidiBackend,
+}
+
+impl MidiOutModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            pitch_in: None,
+            gate_in: None,
+            velocity_in: None,
+            cc_in: Default::default(),
+            channel: 1,
+            // Mod wheel, cutoff, resonance and volume
+            ccs: [1, 74, 71, 7]
+                .into_iter()
+                .map(|controller| CcMapping {
+                    controller,
+                    low: 0.0,
+                    high: 1.0,
+                })
+                .collect(),
+            playing: None,
+            cc_sent: Default::default(),
+            sample_rate: audio_config.sample_rate,
+            backend: backend(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "MIDI Out".to_string()
+    }
+
+    fn play(
+        &mut self,
+        pitch: ControlVoltage,
+        gate: ControlVoltage,
+        velocity: Option<ControlVoltage>,
+        at: Instant,
+    ) {
+        let channel = self.channel.clamp(1, 16) - 1;
+        let key = (69.0 + pitch * 12.0).round().clamp(0.0, 127.0) as u8;
+        if gate > 0.0 {
+            if self.playing == Some((channel, key)) {
+                return;
+            }
+            let velocity = velocity.map_or(DEFAULT_VELOCITY, |velocity| {
+                (velocity.clamp(0.0, 1.0) * 127.0).round().max(1.0) as u8
+            });
+            self.backend.send(&[NOTE_ON | channel, key, velocity], at);
+            if let Some((channel, key)) = self.playing.replace((channel, key)) {
+                self.backend.send(&[NOTE_OFF | channel, key, 0], at);
+            }
+        } else if let Some((channel, key)) = self.playing.take() {
+            self.backend.send(&[NOTE_OFF | channel, key, 0], at);
+        }
+    }
+
+    fn control(&mut self, idx: usize, cv: ControlVoltage, at: Instant) {
+        let channel = self.channel.clamp(1, 16) - 1;
+        let mapping = &self.ccs[idx];
+        let sent = (mapping.controller, mapping.value(cv));
+        if self.cc_sent[idx] != Some(sent) {
+            self.cc_sent[idx] = Some(sent);
+            self.backend
+                .send(&[CONTROL_CHANGE | channel, sent.0, sent.1], at);
+        }
+    }
+}
+
+impl SynthModule for MidiOutModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate;
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        3 + CC_INPUTS as u8
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.pitch_in.clone()),
+            1 => Ok(self.gate_in.clone()),
+            2 => Ok(self.velocity_in.clone()),
+            n => match self.cc_in.get(n as usize - 3) {
+                Some(input) => Ok(input.clone()),
+                None => Err(()),
+            },
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => self.pitch_in = Some((src_module, src_port)),
+            1 => self.gate_in = Some((src_module, src_port)),
+            2 => self.velocity_in = Some((src_module, src_port)),
+            n => match self.cc_in.get_mut(n as usize - 3) {
+                Some(input) => *input = Some((src_module, src_port)),
+                None => return Err(()),
+            },
+        }
+        Ok(())
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => self.pitch_in = None,
+            1 => self.gate_in = None,
+            2 => self.velocity_in = None,
+            n => match self.cc_in.get_mut(n as usize - 3) {
+                Some(input) => *input = None,
+                None => return Err(()),
+            },
+        }
+        Ok(())
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Pitch".to_string())),
+            1 => Ok(Some("Gate".to_string())),
+            2 => Ok(Some("Velocity".to_string())),
+            n if (n as usize - 3) < CC_INPUTS => Ok(Some(format!("CC {}", n - 2))),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        0
+    }
+
+    fn get_output(&self, _output_idx: u8) -> Result<AudioBuffer, ()> {
+        Err(())
+    }
+
+    fn get_output_label(&self, _output_idx: u8) -> Result<Option<String>, ()> {
+        Err(())
+    }
+
+    fn calc(&mut self) {
+        let inputs: Vec<_> = (0..self.get_num_inputs())
+            .map(|n| self.resolve_input(n).unwrap())
+            .collect();
+        let start = Instant::now();
+        AudioBuffer::with_read_many(inputs, |bufs| {
+            let (pitch_in, gate_in, velocity_in) = (bufs[0], bufs[1], bufs[2]);
+            for (idx, cc_in) in bufs[3..].iter().enumerate() {
+                if let Some(buf) = cc_in {
+                    self.control(idx, buf[0], start);
+                }
+            }
+            match gate_in {
+                Some(gate_in) => {
+                    for (idx, gate) in gate_in.iter().enumerate() {
+                        self.play(
+                            pitch_in.map_or(0.0, |buf| buf[idx]),
+                            *gate,
+                            velocity_in.map(|buf| buf[idx]),
+                            sample_time(start, idx, self.sample_rate),
+                        );
+                    }
+                }
+                // End any note left playing when the gate was disconnected
+                None => self.play(0.0, 0.0, None, start),
+            }
+        });
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.vertical(|ui| {
+            unavailable_ui(ui);
+            ui.add(
+                egui::DragValue::new(&mut self.channel)
+                    .range(1..=16)
+                    .prefix("Channel "),
+            );
+            egui::Grid::new((&self.id, "ccs")).show(ui, |ui| {
+                for (idx, mapping) in self.ccs.iter_mut().enumerate() {
+                    ui.label(format!("CC {}", idx + 1));
+                    ui.add(
+                        egui::DragValue::new(&mut mapping.controller)
+                            .range(0..=127)
+                            .prefix("#"),
+                    );
+                    ui.add(
+                        egui::DragValue::new(&mut mapping.low)
+                            .speed(0.01)
+                            .suffix(" V"),
+                    )
+                    .on_hover_text("Voltage sent as 0");
+                    ui.add(
+                        egui::DragValue::new(&mut mapping.high)
+                            .speed(0.01)
+                            .suffix(" V"),
+                    )
+                    .on_hover_text("Voltage sent as 127");
+                    ui.end_row();
+                }
+            });
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Sends MIDI clock following a clock input with fewer pulses per quarter
+/// note. The MIDI clocks between input pulses are spread out over the time
+/// between the last two, and each is sent at the time of its sample.
+///
+/// A rising Run gate sends Start and a falling one Stop. With Run
+/// unconnected the clock is always sent, without either.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MidiClockOutModule {
+    id: String,
+    #[serde(skip)]
+    clock_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    run_in: Option<(SharedSynthModule, u8)>,
+    pulses_per_quarter: u8,
+    running: bool,
+    /// MIDI clocks sent since the last input pulse
+    sent: u32,
+    /// None until the first input pulse
+    samples_since_pulse: Option<u32>,
+    /// Samples between the last two input pulses, 0 until measured
+    period: u32,
+    transition_detector: TransitionDetector,
+    #[serde(skip)]
+    sample_rate: u16,
+    #[serde(skip, default = "backend")]
+    backend: SharedMidiBackend,
+}
+
+impl MidiClockOutModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            clock_in: None,
+            run_in: None,
+            pulses_per_quarter: 4,
+            running: false,
+            sent: CLOCKS_PER_QUARTER,
+            samples_since_pulse: None,
+            period: 0,
+            transition_detector: TransitionDetector::new(),
+            sample_rate: audio_config.sample_rate,
+            backend: backend(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "MIDI Clock Out".to_string()
+    }
+
+    fn process(&mut self, clock: ControlVoltage, run: Option<ControlVoltage>, at: Instant) {
+        match run {
+            Some(run) if (run > 0.0) != self.running => {
+                self.running = run > 0.0;
+                self.backend
+                    .send(&[if self.running { START } else { STOP }], at);
+                // Nothing to catch up on from before
+                self.sent = CLOCKS_PER_QUARTER;
+            }
+            Some(_) => {}
+            None => self.running = true,
+        }
+        let clocks = CLOCKS_PER_QUARTER / self.pulses_per_quarter as u32;
+        if self.transition_detector.is_transition(&clock) {
+            if let Some(samples) = self.samples_since_pulse {
+                self.period = samples;
+            }
+            self.samples_since_pulse = Some(0);
+            if self.running {
+                // Catch up on clocks not yet sent since the last pulse
+                for _ in self.sent..clocks {
+                    self.backend.send(&[CLOCK], at);
+                }
+                self.backend.send(&[CLOCK], at);
+                self.sent = 1;
+            }
+        } else if let Some(samples) = self.samples_since_pulse
+            && self.running
+            && self.period > 0
+        {
+            let due =
+                (1 + samples as u64 * clocks as u64 / self.period as u64).min(clocks as u64) as u32;
+            for _ in self.sent..due {
+                self.backend.send(&[CLOCK], at);
+            }
+            self.sent = self.sent.max(due);
+        }
+        self.samples_since_pulse = self
+            .samples_since_pulse
+            .map(|samples| samples.saturating_add(1));
+    }
+}
+
+impl SynthModule for MidiClockOutModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate;
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.clock_in.clone()),
+            1 => Ok(self.run_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => self.clock_in = Some((src_module, src_port)),
+            1 => self.run_in = Some((src_module, src_port)),
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => self.clock_in = None,
+            1 => self.run_in = None,
+            _ => return Err(()),
+        }
+        Ok(())
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("Clock".to_string())),
+            1 => Ok(Some("Run".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        0
+    }
+
+    fn get_output(&self, _output_idx: u8) -> Result<AudioBuffer, ()> {
+        Err(())
+    }
+
+    fn get_output_label(&self, _output_idx: u8) -> Result<Option<String>, ()> {
+        Err(())
+    }
+
+    fn calc(&mut self) {
+        let start = Instant::now();
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (clock_in, run_in) = (bufs[0], bufs[1]);
+                if let Some(clock_in) = clock_in {
+                    for (idx, clock) in clock_in.iter().enumerate() {
+                        self.process(
+                            *clock,
+                            run_in.map(|buf| buf[idx]),
+                            sample_time(start, idx, self.sample_rate),
+                        );
+                    }
+                }
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        unavailable_ui(ui);
+        pulses_per_quarter_ui(ui, &self.id, &mut self.pulses_per_quarter);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+/// Follows MIDI clock and transport from the MIDI input.
+///
+/// Clock pulses at a number of pulses per quarter note while running,
+/// counting from the first MIDI clock after Start. Run is high from Start or
+/// Continue until Stop, and Reset triggers on Start. Messages take effect at
+/// the sample matching when they arrived during the last buffer, so a buffer
+/// late but as evenly spaced as they came.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MidiClockInModule {
+    id: String,
+    clock_out: AudioBuffer,
+    run_out: AudioBuffer,
+    reset_out: AudioBuffer,
+    pulses_per_quarter: u8,
+    running: bool,
+    /// MIDI clocks since Start, none until the first after it
+    clocks: Option<u32>,
+    reset_samples: u32,
+    /// Samples left of the reset trigger
+    reset_remaining: u32,
+    #[serde(skip)]
+    sample_rate: u16,
+    #[serde(skip, default = "inbox")]
+    inbox: MidiInbox,
+}
+
+impl MidiClockInModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            clock_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            run_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            reset_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            pulses_per_quarter: 4,
+            running: false,
+            clocks: None,
+            reset_samples: audio_config.sample_rate as u32 * RESET_MS / 1000,
+            reset_remaining: 0,
+            sample_rate: audio_config.sample_rate,
+            inbox: inbox(),
+        }
+    }
+
+    pub fn get_name() -> String {
+        "MIDI Clock In".to_string()
+    }
+
+    fn receive(&mut self, message: &[u8]) {
+        match message.first() {
+            Some(&START) => {
+                self.running = true;
+                self.clocks = None;
+                self.reset_remaining = self.reset_samples;
+            }
+            Some(&CONTINUE) => self.running = true,
+            Some(&STOP) => self.running = false,
+            Some(&CLOCK) if self.running => {
+                self.clocks = Some(self.clocks.map_or(0, |clocks| clocks + 1));
+            }
+            _ => {}
+        }
+    }
+
+    /// Clock, Run and Reset
+    fn process(&mut self) -> [ControlVoltage; 3] {
+        let division = CLOCKS_PER_QUARTER / self.pulses_per_quarter as u32;
+        let clock = match self.clocks {
+            Some(clocks) if self.running && clocks % division < division.div_ceil(2) => 1.0,
+            _ => 0.0,
+        };
+        let reset = if self.reset_remaining > 0 {
+            self.reset_remaining -= 1;
+            1.0
+        } else {
+            0.0
+        };
+        [clock, if self.running { 1.0 } else { 0.0 }, reset]
+    }
+
+    /// Fill the outputs with messages received during the buffer before
+    /// `end`, each from the sample matching its arrival
+    fn render(&mut self, messages: Vec<Timed>, end: Instant) {
+        AudioBuffer::with_write_many(
+            vec![
+                self.clock_out.clone(),
+                self.run_out.clone(),
+                self.reset_out.clone(),
+            ],
+            |outs| {
+                let mut outs: Vec<_> = outs.into_iter().map(|o| o.unwrap()).collect();
+                let len = outs[0].len();
+                let sample_rate = self.sample_rate.max(1) as f64;
+                let start = end
+                    .checked_sub(Duration::from_secs_f64(len as f64 / sample_rate))
+                    .unwrap_or(end);
+                // late arrivals from before the buffer go at its start
+                let sample = |at: Instant| {
+                    ((at.saturating_duration_since(start).as_secs_f64() * sample_rate) as usize)
+                        .min(len.saturating_sub(1))
+                };
+                let mut pending = messages.iter().peekable();
+                for idx in 0..len {
+                    while let Some((_, message)) = pending.next_if(|(at, _)| sample(*at) <= idx) {
+                        self.receive(message);
+                    }
+                    for (out, value) in outs.iter_mut().zip(self.process()) {
+                        out[idx] = value;
+                    }
+                }
+            },
+        );
+    }
+}
+
+impl SynthModule for MidiClockInModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.clock_out.resize(audio_config.buffer_size);
+        self.run_out.resize(audio_config.buffer_size);
+        self.reset_out.resize(audio_config.buffer_size);
+        self.reset_samples = audio_config.sample_rate as u32 * RESET_MS / 1000;
+        self.sample_rate = audio_config.sample_rate;
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        0
+    }
+
+    fn get_input(&self, _input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        Err(())
+    }
+
+    fn set_input(
+        &mut self,
+        _input_idx: u8,
+        _src_module: SharedSynthModule,
+        _src_port: u8,
+    ) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn disconnect_input(&mut self, _input_idx: u8) -> Result<(), ()> {
+        Err(())
+    }
+
+    fn get_input_label(&self, _input_idx: u8) -> Result<Option<String>, ()> {
+        Err(())
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        3
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.clock_out.clone()),
+            1 => Ok(self.run_out.clone()),
+            2 => Ok(self.reset_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("Clock".to_string())),
+            1 => Ok(Some("Run".to_string())),
+            2 => Ok(Some("Reset".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        let messages = self.inbox.take();
+        self.render(messages, Instant::now());
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        unavailable_ui(ui);
+        pulses_per_quarter_ui(ui, &self.id, &mut self.pulses_per_quarter);
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::midi::CaptureBackend;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 16,
+        channels: 2,
+    };
+
+    #[test]
+    fn plays_notes_and_control_changes() {
+        let capture = Arc::new(CaptureBackend::default());
+        let mut out = MidiOutModule::new(&CONFIG);
+        out.backend = capture.clone();
+        out.channel = 2;
+        let now = Instant::now();
+        out.play(0.0, 1.0, None, now);
+        out.play(0.0, 1.0, None, now);
+        // Legato to C5
+        out.play(0.25, 1.0, Some(0.5), now);
+        out.play(0.25, 0.0, None, now);
+        assert_eq!(
+            capture.take_sent(),
+            vec![
+                vec![0x91, 69, DEFAULT_VELOCITY],
+                vec![0x91, 72, 64],
+                vec![0x81, 69, 0],
+                vec![0x81, 72, 0],
+            ]
+        );
+
+        out.ccs[0].low = -1.0;
+        out.control(0, 0.0, now);
+        out.control(0, 0.001, now);
+        out.control(0, 5.0, now);
+        assert_eq!(
+            capture.take_sent(),
+            vec![vec![0xB1, 1, 64], vec![0xB1, 1, 127]]
+        );
+    }
+
+    #[test]
+    fn sends_clock_between_pulses() {
+        let capture = Arc::new(CaptureBackend::default());
+        let mut clock_out = MidiClockOutModule::new(&CONFIG);
+        clock_out.backend = capture.clone();
+        let now = Instant::now();
+        clock_out.process(0.0, Some(0.0), now);
+        assert!(capture.take_sent().is_empty());
+        // Pulses every 12 samples at 4 per quarter note, so a MIDI clock
+        // every 2 samples
+        let mut sent_at = vec![];
+        for sample in 0..36 {
+            let clock = if sample % 12 < 6 { 1.0 } else { 0.0 };
+            clock_out.process(clock, Some(1.0), now);
+            for message in capture.take_sent() {
+                sent_at.push((sample, message[0]));
+            }
+        }
+        assert_eq!(&sent_at[..2], [(0, START), (0, CLOCK)]);
+        let clocks: Vec<_> = sent_at[1..].iter().map(|(sample, _)| *sample).collect();
+        // The first pulse has no period to spread its clocks over, so they
+        // catch up on the next
+        assert_eq!(
+            clocks,
+            [
+                0, 12, 12, 12, 12, 12, 12, 14, 16, 18, 20, 22, 24, 26, 28, 30, 32, 34
+            ]
+        );
+        clock_out.process(0.0, Some(0.0), now);
+        assert_eq!(capture.take_sent(), vec![vec![STOP]]);
+    }
+
+    #[test]
+    fn follows_clock_and_transport() {
+        let capture = Arc::new(CaptureBackend::default());
+        let mut clock_in = MidiClockInModule::new(&CONFIG);
+        clock_in.inbox = MidiInbox::new(capture.clone());
+        clock_in.pulses_per_quarter = 12;
+        // A message every 4ms of a 16ms buffer, just after each sample
+        let end = Instant::now() + Duration::from_secs(1);
+        let start = end - Duration::from_millis(16);
+        let mut outputs = |messages: &[u8]| {
+            for (n, message) in messages.iter().enumerate() {
+                let at = start + Duration::from_micros(4000 * n as u64 + 500);
+                capture.queue_at(&[*message], at);
+            }
+            clock_in.render(clock_in.inbox.take(), end);
+            let clock = clock_in.clock_out.get().unwrap().to_vec();
+            let run = clock_in.run_out.get().unwrap()[15];
+            let reset = clock_in.reset_out.get().unwrap()[0];
+            (clock, run, reset)
+        };
+        let (clock, run, _) = outputs(&[CLOCK]);
+        assert!(clock.iter().all(|clock| *clock == 0.0));
+        assert_eq!(run, 0.0);
+
+        let (clock, run, reset) = outputs(&[START, CLOCK, CLOCK, CLOCK]);
+        assert_eq!((run, reset), (1.0, 1.0));
+        // Each message takes effect at the sample it arrived in, and the clock
+        // output is high for every other MIDI clock
+        assert_eq!(&clock[..4], [0.0; 4]);
+        assert_eq!(&clock[4..8], [1.0; 4]);
+        assert_eq!(&clock[8..12], [0.0; 4]);
+        assert_eq!(&clock[12..], [1.0; 4]);
+
+        let (clock, run, reset) = outputs(&[STOP]);
+        assert_eq!((run, reset), (0.0, 0.0));
+        assert!(clock.iter().all(|clock| *clock == 0.0));
+    }
+}
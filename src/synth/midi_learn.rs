@@ -0,0 +1,380 @@
+use super::midi::{CONTROL_CHANGE, MidiInbox, inbox};
+use super::{Param, SharedSynthModule};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+const LEARNING: &str = "midi learning";
+const FORGET: &str = "midi forget";
+/// How close a CC has to come to a parameter to pick it up with soft
+/// takeover, as a fraction of the mapped range
+const PICK_UP: f32 = 2.0 / 127.0;
+
+/// A MIDI CC controlling a module parameter
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct MidiMapping {
+    pub module_id: String,
+    pub param_id: String,
+    /// From 0 to 15
+    pub channel: u8,
+    pub controller: u8,
+    /// Values set by CC 0 and 127
+    pub min: f32,
+    pub max: f32,
+    /// Leave the parameter alone until the CC reaches its value, so moving
+    /// the control doesn't make it jump
+    pub soft_takeover: bool,
+    /// Value last set, to tell when the parameter has been changed elsewhere
+    #[serde(skip)]
+    set: Option<f32>,
+    /// Value of the CC last received
+    #[serde(skip)]
+    received: Option<f32>,
+    /// Where the parameter was found when the modules last changed
+    #[serde(skip)]
+    target: Option<Target>,
+}
+
+/// A mapped parameter, looked up ahead of time so setting it doesn't
+/// allocate on the audio thread
+#[derive(Clone)]
+struct Target {
+    module: SharedSynthModule,
+    /// Index into the module's [`super::SynthModule::params`]
+    param: usize,
+    min: f32,
+    max: f32,
+}
+
+impl std::fmt::Debug for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Target")
+            .field("param", &self.param)
+            .finish()
+    }
+}
+
+impl PartialEq for Target {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.module, &other.module) && self.param == other.param
+    }
+}
+
+impl MidiMapping {
+    fn new(module_id: &str, param: &Param, channel: u8, controller: u8) -> Self {
+        Self {
+            module_id: module_id.to_string(),
+            param_id: param.id.clone(),
+            channel,
+            controller,
+            min: param.min,
+            max: param.max,
+            soft_takeover: false,
+            set: None,
+            received: None,
+            target: None,
+        }
+    }
+
+    /// Find the module and parameter this maps to among `modules`
+    fn resolve(&mut self, modules: &[SharedSynthModule]) {
+        self.target = modules.iter().find_map(|module| {
+            let mut locked = module.write().ok()?;
+            if locked.get_id() != self.module_id {
+                return None;
+            }
+            let (param, (found, _)) = locked
+                .params()
+                .into_iter()
+                .enumerate()
+                .find(|(_, (param, _))| param.id == self.param_id)?;
+            Some(Target {
+                module: module.clone(),
+                param,
+                min: found.min,
+                max: found.max,
+            })
+        });
+    }
+
+    /// The value to set for a CC, if any, given the parameter's current value
+    fn value(&mut self, cc: u8, current: f32) -> Option<f32> {
+        let value = self.min + (self.max - self.min) * cc as f32 / 127.0;
+        let last = self.received.replace(value);
+        if self.soft_takeover && self.set != Some(current) {
+            let close = (value - current).abs() <= (self.max - self.min).abs() * PICK_UP;
+            let crossed =
+                last.is_some_and(|last| (last - current).signum() != (value - current).signum());
+            if !close && !crossed {
+                return None;
+            }
+        }
+        Some(value)
+    }
+}
+
+/// Channel, controller and value of a control change message
+pub fn control_change(message: &[u8]) -> Option<(u8, u8, u8)> {
+    match *message {
+        [status, controller, value] if status & 0xF0 == CONTROL_CHANGE => {
+            Some((status & 0x0F, controller, value))
+        }
+        _ => None,
+    }
+}
+
+/// The MIDI mappings of a patch, applied to its modules as CCs arrive
+pub struct MidiMappings {
+    pub mappings: Vec<MidiMapping>,
+    inbox: MidiInbox,
+}
+
+impl Default for MidiMappings {
+    fn default() -> Self {
+        Self {
+            mappings: vec![],
+            inbox: inbox(),
+        }
+    }
+}
+
+impl MidiMappings {
+    /// Map a CC to a module parameter, replacing any other mapping of either
+    pub fn learn(&mut self, module_id: &str, param: &Param, channel: u8, controller: u8) {
+        self.mappings.retain(|mapping| {
+            (mapping.channel, mapping.controller) != (channel, controller)
+                && (mapping.module_id != module_id || mapping.param_id != param.id)
+        });
+        self.mappings
+            .push(MidiMapping::new(module_id, param, channel, controller));
+    }
+
+    pub fn forget(&mut self, module_id: &str, param_id: Option<&str>) {
+        self.mappings.retain(|mapping| {
+            mapping.module_id != module_id || param_id.is_some_and(|id| mapping.param_id != id)
+        });
+    }
+
+    /// Look up the mapped parameters among `modules`, to call when a mapping
+    /// is learned or the modules change
+    pub fn resolve(&mut self, modules: &[SharedSynthModule]) {
+        for mapping in self.mappings.iter_mut() {
+            mapping.resolve(modules);
+        }
+    }
+
+    /// Set parameters from the CCs received since the last call. Returns true
+    /// if any changed.
+    pub fn apply(&mut self) -> bool {
+        let mut changed = false;
+        for (channel, controller, cc) in self
+            .inbox
+            .take()
+            .iter()
+            .filter_map(|(_, message)| control_change(message))
+        {
+            for mapping in self
+                .mappings
+                .iter_mut()
+                .filter(|mapping| (mapping.channel, mapping.controller) == (channel, controller))
+            {
+                let Some(target) = mapping.target.clone() else {
+                    continue;
+                };
+                // a poisoned module is left for execute to disable
+                let Ok(mut module) = target.module.write() else {
+                    continue;
+                };
+                let Some(mut value) = module.param_value(target.param) else {
+                    continue;
+                };
+                if let Some(new) = mapping.value(cc, value.get()) {
+                    value.set(new.clamp(target.min, target.max));
+                    mapping.set = Some(value.get());
+                    changed = true;
+                }
+            }
+        }
+        changed
+    }
+}
+
+/// Listens for the CC to map to the parameter being learned
+pub struct Learner(MidiInbox);
+
+impl Default for Learner {
+    fn default() -> Self {
+        Self(inbox())
+    }
+}
+
+impl Learner {
+    /// Channel and controller of the first CC received since the last call
+    pub fn take(&self) -> Option<(u8, u8)> {
+        self.0
+            .take()
+            .iter()
+            .find_map(|(_, message)| control_change(message))
+            .map(|(channel, controller, _)| (channel, controller))
+    }
+}
+
+/// Module and parameter id waiting for a CC to map to
+pub fn learning(ctx: &egui::Context) -> Option<(String, String)> {
+    ctx.data(|data| data.get_temp(egui::Id::new(LEARNING)))
+        .flatten()
+}
+
+pub fn set_learning(ctx: &egui::Context, target: Option<(String, String)>) {
+    ctx.data_mut(|data| data.insert_temp(egui::Id::new(LEARNING), target));
+}
+
+/// Module and parameter id whose mapping was asked to be removed
+pub fn take_forget(ctx: &egui::Context) -> Option<(String, String)> {
+    ctx.data_mut(|data| data.remove_temp(egui::Id::new(FORGET)))
+}
+
+/// Offer MIDI learn in the context menu of the control for a module's
+/// parameter, outlining the control while it's waiting for a CC
+pub fn learnable(response: egui::Response, module_id: &str, param_id: &str) -> egui::Response {
+    let target = (module_id.to_string(), param_id.to_string());
+    let ctx = response.ctx.clone();
+    let is_learning = learning(&ctx).as_ref() == Some(&target);
+    if is_learning {
+        ctx.layer_painter(response.layer_id).rect_stroke(
+            response.rect.expand(2.0),
+            2.0,
+            egui::Stroke::new(1.0, egui::Color32::RED),
+        );
+    }
+    response.context_menu(|ui| {
+        if is_learning {
+            if ui.button("Cancel MIDI learn").clicked() {
+                set_learning(&ctx, None);
+                ui.close_menu();
+            }
+        } else if ui
+            .button("MIDI learn")
+            .on_hover_text("Map the next MIDI CC received to this control")
+            .clicked()
+        {
+            set_learning(&ctx, Some(target.clone()));
+            ui.close_menu();
+        }
+        if ui.button("Forget MIDI mapping").clicked() {
+            ctx.data_mut(|data| data.insert_temp(egui::Id::new(FORGET), target.clone()));
+            ui.close_menu();
+        }
+    });
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::AudioConfig;
+    use crate::synth::logic::ComparatorModule;
+    use crate::synth::midi::CaptureBackend;
+    use std::sync::{Arc, RwLock};
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 16,
+        channels: 2,
+    };
+
+    fn threshold(module: &SharedSynthModule) -> f32 {
+        let mut module = module.write().unwrap();
+        let (_, value) = module
+            .params()
+            .into_iter()
+            .find(|(param, _)| param.id == "threshold")
+            .unwrap();
+        value.get()
+    }
+
+    fn mapped() -> (Arc<CaptureBackend>, MidiMappings, SharedSynthModule) {
+        let capture = Arc::new(CaptureBackend::default());
+        let module: SharedSynthModule = Arc::new(RwLock::new(ComparatorModule::new(&CONFIG)));
+        let mut mappings = MidiMappings {
+            mappings: vec![],
+            inbox: MidiInbox::new(capture.clone()),
+        };
+        let (id, param) = {
+            let mut module = module.write().unwrap();
+            let param = module.params().remove(0).0;
+            (module.get_id(), param)
+        };
+        mappings.learn(&id, &param, 2, 74);
+        mappings.resolve(std::slice::from_ref(&module));
+        (capture, mappings, module)
+    }
+
+    #[test]
+    fn sets_mapped_parameter() {
+        let (capture, mut mappings, module) = mapped();
+        // other channels and controllers are ignored
+        capture.queue(&[CONTROL_CHANGE | 3, 74, 127]);
+        capture.queue(&[CONTROL_CHANGE | 2, 75, 127]);
+        assert!(!mappings.apply());
+        assert_eq!(threshold(&module), 0.0);
+
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 127]);
+        assert!(mappings.apply());
+        assert_eq!(threshold(&module), 2.0);
+
+        mappings.mappings[0].min = -1.0;
+        mappings.mappings[0].max = 1.0;
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 0]);
+        mappings.apply();
+        assert_eq!(threshold(&module), -1.0);
+    }
+
+    #[test]
+    fn skips_modules_not_played() {
+        let (capture, mut mappings, module) = mapped();
+        mappings.resolve(&[]);
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 127]);
+        assert!(!mappings.apply());
+        assert_eq!(threshold(&module), 0.0);
+    }
+
+    #[test]
+    fn learning_replaces_mappings() {
+        let (_, mut mappings, module) = mapped();
+        let (id, params) = {
+            let mut module = module.write().unwrap();
+            let params: Vec<Param> = module.params().into_iter().map(|(p, _)| p).collect();
+            (module.get_id(), params)
+        };
+        // same parameter, new CC
+        mappings.learn(&id, &params[0], 2, 1);
+        // same CC, new parameter
+        mappings.learn(&id, &params[1], 2, 1);
+        assert_eq!(mappings.mappings.len(), 1);
+        assert_eq!(mappings.mappings[0].param_id, "hysteresis");
+
+        mappings.forget(&id, None);
+        assert!(mappings.mappings.is_empty());
+    }
+
+    #[test]
+    fn soft_takeover_waits_for_parameter() {
+        let (capture, mut mappings, module) = mapped();
+        mappings.mappings[0].soft_takeover = true;
+        // far from the threshold of 0
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 10]);
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 40]);
+        assert!(!mappings.apply());
+        assert_eq!(threshold(&module), 0.0);
+
+        // crossing it picks it up
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 80]);
+        assert!(mappings.apply());
+        assert_eq!(threshold(&module), -2.0 + 4.0 * 80.0 / 127.0);
+
+        // and then follows the CC
+        capture.queue(&[CONTROL_CHANGE | 2, 74, 0]);
+        mappings.apply();
+        assert_eq!(threshold(&module), -2.0);
+    }
+}
@@ -1,15 +1,63 @@
-use super::{AudioBuffer, AudioConfig, SharedSynthModule, SynthModule};
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, Param, ParamValue, Ramp, SharedSynthModule, Smoother, SynthModule,
+};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 
+const MASTER_PARAM: &str = "master";
+
+const MIN_CHANNELS: usize = 1;
+const MAX_CHANNELS: usize = 32;
+const RETURN_PARAMS: [&str; 2] = ["return a", "return b"];
+const RETURN_LABELS: [&str; 2] = ["Return A", "Return B"];
+
+/// Id of a channel's parameter, such as "gain 1"
+fn channel_param(name: &str, idx: usize) -> String {
+    format!("{name} {}", idx + 1)
+}
+
+fn gain_smoother() -> Smoother {
+    Smoother::default().with_ramp(Ramp::Linear)
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct MonoMixerChannel {
+    label: String,
+    gain: f32,
+    mute: bool,
+    #[serde(skip, default = "gain_smoother")]
+    gain_smoother: Smoother,
+}
+
+impl MonoMixerChannel {
+    fn new(idx: usize, gain: f32) -> Self {
+        Self {
+            label: format!("{}", idx + 1),
+            gain,
+            mute: false,
+            gain_smoother: gain_smoother(),
+        }
+    }
+}
+
+/// Mono channels summed into one output.
+///
+/// Inputs are the audio inputs for every channel followed by a CV gain input
+/// for every channel, so old patches with only audio inputs keep their port
+/// numbers.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct MonoMixerModule {
     id: String,
     #[serde(skip)]
     audio_in: Vec<Option<(SharedSynthModule, u8)>>,
-    gain: Vec<f32>,
+    #[serde(skip)]
+    cv_in: Vec<Option<(SharedSynthModule, u8)>>,
+    channels: Vec<MonoMixerChannel>,
     buf: AudioBuffer,
+    #[serde(skip)]
+    sample_rate: u16,
 }
 
 impl MonoMixerModule {
@@ -17,14 +65,44 @@ impl MonoMixerModule {
         Self {
             id: uuid::Uuid::new_v4().to_string(),
             audio_in: vec![None; 4],
-            gain: vec![1.0; 4],
+            cv_in: vec![None; 4],
+            channels: (0..4).map(|idx| MonoMixerChannel::new(idx, 1.0)).collect(),
             buf: AudioBuffer::new(Some(audio_config.buffer_size)),
+            sample_rate: 0,
+        }
+        .with_sample_rate(audio_config.sample_rate)
+    }
+
+    fn with_sample_rate(mut self, sample_rate: u16) -> Self {
+        self.set_sample_rate(sample_rate);
+        self
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u16) {
+        self.sample_rate = sample_rate;
+        for channel in self.channels.iter_mut() {
+            channel.gain_smoother.set_sample_rate(sample_rate);
         }
     }
 
     pub fn get_name() -> String {
         "Mono Mixer".to_string()
     }
+
+    /// Add or remove channels at the end, keeping connections to the remaining ones.
+    pub fn set_num_channels(&mut self, num_channels: usize) {
+        let num_channels = num_channels.clamp(MIN_CHANNELS, MAX_CHANNELS);
+        let len = self.channels.len();
+        if num_channels > len {
+            self.channels
+                .extend((len..num_channels).map(|idx| MonoMixerChannel::new(idx, 1.0)));
+            self.set_sample_rate(self.sample_rate);
+        } else {
+            self.channels.truncate(num_channels);
+        }
+        self.audio_in.resize(num_channels, None);
+        self.cv_in.resize(num_channels, None);
+    }
 }
 
 impl SynthModule for MonoMixerModule {
@@ -37,19 +115,25 @@ impl SynthModule for MonoMixerModule {
     }
 
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
-        self.audio_in.resize(self.gain.len(), None);
+        self.audio_in.resize(self.channels.len(), None);
+        self.cv_in.resize(self.channels.len(), None);
         self.buf.resize(audio_config.buffer_size);
+        self.set_sample_rate(audio_config.sample_rate);
     }
 
     fn get_num_inputs(&self) -> u8 {
-        self.audio_in.len() as u8
+        (self.audio_in.len() + self.cv_in.len()) as u8
     }
 
     fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
-        if input_idx < self.audio_in.len() as u8 {
-            return Ok(self.audio_in[input_idx as usize].clone());
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            return Ok(self.audio_in[input_idx].clone());
+        }
+        match self.cv_in.get(input_idx - self.audio_in.len()) {
+            Some(input) => Ok(input.clone()),
+            None => Err(()),
         }
-        Err(())
     }
 
     fn set_input(
@@ -58,26 +142,44 @@ impl SynthModule for MonoMixerModule {
         src_module: SharedSynthModule,
         src_port: u8,
     ) -> Result<(), ()> {
-        if input_idx < self.audio_in.len() as u8 {
-            self.audio_in[input_idx as usize] = Some((src_module, src_port));
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            self.audio_in[input_idx] = Some((src_module, src_port));
             return Ok(());
         }
-        Err(())
+        match self.cv_in.get_mut(input_idx - self.audio_in.len()) {
+            Some(input) => {
+                *input = Some((src_module, src_port));
+                Ok(())
+            }
+            None => Err(()),
+        }
     }
 
     fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
-        if input_idx < self.audio_in.len() as u8 {
-            self.audio_in[input_idx as usize] = None;
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            self.audio_in[input_idx] = None;
             return Ok(());
         }
-        Err(())
+        match self.cv_in.get_mut(input_idx - self.audio_in.len()) {
+            Some(input) => {
+                *input = None;
+                Ok(())
+            }
+            None => Err(()),
+        }
     }
 
     fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
-        if input_idx < self.audio_in.len() as u8 {
-            return Ok(None);
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            return Ok(Some(self.channels[input_idx].label.clone()));
+        }
+        match self.channels.get(input_idx - self.audio_in.len()) {
+            Some(channel) => Ok(Some(format!("{} CV", channel.label))),
+            None => Err(()),
         }
-        Err(())
     }
 
     fn get_num_outputs(&self) -> u8 {
@@ -99,6 +201,7 @@ impl SynthModule for MonoMixerModule {
     }
 
     fn calc(&mut self) {
+        let num_channels = self.audio_in.len();
         AudioBuffer::with_read_many(
             (0..self.get_num_inputs())
                 .map(|n| self.resolve_input(n).unwrap())
@@ -107,13 +210,30 @@ impl SynthModule for MonoMixerModule {
                 self.buf.with_write(|output| {
                     let output = output.unwrap();
                     output.fill(0.0);
-                    for (buf, gain) in bufs.into_iter().zip(self.gain.iter()) {
-                        if buf.is_none() {
+                    for ((buf, cv), channel) in bufs[..num_channels]
+                        .iter()
+                        .zip(bufs[num_channels..].iter())
+                        .zip(self.channels.iter_mut())
+                    {
+                        let Some(buf) = buf else {
+                            continue;
+                        };
+                        if channel.mute {
                             continue;
                         }
-                        let buf = buf.unwrap();
-                        for (src, dst) in buf.iter().zip(output.iter_mut()) {
-                            *dst += src * gain;
+                        match cv {
+                            Some(cv) => {
+                                for ((src, cv), dst) in
+                                    buf.iter().zip(cv.iter()).zip(output.iter_mut())
+                                {
+                                    *dst += src * channel.gain_smoother.next(channel.gain) * cv;
+                                }
+                            }
+                            None => {
+                                for (src, dst) in buf.iter().zip(output.iter_mut()) {
+                                    *dst += src * channel.gain_smoother.next(channel.gain);
+                                }
+                            }
                         }
                     }
                 });
@@ -123,16 +243,687 @@ impl SynthModule for MonoMixerModule {
 
     fn ui(&mut self, ui: &mut egui::Ui) {
         ui.horizontal(|ui| {
-            for gain in self.gain.iter_mut() {
-                ui.add(
-                    egui::Slider::new(gain, 0.0..=2.0)
-                        .orientation(egui::SliderOrientation::Vertical),
+            ui.label("Channels: ");
+            ui.scope(|ui| {
+                if self.channels.len() <= MIN_CHANNELS {
+                    ui.disable();
+                }
+                if ui.button("-").clicked() {
+                    self.set_num_channels(self.channels.len() - 1);
+                }
+            });
+            ui.label(self.channels.len().to_string());
+            ui.scope(|ui| {
+                if self.channels.len() >= MAX_CHANNELS {
+                    ui.disable();
+                }
+                if ui.button("+").clicked() {
+                    self.set_num_channels(self.channels.len() + 1);
+                }
+            });
+        });
+        ui.horizontal(|ui| {
+            for (idx, channel) in self.channels.iter_mut().enumerate() {
+                ui.vertical(|ui| {
+                    ui.add(egui::TextEdit::singleline(&mut channel.label).desired_width(32.0));
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut channel.gain, 0.0..=2.0)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        ),
+                        &self.id,
+                        &channel_param("gain", idx),
+                    );
+                    ui.toggle_value(&mut channel.mute, "M");
+                });
+            }
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        self.channels
+            .iter_mut()
+            .enumerate()
+            .map(|(idx, channel)| {
+                (
+                    Param::new(
+                        &channel_param("gain", idx),
+                        &format!("Gain {}", idx + 1),
+                        0.0..=2.0,
+                    ),
+                    ParamValue::F32(&mut channel.gain),
+                )
+            })
+            .collect()
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        let channel = self.channels.get_mut(idx)?;
+        Some(ParamValue::F32(&mut channel.gain))
+    }
+}
+
+/// How a mono channel is split between the left and right outputs as it is panned.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum PanLaw {
+    /// -6 dB in the center, gains sum to 1.0
+    Linear,
+    /// -4.5 dB in the center, halfway between linear and constant power
+    Compromise,
+    /// -3 dB in the center, power stays constant across the field
+    ConstantPower,
+}
+
+impl PanLaw {
+    /// Returns (left, right) gains for a pan position from -1.0 (left) to 1.0 (right).
+    fn gains(&self, pan: f32) -> (f32, f32) {
+        let pan = pan.clamp(-1.0, 1.0);
+        let linear = ((1.0 - pan) / 2.0, (1.0 + pan) / 2.0);
+        let angle = (pan + 1.0) * std::f32::consts::FRAC_PI_4;
+        let constant_power = (angle.cos(), angle.sin());
+        match self {
+            PanLaw::Linear => linear,
+            PanLaw::ConstantPower => constant_power,
+            PanLaw::Compromise => (
+                (linear.0 * constant_power.0).sqrt(),
+                (linear.1 * constant_power.1).sqrt(),
+            ),
+        }
+    }
+
+    fn label(&self) -> &'static str {
+        match self {
+            PanLaw::Linear => "-6 dB",
+            PanLaw::Compromise => "-4.5 dB",
+            PanLaw::ConstantPower => "-3 dB",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct StereoMixerChannel {
+    gain: f32,
+    pan: f32,
+    mute: bool,
+    solo: bool,
+    send_a: f32,
+    send_b: f32,
+    /// Gain, pan, send A and send B
+    #[serde(skip, default = "channel_smoothers")]
+    smoothers: [Smoother; 4],
+    /// Smoothed pan position and law the pan gains were worked out for, with
+    /// the gains, so they're only worked out again while the pan moves
+    #[serde(skip)]
+    pan_gains: Option<(f32, PanLaw, (f32, f32))>,
+}
+
+fn channel_smoothers() -> [Smoother; 4] {
+    std::array::from_fn(|_| gain_smoother())
+}
+
+fn bus_smoothers() -> [Smoother; 3] {
+    std::array::from_fn(|_| gain_smoother())
+}
+
+impl Default for StereoMixerChannel {
+    fn default() -> Self {
+        Self {
+            gain: 1.0,
+            pan: 0.0,
+            mute: false,
+            solo: false,
+            send_a: 0.0,
+            send_b: 0.0,
+            smoothers: channel_smoothers(),
+            pan_gains: None,
+        }
+    }
+}
+
+const STEREO_MIXER_RETURNS: usize = 4;
+const METER_WIDTH: f32 = 6.0;
+const METER_HEIGHT: f32 = 100.0;
+const METER_DECAY: f32 = 0.9;
+
+/// Mono channels mixed down to a stereo bus, with two post-fader aux sends and
+/// stereo returns for them.
+///
+/// Inputs are the channels followed by Return A L/R and Return B L/R. A return
+/// with only its left input connected is fed to both sides. Outputs are the
+/// main left and right, then Send A and Send B.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct StereoMixerModule {
+    id: String,
+    #[serde(skip)]
+    audio_in: Vec<Option<(SharedSynthModule, u8)>>,
+    #[serde(skip)]
+    return_in: [Option<(SharedSynthModule, u8)>; STEREO_MIXER_RETURNS],
+    channels: Vec<StereoMixerChannel>,
+    pan_law: PanLaw,
+    return_gain: [f32; 2],
+    master: f32,
+    left_out: AudioBuffer,
+    right_out: AudioBuffer,
+    send_a_out: AudioBuffer,
+    send_b_out: AudioBuffer,
+    /// Return A, return B and master
+    #[serde(skip, default = "bus_smoothers")]
+    bus_smoothers: [Smoother; 3],
+    #[serde(skip)]
+    meter: [f32; 2],
+    #[serde(skip)]
+    ui_dirty: bool,
+}
+
+impl StereoMixerModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            audio_in: vec![None; 4],
+            return_in: Default::default(),
+            channels: vec![StereoMixerChannel::default(); 4],
+            pan_law: PanLaw::ConstantPower,
+            return_gain: [1.0; 2],
+            master: 1.0,
+            left_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            right_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            send_a_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            send_b_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            bus_smoothers: bus_smoothers(),
+            meter: [0.0; 2],
+            ui_dirty: false,
+        }
+        .with_sample_rate(audio_config.sample_rate)
+    }
+
+    fn with_sample_rate(mut self, sample_rate: u16) -> Self {
+        self.set_sample_rate(sample_rate);
+        self
+    }
+
+    fn set_sample_rate(&mut self, sample_rate: u16) {
+        let channel_smoothers = self
+            .channels
+            .iter_mut()
+            .flat_map(|c| c.smoothers.iter_mut());
+        for smoother in channel_smoothers.chain(self.bus_smoothers.iter_mut()) {
+            smoother.set_sample_rate(sample_rate);
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Stereo Mixer".to_string()
+    }
+
+    fn meter_ui(ui: &mut egui::Ui, level: f32) {
+        let (_id, rect) = ui.allocate_space([METER_WIDTH, METER_HEIGHT].into());
+        ui.painter()
+            .rect_filled(rect, 1.0, egui::Color32::DARK_GRAY);
+        let height = level.min(1.0) * rect.height();
+        let color = if level >= 1.0 {
+            egui::Color32::RED
+        } else if level >= 0.5 {
+            egui::Color32::YELLOW
+        } else {
+            egui::Color32::GREEN
+        };
+        ui.painter().rect_filled(
+            egui::Rect::from_min_max(egui::pos2(rect.min.x, rect.max.y - height), rect.max),
+            1.0,
+            color,
+        );
+    }
+}
+
+impl SynthModule for StereoMixerModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.audio_in.resize(self.channels.len(), None);
+        self.left_out.resize(audio_config.buffer_size);
+        self.right_out.resize(audio_config.buffer_size);
+        self.send_a_out.resize(audio_config.buffer_size);
+        self.send_b_out.resize(audio_config.buffer_size);
+        self.set_sample_rate(audio_config.sample_rate);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        (self.audio_in.len() + STEREO_MIXER_RETURNS) as u8
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            return Ok(self.audio_in[input_idx].clone());
+        }
+        match self.return_in.get(input_idx - self.audio_in.len()) {
+            Some(input) => Ok(input.clone()),
+            None => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            self.audio_in[input_idx] = Some((src_module, src_port));
+            return Ok(());
+        }
+        match self.return_in.get_mut(input_idx - self.audio_in.len()) {
+            Some(input) => {
+                *input = Some((src_module, src_port));
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            self.audio_in[input_idx] = None;
+            return Ok(());
+        }
+        match self.return_in.get_mut(input_idx - self.audio_in.len()) {
+            Some(input) => {
+                *input = None;
+                Ok(())
+            }
+            None => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        let input_idx = input_idx as usize;
+        if input_idx < self.audio_in.len() {
+            return Ok(Some(format!("{}", input_idx + 1)));
+        }
+        match input_idx - self.audio_in.len() {
+            0 => Ok(Some("Return A L".to_string())),
+            1 => Ok(Some("Return A R".to_string())),
+            2 => Ok(Some("Return B L".to_string())),
+            3 => Ok(Some("Return B R".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        4
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.left_out.clone()),
+            1 => Ok(self.right_out.clone()),
+            2 => Ok(self.send_a_out.clone()),
+            3 => Ok(self.send_b_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("Left".to_string())),
+            1 => Ok(Some("Right".to_string())),
+            2 => Ok(Some("Send A".to_string())),
+            3 => Ok(Some("Send B".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        let num_channels = self.audio_in.len();
+        AudioBuffer::with_read_many(
+            (0..self.get_num_inputs())
+                .map(|n| self.resolve_input(n).unwrap())
+                .collect_vec(),
+            |bufs| {
+                AudioBuffer::with_write_many(
+                    vec![
+                        self.left_out.clone(),
+                        self.right_out.clone(),
+                        self.send_a_out.clone(),
+                        self.send_b_out.clone(),
+                    ],
+                    |outputs| {
+                        let (left, right, send_a, send_b) = outputs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        left.fill(0.0);
+                        right.fill(0.0);
+                        send_a.fill(0.0);
+                        send_b.fill(0.0);
+                        let any_solo = self.channels.iter().any(|c| c.solo);
+                        for (buf, channel) in
+                            bufs[..num_channels].iter().zip(self.channels.iter_mut())
+                        {
+                            let Some(buf) = buf else {
+                                continue;
+                            };
+                            if channel.mute || (any_solo && !channel.solo) {
+                                continue;
+                            }
+                            let [gain, pan, send_a_gain, send_b_gain] = &mut channel.smoothers;
+                            for (idx, src) in buf.iter().enumerate() {
+                                let val = src * gain.next(channel.gain);
+                                let position = pan.next(channel.pan);
+                                let (left_gain, right_gain) = match channel.pan_gains {
+                                    Some((at, law, gains))
+                                        if at == position && law == self.pan_law =>
+                                    {
+                                        gains
+                                    }
+                                    _ => {
+                                        let gains = self.pan_law.gains(position);
+                                        channel.pan_gains = Some((position, self.pan_law, gains));
+                                        gains
+                                    }
+                                };
+                                left[idx] += val * left_gain;
+                                right[idx] += val * right_gain;
+                                send_a[idx] += val * send_a_gain.next(channel.send_a);
+                                send_b[idx] += val * send_b_gain.next(channel.send_b);
+                            }
+                        }
+                        let [return_a, return_b, master] = &mut self.bus_smoothers;
+                        for ((ret, gain), smoother) in bufs[num_channels..]
+                            .chunks(2)
+                            .zip(self.return_gain)
+                            .zip([return_a, return_b])
+                        {
+                            let (ret_left, ret_right) = match (ret[0], ret[1]) {
+                                (Some(l), Some(r)) => (l, r),
+                                (Some(l), None) => (l, l),
+                                (None, Some(r)) => (r, r),
+                                (None, None) => continue,
+                            };
+                            for idx in 0..left.len() {
+                                let gain = smoother.next(gain);
+                                left[idx] += ret_left[idx] * gain;
+                                right[idx] += ret_right[idx] * gain;
+                            }
+                        }
+                        for idx in 0..left.len() {
+                            let master = master.next(self.master);
+                            left[idx] *= master;
+                            right[idx] *= master;
+                        }
+                        for (out, meter) in [left, right].into_iter().zip(self.meter.iter_mut()) {
+                            let mut peak: f32 = 0.0;
+                            for val in out.iter() {
+                                peak = peak.max(val.abs());
+                            }
+                            let level = peak.max(*meter * METER_DECAY);
+                            if (level - *meter).abs() > 0.001 {
+                                self.ui_dirty = true;
+                            }
+                            *meter = level;
+                        }
+                    },
                 );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for (idx, channel) in self.channels.iter_mut().enumerate() {
+                ui.vertical(|ui| {
+                    ui.label(format!("{}", idx + 1));
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut channel.gain, 0.0..=2.0)
+                                .orientation(egui::SliderOrientation::Vertical)
+                                .show_value(false),
+                        ),
+                        &self.id,
+                        &channel_param("gain", idx),
+                    );
+                    learnable(
+                        ui.add(
+                            egui::DragValue::new(&mut channel.pan)
+                                .range(-1.0..=1.0)
+                                .speed(0.01),
+                        )
+                        .on_hover_text("Pan"),
+                        &self.id,
+                        &channel_param("pan", idx),
+                    );
+                    ui.horizontal(|ui| {
+                        ui.toggle_value(&mut channel.mute, "M");
+                        ui.toggle_value(&mut channel.solo, "S");
+                    });
+                    learnable(
+                        ui.add(
+                            egui::DragValue::new(&mut channel.send_a)
+                                .range(0.0..=1.0)
+                                .speed(0.01)
+                                .prefix("A "),
+                        ),
+                        &self.id,
+                        &channel_param("send a", idx),
+                    );
+                    learnable(
+                        ui.add(
+                            egui::DragValue::new(&mut channel.send_b)
+                                .range(0.0..=1.0)
+                                .speed(0.01)
+                                .prefix("B "),
+                        ),
+                        &self.id,
+                        &channel_param("send b", idx),
+                    );
+                });
+            }
+            ui.separator();
+            for (idx, gain) in self.return_gain.iter_mut().enumerate() {
+                ui.vertical(|ui| {
+                    ui.label(if idx == 0 { "Ret A" } else { "Ret B" });
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(gain, 0.0..=2.0)
+                                .orientation(egui::SliderOrientation::Vertical)
+                                .show_value(false),
+                        ),
+                        &self.id,
+                        RETURN_PARAMS[idx],
+                    );
+                });
             }
+            ui.separator();
+            ui.vertical(|ui| {
+                ui.label("Master");
+                ui.horizontal(|ui| {
+                    learnable(
+                        ui.add(
+                            egui::Slider::new(&mut self.master, 0.0..=2.0)
+                                .orientation(egui::SliderOrientation::Vertical)
+                                .show_value(false),
+                        ),
+                        &self.id,
+                        MASTER_PARAM,
+                    );
+                    Self::meter_ui(ui, self.meter[0]);
+                    Self::meter_ui(ui, self.meter[1]);
+                });
+                egui::ComboBox::from_id_source((&self.id, "pan_law"))
+                    .selected_text(self.pan_law.label())
+                    .show_ui(ui, |ui| {
+                        for law in [PanLaw::Linear, PanLaw::Compromise, PanLaw::ConstantPower] {
+                            ui.selectable_value(&mut self.pan_law, law, law.label());
+                        }
+                    });
+            });
         });
+        self.ui_dirty = false;
+    }
+
+    fn ui_dirty(&self) -> bool {
+        self.ui_dirty
     }
 
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        let mut params = vec![];
+        for (idx, channel) in self.channels.iter_mut().enumerate() {
+            let n = idx + 1;
+            params.extend([
+                (
+                    Param::new(&channel_param("gain", idx), &format!("Gain {n}"), 0.0..=2.0),
+                    ParamValue::F32(&mut channel.gain),
+                ),
+                (
+                    Param::new(&channel_param("pan", idx), &format!("Pan {n}"), -1.0..=1.0),
+                    ParamValue::F32(&mut channel.pan),
+                ),
+                (
+                    Param::new(
+                        &channel_param("send a", idx),
+                        &format!("Send A {n}"),
+                        0.0..=1.0,
+                    ),
+                    ParamValue::F32(&mut channel.send_a),
+                ),
+                (
+                    Param::new(
+                        &channel_param("send b", idx),
+                        &format!("Send B {n}"),
+                        0.0..=1.0,
+                    ),
+                    ParamValue::F32(&mut channel.send_b),
+                ),
+            ]);
+        }
+        for (idx, gain) in self.return_gain.iter_mut().enumerate() {
+            params.push((
+                Param::new(RETURN_PARAMS[idx], RETURN_LABELS[idx], 0.0..=2.0),
+                ParamValue::F32(gain),
+            ));
+        }
+        params.push((
+            Param::new(MASTER_PARAM, "Master", 0.0..=2.0),
+            ParamValue::F32(&mut self.master),
+        ));
+        params
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        let channels = self.channels.len() * 4;
+        if idx < channels {
+            let channel = &mut self.channels[idx / 4];
+            return Some(ParamValue::F32(match idx % 4 {
+                0 => &mut channel.gain,
+                1 => &mut channel.pan,
+                2 => &mut channel.send_a,
+                _ => &mut channel.send_b,
+            }));
+        }
+        match idx - channels {
+            idx if idx < self.return_gain.len() => {
+                Some(ParamValue::F32(&mut self.return_gain[idx]))
+            }
+            idx if idx == self.return_gain.len() => Some(ParamValue::F32(&mut self.master)),
+            _ => None,
+        }
+    }
+}
+
+// MIGRATIONS
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct MonoMixerModuleV0 {
+    id: String,
+    #[serde(skip)]
+    audio_in: Vec<Option<(SharedSynthModule, u8)>>,
+    gain: Vec<f32>,
+    buf: AudioBuffer,
+}
+
+impl From<MonoMixerModuleV0> for MonoMixerModule {
+    fn from(other: MonoMixerModuleV0) -> Self {
+        let num_channels = other.gain.len();
+        Self {
+            id: other.id,
+            audio_in: other.audio_in,
+            cv_in: vec![None; num_channels],
+            channels: other
+                .gain
+                .into_iter()
+                .enumerate()
+                .map(|(idx, gain)| MonoMixerChannel::new(idx, gain))
+                .collect(),
+            buf: other.buf,
+            sample_rate: 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{get_inputs, shared_are_eq};
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn pan_laws_at_center() {
+        let (l, r) = PanLaw::Linear.gains(0.0);
+        assert!((l - 0.5).abs() < 0.0001 && (r - 0.5).abs() < 0.0001);
+        let (l, r) = PanLaw::ConstantPower.gains(0.0);
+        assert!((l * l + r * r - 1.0).abs() < 0.0001);
+        let (l, r) = PanLaw::ConstantPower.gains(-1.0);
+        assert!((l - 1.0).abs() < 0.0001 && r.abs() < 0.0001);
+        let (l, _) = PanLaw::Compromise.gains(0.0);
+        assert!((20.0 * l.log10() + 4.5).abs() < 0.1);
+    }
+
+    #[test]
+    fn removing_last_channel_keeps_connections() {
+        let config = AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 8,
+            channels: 2,
+        };
+        let source: SharedSynthModule = Arc::new(RwLock::new(MonoMixerModule::new(&config)));
+        let mut mixer = MonoMixerModule::new(&config);
+        // audio and CV of the first and last channels
+        for input_idx in [0, 3, 4, 7] {
+            mixer.set_input(input_idx, source.clone(), 0).unwrap();
+        }
+        mixer.set_num_channels(3);
+        assert_eq!(mixer.get_num_inputs(), 6);
+        let connected: Vec<_> = get_inputs(&mixer)
+            .iter()
+            .positions(|input| {
+                input
+                    .as_ref()
+                    .is_some_and(|(m, _)| shared_are_eq(m, &source))
+            })
+            .collect();
+        assert_eq!(connected, vec![0, 3]);
+        for input_idx in 0..6 {
+            assert_eq!(
+                mixer.resolve_input(input_idx).unwrap().get().is_some(),
+                connected.contains(&(input_idx as usize))
+            );
+        }
+    }
 }
@@ -1,11 +1,22 @@
+use super::midi_learn::learnable;
 use super::{
-    AudioBuffer, AudioConfig, ControlVoltage, SharedSynthModule, SynthModule, TransitionDetector,
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, Smoother,
+    SynthModule, TransitionDetector,
 };
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::cell::Cell;
 use std::f64::consts::PI;
 
+const PITCH_PARAM: &str = "pitch";
+const NOTE_PARAM: &str = "note";
+const FINE_PARAM: &str = "fine";
+/// A semitone, in volts
+const NOTE: f32 = 1.0 / 12.0;
+/// Range of the fine control around a note
+const FINE: f32 = NOTE / 2.0;
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OscillatorModule {
     id: String,
@@ -21,6 +32,8 @@ pub struct OscillatorModule {
     pos: f64,
     antialiasing: bool,
     sync_detector: TransitionDetector,
+    #[serde(skip)]
+    val_smoother: Smoother,
 }
 
 impl OscillatorModule {
@@ -37,13 +50,15 @@ impl OscillatorModule {
             pos: 0.0,
             antialiasing: true,
             sync_detector: TransitionDetector::new(),
+            val_smoother: Smoother::new(audio_config.sample_rate),
         }
     }
 
-    fn get_freq_in_hz(&self, buf: Option<&[ControlVoltage]>, i: usize) -> f64 {
+    fn get_freq_in_hz(&mut self, buf: Option<&[ControlVoltage]>, i: usize) -> f64 {
+        let val = self.val_smoother.next(self.val);
         match buf {
-            Some(buf) => 440.0 * (2.0_f64.powf(<f64>::from(buf[i]) + <f64>::from(self.val))),
-            None => 440.0 * (2.0_f64.powf(<f64>::from(self.val))),
+            Some(buf) => 440.0 * (2.0_f64.powf(<f64>::from(buf[i]) + <f64>::from(val))),
+            None => 440.0 * (2.0_f64.powf(<f64>::from(val))),
         }
     }
 
@@ -69,6 +84,11 @@ impl OscillatorModule {
     pub fn get_name() -> String {
         "Oscillator".to_string()
     }
+
+    /// The pitch of the note closest to a pitch
+    fn nearest_note(pitch: f32) -> f32 {
+        ((pitch + NOTE / 2.0) / NOTE).floor() * NOTE
+    }
 }
 
 impl SynthModule for OscillatorModule {
@@ -82,6 +102,7 @@ impl SynthModule for OscillatorModule {
 
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
         self.sample_rate = audio_config.sample_rate;
+        self.val_smoother.set_sample_rate(audio_config.sample_rate);
         self.sine.resize(audio_config.buffer_size);
         self.square.resize(audio_config.buffer_size);
         self.saw.resize(audio_config.buffer_size);
@@ -217,10 +238,14 @@ impl SynthModule for OscillatorModule {
     fn ui(&mut self, ui: &mut egui::Ui) {
         egui::Grid::new("osc").show(ui, |ui| {
             ui.label("Coarse");
-            ui.add(
-                egui::Slider::new(&mut self.val, -9.0..=6.0)
-                    .step_by(1.0 / 12.0)
-                    .show_value(false),
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.val, -9.0..=6.0)
+                        .step_by(1.0 / 12.0)
+                        .show_value(false),
+                ),
+                &self.id,
+                PITCH_PARAM,
             );
             ui.scope(|ui| {
                 if ui.button("-").clicked() {
@@ -233,10 +258,14 @@ impl SynthModule for OscillatorModule {
             ui.end_row();
             ui.label("Note");
             let floor = self.val.floor();
-            ui.add(
-                egui::Slider::new(&mut self.val, floor..=floor + 11.0 / 12.0)
-                    .step_by(1.0 / 12.0)
-                    .show_value(false),
+            learnable(
+                ui.add(
+                    egui::Slider::new(&mut self.val, floor..=floor + 11.0 / 12.0)
+                        .step_by(1.0 / 12.0)
+                        .show_value(false),
+                ),
+                &self.id,
+                NOTE_PARAM,
             );
             ui.scope(|ui| {
                 if ui.button("-").clicked() {
@@ -246,16 +275,20 @@ impl SynthModule for OscillatorModule {
                     self.val += 1.0 / 12.0;
                 }
             });
-            let note = ((self.val + (1.0 / 24.0)) * 12.0).floor() / 12.0;
+            let note = Self::nearest_note(self.val);
             ui.end_row();
             ui.label("Fine");
-            ui.add(
-                egui::Slider::new(
-                    &mut self.val,
-                    note - 1.0 / 24.0 + 0.00001..=note + 1.0 / 24.0 - (1.0 / 12.0 / 100.0),
-                )
-                .step_by(1.0 / 12.0 / 100.0)
-                .show_value(false),
+            learnable(
+                ui.add(
+                    egui::Slider::new(
+                        &mut self.val,
+                        note - 1.0 / 24.0 + 0.00001..=note + 1.0 / 24.0 - (1.0 / 12.0 / 100.0),
+                    )
+                    .step_by(1.0 / 12.0 / 100.0)
+                    .show_value(false),
+                ),
+                &self.id,
+                FINE_PARAM,
             );
             ui.scope(|ui| {
                 if ui.button("-").clicked() {
@@ -272,6 +305,53 @@ impl SynthModule for OscillatorModule {
         self
     }
 
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        let (octave, note) = (self.val.floor(), Self::nearest_note(self.val));
+        // Note and fine set parts of the pitch
+        let val = Cell::from_mut(&mut self.val);
+        vec![
+            (
+                Param::new(PITCH_PARAM, "Pitch", -9.0..=6.0),
+                ParamValue::Offset {
+                    value: val,
+                    base: 0.0,
+                    step: 0.0,
+                },
+            ),
+            (
+                Param::new(NOTE_PARAM, "Note", 0.0..=11.0 * NOTE),
+                ParamValue::Offset {
+                    value: val,
+                    base: octave,
+                    step: NOTE,
+                },
+            ),
+            (
+                Param::new(FINE_PARAM, "Fine", -FINE..=FINE),
+                ParamValue::Offset {
+                    value: val,
+                    base: note,
+                    step: 0.0,
+                },
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        let (octave, note) = (self.val.floor(), Self::nearest_note(self.val));
+        let (base, step) = match idx {
+            0 => (0.0, 0.0),
+            1 => (octave, NOTE),
+            2 => (note, 0.0),
+            _ => return None,
+        };
+        Some(ParamValue::Offset {
+            value: Cell::from_mut(&mut self.val),
+            base,
+            step,
+        })
+    }
+
     fn ui_dirty(&self) -> bool {
         false
     }
@@ -303,6 +383,25 @@ mod dco_tests {
         let buf = output.get().unwrap();
         assert!((buf[0] - 1.0).abs() < 0.00001); // should continue smoothly into next buffer
     }
+
+    #[test]
+    fn note_and_fine_set_parts_of_pitch() {
+        let mut module = OscillatorModule::new(&AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 16,
+            channels: 2,
+        });
+        let mut set = |id: &str, value: f32| {
+            let mut params = module.params();
+            let (_, param) = params.iter_mut().find(|(param, _)| param.id == id).unwrap();
+            param.set(value);
+        };
+        set(PITCH_PARAM, 1.5);
+        // Rounded to the nearest note of the octave
+        set(NOTE_PARAM, 4.1 * NOTE);
+        set(FINE_PARAM, 0.25 * NOTE);
+        assert!((module.val - (1.0 + 4.25 * NOTE)).abs() < 0.00001);
+    }
 }
 
 #[derive(Serialize, Deserialize, Clone)]
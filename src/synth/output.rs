@@ -1,30 +1,229 @@
-use super::{AudioBuffer, AudioConfig, SharedSynthModule, SynthModule};
+use super::midi_learn::learnable;
+use super::{AudioBuffer, AudioConfig, Param, ParamValue, SharedSynthModule, SynthModule};
 use serde::{Deserialize, Serialize};
 use std::any::Any;
+use std::f32::consts::PI;
 use uuid;
 
+const CEILING_PARAM: &str = "ceiling";
+const RELEASE_PARAM: &str = "release";
+
+const MIN_CEILING_DB: f32 = -24.0;
+const MIN_RELEASE_SEC: f32 = 0.01;
+const MAX_RELEASE_SEC: f32 = 1.0;
+/// Cutoff of the DC blocker, in Hz
+const DC_CUTOFF_HZ: f32 = 10.0;
+/// How long the clip and warning lights stay on after the last bad sample
+const HOLD_SEC: f32 = 1.0;
+/// Peak level in dBFS from which the peak light shows a signal
+const SIGNAL_DB: f32 = -48.0;
+/// Peak level in dBFS from which the peak light shows the signal is hot
+const HOT_DB: f32 = -6.0;
+const LED_SIZE: f32 = 10.0;
+
+fn db_to_gain(db: f32) -> f32 {
+    10.0_f32.powf(db / 20.0)
+}
+
+/// Settings for the stage between the patch and the audio device
+#[derive(Serialize, Deserialize, Clone)]
+struct Protection {
+    limiter: bool,
+    /// Highest level the limiter lets through, in dBFS
+    ceiling_db: f32,
+    /// Seconds for the limiter to bring the level back up after reducing it
+    release_sec: f32,
+    dc_blocker: bool,
+}
+
+impl Protection {
+    fn on() -> Self {
+        Self {
+            limiter: true,
+            ceiling_db: -0.3,
+            release_sec: 0.1,
+            dc_blocker: true,
+        }
+    }
+
+    fn off() -> Self {
+        Self {
+            limiter: false,
+            dc_blocker: false,
+            ..Self::on()
+        }
+    }
+}
+
+/// Values derived from the settings and sample rate, worked out once a block
+struct Coefficients {
+    ceiling: f32,
+    release: f32,
+    dc: f32,
+    hold: u32,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Level {
+    Silent,
+    Signal,
+    Hot,
+}
+
+#[derive(Clone, Default)]
+struct ChannelState {
+    /// Last input and output of the DC blocker
+    dc_in: f32,
+    dc_out: f32,
+    /// Peak of the last block, after protection
+    peak: f32,
+    /// Samples left to show the clip light for
+    clip_hold: u32,
+}
+
+impl ChannelState {
+    /// Peak level and whether the clip light is on
+    fn lights(&self) -> (Level, bool) {
+        let level = if self.peak >= db_to_gain(HOT_DB) {
+            Level::Hot
+        } else if self.peak >= db_to_gain(SIGNAL_DB) {
+            Level::Signal
+        } else {
+            Level::Silent
+        };
+        (level, self.clip_hold > 0)
+    }
+}
+
+/// Sends its inputs to the audio device, one per channel.
+///
+/// On the way they go through a protection stage: samples that are NaN or
+/// infinite are muted, DC is blocked and a brickwall limiter keeps peaks under
+/// the ceiling, so a runaway patch can't blast the speakers. The limiter reacts
+/// within the sample, so it adds no latency, and links the channels to keep the
+/// stereo image. The clip lights show where the patch went over full scale
+/// before protection.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct OutputModule {
     id: String,
     pub bufs: Box<[AudioBuffer]>,
     #[serde(skip)]
     inputs: Box<[Option<(SharedSynthModule, u8)>]>,
+    protection: Protection,
+    #[serde(skip)]
+    states: Vec<ChannelState>,
+    #[serde(skip)]
+    sample_rate: u16,
+    /// Limiter gain
+    #[serde(skip)]
+    gain: f32,
+    /// Samples left to show the warning about NaN or infinite samples for
+    #[serde(skip)]
+    non_finite_hold: u32,
+    /// One sample of every channel, kept to avoid allocating each buffer
+    #[serde(skip)]
+    frame: Vec<f32>,
+    /// Lights of each channel and the warning as last shown
+    #[serde(skip)]
+    lights: Vec<(Level, bool)>,
+    #[serde(skip)]
+    non_finite: bool,
+    #[serde(skip)]
+    ui_dirty: bool,
 }
 
 impl OutputModule {
     pub fn new(audio_config: &AudioConfig) -> OutputModule {
-        OutputModule {
+        let mut module = OutputModule {
             id: uuid::Uuid::new_v4().into(),
-            bufs: (0..audio_config.channels)
-                .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
-                .collect(),
-            inputs: (0..audio_config.channels).map(|_| None).collect(),
-        }
+            bufs: Box::new([]),
+            inputs: Box::new([]),
+            protection: Protection::on(),
+            states: vec![],
+            sample_rate: audio_config.sample_rate,
+            gain: 1.0,
+            non_finite_hold: 0,
+            frame: vec![],
+            lights: vec![],
+            non_finite: false,
+            ui_dirty: false,
+        };
+        module.set_audio_config(audio_config);
+        module
     }
 
     pub fn get_name() -> String {
         "Output".to_string()
     }
+
+    fn coefficients(&self) -> Coefficients {
+        let sample_rate = self.sample_rate as f32;
+        Coefficients {
+            ceiling: db_to_gain(self.protection.ceiling_db),
+            // recover to within 1% in the release time
+            release: (-4.6 / (self.protection.release_sec * sample_rate)).exp(),
+            dc: 1.0 - 2.0 * PI * DC_CUTOFF_HZ / sample_rate,
+            hold: (HOLD_SEC * sample_rate) as u32,
+        }
+    }
+
+    /// Run one sample of every channel through the protection stage
+    fn protect(&mut self, frame: &mut [f32], coefficients: &Coefficients) {
+        self.non_finite_hold = self.non_finite_hold.saturating_sub(1);
+        for (sample, state) in frame.iter_mut().zip(self.states.iter_mut()) {
+            if !sample.is_finite() {
+                *sample = 0.0;
+                self.non_finite_hold = coefficients.hold;
+            }
+            state.clip_hold = state.clip_hold.saturating_sub(1);
+            if sample.abs() > 1.0 {
+                state.clip_hold = coefficients.hold;
+            }
+            if self.protection.dc_blocker {
+                let out = *sample - state.dc_in + coefficients.dc * state.dc_out;
+                state.dc_in = *sample;
+                state.dc_out = out;
+                *sample = out;
+            }
+        }
+        if self.protection.limiter {
+            let peak = frame.iter().fold(0.0_f32, |peak, s| peak.max(s.abs()));
+            // come back up slowly, but go down at once so nothing passes the ceiling
+            self.gain = 1.0 + (self.gain - 1.0) * coefficients.release;
+            if peak * self.gain > coefficients.ceiling {
+                self.gain = coefficients.ceiling / peak;
+            }
+            for sample in frame.iter_mut() {
+                *sample *= self.gain;
+            }
+        }
+    }
+
+    /// Bring the lights and the warning up to date, returning whether any of
+    /// them changed
+    fn update_indicators(&mut self) -> bool {
+        let mut changed = false;
+        for (shown, state) in self.lights.iter_mut().zip(self.states.iter()) {
+            let lights = state.lights();
+            changed |= *shown != lights;
+            *shown = lights;
+        }
+        let non_finite = self.non_finite_hold > 0;
+        changed |= self.non_finite != non_finite;
+        self.non_finite = non_finite;
+        changed
+    }
+
+    fn led(ui: &mut egui::Ui, color: Option<egui::Color32>) -> egui::Response {
+        let (rect, response) =
+            ui.allocate_exact_size(egui::vec2(LED_SIZE, LED_SIZE), egui::Sense::hover());
+        ui.painter().circle_filled(
+            rect.center(),
+            LED_SIZE / 2.0,
+            color.unwrap_or(egui::Color32::DARK_GRAY),
+        );
+        response
+    }
 }
 
 impl SynthModule for OutputModule {
@@ -41,21 +240,40 @@ impl SynthModule for OutputModule {
         self.bufs = (0..audio_config.channels)
             .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
             .collect();
+        self.states = vec![ChannelState::default(); audio_config.channels.into()];
+        self.frame = vec![0.0; audio_config.channels.into()];
+        self.lights = vec![(Level::Silent, false); audio_config.channels.into()];
+        self.sample_rate = audio_config.sample_rate;
+        self.gain = 1.0;
     }
 
     fn calc(&mut self) {
-        for input_idx in 0..self.get_num_inputs() {
-            self.resolve_input(input_idx)
-                .unwrap()
-                .with_read(|input_buf| {
-                    self.bufs[input_idx as usize].with_write(|output_buf| {
-                        let output_buf = output_buf.unwrap();
-                        match input_buf {
-                            Some(buf) => output_buf.clone_from_slice(buf),
-                            None => output_buf.fill(0.0),
-                        }
-                    });
-                });
+        let inputs = (0..self.get_num_inputs())
+            .map(|idx| self.resolve_input(idx).unwrap())
+            .collect();
+        let coefficients = self.coefficients();
+        // Taken out so it can be passed to `protect` along with the module
+        let mut frame = std::mem::take(&mut self.frame);
+        AudioBuffer::with_read_many(inputs, |inputs| {
+            AudioBuffer::with_write_many(self.bufs.to_vec(), |outputs| {
+                let mut outputs: Vec<_> = outputs.into_iter().map(|o| o.unwrap()).collect();
+                for idx in 0..outputs.first().map_or(0, |o| o.len()) {
+                    for (sample, input) in frame.iter_mut().zip(inputs.iter()) {
+                        *sample = input.map_or(0.0, |i| i[idx]);
+                    }
+                    self.protect(&mut frame, &coefficients);
+                    for (output, sample) in outputs.iter_mut().zip(frame.iter()) {
+                        output[idx] = *sample;
+                    }
+                }
+                for (state, output) in self.states.iter_mut().zip(outputs.iter()) {
+                    state.peak = output.iter().fold(0.0, |peak, s| peak.max(s.abs()));
+                }
+            });
+        });
+        self.frame = frame;
+        if self.update_indicators() {
+            self.ui_dirty = true;
         }
     }
 
@@ -110,7 +328,196 @@ impl SynthModule for OutputModule {
         Ok(())
     }
 
+    fn zero_outputs(&self) {
+        for buf in self.bufs.iter() {
+            buf.with_write(|buf| buf.map(|buf| buf.fill(0.0)));
+        }
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for (idx, &(level, clipping)) in self.lights.iter().enumerate() {
+                ui.vertical(|ui| {
+                    ui.label(format!("{}", idx + 1));
+                    let color = match level {
+                        Level::Silent => None,
+                        Level::Signal => Some(egui::Color32::GREEN),
+                        Level::Hot => Some(egui::Color32::YELLOW),
+                    };
+                    Self::led(ui, color).on_hover_text("Peak");
+                    Self::led(ui, clipping.then_some(egui::Color32::RED))
+                        .on_hover_text("Clipped before protection");
+                });
+            }
+        });
+        if self.non_finite {
+            ui.colored_label(egui::Color32::RED, "Muted NaN or infinite samples");
+        }
+        ui.checkbox(&mut self.protection.limiter, "Limiter");
+        ui.add_enabled_ui(self.protection.limiter, |ui| {
+            ui.horizontal(|ui| {
+                learnable(
+                    ui.add(
+                        egui::Slider::new(&mut self.protection.ceiling_db, MIN_CEILING_DB..=0.0)
+                            .suffix(" dB"),
+                    ),
+                    &self.id,
+                    CEILING_PARAM,
+                );
+                ui.label("Ceiling");
+            });
+            ui.horizontal(|ui| {
+                learnable(
+                    ui.add(
+                        egui::Slider::new(
+                            &mut self.protection.release_sec,
+                            MIN_RELEASE_SEC..=MAX_RELEASE_SEC,
+                        )
+                        .logarithmic(true)
+                        .suffix(" s"),
+                    ),
+                    &self.id,
+                    RELEASE_PARAM,
+                );
+                ui.label("Release");
+            });
+        });
+        ui.checkbox(&mut self.protection.dc_blocker, "DC blocker");
+        self.ui_dirty = false;
+    }
+
+    fn ui_dirty(&self) -> bool {
+        self.ui_dirty
+    }
+
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(CEILING_PARAM, "Ceiling", MIN_CEILING_DB..=0.0),
+                ParamValue::F32(&mut self.protection.ceiling_db),
+            ),
+            (
+                Param::new(RELEASE_PARAM, "Release", MIN_RELEASE_SEC..=MAX_RELEASE_SEC),
+                ParamValue::F32(&mut self.protection.release_sec),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.protection.ceiling_db),
+            1 => ParamValue::F32(&mut self.protection.release_sec),
+            _ => return None,
+        })
+    }
+}
+
+// MIGRATIONS
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct OutputModuleV0 {
+    id: String,
+    pub bufs: Box<[AudioBuffer]>,
+    #[serde(skip)]
+    inputs: Box<[Option<(SharedSynthModule, u8)>]>,
+}
+
+/// Patches from before the protection stage keep sounding as they did, with
+/// only the guard against NaN and infinite samples
+impl From<OutputModuleV0> for OutputModule {
+    fn from(other: OutputModuleV0) -> Self {
+        Self {
+            id: other.id,
+            bufs: other.bufs,
+            inputs: other.inputs,
+            protection: Protection::off(),
+            states: vec![],
+            sample_rate: 0,
+            gain: 1.0,
+            non_finite_hold: 0,
+            frame: vec![],
+            lights: vec![],
+            non_finite: false,
+            ui_dirty: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{SynthModuleType, enum_to_sharedsynthmodule};
+
+    const CONFIG: AudioConfig = AudioConfig {
+        sample_rate: 1000,
+        buffer_size: 100,
+        channels: 2,
+    };
+
+    #[test]
+    fn mutes_non_finite_samples() {
+        let mut output = OutputModule::new(&CONFIG);
+        output.protection = Protection::off();
+        let coefficients = output.coefficients();
+        let mut frame = [f32::NAN, 0.5];
+        output.protect(&mut frame, &coefficients);
+        assert_eq!(frame, [0.0, 0.5]);
+        let mut frame = [0.25, f32::NEG_INFINITY];
+        output.protect(&mut frame, &coefficients);
+        assert_eq!(frame, [0.25, 0.0]);
+        assert!(output.update_indicators());
+        assert!(output.non_finite);
+    }
+
+    #[test]
+    fn limits_linked_channels_to_ceiling() {
+        let mut output = OutputModule::new(&CONFIG);
+        output.protection.dc_blocker = false;
+        output.protection.ceiling_db = -6.0;
+        let coefficients = output.coefficients();
+        let mut frame = [4.0, -2.0];
+        output.protect(&mut frame, &coefficients);
+        assert!((frame[0] - db_to_gain(-6.0)).abs() < 0.0001);
+        assert!((frame[1] * 2.0 + frame[0]).abs() < 0.0001);
+        output.update_indicators();
+        assert!(output.lights.iter().all(|(_, clipping)| *clipping));
+        // recovers once the peak has passed
+        for _ in 0..1000 {
+            let mut frame = [0.1, 0.1];
+            output.protect(&mut frame, &coefficients);
+        }
+        assert!(output.gain > 0.99);
+    }
+
+    #[test]
+    fn blocks_dc() {
+        let mut output = OutputModule::new(&CONFIG);
+        output.protection.limiter = false;
+        let coefficients = output.coefficients();
+        let mut frame = [0.0, 0.0];
+        for _ in 0..1000 {
+            frame = [0.5, 0.5];
+            output.protect(&mut frame, &coefficients);
+        }
+        assert!(frame[0].abs() < 0.001, "{}", frame[0]);
+    }
+    #[test]
+    fn migrates_v0_without_protection() {
+        let v0 = OutputModuleV0 {
+            id: "output".to_string(),
+            bufs: Box::new([AudioBuffer::new(Some(100)), AudioBuffer::new(Some(100))]),
+            inputs: Box::new([]),
+        };
+        let bytes = rmp_serde::to_vec(&SynthModuleType::OutputModuleV0(v0)).unwrap();
+        let module = enum_to_sharedsynthmodule(rmp_serde::from_slice(&bytes).unwrap());
+        let module = module.read().unwrap();
+        let output = module.as_any().downcast_ref::<OutputModule>().unwrap();
+        assert_eq!(output.get_id(), "output");
+        assert_eq!(output.bufs.len(), 2);
+        assert!(!output.protection.limiter && !output.protection.dc_blocker);
+    }
 }
@@ -0,0 +1,418 @@
+use super::midi_learn::learnable;
+use super::tuning::{Tuning, TuningLoader, tuning_ui};
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, SynthModule,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const ROOT_PARAM: &str = "root";
+const TRANSPOSE_PARAM: &str = "transpose";
+
+const MAX_TRANSPOSE: i64 = 48;
+
+/// Length of the trigger sent when the output note changes, in seconds
+const TRIGGER_SEC: f32 = 0.001;
+
+/// Scales for 12 note tunings, as semitones above the root
+pub const SCALES: &[(&str, &[usize])] = &[
+    ("Chromatic", &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11]),
+    ("Major", &[0, 2, 4, 5, 7, 9, 11]),
+    ("Natural minor", &[0, 2, 3, 5, 7, 8, 10]),
+    ("Harmonic minor", &[0, 2, 3, 5, 7, 8, 11]),
+    ("Melodic minor", &[0, 2, 3, 5, 7, 9, 11]),
+    ("Dorian", &[0, 2, 3, 5, 7, 9, 10]),
+    ("Phrygian", &[0, 1, 3, 5, 7, 8, 10]),
+    ("Lydian", &[0, 2, 4, 6, 7, 9, 11]),
+    ("Mixolydian", &[0, 2, 4, 5, 7, 9, 10]),
+    ("Locrian", &[0, 1, 3, 5, 6, 8, 10]),
+    ("Major pentatonic", &[0, 2, 4, 7, 9]),
+    ("Minor pentatonic", &[0, 3, 5, 7, 10]),
+    ("Blues", &[0, 3, 5, 6, 7, 10]),
+    ("Whole tone", &[0, 2, 4, 6, 8, 10]),
+];
+
+/// Names of the 12-TET keys, starting from A at 0V
+const NOTE_NAMES: [&str; 12] = [
+    "A", "A#", "B", "C", "C#", "D", "D#", "E", "F", "F#", "G", "G#",
+];
+
+/// Pitch of a note counted from the root across periods
+fn pitch_at(pitches: &[f64], period: f64, note: i64) -> f64 {
+    let len = pitches.len() as i64;
+    note.div_euclid(len) as f64 * period + pitches[note.rem_euclid(len) as usize]
+}
+
+/// The note nearest to a pitch, counted from the root across periods
+fn nearest_note(pitches: &[f64], period: f64, pitch: f64) -> i64 {
+    let len = pitches.len() as i64;
+    let first = (pitch / period).floor() as i64 * len;
+    // the nearest note may be the last one of the period below or the root
+    // of the period above
+    (first - 1..=first + len)
+        .min_by(|a, b| {
+            (pitch_at(pitches, period, *a) - pitch)
+                .abs()
+                .total_cmp(&(pitch_at(pitches, period, *b) - pitch).abs())
+        })
+        .unwrap()
+}
+
+/// Forces pitch CV to the nearest note of a scale.
+///
+/// The scale is picked from the degrees of the tuning, relative to the root.
+/// The root input adds to the root in volts per octave, the transpose input
+/// moves the output by whole scale notes, one volt per period.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct QuantizerModule {
+    id: String,
+    tuning: Tuning,
+    /// Which notes of one period of the tuning are part of the scale, counted
+    /// from the root
+    enabled: Vec<bool>,
+    /// Root as a key of the tuning
+    root: i64,
+    /// Transposition in scale notes
+    transpose: i64,
+    #[serde(skip)]
+    cv_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    root_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    transpose_in: Option<(SharedSynthModule, u8)>,
+    cv_out: AudioBuffer,
+    trigger_out: AudioBuffer,
+    last_note: Option<i64>,
+    trigger_remaining: u32,
+    sample_rate: f32,
+    #[serde(skip)]
+    loader: TuningLoader,
+    /// The pitches from `scale()`, rebuilt when the tuning or the enabled
+    /// notes change rather than on every buffer
+    #[serde(skip)]
+    scale: Option<(Vec<f64>, f64)>,
+}
+
+impl QuantizerModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        let tuning = Tuning::default();
+        Self {
+            id: uuid::Uuid::new_v4().into(),
+            enabled: vec![true; tuning.period_pitches().0.len()],
+            tuning,
+            root: 0,
+            transpose: 0,
+            cv_in: None,
+            root_in: None,
+            transpose_in: None,
+            cv_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            trigger_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            last_note: None,
+            trigger_remaining: 0,
+            sample_rate: audio_config.sample_rate as f32,
+            loader: TuningLoader::default(),
+            scale: None,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Quantizer".to_string()
+    }
+
+    /// Pitches of the scale notes in one period above the root, with the
+    /// length of the period
+    fn scale(&self) -> (Vec<f64>, f64) {
+        let (pitches, period) = self.tuning.period_pitches();
+        let scale = pitches
+            .iter()
+            .zip(self.enabled.iter())
+            .filter(|(_, enabled)| **enabled)
+            .map(|(pitch, _)| *pitch)
+            .collect();
+        (scale, period)
+    }
+
+    fn root_label(&self) -> String {
+        if self.tuning.is_12tet() {
+            NOTE_NAMES[self.root.rem_euclid(12) as usize].to_string()
+        } else {
+            self.root.to_string()
+        }
+    }
+
+    fn matching_scale(&self) -> Option<&'static str> {
+        if self.enabled.len() != 12 {
+            return None;
+        }
+        SCALES
+            .iter()
+            .find(|(_, notes)| (0..12).all(|note| self.enabled[note] == notes.contains(&note)))
+            .map(|(name, _)| *name)
+    }
+}
+
+impl SynthModule for QuantizerModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        let last_key = self.tuning.keys_per_period().saturating_sub(1) as f32;
+        let max_transpose = MAX_TRANSPOSE as f32;
+        vec![
+            (
+                Param::new(ROOT_PARAM, "Root", 0.0..=last_key),
+                ParamValue::I64(&mut self.root),
+            ),
+            (
+                Param::new(TRANSPOSE_PARAM, "Transpose", -max_transpose..=max_transpose),
+                ParamValue::I64(&mut self.transpose),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::I64(&mut self.root),
+            1 => ParamValue::I64(&mut self.transpose),
+            _ => return None,
+        })
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate as f32;
+        self.cv_out.resize(audio_config.buffer_size);
+        self.trigger_out.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        3
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.cv_in.clone()),
+            1 => Ok(self.root_in.clone()),
+            2 => Ok(self.transpose_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.cv_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.root_in = Some((src_module, src_port));
+                Ok(())
+            }
+            2 => {
+                self.transpose_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.cv_in = None;
+                Ok(())
+            }
+            1 => {
+                self.root_in = None;
+                Ok(())
+            }
+            2 => {
+                self.transpose_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(Some("CV".to_string())),
+            1 => Ok(Some("Root".to_string())),
+            2 => Ok(Some("Transpose".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        2
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.cv_out.clone()),
+            1 => Ok(self.trigger_out.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(Some("CV".to_string())),
+            1 => Ok(Some("Changed".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        if self.scale.is_none() {
+            self.scale = Some(self.scale());
+        }
+        let (scale, period) = self.scale.as_ref().unwrap();
+        let period = *period;
+        let root = self
+            .tuning
+            .key_pitch(self.root)
+            .unwrap_or(self.tuning.root());
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
+            ],
+            |bufs| {
+                let (cv_in, root_in, transpose_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.cv_out.clone(), self.trigger_out.clone()],
+                    |bufs| {
+                        let (cv_out, trigger_out) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        for idx in 0..cv_out.len() {
+                            let cv = cv_in.map(|buf| buf[idx]).unwrap_or(0.0) as f64;
+                            if scale.is_empty() {
+                                cv_out[idx] = cv as ControlVoltage;
+                                trigger_out[idx] = 0.0;
+                                continue;
+                            }
+                            let root = root + root_in.map(|buf| buf[idx]).unwrap_or(0.0) as f64;
+                            let transpose = self.transpose
+                                + (transpose_in.map(|buf| buf[idx]).unwrap_or(0.0) as f64
+                                    * scale.len() as f64)
+                                    .round() as i64;
+                            let note = nearest_note(scale, period, cv - root) + transpose;
+                            if self.last_note != Some(note) {
+                                self.last_note = Some(note);
+                                self.trigger_remaining =
+                                    (TRIGGER_SEC * self.sample_rate).ceil() as u32;
+                            }
+                            cv_out[idx] = (root + pitch_at(scale, period, note)) as ControlVoltage;
+                            trigger_out[idx] = if self.trigger_remaining > 0 {
+                                self.trigger_remaining -= 1;
+                                1.0
+                            } else {
+                                0.0
+                            };
+                        }
+                    },
+                );
+            },
+        );
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        let mut scale_changed = false;
+        ui.vertical(|ui| {
+            if tuning_ui(ui, &mut self.tuning, &self.loader) {
+                self.enabled = vec![true; self.tuning.period_pitches().0.len()];
+                self.root = 0;
+                scale_changed = true;
+            }
+            ui.horizontal(|ui| {
+                ui.label("Root: ");
+                if ui.button("-").clicked() {
+                    self.root -= 1;
+                }
+                learnable(
+                    ui.label(self.root_label()).interact(egui::Sense::click()),
+                    &self.id,
+                    ROOT_PARAM,
+                );
+                if ui.button("+").clicked() {
+                    self.root += 1;
+                }
+                self.root = self.root.rem_euclid(self.tuning.keys_per_period() as i64);
+                ui.label("Transpose: ");
+                learnable(
+                    ui.add(
+                        egui::DragValue::new(&mut self.transpose)
+                            .range(-MAX_TRANSPOSE..=MAX_TRANSPOSE),
+                    ),
+                    &self.id,
+                    TRANSPOSE_PARAM,
+                );
+            });
+            ui.horizontal(|ui| {
+                ui.label("Scale: ");
+                ui.scope(|ui| {
+                    if self.enabled.len() != 12 {
+                        ui.disable();
+                    }
+                    egui::ComboBox::from_id_source((&self.id, "scale"))
+                        .selected_text(self.matching_scale().unwrap_or("Custom"))
+                        .show_ui(ui, |ui| {
+                            for (name, notes) in SCALES {
+                                if ui
+                                    .selectable_label(self.matching_scale() == Some(name), *name)
+                                    .clicked()
+                                {
+                                    self.enabled = (0..12).map(|n| notes.contains(&n)).collect();
+                                    scale_changed = true;
+                                }
+                            }
+                        });
+                });
+            });
+            ui.horizontal_wrapped(|ui| {
+                for (note, enabled) in self.enabled.iter_mut().enumerate() {
+                    scale_changed |= ui.toggle_value(enabled, note.to_string()).changed();
+                }
+            });
+        });
+        if scale_changed {
+            self.scale = Some(self.scale());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snaps_to_nearest_note() {
+        // A major pentatonic above A
+        let scale = [0.0, 2.0 / 12.0, 4.0 / 12.0, 7.0 / 12.0, 9.0 / 12.0];
+        assert_eq!(nearest_note(&scale, 1.0, 0.9 / 12.0), 0);
+        assert_eq!(nearest_note(&scale, 1.0, 1.1 / 12.0), 1);
+        assert_eq!(nearest_note(&scale, 1.0, 11.0 / 12.0), 5);
+        assert_eq!(nearest_note(&scale, 1.0, -2.0 / 12.0), -1);
+        assert!((pitch_at(&scale, 1.0, 7) - 16.0 / 12.0).abs() < 0.0001);
+        assert!((pitch_at(&scale, 1.0, -1) + 3.0 / 12.0).abs() < 0.0001);
+    }
+}
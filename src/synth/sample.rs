@@ -1,74 +1,186 @@
+use super::decode;
+use super::midi_learn::learnable;
 use super::{
-    AudioBuffer, AudioConfig, ControlVoltage, SharedSynthModule, SynthModule, TransitionDetector,
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, SynthModule,
+    TransitionDetector,
 };
 use crate::ui::run_async;
-use cpal::{I24, Sample};
-use hound::{SampleFormat, WavReader};
 use itertools::Itertools;
 use rfd::AsyncFileDialog;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 use std::error::Error;
-use std::io::Cursor;
+use std::f32::consts::PI;
 use std::sync::{Arc, Mutex};
 
-#[derive(Default, Serialize, Deserialize)]
+const RELEASE_PARAM: &str = "release";
+
+/// Number of input samples each output sample is built from in sinc interpolation
+const SINC_TAPS: isize = 8;
+const WAVEFORM_WIDTH: f32 = 240.0;
+const WAVEFORM_HEIGHT: f32 = 60.0;
+/// How close, in points, the pointer has to be to a marker to drag it
+const MARKER_GRAB_DISTANCE: f32 = 6.0;
+
+#[derive(Default, Clone, Serialize, Deserialize)]
 struct WaveBox {
-    samples: Vec<ControlVoltage>,
+    /// Samples for each channel, at most two
+    channels: Vec<Vec<ControlVoltage>>,
     sample_rate: f32,
     new: bool,
+    /// Per-pixel minimum and maximum of the first channel, for drawing
+    #[serde(skip)]
+    overview: Vec<(ControlVoltage, ControlVoltage)>,
 }
 
-#[derive(Debug)]
-struct DecodeError {}
-impl std::fmt::Display for DecodeError {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Error decoding wav file")
+impl WaveBox {
+    /// Decode an audio file and resample it to the engine's sample rate
+    fn load(
+        data: Vec<u8>,
+        extension: Option<&str>,
+        sample_rate: f32,
+    ) -> Result<Self, Box<dyn Error>> {
+        let decoded = decode::decode(data, extension)?;
+        let wavebox = Self {
+            channels: decoded.channels.into_iter().take(2).collect(),
+            sample_rate: decoded.sample_rate,
+            new: true,
+            overview: vec![],
+        };
+        Ok(wavebox.resampled(sample_rate))
     }
-}
-impl Error for DecodeError {}
 
-impl WaveBox {
-    fn load(&mut self, wav_data: Vec<u8>) -> Result<(), Box<dyn Error>> {
-        let reader = WavReader::new(Cursor::new(wav_data))?;
-        let spec = reader.spec();
-        self.samples.clear();
-        match spec.sample_format {
-            SampleFormat::Float => {
-                for sample in reader
-                    .into_samples()
-                    .map(|s| s.unwrap())
-                    .enumerate()
-                    .filter(|(idx, _)| idx % spec.channels as usize == 0)
-                    .map(|(_, sample)| sample)
-                {
-                    self.samples.push(sample);
+    fn resampled(self, sample_rate: f32) -> Self {
+        if self.sample_rate == sample_rate {
+            return self;
+        }
+        Self {
+            channels: self
+                .channels
+                .iter()
+                .map(|channel| decode::resample(channel, self.sample_rate, sample_rate))
+                .collect(),
+            sample_rate,
+            new: self.new,
+            overview: vec![],
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.channels.first().map(|c| c.len()).unwrap_or(0)
+    }
+
+    /// Read a channel at a fractional position. Mono samples are read the same
+    /// for both channels.
+    #[inline]
+    fn read(&self, channel: usize, pos: f64, interpolation: Interpolation) -> ControlVoltage {
+        let samples = &self.channels[channel.min(self.channels.len() - 1)];
+        let last = samples.len() as isize - 1;
+        let at = |idx: isize| samples[idx.clamp(0, last) as usize];
+        let idx = pos.floor() as isize;
+        let frac = (pos - pos.floor()) as f32;
+        match interpolation {
+            Interpolation::Nearest => at(idx),
+            Interpolation::Linear => at(idx) + (at(idx + 1) - at(idx)) * frac,
+            Interpolation::Sinc => {
+                if frac == 0.0 {
+                    return at(idx);
                 }
-            }
-            SampleFormat::Int => {
-                let convert = match spec.bits_per_sample {
-                    8 => |x: i32| x as f32 / (i8::MAX as f32 + 1.0),
-                    16 => |x: i32| x as f32 / (i16::MAX as f32 + 1.0),
-                    24 => |x: i32| I24::new_unchecked(x).to_float_sample(),
-                    _ => return Err(Box::new(DecodeError {})),
-                };
-                for sample in reader
-                    .into_samples()
-                    .map(|s| convert(s.unwrap()))
-                    .enumerate()
-                    .filter(|(idx, _)| idx % spec.channels as usize == 0)
-                    .map(|(_, sample)| sample)
-                {
-                    self.samples.push(sample);
+                // sin(pi * (frac - tap)) only changes sign from tap to tap
+                let sin_frac = (PI * frac).sin();
+                let mut acc = 0.0;
+                for tap in (1 - SINC_TAPS / 2)..=(SINC_TAPS / 2) {
+                    let x = frac - tap as f32;
+                    let sign = if tap % 2 == 0 { 1.0 } else { -1.0 };
+                    let sinc = sign * sin_frac / (PI * x);
+                    let window = 0.5 + 0.5 * (PI * x / (SINC_TAPS as f32 / 2.0)).cos();
+                    acc += at(idx + tap) * sinc * window;
                 }
+                acc
             }
         }
-        self.new = true;
-        self.sample_rate = spec.sample_rate as f32;
-        Ok(())
+    }
+
+    fn overview(&mut self, width: usize) -> &[(ControlVoltage, ControlVoltage)] {
+        if self.overview.len() != width && self.len() > 0 {
+            let samples = &self.channels[0];
+            self.overview = (0..width)
+                .map(|col| {
+                    let from = col * samples.len() / width;
+                    let to = ((col + 1) * samples.len() / width).max(from + 1);
+                    samples[from..to.min(samples.len())]
+                        .iter()
+                        .fold((0.0_f32, 0.0_f32), |(min, max), s| {
+                            (min.min(*s), max.max(*s))
+                        })
+                })
+                .collect();
+        }
+        &self.overview
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+enum PlaybackMode {
+    OneShot,
+    Loop,
+    PingPong,
+}
+
+impl PlaybackMode {
+    fn label(&self) -> &'static str {
+        match self {
+            PlaybackMode::OneShot => "One-shot",
+            PlaybackMode::Loop => "Loop",
+            PlaybackMode::PingPong => "Ping-pong",
+        }
     }
 }
 
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+enum Interpolation {
+    Nearest,
+    Linear,
+    Sinc,
+}
+
+impl Interpolation {
+    fn label(&self) -> &'static str {
+        match self {
+            Interpolation::Nearest => "Nearest",
+            Interpolation::Linear => "Linear",
+            Interpolation::Sinc => "Sinc",
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Marker {
+    Start,
+    End,
+    LoopStart,
+    LoopEnd,
+}
+
+/// Playback markers converted to sample frames
+struct Region {
+    start: f64,
+    end: f64,
+    loop_start: f64,
+    loop_end: f64,
+}
+
+impl Region {
+    fn has_loop(&self) -> bool {
+        self.loop_end - self.loop_start >= 1.0
+    }
+}
+
+/// Plays back a sample on a trigger.
+///
+/// Start, end and loop markers are stored as fractions of the sample length so
+/// they survive loading a different file. The offset input moves the start
+/// point through the start/end region, from 0.0 to 1.0, for slicing.
 #[derive(Serialize, Deserialize, Clone)]
 pub struct SampleModule {
     id: String,
@@ -76,12 +188,35 @@ pub struct SampleModule {
     gate_in: Option<(SharedSynthModule, u8)>,
     #[serde(skip)]
     cv_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    offset_in: Option<(SharedSynthModule, u8)>,
     transition_detector: TransitionDetector,
-    pos: f32,
-    buf: AudioBuffer,
+    pos: f64,
+    direction: f64,
+    left_out: AudioBuffer,
+    right_out: AudioBuffer,
     wavebox: Arc<Mutex<WaveBox>>,
+    /// Why the last file chosen couldn't be loaded
+    #[serde(skip)]
+    load_error: Arc<Mutex<Option<String>>>,
     playing: bool,
     sample_rate: f32,
+    start: f32,
+    end: f32,
+    loop_start: f32,
+    loop_end: f32,
+    mode: PlaybackMode,
+    reverse: bool,
+    interpolation: Interpolation,
+    /// Only play while the gate is held, fading out over `release_sec` after
+    gate_mode: bool,
+    release_sec: f32,
+    releasing: bool,
+    release_gain: f32,
+    #[serde(skip)]
+    dragging: Option<Marker>,
+    #[serde(skip)]
+    ui_dirty: bool,
 }
 
 impl SampleModule {
@@ -90,18 +225,173 @@ impl SampleModule {
             id: uuid::Uuid::new_v4().to_string(),
             gate_in: None,
             cv_in: None,
+            offset_in: None,
             transition_detector: TransitionDetector::new(),
             pos: 0.0,
-            buf: AudioBuffer::new(Some(audio_config.buffer_size)),
+            direction: 1.0,
+            left_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            right_out: AudioBuffer::new(Some(audio_config.buffer_size)),
             wavebox: Arc::new(Mutex::new(WaveBox::default())),
+            load_error: Default::default(),
             playing: false,
             sample_rate: audio_config.sample_rate as f32,
+            start: 0.0,
+            end: 1.0,
+            loop_start: 0.0,
+            loop_end: 1.0,
+            mode: PlaybackMode::OneShot,
+            reverse: false,
+            interpolation: Interpolation::Linear,
+            gate_mode: false,
+            release_sec: 0.05,
+            releasing: false,
+            release_gain: 1.0,
+            dragging: None,
+            ui_dirty: false,
         }
     }
 
     pub fn get_name() -> String {
         "Sample".to_string()
     }
+
+    fn region(&self, len: usize) -> Region {
+        let len = len as f64;
+        let frame = |val: f32| (val as f64 * len).round();
+        let start = frame(self.start.min(self.end));
+        let end = frame(self.start.max(self.end));
+        Region {
+            start,
+            end,
+            loop_start: frame(self.loop_start.min(self.loop_end)).clamp(start, end),
+            loop_end: frame(self.loop_start.max(self.loop_end)).clamp(start, end),
+        }
+    }
+
+    fn trigger(&mut self, region: &Region, offset: ControlVoltage) {
+        let offset = offset.clamp(0.0, 1.0) as f64 * (region.end - region.start);
+        if self.reverse {
+            self.pos = (region.end - 1.0 - offset).max(region.start);
+            self.direction = -1.0;
+        } else {
+            self.pos = region.start + offset;
+            self.direction = 1.0;
+        }
+        self.playing = true;
+        self.releasing = false;
+        self.release_gain = 1.0;
+    }
+
+    /// Move the play head, handling the loop and the ends of the region
+    #[inline]
+    fn advance(&mut self, region: &Region, rate: f64) {
+        self.pos += self.direction * rate;
+        match self.mode {
+            PlaybackMode::Loop if region.has_loop() => {
+                let loop_len = region.loop_end - region.loop_start;
+                if (self.direction > 0.0 && self.pos >= region.loop_end)
+                    || (self.direction < 0.0 && self.pos < region.loop_start)
+                {
+                    self.pos =
+                        region.loop_start + (self.pos - region.loop_start).rem_euclid(loop_len);
+                }
+            }
+            PlaybackMode::PingPong if region.has_loop() => {
+                // The loop end is exclusive, so turn around at the frame before
+                let last = region.loop_end - 1.0;
+                if self.direction > 0.0 && self.pos > last {
+                    self.pos = (2.0 * last - self.pos).max(region.loop_start);
+                    self.direction = -1.0;
+                } else if self.direction < 0.0 && self.pos < region.loop_start {
+                    self.pos = (2.0 * region.loop_start - self.pos).min(last);
+                    self.direction = 1.0;
+                }
+            }
+            _ => {}
+        }
+        if self.pos >= region.end || self.pos < region.start {
+            self.playing = false;
+        }
+    }
+
+    fn waveform_ui(&mut self, ui: &mut egui::Ui) {
+        let (id, rect) = ui.allocate_space([WAVEFORM_WIDTH, WAVEFORM_HEIGHT].into());
+        let response = ui.interact(rect, id, egui::Sense::click_and_drag());
+        let painter = ui.painter();
+        painter.rect_filled(rect, 1.0, ui.visuals().extreme_bg_color);
+        let x_of = |val: f32| rect.min.x + val.clamp(0.0, 1.0) * rect.width();
+        if matches!(self.mode, PlaybackMode::Loop | PlaybackMode::PingPong) {
+            painter.rect_filled(
+                egui::Rect::from_x_y_ranges(
+                    x_of(self.loop_start.min(self.loop_end))
+                        ..=x_of(self.loop_start.max(self.loop_end)),
+                    rect.y_range(),
+                ),
+                0.0,
+                egui::Color32::from_rgba_unmultiplied(255, 255, 0, 24),
+            );
+        }
+        let len = {
+            let Ok(mut wavebox) = self.wavebox.try_lock() else {
+                return;
+            };
+            let len = wavebox.len();
+            for (col, (min, max)) in wavebox.overview(rect.width() as usize).iter().enumerate() {
+                let x = rect.min.x + col as f32 + 0.5;
+                painter.line_segment(
+                    [
+                        egui::pos2(
+                            x,
+                            rect.center().y - max.clamp(-1.0, 1.0) * rect.height() / 2.0,
+                        ),
+                        egui::pos2(
+                            x,
+                            rect.center().y - min.clamp(-1.0, 1.0) * rect.height() / 2.0,
+                        ),
+                    ],
+                    egui::Stroke::new(1.0, ui.visuals().text_color()),
+                );
+            }
+            len
+        };
+        let markers = [
+            (Marker::Start, self.start, egui::Color32::GREEN),
+            (Marker::End, self.end, egui::Color32::RED),
+            (Marker::LoopStart, self.loop_start, egui::Color32::YELLOW),
+            (Marker::LoopEnd, self.loop_end, egui::Color32::YELLOW),
+        ];
+        for (_, val, color) in markers {
+            painter.vline(x_of(val), rect.y_range(), egui::Stroke::new(1.0, color));
+        }
+        if self.playing && len > 0 {
+            painter.vline(
+                x_of((self.pos / len as f64) as f32),
+                rect.y_range(),
+                egui::Stroke::new(1.0, egui::Color32::LIGHT_BLUE),
+            );
+        }
+        if let Some(pointer) = response.interact_pointer_pos() {
+            if response.drag_started() {
+                self.dragging = markers
+                    .iter()
+                    .map(|(marker, val, _)| (*marker, (x_of(*val) - pointer.x).abs()))
+                    .filter(|(_, distance)| *distance <= MARKER_GRAB_DISTANCE)
+                    .min_by(|(_, a), (_, b)| a.total_cmp(b))
+                    .map(|(marker, _)| marker);
+            }
+            let val = ((pointer.x - rect.min.x) / rect.width()).clamp(0.0, 1.0);
+            match self.dragging {
+                Some(Marker::Start) => self.start = val,
+                Some(Marker::End) => self.end = val,
+                Some(Marker::LoopStart) => self.loop_start = val,
+                Some(Marker::LoopEnd) => self.loop_end = val,
+                None => {}
+            }
+        }
+        if response.drag_stopped() {
+            self.dragging = None;
+        }
+    }
 }
 
 impl SynthModule for SampleModule {
@@ -115,17 +405,28 @@ impl SynthModule for SampleModule {
 
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
         self.sample_rate = audio_config.sample_rate as f32;
-        self.buf.resize(audio_config.buffer_size);
+        // Resample a copy so playback carries on until it's swapped in
+        let stale = {
+            let wavebox = self.wavebox.lock().unwrap();
+            (wavebox.sample_rate != self.sample_rate).then(|| wavebox.clone())
+        };
+        if let Some(stale) = stale {
+            let resampled = stale.resampled(self.sample_rate);
+            let _old = std::mem::replace(&mut *self.wavebox.lock().unwrap(), resampled);
+        }
+        self.left_out.resize(audio_config.buffer_size);
+        self.right_out.resize(audio_config.buffer_size);
     }
 
     fn get_num_inputs(&self) -> u8 {
-        2
+        3
     }
 
     fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
         match input_idx {
             0 => Ok(self.gate_in.clone()),
             1 => Ok(self.cv_in.clone()),
+            2 => Ok(self.offset_in.clone()),
             _ => Err(()),
         }
     }
@@ -145,6 +446,10 @@ impl SynthModule for SampleModule {
                 self.cv_in = Some((src_module, src_port));
                 Ok(())
             }
+            2 => {
+                self.offset_in = Some((src_module, src_port));
+                Ok(())
+            }
             _ => Err(()),
         }
     }
@@ -159,6 +464,10 @@ impl SynthModule for SampleModule {
                 self.cv_in = None;
                 Ok(())
             }
+            2 => {
+                self.offset_in = None;
+                Ok(())
+            }
             _ => Err(()),
         }
     }
@@ -167,24 +476,27 @@ impl SynthModule for SampleModule {
         match input_idx {
             0 => Ok(Some("Gate".to_string())),
             1 => Ok(Some("CV".to_string())),
+            2 => Ok(Some("Start offset".to_string())),
             _ => Err(()),
         }
     }
 
     fn get_num_outputs(&self) -> u8 {
-        1
+        2
     }
 
     fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
         match output_idx {
-            0 => Ok(self.buf.clone()),
+            0 => Ok(self.left_out.clone()),
+            1 => Ok(self.right_out.clone()),
             _ => Err(()),
         }
     }
 
     fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
         match output_idx {
-            0 => Ok(None),
+            0 => Ok(Some("Left".to_string())),
+            1 => Ok(Some("Right".to_string())),
             _ => Err(()),
         }
     }
@@ -194,69 +506,294 @@ impl SynthModule for SampleModule {
             vec![
                 self.resolve_input(0).unwrap(),
                 self.resolve_input(1).unwrap(),
+                self.resolve_input(2).unwrap(),
             ],
             |bufs| {
-                let (gate_in, cv_in) = bufs.into_iter().collect_tuple().unwrap();
-                self.buf.with_write(|output| {
-                    let output = output.unwrap();
-                    let wavebox = self.wavebox.try_lock();
-                    if wavebox.is_err() {
-                        self.transition_detector
-                            .is_transition(gate_in.map(|i| &i[i.len() - 1]).unwrap_or(&0.0));
-                        output.fill(0.0);
-                        return;
-                    }
-                    let mut wavebox = wavebox.unwrap();
-                    if wavebox.new {
-                        self.pos = 0.0;
-                        self.playing = false;
-                        wavebox.new = false;
-                    }
-                    for (idx, out) in output.iter_mut().enumerate() {
-                        let trigger = self
-                            .transition_detector
-                            .is_transition(gate_in.map(|i| &i[idx]).unwrap_or(&0.0));
-                        if trigger {
-                            self.pos = 0.0;
-                            self.playing = true;
+                let (gate_in, cv_in, offset_in) = bufs.into_iter().collect_tuple().unwrap();
+                AudioBuffer::with_write_many(
+                    vec![self.left_out.clone(), self.right_out.clone()],
+                    |bufs| {
+                        let (left, right) = bufs
+                            .into_iter()
+                            .map(|b| b.unwrap())
+                            .collect_tuple()
+                            .unwrap();
+                        let wavebox = self.wavebox.clone();
+                        let wavebox = wavebox.try_lock();
+                        if wavebox.is_err() {
+                            self.transition_detector
+                                .is_transition(gate_in.map(|i| &i[i.len() - 1]).unwrap_or(&0.0));
+                            left.fill(0.0);
+                            right.fill(0.0);
+                            return;
                         }
-                        if self.pos as usize >= wavebox.samples.len() {
+                        let mut wavebox = wavebox.unwrap();
+                        if wavebox.new {
                             self.pos = 0.0;
                             self.playing = false;
+                            wavebox.new = false;
                         }
-                        if !wavebox.samples.is_empty() {
-                            *out = wavebox.samples[self.pos as usize];
-                        } else {
-                            *out = 0.0;
-                        }
-                        if self.playing {
-                            self.pos += wavebox.sample_rate / self.sample_rate
-                                * 2.0_f32.powf(cv_in.map(|i| i[idx]).unwrap_or(0.0_f32));
+                        let region = self.region(wavebox.len());
+                        for idx in 0..left.len() {
+                            let gate = gate_in.map(|i| i[idx]).unwrap_or(0.0);
+                            if self.transition_detector.is_transition(&gate) {
+                                self.trigger(&region, offset_in.map(|i| i[idx]).unwrap_or(0.0));
+                            }
+                            if self.gate_mode
+                                && self.playing
+                                && !TransitionDetector::is_above_threshold(&gate)
+                            {
+                                self.releasing = true;
+                            }
+                            if !self.playing || wavebox.len() == 0 {
+                                (left[idx], right[idx]) = (0.0, 0.0);
+                                continue;
+                            }
+                            left[idx] =
+                                wavebox.read(0, self.pos, self.interpolation) * self.release_gain;
+                            right[idx] =
+                                wavebox.read(1, self.pos, self.interpolation) * self.release_gain;
+                            let rate = 2.0_f64.powf(cv_in.map(|i| i[idx]).unwrap_or(0.0) as f64);
+                            self.advance(&region, rate);
+                            if self.releasing {
+                                self.release_gain -=
+                                    1.0 / (self.release_sec.max(0.001) * self.sample_rate);
+                                if self.release_gain <= 0.0 {
+                                    self.playing = false;
+                                }
+                            }
                         }
-                    }
-                });
+                    },
+                );
             },
         );
+        if self.playing {
+            self.ui_dirty = true;
+        }
     }
 
     fn ui(&mut self, ui: &mut egui::Ui) {
         if ui.button("Load Sample...").clicked() {
             let wavebox = self.wavebox.clone();
+            let load_error = self.load_error.clone();
+            let sample_rate = self.sample_rate;
             run_async(async move {
                 let file = AsyncFileDialog::new()
-                    .add_filter("audio", &["wav"])
+                    .add_filter("audio", decode::EXTENSIONS)
                     .pick_file()
                     .await;
                 if let Some(file) = file {
                     let data = file.read().await;
-                    let mut unlocked_wavebox = wavebox.lock().unwrap();
-                    let _ = unlocked_wavebox.load(data);
+                    let file_name = file.file_name();
+                    let extension = file_name.rsplit_once('.').map(|(_, ext)| ext);
+                    // Only lock to swap it in, so playback isn't held up by
+                    // decoding, and the old one is dropped after unlocking
+                    match WaveBox::load(data, extension, sample_rate) {
+                        Ok(loaded) => {
+                            *load_error.lock().unwrap() = None;
+                            let _old = std::mem::replace(&mut *wavebox.lock().unwrap(), loaded);
+                        }
+                        Err(e) => {
+                            *load_error.lock().unwrap() = Some(format!("{file_name}: {e}"));
+                        }
+                    }
                 }
             });
         }
+        if let Some(error) = &*self.load_error.lock().unwrap() {
+            ui.colored_label(egui::Color32::RED, error);
+        }
+        self.waveform_ui(ui);
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source((&self.id, "mode"))
+                .selected_text(self.mode.label())
+                .show_ui(ui, |ui| {
+                    for mode in [
+                        PlaybackMode::OneShot,
+                        PlaybackMode::Loop,
+                        PlaybackMode::PingPong,
+                    ] {
+                        ui.selectable_value(&mut self.mode, mode, mode.label());
+                    }
+                });
+            egui::ComboBox::from_id_source((&self.id, "interpolation"))
+                .selected_text(self.interpolation.label())
+                .show_ui(ui, |ui| {
+                    for interpolation in [
+                        Interpolation::Nearest,
+                        Interpolation::Linear,
+                        Interpolation::Sinc,
+                    ] {
+                        ui.selectable_value(
+                            &mut self.interpolation,
+                            interpolation,
+                            interpolation.label(),
+                        );
+                    }
+                });
+            ui.checkbox(&mut self.reverse, "Reverse");
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut self.gate_mode, "Gate");
+            ui.scope(|ui| {
+                if !self.gate_mode {
+                    ui.disable();
+                }
+                ui.label("Release");
+                learnable(
+                    ui.add(
+                        egui::Slider::new(&mut self.release_sec, 0.001..=5.0)
+                            .logarithmic(true)
+                            .suffix(" s"),
+                    ),
+                    &self.id,
+                    RELEASE_PARAM,
+                );
+            });
+        });
+        self.ui_dirty = false;
+    }
+
+    fn ui_dirty(&self) -> bool {
+        self.ui_dirty
     }
 
     fn as_any(&self) -> &dyn Any {
         self
     }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(RELEASE_PARAM, "Release", 0.001..=5.0),
+            ParamValue::F32(&mut self.release_sec),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::F32(&mut self.release_sec))
+    }
+}
+
+// MIGRATIONS
+
+#[derive(Default, Serialize, Deserialize)]
+struct WaveBoxV0 {
+    samples: Vec<ControlVoltage>,
+    sample_rate: f32,
+    new: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SampleModuleV0 {
+    id: String,
+    #[serde(skip)]
+    gate_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    cv_in: Option<(SharedSynthModule, u8)>,
+    transition_detector: TransitionDetector,
+    pos: f32,
+    buf: AudioBuffer,
+    wavebox: Arc<Mutex<WaveBoxV0>>,
+    playing: bool,
+    sample_rate: f32,
+}
+
+impl From<SampleModuleV0> for SampleModule {
+    fn from(other: SampleModuleV0) -> Self {
+        let buf_size = other.buf.get().unwrap().len();
+        let wavebox = std::mem::take(&mut *other.wavebox.lock().unwrap());
+        Self {
+            id: other.id,
+            gate_in: other.gate_in,
+            cv_in: other.cv_in,
+            offset_in: None,
+            transition_detector: other.transition_detector,
+            pos: other.pos as f64,
+            direction: 1.0,
+            left_out: other.buf,
+            right_out: AudioBuffer::new(Some(buf_size)),
+            wavebox: Arc::new(Mutex::new(WaveBox {
+                channels: vec![wavebox.samples],
+                sample_rate: wavebox.sample_rate,
+                new: true,
+                overview: vec![],
+            })),
+            load_error: Default::default(),
+            playing: false,
+            sample_rate: other.sample_rate,
+            start: 0.0,
+            end: 1.0,
+            loop_start: 0.0,
+            loop_end: 1.0,
+            mode: PlaybackMode::OneShot,
+            reverse: false,
+            interpolation: Interpolation::Nearest,
+            gate_mode: false,
+            release_sec: 0.05,
+            releasing: false,
+            release_gain: 1.0,
+            dragging: None,
+            ui_dirty: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_with_ramp(len: usize) -> SampleModule {
+        let audio_config = AudioConfig {
+            sample_rate: 100,
+            buffer_size: 8,
+            channels: 2,
+        };
+        let module = SampleModule::new(&audio_config);
+        {
+            let mut wavebox = module.wavebox.lock().unwrap();
+            wavebox.channels = vec![(0..len).map(|s| s as ControlVoltage).collect()];
+            wavebox.sample_rate = 100.0;
+        }
+        module
+    }
+
+    fn play(module: &mut SampleModule, steps: usize) -> Vec<f64> {
+        let region = module.region(module.wavebox.lock().unwrap().len());
+        module.trigger(&region, 0.0);
+        (0..steps)
+            .map(|_| {
+                let pos = module.pos;
+                module.advance(&region, 1.0);
+                pos
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loops_and_ping_pongs() {
+        let mut module = module_with_ramp(10);
+        module.mode = PlaybackMode::Loop;
+        module.loop_start = 0.5;
+        module.loop_end = 0.8;
+        assert_eq!(
+            play(&mut module, 10),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 5.0, 6.0]
+        );
+        assert!(module.playing);
+
+        module.mode = PlaybackMode::PingPong;
+        assert_eq!(
+            play(&mut module, 11),
+            vec![0.0, 1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 6.0, 5.0, 6.0]
+        );
+        assert_eq!(play(&mut module, 14)[11..], [7.0, 6.0, 5.0]);
+    }
+
+    #[test]
+    fn reverse_one_shot_stops_at_start() {
+        let mut module = module_with_ramp(4);
+        module.reverse = true;
+        assert_eq!(play(&mut module, 4), vec![3.0, 2.0, 1.0, 0.0]);
+        assert!(!module.playing);
+    }
 }
@@ -1,13 +1,240 @@
+use super::midi::{MidiFile, MidiLoader, MidiNote, save_midi};
+use super::midi_learn::learnable;
+use super::tuning::{Tuning, TuningLoader, is_black_key, tuning_ui};
 use super::{
-    AudioBuffer, AudioConfig, ControlVoltage, SharedSynthModule, SynthModule, TransitionDetector,
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SeededRng, SharedSynthModule,
+    SynthModule, TransitionDetector, seed_ui,
 };
 use egui::{self};
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::any::Any;
 
+const OCTAVES_PARAM: &str = "octaves";
+
 const GRID_CELL_SIZE: f32 = 7.0;
 const GRID_CELL_PADDING: f32 = 1.0;
+/// Most sub-triggers a step can be split into
+const MAX_RATCHETS: u8 = 8;
+/// Latest a step can be delayed, as a fraction of a step
+const MAX_STEP_DELAY: f32 = 0.75;
+const MAX_OCTAVES: u8 = 4;
+const MAX_DIVISION: u8 = 16;
+
+/// Id of a pattern lane's parameter, such as "division 0"
+fn lane_param(name: &str, row: usize) -> String {
+    format!("{name} {row}")
+}
+
+/// A step which plays, shared by both sequencers
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub struct Step {
+    /// Hold the gate into the next step
+    slide: bool,
+    velocity: ControlVoltage,
+    /// Chance of the step playing each time it comes around
+    probability: f32,
+    /// Number of triggers within the step
+    ratchets: u8,
+    /// Glide the pitch into this step, for pitched sequencers
+    glide: bool,
+    /// How late the step starts, as a fraction of a step. Steps can only be
+    /// late as the step input isn't known in advance.
+    delay: f32,
+}
+
+impl Step {
+    fn new(slide: bool) -> Self {
+        Self {
+            slide,
+            velocity: 1.0,
+            probability: 1.0,
+            ratchets: 1,
+            glide: false,
+            delay: 0.0,
+        }
+    }
+
+    /// Whether the step plays this time around
+    fn roll(&self, rng: &mut SeededRng) -> bool {
+        self.probability >= 1.0 || rng.next_f32() < self.probability
+    }
+
+    /// Colour of the step's cell in a grid, fading out with lower velocity
+    fn color(&self) -> egui::Color32 {
+        let full = if self.slide {
+            egui::Color32::BLACK
+        } else {
+            egui::Color32::BLUE
+        };
+        let faded = egui::Color32::LIGHT_GRAY;
+        let mix =
+            |a: u8, b: u8| (a as f32 * self.velocity + b as f32 * (1.0 - self.velocity)) as u8;
+        egui::Color32::from_rgb(
+            mix(full.r(), faded.r()),
+            mix(full.g(), faded.g()),
+            mix(full.b(), faded.b()),
+        )
+    }
+
+    /// Editor for a selected step
+    fn ui(&mut self, ui: &mut egui::Ui, pitched: bool) {
+        ui.horizontal(|ui| {
+            ui.add(egui::Slider::new(&mut self.velocity, 0.0..=1.0).text("Velocity"));
+            ui.add(egui::Slider::new(&mut self.probability, 0.0..=1.0).text("Probability"));
+        });
+        ui.horizontal(|ui| {
+            ui.label("Ratchets: ");
+            ui.add(egui::DragValue::new(&mut self.ratchets).range(1..=MAX_RATCHETS));
+            ui.add(egui::Slider::new(&mut self.delay, 0.0..=MAX_STEP_DELAY).text("Delay"));
+            ui.checkbox(&mut self.slide, "Slide");
+            if pitched {
+                ui.checkbox(&mut self.glide, "Glide");
+            }
+        });
+    }
+}
+
+/// Measures the time between step pulses, so steps can be delayed and split
+/// into ratchets.
+#[derive(Default, Clone)]
+struct StepClock {
+    /// Samples since the last step pulse
+    elapsed: u32,
+    /// Samples between the last two step pulses, 0 until measured
+    period: u32,
+}
+
+impl StepClock {
+    fn step(&mut self) {
+        if self.elapsed > 0 {
+            self.period = self.elapsed;
+        }
+        self.elapsed = 0;
+    }
+
+    fn tick(&mut self) {
+        self.elapsed = self.elapsed.saturating_add(1);
+    }
+
+    /// Gate for a step. Plain steps follow the step input, as do all steps
+    /// until the time between steps is known.
+    fn gate(&self, step: &Step, step_in: ControlVoltage) -> ControlVoltage {
+        if step.slide {
+            return 1.0;
+        }
+        if (step.ratchets <= 1 && step.delay <= 0.0) || self.period == 0 {
+            return step_in;
+        }
+        let period = self.period as f32;
+        let start = step.delay * period;
+        let ratchets = step.ratchets.max(1) as f32;
+        let sub = (period - start) / ratchets;
+        let since_start = self.elapsed as f32 - start;
+        if since_start < 0.0 || since_start >= sub * ratchets {
+            return 0.0;
+        }
+        if since_start % sub < sub / 2.0 {
+            1.0
+        } else {
+            0.0
+        }
+    }
+
+    /// Progress of a glide, which takes half a step
+    fn glide(&self) -> f32 {
+        if self.period == 0 {
+            1.0
+        } else {
+            (self.elapsed as f32 * 2.0 / self.period as f32).min(1.0)
+        }
+    }
+}
+
+fn selected_outline(ui: &egui::Ui, rect: egui::Rect) {
+    ui.painter()
+        .rect_stroke(rect, 1.0, egui::Stroke::new(1.0, egui::Color32::YELLOW));
+}
+
+const RHYTHM_CLIPBOARD: &str = "rhythm clipboard";
+
+/// Copy a rhythm for pasting into a pattern sequencer lane, as a hit or not
+/// for each step
+pub fn copy_rhythm(ctx: &egui::Context, rhythm: Vec<bool>) {
+    ctx.data_mut(|data| data.insert_temp(egui::Id::new(RHYTHM_CLIPBOARD), rhythm));
+}
+
+fn copied_rhythm(ctx: &egui::Context) -> Option<Vec<bool>> {
+    ctx.data(|data| data.get_temp(egui::Id::new(RHYTHM_CLIPBOARD)))
+}
+
+const NOTES_CLIPBOARD: &str = "notes clipboard";
+
+/// Notes copied from a grid sequencer, as the pitch of each step which plays
+pub fn copied_notes(ctx: &egui::Context) -> Option<Vec<ControlVoltage>> {
+    ctx.data(|data| data.get_temp(egui::Id::new(NOTES_CLIPBOARD)))
+}
+
+/// General MIDI drum keys for the pattern sequencer lanes: kick, snare, closed
+/// and open hi-hat, clap, low and high tom, crash
+const DRUM_KEYS: [u8; 8] = [36, 38, 42, 46, 39, 45, 50, 49];
+const DRUM_CHANNEL: u8 = 9;
+
+/// First step of a MIDI note and the steps it covers, sliding into each other
+/// so a long note is held
+fn midi_note_steps(note: &MidiNote, step_ticks: u32) -> (usize, Vec<Step>) {
+    let first = (note.start as f32 / step_ticks as f32).round() as usize;
+    let len = ((note.length as f32 / step_ticks as f32).round() as usize).max(1);
+    let steps = (0..len)
+        .map(|idx| Step {
+            velocity: note.velocity as f32 / 127.0,
+            ..Step::new(idx + 1 < len)
+        })
+        .collect();
+    (first, steps)
+}
+
+/// Steps needed for the notes of a MIDI file, in whole beats
+fn midi_len(file: &MidiFile, step_ticks: u32) -> usize {
+    let steps = file
+        .notes
+        .iter()
+        .map(|note| (note.start + note.length).div_ceil(step_ticks) as usize)
+        .max()
+        .unwrap_or(1);
+    (steps.div_ceil(4) * 4).clamp(4, 64)
+}
+
+/// MIDI note for a run of steps from `first`, sliding into each other up to
+/// `last`. The last step is taken to last half a step unless it slides.
+fn steps_midi_note(
+    steps: &[Option<Step>],
+    first: usize,
+    last: usize,
+    step_ticks: u32,
+    channel: u8,
+    key: u8,
+) -> MidiNote {
+    let velocity = steps[first].map_or(1.0, |step| step.velocity);
+    let held = steps[last].is_some_and(|step| step.slide);
+    MidiNote {
+        channel,
+        key,
+        velocity: (velocity * 127.0).round().clamp(1.0, 127.0) as u8,
+        start: first as u32 * step_ticks,
+        length: (last - first) as u32 * step_ticks + if held { step_ticks } else { step_ticks / 2 },
+    }
+}
+
+/// Last step of the run of sliding steps from `first`, which `same` says
+/// belong to the same note
+fn slide_end(steps: &[Option<Step>], first: usize, same: impl Fn(usize) -> bool) -> usize {
+    let mut last = first;
+    while steps[last].is_some_and(|step| step.slide) && last + 1 < steps.len() && same(last + 1) {
+        last += 1;
+    }
+    last
+}
 
 #[derive(Serialize, Deserialize, Clone)]
 pub struct GridSequencerModule {
@@ -15,9 +242,11 @@ pub struct GridSequencerModule {
     cv_out: AudioBuffer,
     gate_out: AudioBuffer,
     sync_out: AudioBuffer,
-    sequence: Vec<Option<(u16, bool)>>,
+    velocity_out: AudioBuffer,
+    sequence: Vec<Option<(u16, Step)>>,
     octaves: u8,
-    steps_per_octave: u16,
+    /// Rows are the keys of the tuning, starting from its root
+    tuning: Tuning,
     #[serde(skip)]
     step_in: Option<(SharedSynthModule, u8)>,
     #[serde(skip)]
@@ -26,7 +255,24 @@ pub struct GridSequencerModule {
     transition_detector: TransitionDetector,
     sync_transition_detector: TransitionDetector,
     last: ControlVoltage,
+    velocity: ControlVoltage,
+    /// Rolls the step probabilities, restarted by sync
+    rng: SeededRng,
     ui_dirty: bool,
+    #[serde(skip)]
+    loader: TuningLoader,
+    #[serde(skip)]
+    midi_loader: MidiLoader,
+    #[serde(skip)]
+    clock: StepClock,
+    /// The current step lost its roll of the dice
+    #[serde(skip)]
+    skipped: bool,
+    #[serde(skip)]
+    glide_from: ControlVoltage,
+    /// Column of the step being edited
+    #[serde(skip)]
+    selected: Option<usize>,
 }
 
 impl GridSequencerModule {
@@ -36,32 +282,112 @@ impl GridSequencerModule {
             cv_out: AudioBuffer::new(Some(audio_config.buffer_size)),
             gate_out: AudioBuffer::new(Some(audio_config.buffer_size)),
             sync_out: AudioBuffer::new(Some(audio_config.buffer_size)),
+            velocity_out: AudioBuffer::new(Some(audio_config.buffer_size)),
             octaves: 2,
             sequence: vec![None; 64],
             step_in: None,
             sync_in: None,
             current_step: 0,
-            steps_per_octave: 12,
+            tuning: Tuning::default(),
             transition_detector: TransitionDetector::new(),
             sync_transition_detector: TransitionDetector::new(),
             last: 0.0,
+            velocity: 0.0,
+            rng: SeededRng::new(rand::random()),
             ui_dirty: false,
+            loader: TuningLoader::default(),
+            midi_loader: MidiLoader::default(),
+            clock: StepClock::default(),
+            skipped: false,
+            glide_from: 0.0,
+            selected: None,
         }
     }
 
     pub fn get_name() -> String {
         "Grid Sequencer".to_string()
     }
-}
 
-fn is_black_key(note: u16) -> bool {
-    match note % 12 {
-        1 => true,
-        3 => true,
-        6 => true,
-        8 => true,
-        10 => true,
-        _ => false,
+    /// Row nearest a pitch, or none for pitches off the grid
+    fn pitch_row(&self, pitch: f64) -> Option<u16> {
+        let (lowest, highest) = self.pitch_range()?;
+        // Allow up to a quarter tone off the ends
+        if pitch < lowest - 1.0 / 24.0 || pitch > highest + 1.0 / 24.0 {
+            return None;
+        }
+        let rows = self.octaves as u16 * self.tuning.keys_per_period() as u16;
+        (0..rows)
+            .filter_map(|row| Some((row, self.tuning.key_pitch(row.into())?)))
+            .min_by(|a, b| (a.1 - pitch).abs().total_cmp(&(b.1 - pitch).abs()))
+            .map(|(row, _)| row)
+    }
+
+    /// Lowest and highest pitches on the grid
+    fn pitch_range(&self) -> Option<(f64, f64)> {
+        let rows = self.octaves as u16 * self.tuning.keys_per_period() as u16;
+        let pitches = (0..rows).filter_map(|row| self.tuning.key_pitch(row.into()));
+        pitches.minmax().into_option()
+    }
+
+    /// Replace the sequence with the notes of a MIDI file, a step to a
+    /// sixteenth note, taking A4 as 0V. The notes are moved by octaves so the
+    /// lowest is on the grid. Where notes overlap the highest is kept.
+    fn import_midi(&mut self, file: &MidiFile) {
+        let step_ticks = file.step_ticks();
+        self.sequence = vec![None; midi_len(file, step_ticks)];
+        let pitch = |key: u8| (key as f64 - 69.0) / 12.0;
+        let lowest = file
+            .notes
+            .iter()
+            .map(|note| pitch(note.key))
+            .reduce(f64::min);
+        let (Some(lowest), Some((grid_lowest, _))) = (lowest, self.pitch_range()) else {
+            return;
+        };
+        let octaves = (grid_lowest - lowest - 1.0 / 24.0).ceil();
+        let notes = file
+            .notes
+            .iter()
+            .sorted_by_key(|note| (note.start, std::cmp::Reverse(note.key)));
+        for note in notes {
+            let Some(row) = self.pitch_row(pitch(note.key) + octaves) else {
+                continue;
+            };
+            let (first, steps) = midi_note_steps(note, step_ticks);
+            for (idx, step) in (first..self.sequence.len()).zip(steps) {
+                if self.sequence[idx].is_some() {
+                    break;
+                }
+                self.sequence[idx] = Some((row, step));
+            }
+        }
+    }
+
+    fn export_midi(&self) -> MidiFile {
+        let mut file = MidiFile::default();
+        let step_ticks = file.step_ticks();
+        let steps: Vec<_> = self
+            .sequence
+            .iter()
+            .map(|v| v.map(|(_, step)| step))
+            .collect();
+        let mut idx = 0;
+        while idx < self.sequence.len() {
+            let Some((row, _)) = self.sequence[idx] else {
+                idx += 1;
+                continue;
+            };
+            let last = slide_end(&steps, idx, |next| {
+                self.sequence[next].is_some_and(|(next_row, _)| next_row == row)
+            });
+            if let Some(pitch) = self.tuning.key_pitch(row.into()) {
+                let key = (69.0 + pitch * 12.0).round().clamp(0.0, 127.0) as u8;
+                file.notes
+                    .push(steps_midi_note(&steps, idx, last, step_ticks, 0, key));
+            }
+            idx = last + 1;
+        }
+        file
     }
 }
 
@@ -70,14 +396,39 @@ impl SynthModule for GridSequencerModule {
         self
     }
 
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![(
+            Param::new(OCTAVES_PARAM, "Octaves", 1.0..=MAX_OCTAVES as f32),
+            ParamValue::U8(&mut self.octaves),
+        )]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        (idx == 0).then_some(ParamValue::U8(&mut self.octaves))
+    }
+
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
         self.cv_out.resize(audio_config.buffer_size);
         self.gate_out.resize(audio_config.buffer_size);
         self.sync_out.resize(audio_config.buffer_size);
+        self.velocity_out.resize(audio_config.buffer_size);
     }
 
     fn ui(&mut self, ui: &mut egui::Ui) {
         ui.vertical(|ui| {
+            tuning_ui(ui, &mut self.tuning, &self.loader);
+            if let Some(file) = self.midi_loader.take() {
+                self.import_midi(&file);
+            }
+            ui.horizontal(|ui| {
+                if ui.button("Import MIDI...").clicked() {
+                    self.midi_loader.open();
+                }
+                if ui.button("Export MIDI...").clicked() {
+                    save_midi(&self.export_midi(), "Sequence");
+                }
+            });
+            seed_ui(ui, &mut self.rng);
             ui.horizontal(|ui| {
                 ui.label("Octaves: ");
                 ui.scope(|ui| {
@@ -88,12 +439,17 @@ impl SynthModule for GridSequencerModule {
                         self.octaves -= 1;
                     }
                 });
-                ui.label(self.octaves.to_string());
+                learnable(
+                    ui.label(self.octaves.to_string())
+                        .interact(egui::Sense::click()),
+                    &self.id,
+                    OCTAVES_PARAM,
+                );
                 ui.scope(|ui| {
-                    if self.octaves >= 4 {
+                    if self.octaves >= MAX_OCTAVES {
                         ui.disable();
                     }
-                    if ui.button("+").clicked() && self.octaves < 4 {
+                    if ui.button("+").clicked() && self.octaves < MAX_OCTAVES {
                         self.octaves += 1;
                     }
                 });
@@ -131,9 +487,25 @@ impl SynthModule for GridSequencerModule {
                         self.sequence.resize(self.sequence.len() * 2, None);
                     }
                 });
+                if ui
+                    .button("Copy notes")
+                    .on_hover_text("Copy the notes, for a Markov sequencer to learn from")
+                    .clicked()
+                {
+                    let notes: Vec<_> = self
+                        .sequence
+                        .iter()
+                        .flatten()
+                        .filter_map(|(row, _)| self.tuning.key_pitch((*row).into()))
+                        .map(|pitch| pitch as ControlVoltage)
+                        .collect();
+                    ui.ctx()
+                        .data_mut(|data| data.insert_temp(egui::Id::new(NOTES_CLIPBOARD), notes));
+                }
             });
         });
-        let num_rows = self.octaves as u16 * self.steps_per_octave;
+        let keys_per_period = self.tuning.keys_per_period() as u16;
+        let num_rows = self.octaves as u16 * keys_per_period;
         let (id, space_rect) = ui.allocate_space(
             [
                 self.sequence.len() as f32 * (GRID_CELL_SIZE + GRID_CELL_PADDING),
@@ -141,7 +513,10 @@ impl SynthModule for GridSequencerModule {
             ]
             .into(),
         );
-        let clicked = ui.interact(space_rect, id, egui::Sense::click()).clicked();
+        let response = ui.interact(space_rect, id, egui::Sense::click());
+        let clicked = response.clicked();
+        let secondary_clicked = response.secondary_clicked();
+        let mut hovered_row: Option<u16> = None;
         for row in (0..num_rows).rev() {
             for col in 0..self.sequence.len() {
                 let top_left = egui::Pos2::new(
@@ -157,33 +532,59 @@ impl SynthModule for GridSequencerModule {
                 if col % 4 == 0 {
                     color = egui::Color32::GRAY;
                 }
-                if is_black_key(row) {
+                if is_black_key(&self.tuning, row.into()) {
                     color = egui::Color32::DARK_GRAY;
                 }
-                if row % self.steps_per_octave == 0 {
+                if row % keys_per_period == 0 {
                     color = egui::Color32::YELLOW;
                 }
+                if self.tuning.key_pitch(row.into()).is_none() {
+                    color = egui::Color32::from_gray(40);
+                }
                 if usize::from(self.current_step) == col {
                     color = egui::Color32::RED;
                 }
-                if self.sequence[col] == Some((row, true)) {
-                    color = egui::Color32::BLACK;
-                }
-                if self.sequence[col] == Some((row, false)) {
-                    color = egui::Color32::BLUE;
+                let step = match self.sequence[col] {
+                    Some((step_row, step)) if step_row == row => Some(step),
+                    _ => None,
+                };
+                if let Some(step) = step {
+                    color = step.color();
                 }
                 ui.painter().rect_filled(rect, 1.0, color);
+                if step.is_some() && self.selected == Some(col) {
+                    selected_outline(ui, rect);
+                }
+                if ui.rect_contains_pointer(rect) {
+                    hovered_row = Some(row);
+                }
                 if clicked && ui.rect_contains_pointer(rect) {
-                    if self.sequence[col] == Some((row, true)) {
-                        self.sequence[col] = Some((row, false));
-                    } else if self.sequence[col] == Some((row, false)) {
-                        self.sequence[col] = None;
-                    } else {
-                        self.sequence[col] = Some((row, true));
-                    }
+                    self.sequence[col] = match step {
+                        Some(step) if step.slide => Some((
+                            row,
+                            Step {
+                                slide: false,
+                                ..step
+                            },
+                        )),
+                        Some(_) => None,
+                        None => Some((row, Step::new(true))),
+                    };
+                }
+                if secondary_clicked && ui.rect_contains_pointer(rect) && step.is_some() {
+                    self.selected = Some(col);
                 }
             }
         }
+        if let Some(row) = hovered_row {
+            response.on_hover_text_at_pointer(self.tuning.key_label(row.into()));
+        }
+        match self.selected.and_then(|col| self.sequence.get_mut(col)) {
+            Some(Some((_, step))) => step.ui(ui, true),
+            _ => {
+                ui.label("Right-click a step to edit it");
+            }
+        }
         self.ui_dirty = false;
     }
 
@@ -200,9 +601,10 @@ impl SynthModule for GridSequencerModule {
                         self.cv_out.clone(),
                         self.gate_out.clone(),
                         self.sync_out.clone(),
+                        self.velocity_out.clone(),
                     ],
                     |bufs| {
-                        let (cv_out, gate_out, sync_out) = bufs
+                        let (cv_out, gate_out, sync_out, velocity_out) = bufs
                             .into_iter()
                             .map(|b| b.unwrap())
                             .collect_tuple()
@@ -216,28 +618,49 @@ impl SynthModule for GridSequencerModule {
                                 Some(v) => &v[idx],
                                 None => &0.0,
                             };
+                            let mut new_step = false;
                             if self.transition_detector.is_transition(step_in) {
                                 self.current_step += 1;
+                                self.clock.step();
+                                new_step = true;
                                 self.ui_dirty = true;
                             }
                             if self.sync_transition_detector.is_transition(sync_in) {
                                 self.current_step = 0;
+                                self.rng.reset();
+                                new_step = true;
                             }
                             let mut current_step: usize = self.current_step.into();
                             if current_step >= self.sequence.len() {
                                 self.current_step = 0;
                                 current_step = 0;
                             }
-                            (cv_out[idx], gate_out[idx]) = match self.sequence[current_step] {
-                                Some((val, hold)) => (
-                                    val as ControlVoltage
-                                        * (1.0 / self.steps_per_octave as ControlVoltage),
-                                    if hold { 1.0 } else { *step_in },
-                                ),
-                                None => (self.last, 0.0),
+                            let step = self.sequence[current_step].and_then(|(val, step)| {
+                                Some((self.tuning.key_pitch(val.into())?, step))
+                            });
+                            if new_step {
+                                self.skipped =
+                                    !step.is_some_and(|(_, step)| step.roll(&mut self.rng));
+                                self.glide_from = self.last;
+                            }
+                            (cv_out[idx], gate_out[idx]) = match step {
+                                Some((pitch, step)) if !self.skipped => {
+                                    self.velocity = step.velocity;
+                                    let pitch = pitch as ControlVoltage;
+                                    let cv = if step.glide {
+                                        self.glide_from
+                                            + (pitch - self.glide_from) * self.clock.glide()
+                                    } else {
+                                        pitch
+                                    };
+                                    (cv, self.clock.gate(&step, *step_in))
+                                }
+                                _ => (self.last, 0.0),
                             };
+                            velocity_out[idx] = self.velocity;
                             sync_out[idx] = if current_step == 0 { 1.0 } else { 0.0 };
                             self.last = cv_out[idx];
+                            self.clock.tick();
                         }
                     },
                 );
@@ -293,6 +716,7 @@ impl SynthModule for GridSequencerModule {
             0 => Ok(self.cv_out.clone()),
             1 => Ok(self.gate_out.clone()),
             2 => Ok(self.sync_out.clone()),
+            3 => Ok(self.velocity_out.clone()),
             _ => Err(()),
         }
     }
@@ -302,6 +726,7 @@ impl SynthModule for GridSequencerModule {
             0 => Ok(Some("CV".to_string())),
             1 => Ok(Some("Gate".to_string())),
             2 => Ok(Some("Sync".to_string())),
+            3 => Ok(Some("Velocity".to_string())),
             _ => Err(()),
         }
     }
@@ -311,7 +736,7 @@ impl SynthModule for GridSequencerModule {
     }
 
     fn get_num_outputs(&self) -> u8 {
-        3
+        4
     }
 
     fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
@@ -333,43 +758,229 @@ impl SynthModule for GridSequencerModule {
     }
 }
 
+/// Order a pattern sequencer lane plays its steps in
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Forward,
+    Reverse,
+    PingPong,
+    Random,
+}
+
+impl Direction {
+    const ALL: [Direction; 4] = [
+        Direction::Forward,
+        Direction::Reverse,
+        Direction::PingPong,
+        Direction::Random,
+    ];
+
+    fn label(&self) -> &'static str {
+        match self {
+            Direction::Forward => "Forward",
+            Direction::Reverse => "Reverse",
+            Direction::PingPong => "Ping-pong",
+            Direction::Random => "Random",
+        }
+    }
+}
+
+/// One row of the pattern sequencer, with its own length, direction and
+/// clock division
+#[derive(Serialize, Deserialize, Clone)]
+struct Lane {
+    steps: Vec<Option<Step>>,
+    direction: Direction,
+    /// Step pulses per step of this lane
+    division: u8,
+    position: usize,
+    /// Step pulses since the lane last moved
+    count: u8,
+    /// Steps played since the cycle started
+    played: usize,
+    /// Heading backwards, when playing ping-pong
+    reversed: bool,
+    velocity: ControlVoltage,
+    /// The cycle started on the current step
+    eoc: bool,
+    #[serde(skip)]
+    clock: StepClock,
+    /// The current step lost its roll of the dice
+    #[serde(skip)]
+    skipped: bool,
+}
+
+impl Lane {
+    fn new(steps: Vec<Option<Step>>) -> Self {
+        Self {
+            steps,
+            direction: Direction::Forward,
+            division: 1,
+            position: 0,
+            count: 0,
+            played: 0,
+            reversed: false,
+            velocity: 0.0,
+            eoc: false,
+            clock: StepClock::default(),
+            skipped: false,
+        }
+    }
+
+    /// Steps before the lane is back where it started
+    fn cycle_len(&self) -> usize {
+        match self.direction {
+            Direction::PingPong if self.steps.len() > 1 => (self.steps.len() - 1) * 2,
+            _ => self.steps.len(),
+        }
+    }
+
+    fn reset(&mut self) {
+        self.position = match self.direction {
+            Direction::Reverse => self.steps.len() - 1,
+            _ => 0,
+        };
+        self.count = 0;
+        self.played = 0;
+        self.reversed = false;
+        self.eoc = false;
+    }
+
+    /// Count a step pulse, returning whether the lane moved to a new step
+    fn advance(&mut self, rng: &mut SeededRng) -> bool {
+        let len = self.steps.len();
+        // Lanes from old patches may have no steps at all
+        if len == 0 {
+            return false;
+        }
+        self.count += 1;
+        if self.count < self.division {
+            return false;
+        }
+        self.count = 0;
+        let position = self.position.min(len - 1);
+        self.position = match self.direction {
+            Direction::Forward => (position + 1) % len,
+            Direction::Reverse => (position + len - 1) % len,
+            Direction::PingPong if len == 1 => 0,
+            Direction::PingPong => {
+                if position == 0 {
+                    self.reversed = false;
+                } else if position == len - 1 {
+                    self.reversed = true;
+                }
+                if self.reversed {
+                    position - 1
+                } else {
+                    position + 1
+                }
+            }
+            Direction::Random => (rng.next_u64() % len as u64) as usize,
+        };
+        self.played += 1;
+        if self.played >= self.cycle_len() {
+            self.played = 0;
+        }
+        self.eoc = self.played == 0;
+        self.clock.step();
+        true
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct PatternSequencerModule {
     id: String,
     gate_outs: Vec<AudioBuffer>,
     sync_out: AudioBuffer,
-    sequence: Vec<Vec<Option<bool>>>,
+    velocity_outs: Vec<AudioBuffer>,
+    eoc_outs: Vec<AudioBuffer>,
+    lanes: Vec<Lane>,
     #[serde(skip)]
     step_in: Option<(SharedSynthModule, u8)>,
     #[serde(skip)]
     sync_in: Option<(SharedSynthModule, u8)>,
-    current_step: u16,
     transition_detector: TransitionDetector,
     sync_transition_detector: TransitionDetector,
+    /// Shared by the lanes for random directions and step probabilities,
+    /// restarted by sync
+    rng: SeededRng,
     ui_dirty: bool,
+    /// Lane and column of the step being edited
+    #[serde(skip)]
+    selected: Option<(usize, usize)>,
+    #[serde(skip)]
+    midi_loader: MidiLoader,
 }
 
 impl PatternSequencerModule {
     pub fn new(audio_config: &AudioConfig) -> Self {
+        let buffers = || {
+            (0..8)
+                .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
+                .collect()
+        };
         Self {
             id: uuid::Uuid::new_v4().into(),
-            gate_outs: (0..8)
-                .map(|_| AudioBuffer::new(Some(audio_config.buffer_size)))
-                .collect(),
+            gate_outs: buffers(),
             sync_out: AudioBuffer::new(Some(audio_config.buffer_size)),
-            sequence: vec![vec![None; 64]; 8],
+            velocity_outs: buffers(),
+            eoc_outs: buffers(),
+            lanes: vec![Lane::new(vec![None; 64]); 8],
             step_in: None,
             sync_in: None,
-            current_step: 0,
             transition_detector: TransitionDetector::new(),
             sync_transition_detector: TransitionDetector::new(),
+            rng: SeededRng::new(rand::random()),
             ui_dirty: false,
+            selected: None,
+            midi_loader: MidiLoader::default(),
         }
     }
 
     pub fn get_name() -> String {
         "Pattern Sequencer".to_string()
     }
+
+    /// Replace the lanes with the drum notes of a MIDI file, by the keys in
+    /// `DRUM_KEYS`. Each lane keeps its clock division.
+    fn import_midi(&mut self, file: &MidiFile) {
+        for (lane, key) in self.lanes.iter_mut().zip(DRUM_KEYS) {
+            let step_ticks = file.step_ticks() * lane.division as u32;
+            lane.steps = vec![None; midi_len(file, step_ticks)];
+            for note in file.notes.iter().filter(|note| note.key == key) {
+                let (first, steps) = midi_note_steps(note, step_ticks);
+                for (idx, step) in (first..lane.steps.len()).zip(steps) {
+                    lane.steps[idx] = Some(step);
+                }
+            }
+        }
+    }
+
+    fn export_midi(&self) -> MidiFile {
+        let mut file = MidiFile::default();
+        for (lane, key) in self.lanes.iter().zip(DRUM_KEYS) {
+            let step_ticks = file.step_ticks() * lane.division as u32;
+            let mut idx = 0;
+            while idx < lane.steps.len() {
+                if lane.steps[idx].is_none() {
+                    idx += 1;
+                    continue;
+                }
+                let last = slide_end(&lane.steps, idx, |next| lane.steps[next].is_some());
+                file.notes.push(steps_midi_note(
+                    &lane.steps,
+                    idx,
+                    last,
+                    step_ticks,
+                    DRUM_CHANNEL,
+                    key,
+                ));
+                idx = last + 1;
+            }
+        }
+        file.notes.sort_by_key(|note| (note.start, note.key));
+        file
+    }
 }
 
 impl SynthModule for PatternSequencerModule {
@@ -377,71 +988,171 @@ impl SynthModule for PatternSequencerModule {
         self
     }
 
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        self.lanes
+            .iter_mut()
+            .enumerate()
+            .map(|(row, lane)| {
+                (
+                    Param::new(
+                        &lane_param("division", row),
+                        &format!("Division {row}"),
+                        1.0..=MAX_DIVISION as f32,
+                    ),
+                    ParamValue::U8(&mut lane.division),
+                )
+            })
+            .collect()
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        let lane = self.lanes.get_mut(idx)?;
+        Some(ParamValue::U8(&mut lane.division))
+    }
+
     fn set_audio_config(&mut self, audio_config: &AudioConfig) {
-        for out in self.gate_outs.iter_mut() {
+        for out in self
+            .gate_outs
+            .iter_mut()
+            .chain(self.velocity_outs.iter_mut())
+            .chain(self.eoc_outs.iter_mut())
+        {
             out.resize(audio_config.buffer_size)
         }
         self.sync_out.resize(audio_config.buffer_size);
     }
 
     fn ui(&mut self, ui: &mut egui::Ui) {
+        if let Some(file) = self.midi_loader.take() {
+            self.import_midi(&file);
+        }
+        let shortest = self.lanes.iter().map(|l| l.steps.len()).min().unwrap();
+        let longest = self.lanes.iter().map(|l| l.steps.len()).max().unwrap();
         ui.vertical(|ui| {
             ui.horizontal(|ui| {
                 ui.label("Steps: ");
                 ui.scope(|ui| {
-                    if self.sequence[0].len() % 2 == 1 && self.sequence[0].len() <= 2 {
+                    if shortest % 2 == 1 && shortest <= 2 {
                         ui.disable()
                     }
                     if ui.button("/2").clicked() {
-                        for channel in self.sequence.iter_mut() {
-                            channel.resize(channel.len() / 2, None);
+                        for lane in self.lanes.iter_mut() {
+                            lane.steps.resize(lane.steps.len() / 2, None);
                         }
                     }
                 });
                 ui.scope(|ui| {
-                    if self.sequence[0].len() <= 2 {
+                    if shortest <= 2 {
                         ui.disable()
                     }
                     if ui.button("-").clicked() {
-                        for channel in self.sequence.iter_mut() {
-                            channel.resize(channel.len() - 1, None);
+                        for lane in self.lanes.iter_mut() {
+                            lane.steps.resize(lane.steps.len() - 1, None);
                         }
                     }
                 });
-                ui.label(self.sequence[0].len().to_string());
+                if shortest == longest {
+                    ui.label(longest.to_string());
+                } else {
+                    ui.label(format!("{shortest}-{longest}"));
+                }
                 ui.scope(|ui| {
-                    if self.sequence[0].len() >= 64 {
+                    if longest >= 64 {
                         ui.disable()
                     }
                     if ui.button("+").clicked() {
-                        for channel in self.sequence.iter_mut() {
-                            channel.resize(channel.len() + 1, None);
+                        for lane in self.lanes.iter_mut() {
+                            lane.steps.resize(lane.steps.len() + 1, None);
                         }
                     }
                 });
                 ui.scope(|ui| {
-                    if self.sequence[0].len() > 32 {
+                    if longest > 32 {
                         ui.disable()
                     }
                     if ui.button("x2").clicked() {
-                        for channel in self.sequence.iter_mut() {
-                            channel.resize(channel.len() * 2, None);
+                        for lane in self.lanes.iter_mut() {
+                            lane.steps.resize(lane.steps.len() * 2, None);
                         }
                     }
                 });
             });
+            ui.horizontal(|ui| {
+                if ui.button("Import MIDI...").clicked() {
+                    self.midi_loader.open();
+                }
+                if ui.button("Export MIDI...").clicked() {
+                    save_midi(&self.export_midi(), "Pattern");
+                }
+            });
+            seed_ui(ui, &mut self.rng);
+            ui.collapsing("Lanes", |ui| {
+                let copied = copied_rhythm(ui.ctx());
+                egui::Grid::new((&self.id, "lanes")).show(ui, |ui| {
+                    for (row, lane) in self.lanes.iter_mut().enumerate().rev() {
+                        ui.label(row.to_string());
+                        let mut len = lane.steps.len();
+                        if ui
+                            .add(
+                                egui::DragValue::new(&mut len)
+                                    .range(1..=64)
+                                    .suffix(" steps"),
+                            )
+                            .changed()
+                        {
+                            lane.steps.resize(len, None);
+                        }
+                        egui::ComboBox::from_id_source((&self.id, "direction", row))
+                            .selected_text(lane.direction.label())
+                            .show_ui(ui, |ui| {
+                                for direction in Direction::ALL {
+                                    ui.selectable_value(
+                                        &mut lane.direction,
+                                        direction,
+                                        direction.label(),
+                                    );
+                                }
+                            });
+                        learnable(
+                            ui.add(
+                                egui::DragValue::new(&mut lane.division)
+                                    .range(1..=MAX_DIVISION)
+                                    .prefix("÷"),
+                            ),
+                            &self.id,
+                            &lane_param("division", row),
+                        );
+                        if let Some(rhythm) = &copied
+                            && ui
+                                .button("Paste")
+                                .on_hover_text("Replace the lane with the copied rhythm")
+                                .clicked()
+                        {
+                            lane.steps = rhythm
+                                .iter()
+                                .map(|hit| hit.then(|| Step::new(false)))
+                                .collect();
+                        }
+                        ui.end_row();
+                    }
+                });
+            });
         });
-        let num_rows = self.sequence.len();
+        let longest = self.lanes.iter().map(|l| l.steps.len()).max().unwrap();
+        let num_rows = self.lanes.len();
         let (id, space_rect) = ui.allocate_space(
             [
-                self.sequence[0].len() as f32 * (GRID_CELL_SIZE + GRID_CELL_PADDING),
+                longest as f32 * (GRID_CELL_SIZE + GRID_CELL_PADDING),
                 num_rows as f32 * (GRID_CELL_SIZE + GRID_CELL_PADDING),
             ]
             .into(),
         );
-        let clicked = ui.interact(space_rect, id, egui::Sense::click()).clicked();
-        for (row, seq) in self.sequence.iter_mut().enumerate().rev() {
-            for (col, val) in seq.iter_mut().enumerate() {
+        let response = ui.interact(space_rect, id, egui::Sense::click());
+        let clicked = response.clicked();
+        let secondary_clicked = response.secondary_clicked();
+        for (row, lane) in self.lanes.iter_mut().enumerate().rev() {
+            let position = lane.position;
+            for (col, val) in lane.steps.iter_mut().enumerate() {
                 let top_left = egui::Pos2::new(
                     space_rect.min.x + (col as f32 * (GRID_CELL_SIZE + GRID_CELL_PADDING)),
                     space_rect.min.y
@@ -455,25 +1166,38 @@ impl SynthModule for PatternSequencerModule {
                 if col % 4 == 0 {
                     color = egui::Color32::GRAY;
                 }
-                if usize::from(self.current_step) == col {
+                if position == col {
                     color = egui::Color32::RED;
                 }
-                if *val == Some(true) {
-                    color = egui::Color32::BLACK;
-                }
-                if *val == Some(false) {
-                    color = egui::Color32::BLUE;
+                if let Some(step) = val {
+                    color = step.color();
                 }
                 ui.painter().rect_filled(rect, 1.0, color);
+                if val.is_some() && self.selected == Some((row, col)) {
+                    selected_outline(ui, rect);
+                }
                 if clicked && ui.rect_contains_pointer(rect) {
-                    if *val == Some(true) {
-                        *val = Some(false);
-                    } else if *val == Some(false) {
-                        *val = None;
-                    } else {
-                        *val = Some(true);
-                    }
+                    *val = match val {
+                        Some(step) if step.slide => Some(Step {
+                            slide: false,
+                            ..*step
+                        }),
+                        Some(_) => None,
+                        None => Some(Step::new(true)),
+                    };
                 }
+                if secondary_clicked && ui.rect_contains_pointer(rect) && val.is_some() {
+                    self.selected = Some((row, col));
+                }
+            }
+        }
+        match self
+            .selected
+            .and_then(|(row, col)| self.lanes.get_mut(row)?.steps.get_mut(col))
+        {
+            Some(Some(step)) => step.ui(ui, false),
+            _ => {
+                ui.label("Right-click a step to edit it");
             }
         }
         self.ui_dirty = false;
@@ -487,10 +1211,20 @@ impl SynthModule for PatternSequencerModule {
             ],
             |bufs| {
                 let (step_in_buf, sync_in_buf) = bufs.into_iter().collect_tuple().unwrap();
-                AudioBuffer::with_write_many(self.gate_outs.clone(), |outputs| {
+                let buffers = self
+                    .gate_outs
+                    .iter()
+                    .chain(self.velocity_outs.iter())
+                    .chain(self.eoc_outs.iter())
+                    .cloned()
+                    .collect();
+                AudioBuffer::with_write_many(buffers, |outputs| {
                     self.sync_out.with_write(|sync_out| {
                         let sync_out = sync_out.unwrap();
                         let mut outputs: Vec<_> = outputs.into_iter().map(|o| o.unwrap()).collect();
+                        let (outputs, rest) = outputs.split_at_mut(self.gate_outs.len());
+                        let (velocity_outputs, eoc_outputs) =
+                            rest.split_at_mut(self.velocity_outs.len());
                         for idx in 0..outputs[0].len() {
                             let step_in = match step_in_buf {
                                 Some(v) => &v[idx],
@@ -500,31 +1234,43 @@ impl SynthModule for PatternSequencerModule {
                                 Some(v) => &v[idx],
                                 None => &0.0,
                             };
-                            if self.transition_detector.is_transition(step_in) {
-                                self.current_step += 1;
+                            let pulse = self.transition_detector.is_transition(step_in);
+                            if pulse {
                                 self.ui_dirty = true;
                             }
-                            if self.sync_transition_detector.is_transition(sync_in) {
-                                self.current_step = 0;
+                            let sync = self.sync_transition_detector.is_transition(sync_in);
+                            if sync {
+                                self.rng.reset();
                             }
-                            let mut current_step: usize = self.current_step.into();
-                            if current_step >= self.sequence[0].len() {
-                                self.current_step = 0;
-                                current_step = 0;
-                            }
-                            for (seq, out) in self.sequence.iter().zip(outputs.iter_mut()) {
-                                out[idx] = match seq[current_step] {
-                                    Some(v) => {
-                                        if v {
-                                            1.0
-                                        } else {
-                                            *step_in
-                                        }
+                            for (lane_idx, lane) in self.lanes.iter_mut().enumerate() {
+                                let mut new_step = pulse && lane.advance(&mut self.rng);
+                                if sync {
+                                    lane.reset();
+                                    new_step = true;
+                                }
+                                let step = lane.steps.get(lane.position).copied().flatten();
+                                if new_step {
+                                    lane.skipped =
+                                        !step.is_some_and(|step| step.roll(&mut self.rng));
+                                }
+                                // Only the pulse which moved a divided lane plays
+                                let step_in = if lane.count == 0 { *step_in } else { 0.0 };
+                                outputs[lane_idx][idx] = match step {
+                                    Some(step) if !lane.skipped => {
+                                        lane.velocity = step.velocity;
+                                        lane.clock.gate(&step, step_in)
                                     }
-                                    None => 0.0,
+                                    _ => 0.0,
                                 };
+                                velocity_outputs[lane_idx][idx] = lane.velocity;
+                                eoc_outputs[lane_idx][idx] = if lane.eoc { step_in } else { 0.0 };
+                                lane.clock.tick();
                             }
-                            sync_out[idx] = if current_step == 0 { 1.0 } else { 0.0 };
+                            sync_out[idx] = if self.lanes.iter().all(|lane| lane.played == 0) {
+                                1.0
+                            } else {
+                                0.0
+                            };
                         }
                     });
                 });
@@ -576,22 +1322,36 @@ impl SynthModule for PatternSequencerModule {
     }
 
     fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
-        if output_idx == self.gate_outs.len() as u8 {
+        let lanes = self.gate_outs.len() as u8;
+        if output_idx == lanes {
             return Ok(self.sync_out.clone());
         }
-        if output_idx < self.gate_outs.len() as u8 {
+        if output_idx < lanes {
             return Ok(self.gate_outs[output_idx as usize].clone());
         }
+        if output_idx < lanes * 2 + 1 {
+            return Ok(self.velocity_outs[(output_idx - lanes - 1) as usize].clone());
+        }
+        if output_idx < lanes * 3 + 1 {
+            return Ok(self.eoc_outs[(output_idx - lanes * 2 - 1) as usize].clone());
+        }
         Err(())
     }
 
     fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
-        if output_idx == self.gate_outs.len() as u8 {
+        let lanes = self.gate_outs.len() as u8;
+        if output_idx == lanes {
             return Ok(Some("Sync".to_string()));
         }
-        if output_idx < self.gate_outs.len() as u8 {
+        if output_idx < lanes {
             return Ok(Some(format!("{output_idx}")));
         }
+        if output_idx < lanes * 2 + 1 {
+            return Ok(Some(format!("Vel {}", output_idx - lanes - 1)));
+        }
+        if output_idx < lanes * 3 + 1 {
+            return Ok(Some(format!("EOC {}", output_idx - lanes * 2 - 1)));
+        }
         Err(())
     }
 
@@ -600,7 +1360,7 @@ impl SynthModule for PatternSequencerModule {
     }
 
     fn get_num_outputs(&self) -> u8 {
-        self.gate_outs.len() as u8 + 1
+        self.gate_outs.len() as u8 * 3 + 1
     }
 
     fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
@@ -644,7 +1404,103 @@ pub struct GridSequencerModuleV0 {
     ui_dirty: bool,
 }
 
-impl From<GridSequencerModuleV0> for GridSequencerModule {
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GridSequencerModuleV1 {
+    id: String,
+    cv_out: AudioBuffer,
+    gate_out: AudioBuffer,
+    sync_out: AudioBuffer,
+    sequence: Vec<Option<(u16, bool)>>,
+    octaves: u8,
+    steps_per_octave: u16,
+    #[serde(skip)]
+    step_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    sync_in: Option<(SharedSynthModule, u8)>,
+    current_step: u16,
+    transition_detector: TransitionDetector,
+    sync_transition_detector: TransitionDetector,
+    last: ControlVoltage,
+    ui_dirty: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct GridSequencerModuleV2 {
+    id: String,
+    cv_out: AudioBuffer,
+    gate_out: AudioBuffer,
+    sync_out: AudioBuffer,
+    sequence: Vec<Option<(u16, bool)>>,
+    octaves: u8,
+    tuning: Tuning,
+    #[serde(skip)]
+    step_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    sync_in: Option<(SharedSynthModule, u8)>,
+    current_step: u16,
+    transition_detector: TransitionDetector,
+    sync_transition_detector: TransitionDetector,
+    last: ControlVoltage,
+    ui_dirty: bool,
+}
+
+impl From<GridSequencerModuleV2> for GridSequencerModule {
+    fn from(item: GridSequencerModuleV2) -> Self {
+        let buf_size = item.cv_out.get().unwrap().len();
+        Self {
+            id: item.id,
+            cv_out: item.cv_out,
+            gate_out: item.gate_out,
+            sync_out: item.sync_out,
+            velocity_out: AudioBuffer::new(Some(buf_size)),
+            sequence: item
+                .sequence
+                .into_iter()
+                .map(|v| v.map(|(row, slide)| (row, Step::new(slide))))
+                .collect(),
+            octaves: item.octaves,
+            tuning: item.tuning,
+            step_in: item.step_in,
+            sync_in: item.sync_in,
+            current_step: item.current_step,
+            transition_detector: item.transition_detector,
+            sync_transition_detector: item.sync_transition_detector,
+            last: item.last,
+            velocity: 0.0,
+            rng: SeededRng::new(rand::random()),
+            ui_dirty: item.ui_dirty,
+            loader: TuningLoader::default(),
+            midi_loader: MidiLoader::default(),
+            clock: StepClock::default(),
+            skipped: false,
+            glide_from: 0.0,
+            selected: None,
+        }
+    }
+}
+
+impl From<GridSequencerModuleV1> for GridSequencerModuleV2 {
+    fn from(item: GridSequencerModuleV1) -> Self {
+        Self {
+            id: item.id,
+            cv_out: item.cv_out,
+            gate_out: item.gate_out,
+            sync_out: item.sync_out,
+            sequence: item.sequence,
+            octaves: item.octaves,
+            tuning: Tuning::equal_temperament(item.steps_per_octave.max(1).into()),
+            step_in: item.step_in,
+            sync_in: item.sync_in,
+            current_step: item.current_step,
+            transition_detector: item.transition_detector,
+            sync_transition_detector: item.sync_transition_detector,
+            last: item.last,
+            ui_dirty: item.ui_dirty,
+        }
+    }
+}
+
+impl From<GridSequencerModuleV0> for GridSequencerModuleV1 {
     fn from(item: GridSequencerModuleV0) -> Self {
         Self {
             id: item.id,
@@ -668,3 +1524,361 @@ impl From<GridSequencerModuleV0> for GridSequencerModule {
         }
     }
 }
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PatternSequencerModuleV0 {
+    id: String,
+    gate_outs: Vec<AudioBuffer>,
+    sync_out: AudioBuffer,
+    sequence: Vec<Vec<Option<bool>>>,
+    #[serde(skip)]
+    step_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    sync_in: Option<(SharedSynthModule, u8)>,
+    current_step: u16,
+    transition_detector: TransitionDetector,
+    sync_transition_detector: TransitionDetector,
+    ui_dirty: bool,
+}
+
+impl From<PatternSequencerModuleV0> for PatternSequencerModuleV1 {
+    fn from(item: PatternSequencerModuleV0) -> Self {
+        let lanes = item.sequence.len();
+        let buf_size = item.sync_out.get().unwrap().len();
+        Self {
+            id: item.id,
+            gate_outs: item.gate_outs,
+            sync_out: item.sync_out,
+            velocity_outs: (0..lanes)
+                .map(|_| AudioBuffer::new(Some(buf_size)))
+                .collect(),
+            sequence: item
+                .sequence
+                .into_iter()
+                .map(|lane| lane.into_iter().map(|v| v.map(Step::new)).collect())
+                .collect(),
+            step_in: item.step_in,
+            sync_in: item.sync_in,
+            current_step: item.current_step,
+            transition_detector: item.transition_detector,
+            sync_transition_detector: item.sync_transition_detector,
+            velocities: vec![0.0; lanes],
+            ui_dirty: item.ui_dirty,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct PatternSequencerModuleV1 {
+    id: String,
+    gate_outs: Vec<AudioBuffer>,
+    sync_out: AudioBuffer,
+    velocity_outs: Vec<AudioBuffer>,
+    sequence: Vec<Vec<Option<Step>>>,
+    #[serde(skip)]
+    step_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    sync_in: Option<(SharedSynthModule, u8)>,
+    current_step: u16,
+    transition_detector: TransitionDetector,
+    sync_transition_detector: TransitionDetector,
+    velocities: Vec<ControlVoltage>,
+    ui_dirty: bool,
+}
+
+impl From<PatternSequencerModuleV1> for PatternSequencerModule {
+    fn from(item: PatternSequencerModuleV1) -> Self {
+        let buf_size = item.sync_out.get().unwrap().len();
+        let lanes = item
+            .sequence
+            .into_iter()
+            .zip(item.velocities)
+            .map(|(steps, velocity)| {
+                let mut lane = Lane::new(steps);
+                lane.position =
+                    usize::from(item.current_step).min(lane.steps.len().saturating_sub(1));
+                lane.played = lane.position;
+                lane.velocity = velocity;
+                lane
+            })
+            .collect();
+        Self {
+            id: item.id,
+            eoc_outs: (0..item.gate_outs.len())
+                .map(|_| AudioBuffer::new(Some(buf_size)))
+                .collect(),
+            gate_outs: item.gate_outs,
+            sync_out: item.sync_out,
+            velocity_outs: item.velocity_outs,
+            lanes,
+            step_in: item.step_in,
+            sync_in: item.sync_in,
+            transition_detector: item.transition_detector,
+            sync_transition_detector: item.sync_transition_detector,
+            rng: SeededRng::new(rand::random()),
+            ui_dirty: item.ui_dirty,
+            selected: None,
+            midi_loader: MidiLoader::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::{SynthModuleType, enum_to_sharedsynthmodule};
+
+    fn round_trip(module: SynthModuleType) -> SharedSynthModule {
+        let bytes = rmp_serde::to_vec(&module).unwrap();
+        enum_to_sharedsynthmodule(rmp_serde::from_slice(&bytes).unwrap())
+    }
+
+    #[test]
+    fn ratchets_and_delays_steps() {
+        let mut clock = StepClock::default();
+        let plain = Step::new(false);
+        let ratcheted = Step {
+            ratchets: 2,
+            delay: 0.5,
+            ..plain
+        };
+        // Until the step length is known, steps follow the step input
+        assert_eq!(clock.gate(&ratcheted, 0.7), 0.7);
+        for _ in 0..8 {
+            clock.tick();
+        }
+        clock.step();
+        let gates: Vec<_> = (0..8)
+            .map(|_| {
+                let gate = clock.gate(&ratcheted, 1.0);
+                clock.tick();
+                gate
+            })
+            .collect();
+        assert_eq!(gates, vec![0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 1.0, 0.0]);
+        assert_eq!(clock.gate(&plain, 0.3), 0.3);
+        assert_eq!(clock.gate(&Step::new(true), 0.0), 1.0);
+    }
+
+    #[test]
+    fn lanes_follow_direction_and_division() {
+        let mut rng = SeededRng::new(0);
+        let mut lane = Lane::new(vec![None; 3]);
+        lane.direction = Direction::PingPong;
+        let positions: Vec<_> = (0..5)
+            .map(|_| {
+                lane.advance(&mut rng);
+                (lane.position, lane.eoc)
+            })
+            .collect();
+        assert_eq!(
+            positions,
+            vec![(1, false), (2, false), (1, false), (0, true), (1, false)]
+        );
+
+        lane.direction = Direction::Reverse;
+        lane.division = 2;
+        lane.reset();
+        assert_eq!(lane.position, 2);
+        assert!(!lane.advance(&mut rng));
+        assert!(lane.advance(&mut rng));
+        assert_eq!(lane.position, 1);
+
+        assert!(!Lane::new(Vec::new()).advance(&mut rng));
+    }
+
+    #[test]
+    fn random_steps_repeat_from_the_seed() {
+        let step = Step {
+            probability: 0.5,
+            ..Step::new(false)
+        };
+        let mut rng = SeededRng::new(42);
+        let mut lane = Lane::new(vec![Some(step); 16]);
+        lane.direction = Direction::Random;
+        let mut play = |rng: &mut SeededRng| -> Vec<_> {
+            rng.reset();
+            lane.reset();
+            (0..32)
+                .map(|_| {
+                    lane.advance(rng);
+                    (lane.position, step.roll(rng))
+                })
+                .collect()
+        };
+        let first = play(&mut rng);
+        assert_eq!(play(&mut rng), first);
+        assert!(first.iter().any(|&(_, played)| played));
+        assert!(first.iter().any(|&(_, played)| !played));
+        assert!(first.iter().map(|&(position, _)| position).unique().count() > 1);
+    }
+
+    #[test]
+    fn migrates_grid_sequencers() {
+        let v0 = GridSequencerModuleV0 {
+            id: "grid".to_string(),
+            cv_out: AudioBuffer::new(Some(16)),
+            gate_out: AudioBuffer::new(Some(16)),
+            sync_out: AudioBuffer::new(Some(16)),
+            sequence: vec![Some(3), None, Some(14)],
+            octaves: 3,
+            steps_per_octave: 19,
+            step_in: None,
+            sync_in: None,
+            current_step: 1,
+            transition_detector: TransitionDetector::new(),
+            sync_transition_detector: TransitionDetector::new(),
+            last: 0.5,
+            ui_dirty: false,
+        };
+        let v1 = GridSequencerModuleV1 {
+            sequence: vec![Some((3, false)), Some((5, true))],
+            steps_per_octave: 0,
+            ..GridSequencerModuleV1::from(v0.clone())
+        };
+        let v2 = GridSequencerModuleV2 {
+            tuning: Tuning::equal_temperament(31),
+            ..GridSequencerModuleV2::from(v1.clone())
+        };
+        let rows = |grid: &GridSequencerModule| -> Vec<_> {
+            grid.sequence
+                .iter()
+                .map(|v| v.map(|(row, step)| (row, step.slide)))
+                .collect()
+        };
+
+        let module = round_trip(SynthModuleType::GridSequencerModuleV0(v0));
+        let module = module.read().unwrap();
+        let grid = module
+            .as_any()
+            .downcast_ref::<GridSequencerModule>()
+            .unwrap();
+        assert_eq!(grid.get_id(), "grid");
+        assert_eq!(rows(grid), vec![Some((3, false)), None, Some((14, false))]);
+        assert!(grid.tuning == Tuning::equal_temperament(19));
+        assert_eq!((grid.octaves, grid.current_step, grid.last), (3, 1, 0.5));
+        assert_eq!(grid.velocity_out.get().unwrap().len(), 16);
+
+        let module = round_trip(SynthModuleType::GridSequencerModuleV1(v1));
+        let module = module.read().unwrap();
+        let grid = module
+            .as_any()
+            .downcast_ref::<GridSequencerModule>()
+            .unwrap();
+        assert_eq!(rows(grid), vec![Some((3, false)), Some((5, true))]);
+        // A tuning needs at least one step
+        assert!(grid.tuning == Tuning::equal_temperament(1));
+
+        let module = round_trip(SynthModuleType::GridSequencerModuleV2(v2));
+        let module = module.read().unwrap();
+        let grid = module
+            .as_any()
+            .downcast_ref::<GridSequencerModule>()
+            .unwrap();
+        assert!(grid.tuning == Tuning::equal_temperament(31));
+        assert!(grid.sequence[1].is_some_and(|(_, step)| step == Step::new(true)));
+    }
+
+    #[test]
+    fn migrates_pattern_sequencers() {
+        let buffers = |count| (0..count).map(|_| AudioBuffer::new(Some(16))).collect();
+        let v0 = PatternSequencerModuleV0 {
+            id: "pattern".to_string(),
+            gate_outs: buffers(2),
+            sync_out: AudioBuffer::new(Some(16)),
+            sequence: vec![vec![Some(true), None, Some(false)], vec![None; 3]],
+            step_in: None,
+            sync_in: None,
+            current_step: 2,
+            transition_detector: TransitionDetector::new(),
+            sync_transition_detector: TransitionDetector::new(),
+            ui_dirty: false,
+        };
+        let v1 = PatternSequencerModuleV1 {
+            sequence: vec![Vec::new(), vec![Some(Step::new(false))]],
+            velocities: vec![0.5, 0.25],
+            ..PatternSequencerModuleV1::from(v0.clone())
+        };
+
+        let module = round_trip(SynthModuleType::PatternSequencerModuleV0(v0));
+        let module = module.read().unwrap();
+        let pattern = module
+            .as_any()
+            .downcast_ref::<PatternSequencerModule>()
+            .unwrap();
+        assert_eq!(pattern.get_id(), "pattern");
+        assert!(
+            pattern.lanes[0].steps == vec![Some(Step::new(true)), None, Some(Step::new(false))]
+        );
+        assert_eq!(pattern.lanes[1].steps.len(), 3);
+        assert!(pattern.lanes.iter().all(|lane| lane.position == 2));
+        assert_eq!(
+            (pattern.velocity_outs.len(), pattern.eoc_outs.len()),
+            (2, 2)
+        );
+
+        // An empty lane used to underflow when clamping the position
+        let module = round_trip(SynthModuleType::PatternSequencerModuleV1(v1));
+        let module = module.read().unwrap();
+        let pattern = module
+            .as_any()
+            .downcast_ref::<PatternSequencerModule>()
+            .unwrap();
+        let lanes: Vec<_> = pattern
+            .lanes
+            .iter()
+            .map(|lane| (lane.steps.len(), lane.position, lane.velocity))
+            .collect();
+        assert_eq!(lanes, vec![(0, 0, 0.5), (1, 0, 0.25)]);
+    }
+
+    #[test]
+    fn imports_and_exports_midi() {
+        let config = AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 16,
+            channels: 2,
+        };
+        let melody = MidiFile::parse(include_bytes!("fixtures/melody.mid")).unwrap();
+        let mut grid = GridSequencerModule::new(&config);
+        grid.import_midi(&melody);
+        let rows: Vec<_> = grid
+            .sequence
+            .iter()
+            .map(|v| v.map(|(row, step)| (row, step.slide)))
+            .collect();
+        // Moved up an octave, so C4 is 3 keys above A4
+        assert_eq!(
+            rows,
+            vec![
+                Some((3, false)),
+                Some((7, true)),
+                Some((7, true)),
+                Some((7, true)),
+                Some((7, false)),
+                None,
+                None,
+                Some((10, false)),
+            ]
+        );
+        let exported: Vec<_> = grid
+            .export_midi()
+            .notes
+            .iter()
+            .map(|note| (note.key, note.start, note.length))
+            .collect();
+        assert_eq!(exported, vec![(72, 0, 12), (76, 24, 84), (79, 168, 12)]);
+
+        let drums = MidiFile::parse(include_bytes!("fixtures/drums.mid")).unwrap();
+        let mut pattern = PatternSequencerModule::new(&config);
+        pattern.import_midi(&drums);
+        let kicks: Vec<_> = pattern.lanes[0]
+            .steps
+            .iter()
+            .positions(|step| step.is_some())
+            .collect();
+        assert_eq!(kicks, vec![0, 8]);
+        assert_eq!(pattern.lanes[2].steps.len(), 16);
+        assert_eq!(pattern.export_midi(), drums);
+    }
+}
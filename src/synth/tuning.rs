@@ -0,0 +1,414 @@
+use crate::ui::run_async;
+use rfd::AsyncFileDialog;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug)]
+pub struct ScalaError(String);
+impl std::fmt::Display for ScalaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Error parsing Scala file: {}", self.0)
+    }
+}
+impl Error for ScalaError {}
+
+/// Lines of a Scala file, without comments
+fn scala_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|l| l.trim_end_matches('\r'))
+        .filter(|l| !l.starts_with('!'))
+}
+
+/// A Scala pitch, either cents when it has a period or a ratio, as cents
+fn parse_pitch(line: &str) -> Result<f64, ScalaError> {
+    let value = line
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| ScalaError("missing pitch".to_string()))?;
+    let invalid = || ScalaError(format!("invalid pitch {value}"));
+    if value.contains('.') {
+        return value.parse().map_err(|_| invalid());
+    }
+    let (num, den) = match value.split_once('/') {
+        Some((num, den)) => (num, den),
+        None => (value, "1"),
+    };
+    let num: f64 = num.parse().map_err(|_| invalid())?;
+    let den: f64 = den.parse().map_err(|_| invalid())?;
+    if num <= 0.0 || den <= 0.0 {
+        return Err(invalid());
+    }
+    Ok(1200.0 * (num / den).log2())
+}
+
+/// Keyboard mapping from a Scala `.kbm` file, deciding which scale degree each
+/// key plays and the absolute pitch of the scale.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct KeyboardMapping {
+    /// Scale degree for each key in the repeating pattern, None for unmapped
+    /// keys. Empty for a linear mapping, one key per degree.
+    keys: Vec<Option<usize>>,
+    middle_note: i32,
+    reference_note: i32,
+    reference_freq: f64,
+    /// Scale degree which the pattern repeats at
+    octave_degree: usize,
+}
+
+impl KeyboardMapping {
+    pub fn parse(text: &str) -> Result<Self, ScalaError> {
+        let mut lines = scala_lines(text).map(|l| l.trim());
+        let mut next = |what: &str| {
+            lines
+                .next()
+                .map(|l| l.split_whitespace().next().unwrap_or("").to_string())
+                .ok_or_else(|| ScalaError(format!("missing {what}")))
+        };
+        let int = |value: String, what: &str| {
+            value
+                .parse::<i64>()
+                .map_err(|_| ScalaError(format!("invalid {what}")))
+        };
+        let map_size = int(next("map size")?, "map size")? as usize;
+        // the first and last notes to retune don't matter for control voltages
+        next("first note")?;
+        next("last note")?;
+        let middle_note = int(next("middle note")?, "middle note")? as i32;
+        let reference_note = int(next("reference note")?, "reference note")? as i32;
+        let reference_freq: f64 = next("reference frequency")?
+            .parse()
+            .map_err(|_| ScalaError("invalid reference frequency".to_string()))?;
+        let octave_degree = int(next("octave degree")?, "octave degree")? as usize;
+        let mut keys = vec![];
+        for _ in 0..map_size {
+            // missing entries at the end are unmapped
+            let entry = next("mapping").unwrap_or("x".to_string());
+            keys.push(match entry.as_str() {
+                "x" | "X" => None,
+                degree => Some(int(degree.to_string(), "mapping")? as usize),
+            });
+        }
+        if reference_freq <= 0.0 {
+            return Err(ScalaError("invalid reference frequency".to_string()));
+        }
+        Ok(Self {
+            keys,
+            middle_note,
+            reference_note,
+            reference_freq,
+            octave_degree,
+        })
+    }
+}
+
+/// A repeating scale, as described by a Scala `.scl` file, with an optional
+/// keyboard mapping.
+///
+/// Pitches are control voltages, one volt per octave, where 0.0 is the root.
+/// Without a keyboard mapping the root is at 0.0, A 440 Hz. Keys are the rows
+/// of a sequencer or notes of a keyboard, counted from the root.
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub struct Tuning {
+    pub name: String,
+    /// Pitch of each degree above the root in cents, starting with 0.0
+    degrees: Vec<f64>,
+    /// Size of the repeating period in cents, usually an octave
+    period: f64,
+    mapping: Option<KeyboardMapping>,
+}
+
+impl Default for Tuning {
+    fn default() -> Self {
+        Self::equal_temperament(12)
+    }
+}
+
+impl Tuning {
+    pub fn equal_temperament(steps: usize) -> Self {
+        Self {
+            name: format!("{steps}-TET"),
+            degrees: (0..steps)
+                .map(|s| s as f64 * 1200.0 / steps as f64)
+                .collect(),
+            period: 1200.0,
+            mapping: None,
+        }
+    }
+
+    pub fn parse_scl(text: &str) -> Result<Self, ScalaError> {
+        let mut lines = scala_lines(text);
+        let name = lines
+            .next()
+            .ok_or_else(|| ScalaError("missing description".to_string()))?
+            .trim()
+            .to_string();
+        let count: usize = lines
+            .next()
+            .and_then(|l| l.split_whitespace().next())
+            .and_then(|l| l.parse().ok())
+            .ok_or_else(|| ScalaError("missing note count".to_string()))?;
+        let pitches = lines
+            .map(|l| l.trim())
+            .take(count)
+            .map(parse_pitch)
+            .collect::<Result<Vec<f64>, _>>()?;
+        if pitches.len() != count || count == 0 {
+            return Err(ScalaError("wrong number of notes".to_string()));
+        }
+        let period = pitches[count - 1];
+        if period <= 0.0 {
+            return Err(ScalaError("period must be above the root".to_string()));
+        }
+        let mut degrees = vec![0.0];
+        degrees.extend_from_slice(&pitches[..count - 1]);
+        Ok(Self {
+            name: if name.is_empty() {
+                format!("{count} note scale")
+            } else {
+                name
+            },
+            degrees,
+            period,
+            mapping: None,
+        })
+    }
+
+    pub fn set_mapping(&mut self, mapping: Option<KeyboardMapping>) {
+        self.mapping = mapping;
+    }
+
+    pub fn has_mapping(&self) -> bool {
+        self.mapping.is_some()
+    }
+
+    pub fn is_12tet(&self) -> bool {
+        *self == Self::equal_temperament(12)
+    }
+
+    /// Number of degrees in one period
+    pub fn len(&self) -> usize {
+        self.degrees.len()
+    }
+
+    /// Number of keys before the mapping repeats
+    pub fn keys_per_period(&self) -> usize {
+        match &self.mapping {
+            Some(mapping) if !mapping.keys.is_empty() => mapping.keys.len(),
+            _ => self.len(),
+        }
+    }
+
+    /// Pitch of any degree above the root, in cents, continuing into
+    /// following periods
+    fn degree_cents(&self, degree: i64) -> f64 {
+        let len = self.len() as i64;
+        degree.div_euclid(len) as f64 * self.period + self.degrees[degree.rem_euclid(len) as usize]
+    }
+
+    /// Scale degree played by a key, or None if the key is unmapped
+    pub fn key_degree(&self, key: i64) -> Option<i64> {
+        match &self.mapping {
+            Some(mapping) if !mapping.keys.is_empty() => {
+                let size = mapping.keys.len() as i64;
+                let degree = mapping.keys[key.rem_euclid(size) as usize]? as i64;
+                Some(key.div_euclid(size) * mapping.octave_degree as i64 + degree)
+            }
+            _ => Some(key),
+        }
+    }
+
+    /// Pitch of the root in volts, set by the keyboard mapping's reference
+    /// frequency
+    pub fn root(&self) -> f64 {
+        match &self.mapping {
+            Some(mapping) => {
+                let reference_cents = self
+                    .key_degree((mapping.reference_note - mapping.middle_note) as i64)
+                    .map(|d| self.degree_cents(d))
+                    .unwrap_or(0.0);
+                (mapping.reference_freq / 440.0).log2() - reference_cents / 1200.0
+            }
+            None => 0.0,
+        }
+    }
+
+    /// Pitch of a key in volts, or None if the key is unmapped
+    pub fn key_pitch(&self, key: i64) -> Option<f64> {
+        self.key_degree(key)
+            .map(|d| self.root() + self.degree_cents(d) / 1200.0)
+    }
+
+    /// Pitches of one period of keys relative to the root, in volts, with the
+    /// period they repeat at. Unmapped keys are left out.
+    pub fn period_pitches(&self) -> (Vec<f64>, f64) {
+        let period = match &self.mapping {
+            Some(mapping) if !mapping.keys.is_empty() => {
+                self.degree_cents(mapping.octave_degree as i64) / 1200.0
+            }
+            _ => self.period / 1200.0,
+        };
+        let mut pitches: Vec<f64> = (0..self.keys_per_period() as i64)
+            .filter_map(|key| self.key_degree(key))
+            .map(|d| (self.degree_cents(d) / 1200.0).rem_euclid(period))
+            .collect();
+        pitches.sort_by(|a, b| a.total_cmp(b));
+        pitches.dedup();
+        (pitches, period)
+    }
+
+    /// Description of a key for display, such as "3: 386.3¢"
+    pub fn key_label(&self, key: i64) -> String {
+        match self.key_degree(key) {
+            Some(degree) => {
+                let len = self.len() as i64;
+                format!(
+                    "{}: {:.1}¢",
+                    degree.rem_euclid(len),
+                    self.degrees[degree.rem_euclid(len) as usize]
+                )
+            }
+            None => "unmapped".to_string(),
+        }
+    }
+}
+
+/// Whether a key plays closest to a black key of a 12-TET keyboard
+pub fn is_black_key(tuning: &Tuning, key: i64) -> bool {
+    match tuning.key_pitch(key) {
+        Some(pitch) => {
+            let semitone = ((pitch - tuning.root()) * 12.0).round() as i64;
+            matches!(semitone.rem_euclid(12), 1 | 3 | 6 | 8 | 10)
+        }
+        None => false,
+    }
+}
+
+/// A Scala file chosen by the user
+pub enum TuningFile {
+    Scale(Tuning),
+    Mapping(KeyboardMapping),
+}
+
+/// Loads Scala files in the background for a module to pick up from its UI.
+#[derive(Default, Clone)]
+pub struct TuningLoader(Arc<Mutex<Option<TuningFile>>>);
+
+impl TuningLoader {
+    /// Show a file dialog for `.scl` and `.kbm` files
+    pub fn open(&self) {
+        let loaded = self.0.clone();
+        run_async(async move {
+            let file = AsyncFileDialog::new()
+                .add_filter("Scala", &["scl", "kbm"])
+                .pick_file()
+                .await;
+            if let Some(file) = file {
+                let data = file.read().await;
+                let text = String::from_utf8_lossy(&data);
+                let parsed = if file.file_name().to_lowercase().ends_with(".kbm") {
+                    KeyboardMapping::parse(&text).map(TuningFile::Mapping)
+                } else {
+                    Tuning::parse_scl(&text).map(TuningFile::Scale)
+                };
+                match parsed {
+                    Ok(parsed) => *loaded.lock().unwrap() = Some(parsed),
+                    Err(e) => println!("{e}"),
+                }
+            }
+        });
+    }
+
+    pub fn take(&self) -> Option<TuningFile> {
+        self.0.try_lock().ok()?.take()
+    }
+}
+
+/// Name of the tuning with buttons to load Scala files or go back to 12-TET.
+/// Returns true when the tuning changed.
+pub fn tuning_ui(ui: &mut egui::Ui, tuning: &mut Tuning, loader: &TuningLoader) -> bool {
+    let mut changed = false;
+    match loader.take() {
+        Some(TuningFile::Scale(scale)) => {
+            *tuning = scale;
+            changed = true;
+        }
+        Some(TuningFile::Mapping(mapping)) => {
+            tuning.set_mapping(Some(mapping));
+            changed = true;
+        }
+        None => {}
+    }
+    ui.horizontal(|ui| {
+        ui.label("Tuning: ");
+        ui.label(&tuning.name);
+        if tuning.has_mapping() {
+            ui.label("(mapped)");
+        }
+        if ui.button("Load .scl/.kbm").clicked() {
+            loader.open();
+        }
+        ui.scope(|ui| {
+            if tuning.is_12tet() {
+                ui.disable();
+            }
+            if ui.button("12-TET").clicked() {
+                *tuning = Tuning::default();
+                changed = true;
+            }
+        });
+    });
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const JUST_MAJOR: &str = "! just.scl
+!
+5-limit just major
+ 7
+!
+ 9/8
+ 5/4
+ 4/3
+ 3/2
+ 5/3
+ 15/8
+ 2/1
+";
+
+    #[test]
+    fn parses_scl() {
+        let tuning = Tuning::parse_scl(JUST_MAJOR).unwrap();
+        assert_eq!(tuning.name, "5-limit just major");
+        assert_eq!(tuning.len(), 7);
+        assert!((tuning.period - 1200.0).abs() < 0.0001);
+        assert!((tuning.degrees[2] - 386.3137).abs() < 0.001);
+        assert!((tuning.key_pitch(7).unwrap() - 1.0).abs() < 0.0001);
+        assert!((tuning.key_pitch(-3).unwrap() - ((3.0_f64 / 2.0).log2() - 1.0)).abs() < 0.0001);
+        let cents = Tuning::parse_scl("cents\n2\n100.0 semitone\n1200.\n").unwrap();
+        assert_eq!(cents.degrees, vec![0.0, 100.0]);
+        assert!(Tuning::parse_scl("broken\n3\n9/8\n").is_err());
+    }
+
+    #[test]
+    fn parses_kbm() {
+        // white keys only, C as the root, with A at 440 Hz
+        let mapping = KeyboardMapping::parse(
+            "! white.kbm\n12\n0\n127\n60\n69\n440.0\n7\n0\nx\n1\nx\n2\n3\nx\n4\nx\n5\nx\n6\n",
+        )
+        .unwrap();
+        let mut tuning = Tuning::parse_scl(JUST_MAJOR).unwrap();
+        tuning.set_mapping(Some(mapping));
+        assert_eq!(tuning.keys_per_period(), 12);
+        assert_eq!(tuning.key_pitch(1), None);
+        // A is the just major sixth above C
+        assert!((tuning.key_pitch(9).unwrap()).abs() < 0.0001);
+        assert!((tuning.root() + (5.0_f64 / 3.0).log2()).abs() < 0.0001);
+        let (pitches, period) = tuning.period_pitches();
+        assert_eq!(pitches.len(), 7);
+        assert!((period - 1.0).abs() < 0.0001);
+    }
+}
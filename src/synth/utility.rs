@@ -0,0 +1,462 @@
+use super::midi_learn::learnable;
+use super::{
+    AudioBuffer, AudioConfig, ControlVoltage, Param, ParamValue, SharedSynthModule, SynthModule,
+    TransitionDetector,
+};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+
+const RISE_PARAM: &str = "rise";
+const FALL_PARAM: &str = "fall";
+
+/// Shortest slew time, in seconds
+const MIN_SLEW_SEC: f32 = 0.0001;
+/// Longest slew time, in seconds
+const MAX_SLEW_SEC: f32 = 10.0;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+pub enum HoldMode {
+    /// Take the input on each rising edge of the trigger
+    Sample,
+    /// Follow the input while the gate is high, hold it while low
+    Track,
+}
+
+/// Sample & hold and track & hold.
+///
+/// Without a signal connected, white noise is sampled instead, for stepped
+/// random voltages.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HoldModule {
+    id: String,
+    #[serde(skip)]
+    signal_in: Option<(SharedSynthModule, u8)>,
+    #[serde(skip)]
+    trigger_in: Option<(SharedSynthModule, u8)>,
+    buf: AudioBuffer,
+    held: ControlVoltage,
+    mode: HoldMode,
+    transition_detector: TransitionDetector,
+}
+
+impl HoldModule {
+    pub fn new(audio_config: &AudioConfig, mode: HoldMode) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            signal_in: None,
+            trigger_in: None,
+            buf: AudioBuffer::new(Some(audio_config.buffer_size)),
+            held: 0.0,
+            mode,
+            transition_detector: TransitionDetector::new(),
+        }
+    }
+
+    pub fn get_name(mode: &HoldMode) -> String {
+        match mode {
+            HoldMode::Sample => "Sample & Hold".to_string(),
+            HoldMode::Track => "Track & Hold".to_string(),
+        }
+    }
+
+    #[inline]
+    fn process(
+        &mut self,
+        signal: Option<ControlVoltage>,
+        trigger: ControlVoltage,
+    ) -> ControlVoltage {
+        let signal = || signal.unwrap_or_else(|| (rand::random::<f32>() - 0.5) * 2.0);
+        let is_transition = self.transition_detector.is_transition(&trigger);
+        match self.mode {
+            HoldMode::Sample => {
+                if is_transition {
+                    self.held = signal();
+                }
+            }
+            HoldMode::Track => {
+                if TransitionDetector::is_above_threshold(&trigger) {
+                    self.held = signal();
+                }
+            }
+        }
+        self.held
+    }
+}
+
+impl SynthModule for HoldModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name(&self.mode)
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.buf.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        2
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.signal_in.clone()),
+            1 => Ok(self.trigger_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.signal_in = Some((src_module, src_port));
+                Ok(())
+            }
+            1 => {
+                self.trigger_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.signal_in = None;
+                Ok(())
+            }
+            1 => {
+                self.trigger_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match (input_idx, &self.mode) {
+            (0, _) => Ok(Some("In".to_string())),
+            (1, HoldMode::Sample) => Ok(Some("Trigger".to_string())),
+            (1, HoldMode::Track) => Ok(Some("Gate".to_string())),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        1
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.buf.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        AudioBuffer::with_read_many(
+            vec![
+                self.resolve_input(0).unwrap(),
+                self.resolve_input(1).unwrap(),
+            ],
+            |bufs| {
+                let (signal_in, trigger_in) = bufs.into_iter().collect_tuple().unwrap();
+                let buf = self.buf.clone();
+                buf.with_write(|output| {
+                    let output = output.unwrap();
+                    for idx in 0..output.len() {
+                        output[idx] = self.process(
+                            signal_in.map(|buf| buf[idx]),
+                            trigger_in.map(|buf| buf[idx]).unwrap_or(0.0),
+                        );
+                    }
+                });
+            },
+        );
+    }
+
+    fn ui(&mut self, _ui: &mut egui::Ui) {}
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq)]
+pub enum SlewShape {
+    /// Move at a constant rate, the time being for a change of 1V
+    Linear,
+    /// Move a fraction of the remaining distance, the time being the time
+    /// constant
+    Exponential,
+}
+
+impl SlewShape {
+    fn label(&self) -> &'static str {
+        match self {
+            SlewShape::Linear => "Lin",
+            SlewShape::Exponential => "Exp",
+        }
+    }
+}
+
+/// Slew limiter with separate rise and fall times, for glide and smoothing.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SlewModule {
+    id: String,
+    #[serde(skip)]
+    signal_in: Option<(SharedSynthModule, u8)>,
+    buf: AudioBuffer,
+    rise_sec: f32,
+    fall_sec: f32,
+    shape: SlewShape,
+    current: ControlVoltage,
+    sample_rate: f32,
+}
+
+impl SlewModule {
+    pub fn new(audio_config: &AudioConfig) -> Self {
+        Self {
+            id: uuid::Uuid::new_v4().to_string(),
+            signal_in: None,
+            buf: AudioBuffer::new(Some(audio_config.buffer_size)),
+            rise_sec: 0.1,
+            fall_sec: 0.1,
+            shape: SlewShape::Linear,
+            current: 0.0,
+            sample_rate: audio_config.sample_rate as f32,
+        }
+    }
+
+    pub fn get_name() -> String {
+        "Slew".to_string()
+    }
+
+    #[inline]
+    fn process(&mut self, target: ControlVoltage) -> ControlVoltage {
+        let sec = if target > self.current {
+            self.rise_sec
+        } else {
+            self.fall_sec
+        };
+        let samples = sec.max(MIN_SLEW_SEC) * self.sample_rate;
+        match self.shape {
+            SlewShape::Linear => {
+                let step = 1.0 / samples;
+                self.current += (target - self.current).clamp(-step, step);
+            }
+            SlewShape::Exponential => {
+                self.current += (target - self.current) * (1.0 - (-1.0 / samples).exp());
+            }
+        }
+        self.current
+    }
+}
+
+impl SynthModule for SlewModule {
+    fn get_id(&self) -> String {
+        self.id.clone()
+    }
+
+    fn get_name(&self) -> String {
+        Self::get_name()
+    }
+
+    fn set_audio_config(&mut self, audio_config: &AudioConfig) {
+        self.sample_rate = audio_config.sample_rate as f32;
+        self.buf.resize(audio_config.buffer_size);
+    }
+
+    fn get_num_inputs(&self) -> u8 {
+        1
+    }
+
+    fn get_input(&self, input_idx: u8) -> Result<Option<(SharedSynthModule, u8)>, ()> {
+        match input_idx {
+            0 => Ok(self.signal_in.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn set_input(
+        &mut self,
+        input_idx: u8,
+        src_module: SharedSynthModule,
+        src_port: u8,
+    ) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.signal_in = Some((src_module, src_port));
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn disconnect_input(&mut self, input_idx: u8) -> Result<(), ()> {
+        match input_idx {
+            0 => {
+                self.signal_in = None;
+                Ok(())
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn get_input_label(&self, input_idx: u8) -> Result<Option<String>, ()> {
+        match input_idx {
+            0 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    fn get_num_outputs(&self) -> u8 {
+        1
+    }
+
+    fn get_output(&self, output_idx: u8) -> Result<AudioBuffer, ()> {
+        match output_idx {
+            0 => Ok(self.buf.clone()),
+            _ => Err(()),
+        }
+    }
+
+    fn get_output_label(&self, output_idx: u8) -> Result<Option<String>, ()> {
+        match output_idx {
+            0 => Ok(None),
+            _ => Err(()),
+        }
+    }
+
+    fn calc(&mut self) {
+        let input = self.resolve_input(0).unwrap();
+        let buf = self.buf.clone();
+        AudioBuffer::with_read_many(vec![input], |bufs| {
+            let signal_in = bufs[0];
+            buf.with_write(|output| {
+                let output = output.unwrap();
+                for idx in 0..output.len() {
+                    output[idx] = self.process(signal_in.map(|buf| buf[idx]).unwrap_or(0.0));
+                }
+            });
+        });
+    }
+
+    fn ui(&mut self, ui: &mut egui::Ui) {
+        ui.horizontal(|ui| {
+            for (value, label, param_id) in [
+                (&mut self.rise_sec, "Rise", RISE_PARAM),
+                (&mut self.fall_sec, "Fall", FALL_PARAM),
+            ] {
+                ui.vertical(|ui| {
+                    let response = ui
+                        .add(
+                            egui::Slider::new(value, 0.0..=MAX_SLEW_SEC)
+                                .logarithmic(true)
+                                .smallest_positive(MIN_SLEW_SEC as f64)
+                                .show_value(false)
+                                .orientation(egui::SliderOrientation::Vertical),
+                        )
+                        .on_hover_text(format!("{value:.4}"));
+                    learnable(response, &self.id, param_id);
+                    ui.label(label);
+                });
+            }
+            ui.vertical(|ui| {
+                for shape in [SlewShape::Linear, SlewShape::Exponential] {
+                    ui.selectable_value(&mut self.shape, shape, shape.label());
+                }
+            });
+        });
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn params(&mut self) -> Vec<(Param, ParamValue<'_>)> {
+        vec![
+            (
+                Param::new(RISE_PARAM, "Rise", 0.0..=MAX_SLEW_SEC),
+                ParamValue::F32(&mut self.rise_sec),
+            ),
+            (
+                Param::new(FALL_PARAM, "Fall", 0.0..=MAX_SLEW_SEC),
+                ParamValue::F32(&mut self.fall_sec),
+            ),
+        ]
+    }
+
+    fn param_value(&mut self, idx: usize) -> Option<ParamValue<'_>> {
+        Some(match idx {
+            0 => ParamValue::F32(&mut self.rise_sec),
+            1 => ParamValue::F32(&mut self.fall_sec),
+            _ => return None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slews_at_rise_and_fall_rates() {
+        let mut slew = SlewModule::new(&AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 16,
+            channels: 2,
+        });
+        slew.rise_sec = 0.1;
+        slew.fall_sec = 0.01;
+        for _ in 0..50 {
+            slew.process(1.0);
+        }
+        assert!((slew.current - 0.5).abs() < 0.0001);
+        for _ in 0..60 {
+            slew.process(1.0);
+        }
+        assert_eq!(slew.current, 1.0);
+        for _ in 0..5 {
+            slew.process(0.0);
+        }
+        assert!((slew.current - 0.5).abs() < 0.0001);
+    }
+
+    #[test]
+    fn samples_on_rising_edge() {
+        let config = AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 16,
+            channels: 2,
+        };
+        let mut sample = HoldModule::new(&config, HoldMode::Sample);
+        let mut track = HoldModule::new(&config, HoldMode::Track);
+        let signal = [1.0, 2.0, 3.0, 4.0, 5.0];
+        let gate = [0.0, 1.0, 1.0, 0.0, 0.0];
+        let sampled: Vec<_> = (0..5)
+            .map(|i| sample.process(Some(signal[i]), gate[i]))
+            .collect();
+        let tracked: Vec<_> = (0..5)
+            .map(|i| track.process(Some(signal[i]), gate[i]))
+            .collect();
+        assert_eq!(sampled, vec![0.0, 2.0, 2.0, 2.0, 2.0]);
+        assert_eq!(tracked, vec![0.0, 2.0, 3.0, 3.0, 3.0]);
+    }
+}
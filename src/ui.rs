@@ -1,3 +1,4 @@
+use crate::synth::midi_learn::{self, MidiMapping, MidiMappings};
 use crate::synth::{self, SharedSynthModule};
 use by_address::ByAddress;
 use egui::{self, pos2};
@@ -8,10 +9,12 @@ use std::collections::HashMap;
 use std::error::Error;
 use std::future::Future;
 use std::io::Cursor;
-use std::sync::{Arc, Mutex, RwLock};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
 
 const SYNTH_HANDLE_SIZE: f32 = 10.0;
 const SYNTH_HANDLE_PADDING: f32 = 2.0;
+/// How often to check for a CC while waiting to MIDI learn
+const LEARN_POLL: std::time::Duration = std::time::Duration::from_millis(50);
 
 enum SynthModulePort {
     Input(synth::SharedSynthModule, u8),
@@ -57,6 +60,9 @@ pub struct SynthModuleWorkspaceImpl {
     pub plan: Arc<Mutex<Vec<synth::SharedSynthModule>>>,
     pub output: Arc<Mutex<Option<synth::SharedSynthModule>>>,
     pub audio_config: Option<synth::AudioConfig>,
+    pub midi_mappings: Arc<Mutex<MidiMappings>>,
+    learner: midi_learn::Learner,
+    pub faults: Arc<Mutex<synth::Faults>>,
 }
 
 impl SynthModuleWorkspaceImpl {
@@ -78,9 +84,17 @@ impl SynthModuleWorkspaceImpl {
                 *output_ref = None;
             }
         }
+        self.resolve_midi_mappings();
         println!("end plan");
     }
 
+    /// Point the MIDI mappings at the modules being played
+    fn resolve_midi_mappings(&self) {
+        // locked in the same order as the audio thread
+        let plan = self.plan.lock().unwrap();
+        self.midi_mappings.lock().unwrap().resolve(&plan);
+    }
+
     fn find_output(&self) -> Result<synth::SharedSynthModule, ()> {
         for module in self.modules.iter() {
             if let Some(_) = module
@@ -95,10 +109,51 @@ impl SynthModuleWorkspaceImpl {
         Err(())
     }
 
+    /// Map the first CC received to the parameter waiting for MIDI learn, and
+    /// remove mappings asked to be forgotten
+    fn midi_learn(&self, ctx: &egui::Context) {
+        if let Some((module_id, param_id)) = midi_learn::learning(ctx) {
+            if let Some((channel, controller)) = self.learner.take() {
+                // look the parameter up before locking the mappings, which
+                // the audio thread locks before the modules
+                let param = self.modules.iter().find_map(|module| {
+                    let mut module = module.write().unwrap();
+                    if module.get_id() != module_id {
+                        return None;
+                    }
+                    module
+                        .params()
+                        .into_iter()
+                        .map(|(param, _)| param)
+                        .find(|param| param.id == param_id)
+                });
+                if let Some(param) = param {
+                    self.midi_mappings
+                        .lock()
+                        .unwrap()
+                        .learn(&module_id, &param, channel, controller);
+                    self.resolve_midi_mappings();
+                }
+                midi_learn::set_learning(ctx, None);
+            }
+            ctx.request_repaint_after(LEARN_POLL);
+        } else {
+            // drop CCs received while not learning
+            self.learner.take();
+        }
+        if let Some((module_id, param_id)) = midi_learn::take_forget(ctx) {
+            self.midi_mappings
+                .lock()
+                .unwrap()
+                .forget(&module_id, Some(&param_id));
+        }
+    }
+
     fn serialize(&self, ctx: egui::Context, id: &egui::Id) -> Vec<u8> {
         let mut container = FileFormat::default();
         let mut buf: Vec<u8> = Vec::new();
         container.capture_modules(&self.modules);
+        container.midi_mappings = self.midi_mappings.lock().unwrap().mappings.clone();
         container.capture_connections(&self.modules);
         container.capture_pos(&self.modules, |module_id| {
             let module_id = id.with(("module", module_id, self.loads));
@@ -121,6 +176,7 @@ impl SynthModuleWorkspaceImpl {
         }
         self.loads += 1;
         self.modules.clear();
+        self.faults.lock().unwrap().clear_all();
         let reader = Cursor::new(buf);
         let mut container = FileFormat::deserialize(&mut Deserializer::new(reader))?;
         self.modules_pos.clear();
@@ -129,6 +185,7 @@ impl SynthModuleWorkspaceImpl {
         });
         container.unpack_modules(&mut self.modules, self.audio_config.as_ref().unwrap());
         container.unpack_connections(&mut self.modules)?;
+        self.midi_mappings.lock().unwrap().mappings = std::mem::take(&mut container.midi_mappings);
         self.plan();
         Ok(())
     }
@@ -147,6 +204,9 @@ impl SynthModuleWorkspace {
                 plan: Arc::new(Mutex::new(vec![])),
                 output: Arc::new(Mutex::new(None)),
                 audio_config: None,
+                midi_mappings: Arc::new(Mutex::new(MidiMappings::default())),
+                learner: midi_learn::Learner::default(),
+                faults: Arc::new(Mutex::new(synth::Faults::default())),
                 loads: 0,
             })),
             None,
@@ -171,6 +231,13 @@ impl SynthModuleWorkspace {
 
     pub fn delete_module(&self, module: synth::SharedSynthModule) {
         let mut workspace = self.0.write().unwrap();
+        let module_id = module.read().unwrap().get_id();
+        workspace
+            .midi_mappings
+            .lock()
+            .unwrap()
+            .forget(&module_id, None);
+        workspace.faults.lock().unwrap().clear(&module_id);
         // first, disconnect any inputs connected to this module
         for module_ref in workspace.modules.iter() {
             let mut other_module = module_ref.write().unwrap();
@@ -206,6 +273,67 @@ impl SynthModuleWorkspace {
         workspace.plan.clone()
     }
 
+    pub fn get_modules(&self) -> Vec<synth::SharedSynthModule> {
+        let workspace = self.0.read().unwrap();
+        workspace.modules.clone()
+    }
+
+    pub fn find_module(&self, id: &str) -> Option<synth::SharedSynthModule> {
+        let workspace = self.0.read().unwrap();
+        workspace
+            .modules
+            .iter()
+            .find(|module| {
+                module
+                    .read()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .get_id()
+                    == id
+            })
+            .cloned()
+    }
+
+    /// Connect an output to an input, replacing whatever was connected to it
+    pub fn connect(
+        &self,
+        src: &synth::SharedSynthModule,
+        src_port: u8,
+        sink: &synth::SharedSynthModule,
+        sink_port: u8,
+    ) -> Result<(), ()> {
+        if src_port
+            >= src
+                .read()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get_num_outputs()
+        {
+            return Err(());
+        }
+        sink.write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .set_input(sink_port, src.clone(), src_port)?;
+        self.0.write().unwrap().plan();
+        Ok(())
+    }
+
+    pub fn disconnect(&self, sink: &synth::SharedSynthModule, sink_port: u8) -> Result<(), ()> {
+        sink.write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .disconnect_input(sink_port)?;
+        self.0.write().unwrap().plan();
+        Ok(())
+    }
+
+    pub fn get_midi_mappings(&self) -> Arc<Mutex<MidiMappings>> {
+        let workspace = self.0.read().unwrap();
+        workspace.midi_mappings.clone()
+    }
+
+    pub fn get_faults(&self) -> Arc<Mutex<synth::Faults>> {
+        let workspace = self.0.read().unwrap();
+        workspace.faults.clone()
+    }
+
     pub fn open(&mut self) {
         let inner_workspace = self.0.clone();
         run_async(async move {
@@ -280,6 +408,8 @@ impl SynthModuleWorkspace {
             }
         }
 
+        workspace.midi_learn(ui.ctx());
+
         let mut hover_wire: Option<(SharedSynthModule, u8, SharedSynthModule, u8)> = None;
 
         for module_ref in workspace.modules.iter() {
@@ -364,6 +494,25 @@ impl SynthModuleWorkspace {
                                             });
                                         }
                                     });
+                                    let fault = workspace
+                                        .faults
+                                        .lock()
+                                        .unwrap()
+                                        .get(&module.get_id())
+                                        .cloned();
+                                    if let Some(fault) = fault {
+                                        ui.colored_label(
+                                            egui::Color32::RED,
+                                            format!("Disabled: {}", fault),
+                                        );
+                                        if ui.button("Re-enable").clicked() {
+                                            workspace
+                                                .faults
+                                                .lock()
+                                                .unwrap()
+                                                .clear(&module.get_id());
+                                        }
+                                    }
                                     module.ui(ui);
                                 });
                             });
@@ -573,6 +722,78 @@ impl SynthModuleWorkspace {
             self.delete_module(to_delete.unwrap());
         }
     }
+
+    /// Overview of the MIDI mappings, to adjust their range and soft takeover
+    /// or remove them
+    pub fn midi_mappings_ui(&self, ui: &mut egui::Ui) {
+        let workspace = self.0.read().unwrap();
+        let mappings = workspace.midi_mappings.lock().unwrap().mappings.clone();
+        if mappings.is_empty() {
+            ui.label("Right-click a control and pick MIDI learn, then move a knob or fader");
+            return;
+        }
+        // module and parameter names, looked up without holding the mappings
+        let names: Vec<(String, String)> = mappings
+            .iter()
+            .map(|mapping| {
+                workspace
+                    .modules
+                    .iter()
+                    .find_map(|module| {
+                        let mut module = module.write().unwrap();
+                        if module.get_id() != mapping.module_id {
+                            return None;
+                        }
+                        let label = module
+                            .params()
+                            .into_iter()
+                            .find(|(param, _)| param.id == mapping.param_id)
+                            .map_or(mapping.param_id.clone(), |(param, _)| param.label);
+                        Some((module.get_name(), label))
+                    })
+                    .unwrap_or(("Missing".to_string(), mapping.param_id.clone()))
+            })
+            .collect();
+
+        let mut edited = mappings.clone();
+        let mut to_remove = None;
+        egui::Grid::new("midi mappings")
+            .striped(true)
+            .show(ui, |ui| {
+                for header in ["Module", "Control", "MIDI", "Min", "Max", "Soft takeover"] {
+                    ui.strong(header);
+                }
+                ui.end_row();
+                for (idx, (mapping, (module, param))) in edited.iter_mut().zip(names).enumerate() {
+                    ui.label(module);
+                    ui.label(param);
+                    ui.label(format!(
+                        "Ch {} CC {}",
+                        mapping.channel + 1,
+                        mapping.controller
+                    ));
+                    ui.add(egui::DragValue::new(&mut mapping.min).speed(0.01));
+                    ui.add(egui::DragValue::new(&mut mapping.max).speed(0.01));
+                    ui.checkbox(&mut mapping.soft_takeover, "");
+                    if ui.button("Remove").clicked() {
+                        to_remove = Some(idx);
+                    }
+                    ui.end_row();
+                }
+            });
+
+        if edited != mappings || to_remove.is_some() {
+            let mut live = workspace.midi_mappings.lock().unwrap();
+            for (mapping, edit) in live.mappings.iter_mut().zip(edited) {
+                mapping.min = edit.min;
+                mapping.max = edit.max;
+                mapping.soft_takeover = edit.soft_takeover;
+            }
+            if let Some(idx) = to_remove {
+                live.mappings.remove(idx);
+            }
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Default)]
@@ -583,6 +804,10 @@ struct FileFormat {
 
     /// List of workspace positions for modules by id
     positions: Vec<(String, (f32, f32))>,
+
+    /// MIDI CCs mapped to module parameters
+    #[serde(default)]
+    midi_mappings: Vec<MidiMapping>,
 }
 
 impl FileFormat {
@@ -690,3 +915,58 @@ pub fn run_async<F: Future<Output = ()> + Send + 'static>(f: F) {
 pub fn run_async<F: Future<Output = ()> + 'static>(f: F) {
     wasm_bindgen_futures::spawn_local(f);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::synth::SynthModule;
+    use crate::synth::mixer::MonoMixerModule;
+
+    #[test]
+    fn mixer_channels_and_connections_reload() {
+        let config = synth::AudioConfig {
+            sample_rate: 1000,
+            buffer_size: 8,
+            channels: 2,
+        };
+        let source: SharedSynthModule = Arc::new(RwLock::new(MonoMixerModule::new(&config)));
+        let mut mixer = MonoMixerModule::new(&config);
+        mixer.set_num_channels(6);
+        // audio of the last channel and CV of the third
+        mixer.set_input(5, source.clone(), 0).unwrap();
+        mixer.set_input(8, source.clone(), 0).unwrap();
+        let mixer_id = mixer.get_id();
+        let modules: Vec<SharedSynthModule> = vec![source.clone(), Arc::new(RwLock::new(mixer))];
+
+        let mut container = FileFormat::default();
+        container.capture_modules(&modules);
+        container.capture_connections(&modules);
+        let mut buf = vec![];
+        container.serialize(&mut Serializer::new(&mut buf)).unwrap();
+        let mut container =
+            FileFormat::deserialize(&mut Deserializer::new(Cursor::new(buf))).unwrap();
+        let mut loaded = vec![];
+        container.unpack_modules(&mut loaded, &config);
+        container.unpack_connections(&loaded).unwrap();
+
+        let mixer = loaded
+            .iter()
+            .find(|module| module.read().unwrap().get_id() == mixer_id)
+            .unwrap()
+            .read()
+            .unwrap();
+        assert_eq!(mixer.get_num_inputs(), 12);
+        let source_id = source.read().unwrap().get_id();
+        let connected: Vec<_> = synth::get_inputs(&*mixer)
+            .into_iter()
+            .enumerate()
+            .filter_map(|(idx, input)| {
+                input.map(|(module, port)| (idx, module.read().unwrap().get_id(), port))
+            })
+            .collect();
+        assert_eq!(
+            connected,
+            vec![(5, source_id.clone(), 0), (8, source_id, 0)]
+        );
+    }
+}